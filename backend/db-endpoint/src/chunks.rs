@@ -0,0 +1,433 @@
+use crate::embeddings::{text_hash, EmbeddingError, VectorField};
+use crate::indexes::{vector_index, ExpectedIndex};
+use crate::ledger::{UsageLedger, ENDPOINT_EMBEDDINGS};
+use crate::openai::embed::EmbedOpenAI;
+use crate::openai::tokens::{count_tokens, truncate_to_tokens};
+use crate::openai::usage::TokenUsage;
+use futures::{StreamExt, TryStreamExt};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Collection;
+use serde::Serialize;
+
+/// Collection holding one document per embedded passage
+pub const CHUNK_COLLECTION: &str = "listing_chunks";
+
+/// Atlas Vector Search index over the passages
+pub const CHUNK_INDEX: &str = "chunk_vector_index";
+
+/// Field of a passage document holding its vector
+pub const CHUNK_EMBEDDING_PATH: &str = "embedding";
+
+/// Long free-text fields of a listing that are embedded passage by passage
+pub const CHUNK_FIELDS: [&str; 3] = ["description", "space", "neighborhood_overview"];
+
+/// Embedding requests kept in flight while indexing
+const CHUNK_CONCURRENCY: usize = 4;
+
+/// Caller passage embedding usage is recorded under
+const CHUNK_CALLER: &str = "chunk_indexer";
+
+/// Vector index the passages collection needs on Atlas
+pub fn chunk_index(field: &VectorField) -> ExpectedIndex {
+    vector_index(CHUNK_INDEX.to_string(), CHUNK_EMBEDDING_PATH, field.dimensions, &["listing_id", "field"])
+}
+
+/// Splits text into passages of whole sentences
+///
+/// # Fields
+/// * `max_tokens` - Tokens a passage holds at most; longer sentences are cut
+/// * `overlap` - Trailing sentences of a passage repeated at the start of
+///   the next, so a match across the boundary is not lost
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chunker {
+    pub max_tokens: usize,
+    pub overlap: usize,
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Self { max_tokens: 200, overlap: 1 }
+    }
+}
+
+impl Chunker {
+    /// Passages of `text`, counted with the tokenizer of `model`
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let chunker = Chunker { max_tokens: 8, overlap: 0 };
+    /// assert_eq!(chunker.split("One. Two.", "text-embedding-3-small"), ["One. Two."]);
+    /// ```
+    pub fn split(&self, text: &str, model: &str) -> Vec<String> {
+        let max_tokens = self.max_tokens.max(1);
+        let mut sentences: Vec<String> = Vec::new();
+        for sentence in sentences_of(text) {
+            if count_tokens(model, sentence) <= max_tokens {
+                sentences.push(sentence.to_string());
+                continue;
+            }
+
+            let mut rest = sentence;
+            while !rest.is_empty() {
+                let piece = truncate_to_tokens(model, rest, max_tokens);
+                if piece.is_empty() || !rest.starts_with(&piece) {
+                    sentences.push(rest.to_string());
+                    break;
+                }
+                rest = rest[piece.len()..].trim_start();
+                sentences.push(piece.trim().to_string());
+            }
+        }
+
+        // Sentences are counted joined, as the separating space can change
+        // how the tokenizer splits them
+        let fits = |sentences: &[String]| sentences.len() <= 1 || count_tokens(model, &sentences.join(" ")) <= max_tokens;
+        let mut passages = Vec::new();
+        let mut current: Vec<String> = Vec::new();
+        for sentence in sentences {
+            current.push(sentence);
+            if fits(&current) {
+                continue;
+            }
+
+            let sentence = current.pop().unwrap_or_default();
+            passages.push(current.join(" "));
+            let keep = current.len().saturating_sub(self.overlap);
+            current.drain(..keep);
+            current.push(sentence);
+            while !fits(&current) {
+                current.remove(0);
+            }
+        }
+        if !current.is_empty() {
+            passages.push(current.join(" "));
+        }
+        passages
+    }
+}
+
+/// Sentences of a text, ending at `.`, `!` or `?` followed by whitespace,
+/// or at a line break
+pub(crate) fn sentences_of(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next_is_space = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+        let end = match c {
+            '\n' => Some(index),
+            '.' | '!' | '?' if next_is_space => Some(index + c.len_utf8()),
+            _ => None,
+        };
+        if let Some(end) = end {
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+    sentences.push(&text[start..]);
+
+    sentences.into_iter().map(str::trim).filter(|sentence| !sentence.is_empty()).collect()
+}
+
+/// Outcome of indexing every listing's passages
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct ChunkReport {
+    pub listings: u64,
+    pub passages: u64,
+    pub failed: u64,
+}
+
+/// Embeds the passages of listings into `CHUNK_COLLECTION`
+///
+/// Each passage document holds `listing_id`, `field`, `position`, `text`,
+/// its vector and the model and hash of the field text it was cut from, so
+/// a field is only re-embedded when its text or the model changed.
+#[derive(Debug, Clone)]
+pub struct ChunkWriter {
+    listings: Collection<Document>,
+    chunks: Collection<Document>,
+    field: VectorField,
+    client: EmbedOpenAI,
+    usage: UsageLedger,
+    chunker: Chunker,
+}
+
+impl ChunkWriter {
+    /// # Arguments
+    /// * `field` - Model and dimensions passages are embedded with; queries
+    ///   must be embedded the same way
+    pub fn new<T: Send + Sync>(
+        listings: &Collection<T>,
+        chunks: Collection<Document>,
+        field: VectorField,
+        client: EmbedOpenAI,
+        usage: UsageLedger,
+    ) -> Self {
+        Self {
+            listings: listings.clone_with_type(),
+            chunks,
+            field,
+            client,
+            usage,
+            chunker: Chunker::default(),
+        }
+    }
+
+    pub fn collection(&self) -> &Collection<Document> {
+        &self.chunks
+    }
+
+    pub fn field(&self) -> &VectorField {
+        &self.field
+    }
+
+    /// Re-embeds the passages of one listing whose text changed; the number
+    /// of passages written
+    ///
+    /// # Errors
+    /// * `EmbeddingError` - Loading, embedding or saving failed
+    pub async fn refresh(&self, id: &Bson) -> Result<u64, EmbeddingError> {
+        let projection: Document = CHUNK_FIELDS.iter().map(|field| (field.to_string(), Bson::Int32(1))).collect();
+        match self.listings.find_one(doc! { "_id": id.clone() }).projection(projection).await? {
+            Some(listing) => self.index_listing(&listing).await,
+            None => {
+                self.chunks.delete_many(doc! { "listing_id": id.clone() }).await?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Replaces the passages of every `CHUNK_FIELDS` field of `listing`
+    /// whose text or model changed since it was indexed
+    ///
+    /// New passages are inserted under a fresh `version` before the older
+    /// versions are deleted, so a failure between the two leaves the field
+    /// searchable through its previous passages rather than without any.
+    ///
+    /// # Errors
+    /// * `EmbeddingError` - Embedding or saving failed
+    pub async fn index_listing(&self, listing: &Document) -> Result<u64, EmbeddingError> {
+        let id = listing.get("_id").cloned().unwrap_or(Bson::Null);
+        let mut written = 0;
+
+        for field in CHUNK_FIELDS {
+            let text = listing.get_str(field).map(str::trim).unwrap_or_default();
+            let hash = text_hash(text);
+            let scope = doc! { "listing_id": id.clone(), "field": field };
+
+            let indexed = self.chunks
+                .find_one(scope.clone())
+                .projection(doc! { "model": 1, "dimensions": 1, "text_hash": 1 })
+                .await?;
+            let unchanged = match &indexed {
+                Some(indexed) => is_current(indexed, &self.field, &hash),
+                None => text.is_empty(),
+            };
+            if unchanged {
+                continue;
+            }
+
+            let passages = if text.is_empty() { Vec::new() } else { self.chunker.split(text, &self.field.model) };
+            let vectors: Vec<Vec<f32>> = futures::stream::iter(passages.clone())
+                .map(|passage| async move { self.embed(&passage).await })
+                .buffered(CHUNK_CONCURRENCY)
+                .try_collect()
+                .await?;
+
+            let version = ObjectId::new();
+            let documents: Vec<Document> = passages
+                .into_iter()
+                .zip(vectors)
+                .enumerate()
+                .map(|(position, (passage, vector))| doc! {
+                    "listing_id": id.clone(),
+                    "field": field,
+                    "position": position as i32,
+                    "text": passage,
+                    CHUNK_EMBEDDING_PATH: vector,
+                    "model": &self.field.model,
+                    "dimensions": self.field.dimensions as i64,
+                    "text_hash": &hash,
+                    "version": version,
+                })
+                .collect();
+
+            if !documents.is_empty() {
+                written += documents.len() as u64;
+                self.chunks.insert_many(documents).await?;
+            }
+            self.chunks.delete_many(stale_versions(scope, version)).await?;
+        }
+        Ok(written)
+    }
+
+    /// Indexes the passages of every listing, `CHUNK_CONCURRENCY` at a time;
+    /// listings that fail are logged and counted
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Database` - The listings could not be listed
+    pub async fn backfill(&self) -> Result<ChunkReport, EmbeddingError> {
+        let ids: Vec<Bson> = self.listings
+            .find(doc! {})
+            .projection(doc! { "_id": 1 })
+            .await?
+            .try_collect::<Vec<Document>>()
+            .await?
+            .into_iter()
+            .filter_map(|listing| listing.get("_id").cloned())
+            .collect();
+        tracing::info!("Indexing passages of {} listings", ids.len());
+
+        let results: Vec<(Bson, Result<u64, EmbeddingError>)> = futures::stream::iter(ids)
+            .map(|id| async move {
+                let result = self.refresh(&id).await;
+                (id, result)
+            })
+            .buffer_unordered(CHUNK_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut report = ChunkReport::default();
+        for (id, result) in results {
+            report.listings += 1;
+            match result {
+                Ok(passages) => report.passages += passages,
+                Err(e) => {
+                    tracing::warn!("Failed to index passages of listing {}: {}", id, e);
+                    report.failed += 1;
+                }
+            }
+        }
+        tracing::info!(
+            "Indexed passages of {} listings: {} passages written, {} failed",
+            report.listings, report.passages, report.failed
+        );
+        Ok(report)
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError> {
+        let response = self.client
+            .clone()
+            .with_model(&self.field.model)
+            .with_dimensions(self.field.dimensions)
+            .embed_content(text)
+            .await?;
+        self.usage.record_detached(
+            &response.model,
+            ENDPOINT_EMBEDDINGS,
+            CHUNK_CALLER,
+            TokenUsage::from(&response.usage),
+        );
+
+        let vector = response.data.first().map(|data| data.embedding.clone()).unwrap_or_default();
+        if vector.len() != self.field.dimensions as usize {
+            return Err(EmbeddingError::Dimensions {
+                field: CHUNK_COLLECTION.to_string(),
+                expected: self.field.dimensions,
+                actual: vector.len(),
+            });
+        }
+        Ok(vector)
+    }
+}
+
+/// Passages in `scope` written before `version`
+fn stale_versions(mut scope: Document, version: ObjectId) -> Document {
+    scope.insert("version", doc! { "$ne": version });
+    scope
+}
+
+/// Whether a stored passage was embedded from text hashing to `hash` with
+/// `field`'s model and dimensions; passages stored before their dimensions
+/// were recorded never are
+fn is_current(passage: &Document, field: &VectorField, hash: &str) -> bool {
+    passage.get_str("model") == Ok(&field.model)
+        && passage.get_i64("dimensions") == Ok(field.dimensions as i64)
+        && passage.get_str("text_hash") == Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "text-embedding-3-small";
+
+    #[test]
+    fn stale_versions_keep_the_new_version() {
+        let version = ObjectId::new();
+
+        assert_eq!(
+            stale_versions(doc! { "listing_id": 1, "field": "space" }, version),
+            doc! { "listing_id": 1, "field": "space", "version": { "$ne": version } }
+        );
+    }
+
+    #[test]
+    fn passage_is_current_only_for_same_model_dimensions_and_text() {
+        let field = VectorField::new("text_embeddings", MODEL, 1536);
+        let hash = text_hash("Near the river.");
+        let passage = doc! { "model": MODEL, "dimensions": 1536_i64, "text_hash": &hash };
+
+        assert!(is_current(&passage, &field, &hash));
+        assert!(!is_current(&passage, &field, &text_hash("Near the sea.")));
+        assert!(!is_current(&passage, &VectorField::new("text_embeddings", MODEL, 512), &hash));
+        assert!(!is_current(&passage, &VectorField::new("text_embeddings", "text-embedding-3-large", 1536), &hash));
+        assert!(!is_current(&doc! { "model": MODEL, "text_hash": &hash }, &field, &hash));
+    }
+
+    #[test]
+    fn sentences_end_at_punctuation_and_line_breaks() {
+        assert_eq!(
+            sentences_of("Near the river. Costs 3.5 EUR!\nQuiet?  Yes"),
+            ["Near the river.", "Costs 3.5 EUR!", "Quiet?", "Yes"]
+        );
+        assert!(sentences_of("  \n ").is_empty());
+    }
+
+    #[test]
+    fn split_packs_sentences_up_to_the_token_limit() {
+        let text = "One two three. Four five six. Seven eight nine. Ten eleven twelve.";
+        let chunker = Chunker { max_tokens: 8, overlap: 0 };
+
+        let passages = chunker.split(text, MODEL);
+
+        assert_eq!(passages, ["One two three. Four five six.", "Seven eight nine. Ten eleven twelve."]);
+        assert!(passages.iter().all(|passage| count_tokens(MODEL, passage) <= 8));
+    }
+
+    #[test]
+    fn split_counts_the_space_between_joined_sentences() {
+        // Apart the sentences take 13 tokens, joined they take 14
+        let text = "Check-in 15:00. 2BR/1BA!! ~120m² flat.";
+
+        for overlap in 0..2 {
+            let passages = Chunker { max_tokens: 13, overlap }.split(text, MODEL);
+
+            assert!(passages.iter().all(|passage| count_tokens(MODEL, passage) <= 13), "{:?}", passages);
+        }
+    }
+
+    #[test]
+    fn split_repeats_overlapping_sentences() {
+        let text = "One two three. Four five six. Seven eight nine.";
+        let chunker = Chunker { max_tokens: 8, overlap: 1 };
+
+        assert_eq!(
+            chunker.split(text, MODEL),
+            ["One two three. Four five six.", "Four five six. Seven eight nine."]
+        );
+    }
+
+    #[test]
+    fn split_cuts_sentences_longer_than_the_limit() {
+        let text = "word ".repeat(50);
+        let chunker = Chunker { max_tokens: 10, overlap: 0 };
+
+        let passages = chunker.split(&text, MODEL);
+
+        assert_eq!(passages.len(), 5);
+        assert!(passages.iter().all(|passage| count_tokens(MODEL, passage) <= 10));
+        assert_eq!(passages.join(" ").split_whitespace().count(), 50);
+    }
+}
@@ -1,7 +1,8 @@
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ResponseSearch {
     #[serde(rename = "_id")]
     pub id: i32,
@@ -22,7 +23,8 @@ pub struct ResponseSearch {
 
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct ShortTermRental {
     #[serde(rename = "_id")]
     pub id: i32,
@@ -76,17 +78,21 @@ pub struct ShortTermRental {
     pub cancellation_policy: Option<String>,
     
     #[serde(with = "rfc3339_option", default)]
+    #[schemars(with = "Option<DateTime<Utc>>")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_scraped: Option<DateTime<Utc>>,
 
     #[serde(with = "rfc3339_option", default)]
+    #[schemars(with = "Option<DateTime<Utc>>")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub calendar_last_scraped: Option<DateTime<Utc>>,
 
     #[serde(with = "rfc3339_option", default)]
+    #[schemars(with = "Option<DateTime<Utc>>")]
     pub first_review: Option<DateTime<Utc>>,
 
     #[serde(with = "rfc3339_option", default)]
+    #[schemars(with = "Option<DateTime<Utc>>")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_review: Option<DateTime<Utc>>,
 
@@ -145,7 +151,8 @@ pub struct ShortTermRental {
     pub text_embeddings: Option<Vec<f64>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Host {
     pub host_id: String,
 
@@ -180,7 +187,7 @@ pub struct Host {
     pub host_verifications: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Address {
     pub street: String,
 
@@ -198,7 +205,7 @@ pub struct Address {
 
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Location {
     #[serde(rename = "type")]
     pub location_type: String,
@@ -208,7 +215,8 @@ pub struct Location {
     pub is_location_exact: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Availability {
     pub availability_30: i32,
 
@@ -219,7 +227,8 @@ pub struct Availability {
     pub availability_365: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ReviewScores {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub review_scores_accuracy: Option<i32>,
@@ -243,52 +252,64 @@ pub struct ReviewScores {
     pub review_scores_rating: Option<i32>,
 }
 
-impl Default for ShortTermRental {
-    fn default() -> Self {
-        Self {
-            id: 0,
-            name: String::new(),
-            description: None,
-            summary: None,
-            space: None,
-            neighborhood_overview: None,
-            notes: None,
-            transit: None,
-            access: None,
-            interaction: None,
-            house_rules: None,
-            property_type: None,
-            room_type: None,
-            bed_type: None,
-            minimum_nights: None,
-            maximum_nights: None,
-            cancellation_policy: None,
-            last_scraped: None,
-            calendar_last_scraped: None,
-            first_review: None,
-            last_review: None,
-            accommodates: None,
-            bedrooms: None,
-            beds: None,
-            number_of_reviews: None,
-            bathrooms: None,
-            amenities: None,
-            price: None,
-            security_deposit: None,
-            cleaning_fee: None,
-            extra_people: None,
-            guests_included: None,
-            weekly_price: None,
-            monthly_price: None,
-            host: None,
-            address: None,
-            availability: None,
-            review_scores: None,
-            text_embeddings: None,
-        }
-    }
+/// Structured reading of a free-text rental search, used by the strict
+/// schema tests as a nested, nullable and enum-bearing structure
+#[cfg(test)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct QueryIntent {
+    /// What the user is looking for, without the filters below; this is the text that gets embedded
+    pub search_text: String,
+
+    /// City or region, matched against `address.market`
+    pub market: Option<String>,
+
+    /// Property type such as Apartment, House or Loft
+    pub property_type: Option<String>,
+
+    pub room_type: Option<RoomType>,
+
+    pub min_bedrooms: Option<i32>,
+
+    pub min_beds: Option<i32>,
+
+    /// Number of guests the listing must accommodate
+    pub accommodates: Option<i32>,
+
+    /// Maximum nightly price
+    pub max_price: Option<f64>,
+
+    /// Amenities the listing must have, using Airbnb names such as Wifi or Kitchen
+    pub amenities: Vec<String>,
+
+    /// Point the listing should be close to
+    pub near: Option<GeoPoint>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum RoomType {
+    #[serde(rename = "Entire home/apt")]
+    EntireHome,
+
+    #[serde(rename = "Private room")]
+    PrivateRoom,
+
+    #[serde(rename = "Shared room")]
+    SharedRoom,
+}
+
+#[cfg(test)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct GeoPoint {
+    pub longitude: f64,
+
+    pub latitude: f64,
+
+    /// Search radius in meters
+    pub max_distance_meters: Option<f64>,
 }
 
+#[allow(dead_code)]
 mod rfc3339_option {
     use chrono::{DateTime, Utc};
     use serde::{self, Deserialize, Deserializer, Serializer};
@@ -0,0 +1,785 @@
+use crate::ledger::{UsageLedger, ENDPOINT_EMBEDDINGS};
+use crate::openai::embed::EmbedOpenAI;
+use crate::openai::error::OpenAIError;
+use crate::openai::usage::TokenUsage;
+use crate::openai::utils::fnv1a;
+use crate::search::{EMBEDDING_PATH, VECTOR_INDEX};
+use crate::templates::{EmbeddingTemplate, RenderedText, LISTING_V1};
+use crate::{EMBEDDING_DIMENSIONS, EMBEDDING_MODEL};
+use futures::{StreamExt, TryStreamExt};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+
+/// Document holding the `EmbeddingMeta` of every vector field, by field name
+pub const META_PATH: &str = "embedding_meta";
+
+/// Listings a migration loads per query
+pub const MIGRATION_BATCH: i64 = 100;
+
+/// Embedding requests a migration keeps in flight
+pub const MIGRATION_CONCURRENCY: usize = 4;
+
+/// Caller migration usage is recorded under
+const MIGRATION_CALLER: &str = "embedding_migration";
+
+#[allow(dead_code)]
+#[derive(Debug, thiserror::Error)]
+pub enum EmbeddingError {
+    #[error("Configuration Error: {0}")]
+    Config(String),
+
+    #[error("Database Error: Listing embedding query failed. {0}")]
+    Database(#[from] mongodb::error::Error),
+
+    #[error("Serialization Error: Value could not be written as BSON. {0}")]
+    Serialization(#[from] mongodb::bson::ser::Error),
+
+    #[error("Embedding Error: {0}")]
+    Embed(#[from] OpenAIError),
+
+    #[error("Dimension Error: {field} expects {expected} dimensions, the model returned {actual}")]
+    Dimensions { field: String, expected: u32, actual: usize },
+}
+
+/// A named vector field of the listings and the model that fills it
+///
+/// # Fields
+/// * `name` - Top-level field holding the vector, e.g. `text_embeddings`
+/// * `model` - Embedding model
+/// * `dimensions` - Vector length requested from the model
+/// * `template` - Id of the `EmbeddingTemplate` listings are rendered with
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VectorField {
+    pub name: String,
+    pub model: String,
+    pub dimensions: u32,
+    pub template: String,
+}
+
+impl VectorField {
+    /// A field rendered with `LISTING_V1`
+    pub fn new(name: &str, model: &str, dimensions: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            model: model.to_string(),
+            dimensions,
+            template: LISTING_V1.id.to_string(),
+        }
+    }
+
+    pub fn with_template(mut self, template: &EmbeddingTemplate) -> Self {
+        self.template = template.id.to_string();
+        self
+    }
+
+    pub fn template(&self) -> &'static EmbeddingTemplate {
+        EmbeddingTemplate::find(&self.template).unwrap_or(&LISTING_V1)
+    }
+
+    /// Values `EmbeddingMeta::template` may hold for this field; metadata
+    /// written before templates existed has none and means `listing_v1`
+    fn template_values(&self) -> Vec<Bson> {
+        let mut values = vec![Bson::String(self.template.clone())];
+        if self.template == LISTING_V1.id {
+            values.push(Bson::Null);
+        }
+        values
+    }
+
+    /// `text_embeddings`, the field searches used before fields were versioned
+    pub fn legacy() -> Self {
+        Self::new(EMBEDDING_PATH, EMBEDDING_MODEL, EMBEDDING_DIMENSIONS)
+    }
+
+    /// Atlas Vector Search index over the field; the legacy field keeps
+    /// its original `vector_index`
+    pub fn index_name(&self) -> String {
+        if self.name == EMBEDDING_PATH {
+            VECTOR_INDEX.to_string()
+        } else {
+            format!("{}_index", self.name)
+        }
+    }
+
+    /// Path of the field's `EmbeddingMeta`
+    pub fn meta_path(&self) -> String {
+        format!("{}.{}", META_PATH, self.name)
+    }
+
+    /// Whether `meta` records this field's model, dimensions and template
+    /// and, when given, an embedding of the text hashing to `text_hash`
+    pub fn is_current(&self, meta: Option<&EmbeddingMeta>, text_hash: Option<&str>) -> bool {
+        meta.is_some_and(|meta| {
+            meta.model == self.model
+                && meta.dimensions == self.dimensions
+                && meta.template == self.template
+                && text_hash.is_none_or(|hash| meta.text_hash == hash)
+        })
+    }
+
+    /// Listings whose metadata matches this field's model, dimensions and
+    /// template
+    fn current_filter(&self) -> Document {
+        let meta = self.meta_path();
+        doc! {
+            format!("{}.model", meta): &self.model,
+            format!("{}.dimensions", meta): self.dimensions as i64,
+            format!("{}.template", meta): { "$in": self.template_values() },
+        }
+    }
+
+    /// Listings whose metadata was not produced by this field's model,
+    /// dimensions and template, including those without metadata
+    fn stale_filter(&self) -> Document {
+        let meta = self.meta_path();
+        doc! {
+            "$or": [
+                { format!("{}.model", meta): { "$ne": &self.model } },
+                { format!("{}.dimensions", meta): { "$ne": self.dimensions as i64 } },
+                { format!("{}.template", meta): { "$nin": self.template_values() } },
+            ]
+        }
+    }
+
+    /// Parses `name=model:dimensions`, optionally followed by `@template`
+    fn parse(spec: &str) -> Result<Self, EmbeddingError> {
+        let invalid = || EmbeddingError::Config(format!(
+            "vector field `{}` must look like name=model:dimensions[@template]", spec
+        ));
+
+        let (name, rest) = spec.trim().split_once('=').ok_or_else(invalid)?;
+        let (rest, template) = match rest.split_once('@') {
+            Some((rest, id)) => {
+                let template = EmbeddingTemplate::find(id)
+                    .ok_or_else(|| EmbeddingError::Config(format!("unknown embedding template `{}`", id)))?;
+                (rest, template)
+            }
+            None => (rest, &LISTING_V1),
+        };
+        let (model, dimensions) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        let dimensions: u32 = dimensions.parse().map_err(|_| invalid())?;
+
+        if name.is_empty() || name.contains(['.', '$']) || model.is_empty() || dimensions == 0 {
+            return Err(invalid());
+        }
+        Ok(Self::new(name, model, dimensions).with_template(template))
+    }
+}
+
+/// What produced a stored vector
+///
+/// # Fields
+/// * `model` - Embedding model
+/// * `dimensions` - Vector length
+/// * `template` - Template the text was rendered with
+/// * `text_hash` - FNV-1a of the embedded text, to spot listings edited since
+/// * `created_at` - RFC 3339 time of embedding
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingMeta {
+    pub model: String,
+    pub dimensions: u32,
+    #[serde(default = "legacy_template")]
+    pub template: String,
+    pub text_hash: String,
+    pub created_at: String,
+}
+
+impl EmbeddingMeta {
+    /// Metadata `listing` stores for `field`, if any
+    pub fn of(listing: &Document, field: &VectorField) -> Option<Self> {
+        let meta = listing.get_document(META_PATH).ok()?.get_document(&field.name).ok()?;
+        mongodb::bson::from_document(meta.clone()).ok()
+    }
+}
+
+/// Vector fields of the listings, the one searches use, and the one a
+/// migration is filling
+///
+/// # Fields
+/// * `fields` - Every declared field, from `VECTOR_FIELDS`
+/// * `search_field` - Field queried by searches, from `SEARCH_VECTOR_FIELD`
+/// * `migration_target` - Field being backfilled, from `EMBEDDING_MIGRATION_TARGET`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddingConfig {
+    pub fields: Vec<VectorField>,
+    pub search_field: String,
+    pub migration_target: Option<String>,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            fields: vec![VectorField::legacy()],
+            search_field: EMBEDDING_PATH.to_string(),
+            migration_target: None,
+        }
+    }
+}
+
+impl EmbeddingConfig {
+    /// Reads `VECTOR_FIELDS`, `SEARCH_VECTOR_FIELD` and `EMBEDDING_MIGRATION_TARGET`
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Config` - A field is malformed or undeclared
+    pub fn from_env() -> Result<Self, EmbeddingError> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.trim().is_empty());
+        Self::parse(
+            var("VECTOR_FIELDS").as_deref(),
+            var("SEARCH_VECTOR_FIELD").as_deref(),
+            var("EMBEDDING_MIGRATION_TARGET").as_deref(),
+        )
+    }
+
+    /// Builds a config from comma-separated `name=model:dimensions` fields
+    /// (the legacy field is always declared) and the names of the search
+    /// field and migration target
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Config` - A field is malformed, declared twice or
+    ///   undeclared, or the migration target is the search field
+    pub fn parse(
+        fields: Option<&str>,
+        search_field: Option<&str>,
+        migration_target: Option<&str>,
+    ) -> Result<Self, EmbeddingError> {
+        let mut declared = Vec::new();
+        for spec in fields.unwrap_or_default().split(',').filter(|spec| !spec.trim().is_empty()) {
+            let field = VectorField::parse(spec)?;
+            if declared.iter().any(|other: &VectorField| other.name == field.name) {
+                return Err(EmbeddingError::Config(format!("vector field `{}` is declared twice", field.name)));
+            }
+            declared.push(field);
+        }
+        if declared.iter().all(|field| field.name != EMBEDDING_PATH) {
+            declared.insert(0, VectorField::legacy());
+        }
+
+        let config = Self {
+            fields: declared,
+            search_field: search_field.unwrap_or(EMBEDDING_PATH).trim().to_string(),
+            migration_target: migration_target.map(|target| target.trim().to_string()),
+        };
+
+        if config.field(&config.search_field).is_none() {
+            return Err(EmbeddingError::Config(format!(
+                "search field `{}` is not declared in VECTOR_FIELDS", config.search_field
+            )));
+        }
+        if let Some(target) = &config.migration_target {
+            if config.field(target).is_none() {
+                return Err(EmbeddingError::Config(format!(
+                    "migration target `{}` is not declared in VECTOR_FIELDS", target
+                )));
+            }
+            if *target == config.search_field {
+                return Err(EmbeddingError::Config(format!(
+                    "migration target `{}` is already the search field", target
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn field(&self, name: &str) -> Option<&VectorField> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    pub fn search_field(&self) -> &VectorField {
+        self.field(&self.search_field).expect("search field is validated on parse")
+    }
+
+    pub fn migration_target(&self) -> Option<&VectorField> {
+        self.migration_target.as_deref().and_then(|name| self.field(name))
+    }
+
+    /// Fields written whenever a listing is embedded: the search field and,
+    /// during a migration, its target, so both stay current until the switch
+    pub fn active_fields(&self) -> Vec<&VectorField> {
+        std::iter::once(self.search_field()).chain(self.migration_target()).collect()
+    }
+}
+
+fn legacy_template() -> String {
+    LISTING_V1.id.to_string()
+}
+
+pub fn text_hash(text: &str) -> String {
+    format!("{:016x}", fnv1a(text.as_bytes()))
+}
+
+/// How many listings hold a vector for a field
+///
+/// # Fields
+/// * `current` - Vectors whose metadata matches the field's model, dimensions
+///   and template
+/// * `legacy` - Vectors without metadata, stored before fields were versioned
+/// * `missing` - Listings with neither, or with another model's vector
+/// * `complete` - Every listing has a current or legacy vector, so searches
+///   can switch to the field
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Coverage {
+    pub field: VectorField,
+    pub total: u64,
+    pub current: u64,
+    pub legacy: u64,
+    pub missing: u64,
+    pub complete: bool,
+}
+
+/// Coverage of every vector field, and which one searches use
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct EmbeddingStatus {
+    pub search_field: String,
+    pub migration_target: Option<String>,
+    pub fields: Vec<Coverage>,
+}
+
+/// Text a field would embed for a listing, and whether its stored vector
+/// was computed from it
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct TextPreview {
+    pub field: String,
+    pub template: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub rendered: RenderedText,
+    pub text_hash: String,
+    pub current: bool,
+}
+
+/// Outcome of a migration run
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct MigrationReport {
+    pub field: String,
+    pub embedded: u64,
+    /// Listings without text to embed
+    pub skipped: u64,
+    pub failed: u64,
+}
+
+/// Embeds listings into their vector fields and records what produced each
+/// vector
+#[derive(Debug, Clone)]
+pub struct ListingEmbedder {
+    collection: Collection<Document>,
+    config: EmbeddingConfig,
+    client: EmbedOpenAI,
+    usage: UsageLedger,
+}
+
+impl ListingEmbedder {
+    /// # Arguments
+    /// * `client` - Template for the embedding requests; each field sets its
+    ///   own model and dimensions on a clone
+    pub fn new<T: Send + Sync>(
+        collection: &Collection<T>,
+        config: EmbeddingConfig,
+        client: EmbedOpenAI,
+        usage: UsageLedger,
+    ) -> Self {
+        Self {
+            collection: collection.clone_with_type(),
+            config,
+            client,
+            usage,
+        }
+    }
+
+    pub fn config(&self) -> &EmbeddingConfig {
+        &self.config
+    }
+
+    /// Top-level fields the templates of the active fields read; edits to
+    /// any other field leave the vectors current
+    pub fn watched_fields(&self) -> Vec<&'static str> {
+        let mut fields: Vec<&'static str> = Vec::new();
+        for root in self.config.active_fields().iter().flat_map(|field| field.template().roots()) {
+            if !fields.contains(&root) {
+                fields.push(root);
+            }
+        }
+        fields
+    }
+
+    /// Loads what every declared field's template reads, and the metadata
+    fn projection(&self) -> Document {
+        let mut projection = Document::new();
+        for root in self.config.fields.iter().flat_map(|field| field.template().roots()) {
+            projection.insert(root, 1);
+        }
+        projection.insert(META_PATH, 1);
+        projection
+    }
+
+    /// The `$set` embedding `listing` into those of `fields` that are not
+    /// current for its text; empty when there is nothing to do
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Embed` - The embedding request failed
+    /// * `EmbeddingError::Dimensions` - The model returned a vector of another length
+    pub async fn embed_update(
+        &self,
+        listing: &Document,
+        fields: &[&VectorField],
+        caller: &str,
+    ) -> Result<Document, EmbeddingError> {
+        let mut update = Document::new();
+        for field in fields {
+            let text = field.template().render(listing, &field.model).text;
+            if text.is_empty() {
+                continue;
+            }
+            let hash = text_hash(&text);
+            if field.is_current(EmbeddingMeta::of(listing, field).as_ref(), Some(&hash)) {
+                continue;
+            }
+
+            let response = self.client
+                .clone()
+                .with_model(&field.model)
+                .with_dimensions(field.dimensions)
+                .embed_content(&text)
+                .await?;
+            self.usage.record_detached(
+                &response.model,
+                ENDPOINT_EMBEDDINGS,
+                caller,
+                TokenUsage::from(&response.usage),
+            );
+
+            let vector = response.data.first().map(|data| data.embedding.clone()).unwrap_or_default();
+            if vector.len() != field.dimensions as usize {
+                return Err(EmbeddingError::Dimensions {
+                    field: field.name.clone(),
+                    expected: field.dimensions,
+                    actual: vector.len(),
+                });
+            }
+
+            let meta = EmbeddingMeta {
+                model: field.model.clone(),
+                dimensions: field.dimensions,
+                template: field.template.clone(),
+                text_hash: hash,
+                created_at: chrono::Utc::now().to_rfc3339(),
+            };
+            update.insert(field.name.clone(), vector);
+            update.insert(
+                field.meta_path(),
+                mongodb::bson::to_bson(&meta)?,
+            );
+        }
+        Ok(update)
+    }
+
+    /// Embeds a loaded listing into `fields` and saves the vectors; `false`
+    /// when there was nothing to write
+    async fn write(&self, listing: &Document, fields: &[&VectorField], caller: &str) -> Result<bool, EmbeddingError> {
+        let update = self.embed_update(listing, fields, caller).await?;
+        if update.is_empty() {
+            return Ok(false);
+        }
+
+        let id = listing.get("_id").cloned().unwrap_or(Bson::Null);
+        self.collection.update_one(doc! { "_id": id }, doc! { "$set": update }).await?;
+        Ok(true)
+    }
+
+    /// Re-embeds one listing into every active field that is not current;
+    /// `false` when the listing is gone or already current
+    ///
+    /// # Errors
+    /// * `EmbeddingError` - Loading, embedding or saving failed
+    pub async fn refresh(&self, id: &Bson, caller: &str) -> Result<bool, EmbeddingError> {
+        let listing = self.collection
+            .find_one(doc! { "_id": id.clone() })
+            .projection(self.projection())
+            .await?;
+
+        match listing {
+            Some(listing) => self.write(&listing, &self.config.active_fields(), caller).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Counts the listings holding a vector for `field`
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Database` - A count failed
+    pub async fn coverage(&self, field: &VectorField) -> Result<Coverage, EmbeddingError> {
+        let meta = field.meta_path();
+        let total = self.collection.count_documents(doc! {}).await?;
+        let current = self.collection.count_documents(field.current_filter()).await?;
+        let legacy = self.collection
+            .count_documents(doc! {
+                &field.name: { "$exists": true },
+                &meta: { "$exists": false },
+            })
+            .await?;
+
+        let missing = total.saturating_sub(current + legacy);
+        Ok(Coverage { field: field.clone(), total, current, legacy, missing, complete: missing == 0 })
+    }
+
+    /// Renders a listing with the template of every declared field; `None`
+    /// when there is no such listing
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Database` - The listing could not be loaded
+    pub async fn preview(&self, id: &Bson) -> Result<Option<Vec<TextPreview>>, EmbeddingError> {
+        let Some(listing) = self.collection
+            .find_one(doc! { "_id": id.clone() })
+            .projection(self.projection())
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(
+            self.config.fields
+                .iter()
+                .map(|field| {
+                    let rendered = field.template().render(&listing, &field.model);
+                    let hash = text_hash(&rendered.text);
+                    TextPreview {
+                        field: field.name.clone(),
+                        template: field.template.clone(),
+                        model: field.model.clone(),
+                        current: field.is_current(EmbeddingMeta::of(&listing, field).as_ref(), Some(&hash)),
+                        rendered,
+                        text_hash: hash,
+                    }
+                })
+                .collect(),
+        ))
+    }
+
+    /// Coverage of every declared field
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Database` - A count failed
+    pub async fn status(&self) -> Result<EmbeddingStatus, EmbeddingError> {
+        let mut fields = Vec::with_capacity(self.config.fields.len());
+        for field in &self.config.fields {
+            fields.push(self.coverage(field).await?);
+        }
+
+        Ok(EmbeddingStatus {
+            search_field: self.config.search_field.clone(),
+            migration_target: self.config.migration_target.clone(),
+            fields,
+        })
+    }
+
+    /// Embeds every listing whose vector in the migration target is missing
+    /// or came from another model, `MIGRATION_CONCURRENCY` at a time
+    ///
+    /// Listings that fail are logged and left for the next run.
+    ///
+    /// # Errors
+    /// * `EmbeddingError::Config` - No migration target is configured
+    /// * `EmbeddingError::Database` - Listings could not be loaded
+    pub async fn migrate(&self) -> Result<MigrationReport, EmbeddingError> {
+        let target = self.config
+            .migration_target()
+            .ok_or_else(|| EmbeddingError::Config("no EMBEDDING_MIGRATION_TARGET is set".to_string()))?;
+        tracing::info!("Migrating embeddings into {} with {}", target.name, target.model);
+
+        let mut report = MigrationReport { field: target.name.clone(), ..Default::default() };
+        let mut passed: Vec<Bson> = Vec::new();
+        loop {
+            let filter = doc! { "$and": [target.stale_filter(), { "_id": { "$nin": passed.clone() } }] };
+            let batch: Vec<Document> = self.collection
+                .find(filter)
+                .projection(self.projection())
+                .limit(MIGRATION_BATCH)
+                .await?
+                .try_collect()
+                .await?;
+            if batch.is_empty() {
+                break;
+            }
+
+            let results: Vec<(Bson, Result<bool, EmbeddingError>)> = futures::stream::iter(batch)
+                .map(|listing| async move {
+                    let id = listing.get("_id").cloned().unwrap_or(Bson::Null);
+                    (id, self.write(&listing, &[target], MIGRATION_CALLER).await)
+                })
+                .buffer_unordered(MIGRATION_CONCURRENCY)
+                .collect()
+                .await;
+
+            for (id, result) in results {
+                match result {
+                    Ok(true) => report.embedded += 1,
+                    Ok(false) => {
+                        report.skipped += 1;
+                        passed.push(id);
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to embed listing {} into {}: {}", id, target.name, e);
+                        report.failed += 1;
+                        passed.push(id);
+                    }
+                }
+            }
+        }
+
+        tracing::info!(
+            "Embedding migration into {} done: {} embedded, {} skipped, {} failed",
+            report.field, report.embedded, report.skipped, report.failed
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::LISTING_V2;
+
+    #[test]
+    fn config_defaults_to_legacy_field() {
+        let config = EmbeddingConfig::parse(None, None, None).unwrap();
+
+        assert_eq!(config, EmbeddingConfig::default());
+        assert_eq!(config.search_field().index_name(), VECTOR_INDEX);
+        assert_eq!(config.active_fields(), [&VectorField::legacy()]);
+    }
+
+    #[test]
+    fn config_declares_fields_side_by_side() {
+        let config = EmbeddingConfig::parse(
+            Some("text_embeddings_3l=text-embedding-3-large:3072@listing_v2"),
+            None,
+            Some("text_embeddings_3l"),
+        )
+        .unwrap();
+
+        let target = VectorField::new("text_embeddings_3l", "text-embedding-3-large", 3072).with_template(&LISTING_V2);
+        assert_eq!(config.fields, [VectorField::legacy(), target.clone()]);
+        assert_eq!(config.active_fields(), [&VectorField::legacy(), &target]);
+        assert_eq!(target.index_name(), "text_embeddings_3l_index");
+        assert_eq!(target.meta_path(), "embedding_meta.text_embeddings_3l");
+    }
+
+    #[test]
+    fn config_rejects_bad_fields() {
+        let cases = [
+            (Some("v2=text-embedding-3-large"), None, None),
+            (Some("v2=text-embedding-3-large:0"), None, None),
+            (Some("a.b=text-embedding-3-large:8"), None, None),
+            (Some("v2=m:8,v2=m:16"), None, None),
+            (Some("v2=m:8@listing_v9"), None, None),
+            (None, Some("v2"), None),
+            (None, None, Some("v2")),
+            (None, None, Some(EMBEDDING_PATH)),
+        ];
+
+        for (fields, search, target) in cases {
+            assert!(
+                matches!(EmbeddingConfig::parse(fields, search, target), Err(EmbeddingError::Config(_))),
+                "{:?} {:?} {:?}", fields, search, target
+            );
+        }
+    }
+
+    #[test]
+    fn meta_is_current_only_for_same_model_dimensions_and_text() {
+        let field = VectorField::legacy();
+        let meta = EmbeddingMeta {
+            model: EMBEDDING_MODEL.to_string(),
+            dimensions: EMBEDDING_DIMENSIONS,
+            template: LISTING_V1.id.to_string(),
+            text_hash: text_hash("cosy flat"),
+            created_at: "2025-01-01T00:00:00Z".to_string(),
+        };
+
+        assert!(field.is_current(Some(&meta), None));
+        assert!(field.is_current(Some(&meta), Some(&text_hash("cosy flat"))));
+        assert!(!field.is_current(Some(&meta), Some(&text_hash("cosy flat, renovated"))));
+        assert!(!VectorField::new(EMBEDDING_PATH, "text-embedding-3-large", 1536).is_current(Some(&meta), None));
+        assert!(!field.clone().with_template(&LISTING_V2).is_current(Some(&meta), None));
+        assert!(!field.is_current(None, None));
+    }
+
+    #[test]
+    fn meta_round_trips_through_listing() {
+        let field = VectorField::legacy();
+        let meta = EmbeddingMeta {
+            model: EMBEDDING_MODEL.to_string(),
+            dimensions: EMBEDDING_DIMENSIONS,
+            template: LISTING_V1.id.to_string(),
+            text_hash: "00".to_string(),
+            created_at: "2025-01-01T00:00:00Z".to_string(),
+        };
+        let listing = doc! { META_PATH: { EMBEDDING_PATH: mongodb::bson::to_bson(&meta).unwrap() } };
+
+        assert_eq!(EmbeddingMeta::of(&listing, &field), Some(meta));
+        assert_eq!(EmbeddingMeta::of(&doc! {}, &field), None);
+    }
+
+    #[tokio::test]
+    async fn embed_update_writes_stale_fields_with_their_metadata() {
+        let openai = crate::openai::mock::MockOpenAI::start().await;
+        let database = mongodb::Client::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap()
+            .database("db_endpoint_offline");
+        let config = EmbeddingConfig::parse(Some("v2=text-embedding-3-large:8@listing_v2"), None, Some("v2")).unwrap();
+        let client = EmbedOpenAI::new(EMBEDDING_MODEL).with_api_key("sk-test").with_base_url(&openai.base_url());
+        let usage = UsageLedger::new(database.collection("usage_daily"), Default::default());
+        let embedder = ListingEmbedder::new(&database.collection::<Document>("airbnb"), config, client, usage);
+
+        let legacy = VectorField::legacy();
+        let meta = EmbeddingMeta {
+            model: legacy.model.clone(),
+            dimensions: legacy.dimensions,
+            template: legacy.template.clone(),
+            text_hash: text_hash("Duplex"),
+            created_at: "2025-01-01T00:00:00Z".to_string(),
+        };
+        let listing = doc! { "name": "Duplex", META_PATH: { EMBEDDING_PATH: mongodb::bson::to_bson(&meta).unwrap() } };
+        let fields = embedder.config().active_fields();
+
+        let update = embedder.embed_update(&listing, &fields, "test").await.unwrap();
+
+        assert_eq!(update.keys().collect::<Vec<_>>(), ["v2", "embedding_meta.v2"]);
+        assert_eq!(update.get_array("v2").unwrap().len(), 8);
+        let written: EmbeddingMeta = mongodb::bson::from_bson(update.get("embedding_meta.v2").unwrap().clone()).unwrap();
+        assert_eq!((written.model.as_str(), written.dimensions), ("text-embedding-3-large", 8));
+        assert_eq!(written.template, "listing_v2");
+        assert_eq!(written.text_hash, text_hash("Name: Duplex"));
+
+        let received = openai.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].body["model"], "text-embedding-3-large");
+        assert_eq!(received[0].body["input"], "Name: Duplex");
+        assert!(embedder.embed_update(&doc! {}, &fields, "test").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn meta_without_template_was_rendered_with_listing_v1() {
+        let listing = doc! {
+            META_PATH: { EMBEDDING_PATH: {
+                "model": EMBEDDING_MODEL,
+                "dimensions": EMBEDDING_DIMENSIONS as i64,
+                "text_hash": text_hash("Duplex"),
+                "created_at": "2025-01-01T00:00:00Z",
+            } }
+        };
+
+        let meta = EmbeddingMeta::of(&listing, &VectorField::legacy()).unwrap();
+
+        assert_eq!(meta.template, LISTING_V1.id);
+        assert!(VectorField::legacy().is_current(Some(&meta), Some(&text_hash("Duplex"))));
+    }
+
+    #[test]
+    fn text_hash_is_stable_and_tells_texts_apart() {
+        assert_eq!(text_hash("a"), text_hash("a"));
+        assert_ne!(text_hash("a"), text_hash("b"));
+        assert_eq!(text_hash("").len(), 16);
+    }
+}
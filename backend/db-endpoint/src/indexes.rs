@@ -0,0 +1,553 @@
+use crate::embeddings::VectorField;
+use crate::search::{FILTER_FIELDS, TEXT_FIELDS, TEXT_INDEX};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::{Collection, SearchIndexModel, SearchIndexType};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+/// How long a healthy inspection is reused by `SearchIndexes::health`, so a
+/// dropped or rebuilt index shows up in health checks within this window
+pub const HEALTHY_REPORT_TTL: Duration = Duration::from_secs(60);
+
+/// An Atlas Search or Vector Search index the service relies on
+///
+/// # Fields
+/// * `name` - Index name queries refer to
+/// * `kind` - `search` or `vectorSearch`
+/// * `definition` - Definition the live index must match
+#[derive(Debug, Clone)]
+pub struct ExpectedIndex {
+    pub name: String,
+    pub kind: SearchIndexType,
+    pub definition: Document,
+}
+
+/// Indexes the listings collection must have for `AtlasSearch`: a vector
+/// index per vector field, so a migration target is indexed before searches
+/// switch to it, and the text index
+pub fn expected_indexes(fields: &[VectorField]) -> Vec<ExpectedIndex> {
+    let mut indexes: Vec<ExpectedIndex> = fields
+        .iter()
+        .map(|field| vector_index(field.index_name(), &field.name, field.dimensions, &FILTER_FIELDS))
+        .collect();
+
+    let text_fields: Document = TEXT_FIELDS
+        .iter()
+        .map(|field| (field.to_string(), Bson::Document(doc! { "type": "string" })))
+        .collect();
+    indexes.push(ExpectedIndex {
+        name: TEXT_INDEX.to_string(),
+        kind: SearchIndexType::Search,
+        definition: doc! { "mappings": { "dynamic": false, "fields": text_fields } },
+    });
+    indexes
+}
+
+/// A cosine vector index over `path` that can also filter on `filters`
+pub fn vector_index(name: String, path: &str, dimensions: u32, filters: &[&str]) -> ExpectedIndex {
+    let mut fields = vec![doc! {
+        "type": "vector",
+        "path": path,
+        "numDimensions": dimensions as i32,
+        "similarity": "cosine",
+    }];
+    fields.extend(filters.iter().map(|path| doc! { "type": "filter", "path": *path }));
+
+    ExpectedIndex {
+        name,
+        kind: SearchIndexType::VectorSearch,
+        definition: doc! { "fields": fields },
+    }
+}
+
+/// How a live index compares with its declaration
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexStatus {
+    /// Queryable and matching its declaration
+    Ready,
+    /// Declared but not created
+    Missing,
+    /// Created but still building or not queryable
+    Pending,
+    /// Definition differs from the declaration
+    Mismatch,
+    /// Live but not declared in code; ignored by the health check
+    Undeclared,
+}
+
+impl IndexStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ready => "ready",
+            Self::Missing => "missing",
+            Self::Pending => "pending",
+            Self::Mismatch => "mismatch",
+            Self::Undeclared => "undeclared",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct IndexState {
+    pub name: String,
+    pub status: IndexStatus,
+    /// Status reported by Atlas, e.g. `READY` or `BUILDING`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub live_status: Option<String>,
+    /// What differs from the declaration, for `Mismatch`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub differences: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct IndexReport {
+    /// Every declared index is `Ready`
+    pub healthy: bool,
+    pub indexes: Vec<IndexState>,
+    /// Why the indexes could not be listed, e.g. on a `mongod` without Atlas Search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl IndexReport {
+    /// One line naming every index that is not ready, for logs and health checks
+    pub fn summary(&self) -> String {
+        if let Some(error) = &self.error {
+            return format!("search indexes unavailable: {}", error);
+        }
+
+        self.indexes
+            .iter()
+            .filter(|index| !matches!(index.status, IndexStatus::Ready | IndexStatus::Undeclared))
+            .map(|index| {
+                let mut line = format!("{} is {}", index.name, index.status.as_str());
+                if !index.differences.is_empty() {
+                    line.push_str(&format!(" ({})", index.differences.join("; ")));
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, thiserror::Error)]
+pub enum IndexError {
+    #[error("Database Error: Search index command failed. {0}")]
+    Database(#[from] mongodb::error::Error),
+
+    #[error("Undeclared Index: `{0}` has no definition in code")]
+    Undeclared(String),
+}
+
+/// Creates, inspects, updates and drops the search indexes of the listings
+/// collection, and remembers the last inspection for health checks
+#[derive(Debug, Clone)]
+pub struct SearchIndexes {
+    collection: Collection<Document>,
+    expected: Vec<ExpectedIndex>,
+    /// Last inspection and when it ran
+    last_report: Arc<RwLock<Option<(Instant, IndexReport)>>>,
+    report_ttl: Duration,
+}
+
+impl SearchIndexes {
+    /// # Arguments
+    /// * `expected` - Indexes the collection must have, e.g. `expected_indexes`
+    pub fn new<T: Send + Sync>(collection: &Collection<T>, expected: Vec<ExpectedIndex>) -> Self {
+        Self {
+            collection: collection.clone_with_type(),
+            expected,
+            last_report: Arc::new(RwLock::new(None)),
+            report_ttl: HEALTHY_REPORT_TTL,
+        }
+    }
+
+    /// Compares the live indexes with the expected ones
+    ///
+    /// # Errors
+    /// * `mongodb::error::Error` - The indexes could not be listed
+    pub async fn inspect(&self) -> mongodb::error::Result<IndexReport> {
+        let live: Vec<Document> = self.collection
+            .list_search_indexes()
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut indexes: Vec<IndexState> = self.expected
+            .iter()
+            .map(|expected| {
+                let found = live.iter().find(|index| index.get_str("name") == Ok(expected.name.as_str()));
+                compare(expected, found)
+            })
+            .collect();
+        indexes.extend(
+            live.iter()
+                .filter_map(|index| index.get_str("name").ok())
+                .filter(|name| self.expected.iter().all(|expected| expected.name != *name))
+                .map(|name| IndexState {
+                    name: name.to_string(),
+                    status: IndexStatus::Undeclared,
+                    live_status: None,
+                    differences: Vec::new(),
+                }),
+        );
+
+        let healthy = indexes
+            .iter()
+            .all(|index| matches!(index.status, IndexStatus::Ready | IndexStatus::Undeclared));
+        Ok(IndexReport { healthy, indexes, error: None })
+    }
+
+    /// Inspects the indexes and remembers the outcome; a failed inspection
+    /// is reported as unhealthy
+    pub async fn verify(&self) -> IndexReport {
+        let report = self.inspect().await.unwrap_or_else(|e| IndexReport {
+            healthy: false,
+            indexes: Vec::new(),
+            error: Some(e.to_string()),
+        });
+        if !report.healthy {
+            tracing::warn!("Search indexes are not ready: {}", report.summary());
+        }
+
+        *self.last_report.write().await = Some((Instant::now(), report.clone()));
+        report
+    }
+
+    /// Last report while it is healthy and younger than the report TTL;
+    /// otherwise inspects again, so a finished build or a fix is picked up
+    /// at once and a dropped index within the TTL
+    pub async fn health(&self) -> IndexReport {
+        if let Some((checked_at, report)) = self.last_report.read().await.as_ref() {
+            if report.healthy && checked_at.elapsed() < self.report_ttl {
+                return report.clone();
+            }
+        }
+        self.verify().await
+    }
+
+    /// Creates a declared index, or updates it when its definition differs
+    ///
+    /// Atlas builds indexes asynchronously, so the returned state is usually
+    /// `Pending`.
+    ///
+    /// # Errors
+    /// * `IndexError::Undeclared` - `name` is not an expected index
+    /// * `IndexError::Database` - The index command failed
+    pub async fn apply(&self, name: &str) -> Result<IndexState, IndexError> {
+        let expected = self.expected
+            .iter()
+            .find(|expected| expected.name == name)
+            .ok_or_else(|| IndexError::Undeclared(name.to_string()))?;
+
+        let current = self.state(name).await?;
+        match current.map(|state| state.status) {
+            None | Some(IndexStatus::Missing) => {
+                tracing::info!("Creating search index {}", name);
+                let model = SearchIndexModel::builder()
+                    .name(name.to_string())
+                    .index_type(expected.kind.clone())
+                    .definition(expected.definition.clone())
+                    .build();
+                self.collection.create_search_index(model).await?;
+            }
+            Some(IndexStatus::Mismatch) => {
+                tracing::info!("Updating search index {}", name);
+                self.collection
+                    .update_search_index(name, expected.definition.clone())
+                    .await?;
+            }
+            Some(_) => {}
+        }
+
+        *self.last_report.write().await = None;
+        Ok(self.state(name).await?.unwrap_or_else(|| compare(expected, None)))
+    }
+
+    /// Drops a live declared index; `false` when there is none by that name
+    ///
+    /// # Errors
+    /// * `IndexError::Undeclared` - `name` is not an expected index
+    /// * `IndexError::Database` - The index command failed
+    pub async fn drop(&self, name: &str) -> Result<bool, IndexError> {
+        if !self.expected.iter().any(|expected| expected.name == name) {
+            return Err(IndexError::Undeclared(name.to_string()));
+        }
+
+        let exists = self.state(name).await?.is_some_and(|state| state.status != IndexStatus::Missing);
+        if exists {
+            tracing::info!("Dropping search index {}", name);
+            self.collection.drop_search_index(name).await?;
+            *self.last_report.write().await = None;
+        }
+        Ok(exists)
+    }
+
+    async fn state(&self, name: &str) -> mongodb::error::Result<Option<IndexState>> {
+        Ok(self.inspect().await?.indexes.into_iter().find(|index| index.name == name))
+    }
+}
+
+/// State of a declared index given the live one, if any
+fn compare(expected: &ExpectedIndex, live: Option<&Document>) -> IndexState {
+    let Some(live) = live else {
+        return IndexState {
+            name: expected.name.clone(),
+            status: IndexStatus::Missing,
+            live_status: None,
+            differences: Vec::new(),
+        };
+    };
+
+    let live_status = live.get_str("status").ok().map(str::to_string);
+    let mut differences = Vec::new();
+    match live.get_document("latestDefinition") {
+        Ok(definition) => differences_between(
+            &Bson::Document(expected.definition.clone()),
+            &Bson::Document(definition.clone()),
+            "definition",
+            &mut differences,
+        ),
+        Err(_) => differences.push("definition is missing".to_string()),
+    }
+
+    let status = if !differences.is_empty() {
+        IndexStatus::Mismatch
+    } else if live.get_bool("queryable").unwrap_or(false) && live_status.as_deref() == Some("READY") {
+        IndexStatus::Ready
+    } else {
+        IndexStatus::Pending
+    };
+
+    IndexState { name: expected.name.clone(), status, live_status, differences }
+}
+
+/// Collects where `live` differs from `expected`
+///
+/// Only the keys `expected` declares are compared, so defaults Atlas adds
+/// do not count as differences. Arrays match when they have the same length
+/// and every expected entry matches a live one in any order; numbers match
+/// by value whatever their BSON type.
+fn differences_between(expected: &Bson, live: &Bson, path: &str, differences: &mut Vec<String>) {
+    match (expected, live) {
+        (Bson::Document(expected), Bson::Document(live)) => {
+            for (key, value) in expected {
+                let path = format!("{}.{}", path, key);
+                match live.get(key) {
+                    Some(live_value) => differences_between(value, live_value, &path, differences),
+                    None => differences.push(format!("{} is missing", path)),
+                }
+            }
+        }
+        (Bson::Array(expected), Bson::Array(live)) => {
+            if expected.len() != live.len() {
+                differences.push(format!("{} has {} entries, expected {}", path, live.len(), expected.len()));
+            }
+            for entry in expected {
+                let matched = live.iter().any(|candidate| {
+                    let mut nested = Vec::new();
+                    differences_between(entry, candidate, path, &mut nested);
+                    nested.is_empty()
+                });
+                if !matched {
+                    differences.push(format!("{} lacks {}", path, entry));
+                }
+            }
+        }
+        (expected, live) => {
+            let equal = match (as_number(expected), as_number(live)) {
+                (Some(expected), Some(live)) => expected == live,
+                _ => expected == live,
+            };
+            if !equal {
+                differences.push(format!("{} is {}, expected {}", path, live, expected));
+            }
+        }
+    }
+}
+
+fn as_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Double(value) => Some(*value),
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::{EMBEDDING_PATH, VECTOR_INDEX};
+    use crate::EMBEDDING_DIMENSIONS;
+
+    fn vector_index() -> ExpectedIndex {
+        expected_indexes(&[VectorField::legacy()]).into_iter().find(|index| index.name == VECTOR_INDEX).unwrap()
+    }
+
+    /// A live index as `$listSearchIndexes` returns it
+    fn live(definition: Document, status: &str, queryable: bool) -> Document {
+        doc! {
+            "id": "6524096020da840844a4c4a7",
+            "name": VECTOR_INDEX,
+            "type": "vectorSearch",
+            "status": status,
+            "queryable": queryable,
+            "latestDefinition": definition,
+        }
+    }
+
+    #[test]
+    fn vector_index_declares_dimensions_and_filters() {
+        let fields = vector_index().definition.get_array("fields").unwrap().clone();
+        assert_eq!(fields.len(), 1 + FILTER_FIELDS.len());
+        assert_eq!(
+            fields[0].as_document().unwrap().get_i32("numDimensions").unwrap(),
+            EMBEDDING_DIMENSIONS as i32
+        );
+    }
+
+    #[test]
+    fn every_vector_field_gets_an_index() {
+        let fields = [
+            VectorField::legacy(),
+            VectorField::new("text_embeddings_3l", "text-embedding-3-large", 3072),
+        ];
+
+        let names: Vec<String> = expected_indexes(&fields).into_iter().map(|index| index.name).collect();
+
+        assert_eq!(names, [VECTOR_INDEX, "text_embeddings_3l_index", TEXT_INDEX]);
+    }
+
+    #[test]
+    fn matching_index_is_ready_whatever_field_order() {
+        let expected = vector_index();
+        let mut fields = expected.definition.get_array("fields").unwrap().clone();
+        fields.reverse();
+        let mut vector = fields.pop().unwrap().as_document().unwrap().clone();
+        vector.insert("numDimensions", EMBEDDING_DIMENSIONS as i64);
+        fields.insert(0, Bson::Document(vector));
+
+        let state = compare(&expected, Some(&live(doc! { "fields": fields }, "READY", true)));
+
+        assert_eq!(state.status, IndexStatus::Ready, "{:?}", state.differences);
+    }
+
+    #[test]
+    fn building_index_is_pending() {
+        let expected = vector_index();
+        let state = compare(&expected, Some(&live(expected.definition.clone(), "BUILDING", false)));
+
+        assert_eq!(state.status, IndexStatus::Pending);
+        assert_eq!(state.live_status.as_deref(), Some("BUILDING"));
+    }
+
+    #[test]
+    fn wrong_dimensions_are_a_mismatch() {
+        let expected = vector_index();
+        let mut fields = expected.definition.get_array("fields").unwrap().clone();
+        fields[0] = Bson::Document(doc! {
+            "type": "vector",
+            "path": EMBEDDING_PATH,
+            "numDimensions": 3072,
+            "similarity": "cosine",
+        });
+
+        let state = compare(&expected, Some(&live(doc! { "fields": fields }, "READY", true)));
+
+        assert_eq!(state.status, IndexStatus::Mismatch);
+        assert_eq!(state.differences.len(), 1);
+        assert!(state.differences[0].contains("definition.fields lacks"), "{:?}", state.differences);
+    }
+
+    #[test]
+    fn missing_filter_field_is_a_mismatch() {
+        let expected = vector_index();
+        let mut fields = expected.definition.get_array("fields").unwrap().clone();
+        fields.pop();
+
+        let state = compare(&expected, Some(&live(doc! { "fields": fields }, "READY", true)));
+
+        assert_eq!(state.status, IndexStatus::Mismatch);
+        assert!(state.differences[0].contains("has 5 entries, expected 6"), "{:?}", state.differences);
+    }
+
+    #[test]
+    fn atlas_defaults_are_not_differences() {
+        let expected = expected_indexes(&[]).into_iter().find(|index| index.name == TEXT_INDEX).unwrap();
+        let mut definition = expected.definition.clone();
+        definition.insert("analyzer", "lucene.standard");
+        definition.insert("searchAnalyzer", "lucene.standard");
+
+        let state = compare(&expected, Some(&live(definition, "READY", true)));
+
+        assert_eq!(state.status, IndexStatus::Ready, "{:?}", state.differences);
+        assert_eq!(compare(&expected, None).status, IndexStatus::Missing);
+    }
+
+    #[test]
+    fn summary_names_indexes_that_are_not_ready() {
+        let report = IndexReport {
+            healthy: false,
+            indexes: vec![
+                IndexState {
+                    name: VECTOR_INDEX.to_string(),
+                    status: IndexStatus::Mismatch,
+                    live_status: Some("READY".to_string()),
+                    differences: vec!["definition.fields has 5 entries, expected 6".to_string()],
+                },
+                IndexState {
+                    name: TEXT_INDEX.to_string(),
+                    status: IndexStatus::Ready,
+                    live_status: Some("READY".to_string()),
+                    differences: Vec::new(),
+                },
+            ],
+            error: None,
+        };
+
+        assert_eq!(
+            report.summary(),
+            "vector_index is mismatch (definition.fields has 5 entries, expected 6)"
+        );
+    }
+
+    /// Indexes of a collection nobody serves, with a healthy report cached
+    async fn cached_healthy(report_ttl: Duration) -> SearchIndexes {
+        let client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap();
+        let indexes = SearchIndexes {
+            report_ttl,
+            ..SearchIndexes::new(&client.database("db_endpoint_offline").collection::<Document>("airbnb"), vec![vector_index()])
+        };
+        let report = IndexReport { healthy: true, indexes: Vec::new(), error: None };
+        *indexes.last_report.write().await = Some((Instant::now(), report));
+        indexes
+    }
+
+    #[tokio::test]
+    async fn health_reuses_a_recent_healthy_report() {
+        let indexes = cached_healthy(HEALTHY_REPORT_TTL).await;
+
+        assert!(indexes.health().await.healthy);
+    }
+
+    #[tokio::test]
+    async fn health_inspects_again_once_the_healthy_report_expires() {
+        let indexes = cached_healthy(Duration::ZERO).await;
+
+        let report = indexes.health().await;
+
+        assert!(!report.healthy);
+        assert!(report.summary().starts_with("search indexes unavailable"));
+        assert!(!indexes.last_report.read().await.as_ref().unwrap().1.healthy);
+    }
+}
@@ -0,0 +1,245 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::openai::usage::{PriceTable, TokenUsage, UsageSink};
+
+pub use crate::openai::usage::ENDPOINT_EMBEDDINGS;
+
+/// Daily usage totals for one model, endpoint and API caller
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UsageRollup {
+    /// UTC day in `YYYY-MM-DD` format
+    pub day: String,
+
+    pub model: String,
+
+    pub endpoint: String,
+
+    pub caller: String,
+
+    pub requests: i64,
+
+    pub prompt_tokens: i64,
+
+    pub completion_tokens: i64,
+
+    pub reasoning_tokens: i64,
+
+    pub cached_tokens: i64,
+
+    /// Estimated cost in USD; zero for models missing from the price table
+    pub cost_usd: f64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+/// Filters accepted by the usage admin endpoint
+#[derive(Debug, Deserialize, Default)]
+pub struct UsageQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub model: Option<String>,
+    pub endpoint: Option<String>,
+    pub caller: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UsageReport {
+    pub rollups: Vec<UsageRollup>,
+    pub total_requests: i64,
+    pub total_tokens: i64,
+    pub total_cost_usd: f64,
+}
+
+/// Records token usage and persists it as daily rollups in MongoDB
+#[derive(Debug, Clone)]
+pub struct UsageLedger {
+    collection: Collection<UsageRollup>,
+    prices: Arc<PriceTable>,
+}
+
+impl UsageLedger {
+    pub fn new(collection: Collection<UsageRollup>, prices: PriceTable) -> Self {
+        Self {
+            collection,
+            prices: Arc::new(prices),
+        }
+    }
+
+    /// Adds one request's usage to today's rollup for `model`, `endpoint` and `caller`
+    pub async fn record(
+        &self,
+        model: &str,
+        endpoint: &str,
+        caller: &str,
+        usage: &TokenUsage,
+    ) -> mongodb::error::Result<()> {
+        let now = Utc::now();
+        let cost = self.prices.estimate_cost(model, usage).unwrap_or_else(|| {
+            tracing::warn!("No price configured for model {}", model);
+            0.0
+        });
+
+        let filter = rollup_key(now, model, endpoint, caller);
+        let update = doc! {
+            "$inc": {
+                "requests": 1_i64,
+                "prompt_tokens": usage.prompt_tokens as i64,
+                "completion_tokens": usage.completion_tokens as i64,
+                "reasoning_tokens": usage.reasoning_tokens as i64,
+                "cached_tokens": usage.cached_tokens as i64,
+                "cost_usd": cost,
+            },
+            "$set": { "updated_at": now.to_rfc3339() },
+        };
+
+        self.collection
+            .update_one(filter, update)
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records usage in the background so the caller's response is not delayed
+    pub fn record_detached(
+        &self,
+        model: &str,
+        endpoint: &str,
+        caller: &str,
+        usage: TokenUsage,
+    ) {
+        let ledger = self.clone();
+        let model = model.to_string();
+        let endpoint = endpoint.to_string();
+        let caller = caller.to_string();
+
+        tokio::spawn(async move {
+            if let Err(e) = ledger.record(&model, &endpoint, &caller, &usage).await {
+                tracing::error!("Failed to record usage: {}", e);
+            }
+        });
+    }
+
+    /// Sink the chat and responses clients report to on behalf of `caller`
+    pub fn for_caller(&self, caller: &str) -> Arc<CallerUsage> {
+        Arc::new(CallerUsage { ledger: self.clone(), caller: caller.to_string() })
+    }
+
+    pub async fn report(&self, query: &UsageQuery) -> mongodb::error::Result<UsageReport> {
+        let rollups: Vec<UsageRollup> = self.collection
+            .find(report_filter(query))
+            .sort(doc! { "day": 1, "model": 1, "endpoint": 1, "caller": 1 })
+            .await?
+            .try_collect()
+            .await?;
+
+        let total_requests = rollups.iter().map(|r| r.requests).sum();
+        let total_tokens = rollups
+            .iter()
+            .map(|r| r.prompt_tokens + r.completion_tokens)
+            .sum();
+        let total_cost_usd = rollups.iter().map(|r| r.cost_usd).sum();
+
+        Ok(UsageReport {
+            rollups,
+            total_requests,
+            total_tokens,
+            total_cost_usd,
+        })
+    }
+}
+
+/// Rollup a request made at `now` adds to: one per UTC day, model, endpoint
+/// and caller
+fn rollup_key(now: DateTime<Utc>, model: &str, endpoint: &str, caller: &str) -> Document {
+    doc! {
+        "day": now.format("%Y-%m-%d").to_string(),
+        "model": model,
+        "endpoint": endpoint,
+        "caller": caller,
+    }
+}
+
+/// Rollups matching the filters of `query`; `from` and `to` are inclusive days
+fn report_filter(query: &UsageQuery) -> Document {
+    let mut filter = Document::new();
+    let mut day = Document::new();
+    if let Some(from) = &query.from {
+        day.insert("$gte", from);
+    }
+    if let Some(to) = &query.to {
+        day.insert("$lte", to);
+    }
+    if !day.is_empty() {
+        filter.insert("day", day);
+    }
+    if let Some(model) = &query.model {
+        filter.insert("model", model);
+    }
+    if let Some(endpoint) = &query.endpoint {
+        filter.insert("endpoint", endpoint);
+    }
+    if let Some(caller) = &query.caller {
+        filter.insert("caller", caller);
+    }
+    filter
+}
+
+/// Usage of one API caller, recorded in the background as clients report it
+#[derive(Debug, Clone)]
+pub struct CallerUsage {
+    ledger: UsageLedger,
+    caller: String,
+}
+
+impl UsageSink for CallerUsage {
+    fn record(&self, model: &str, endpoint: &str, usage: TokenUsage) {
+        self.ledger.record_detached(model, endpoint, &self.caller, usage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::usage::ENDPOINT_CHAT;
+    use chrono::TimeZone;
+
+    #[test]
+    fn rollups_are_keyed_by_utc_day_model_endpoint_and_caller() {
+        let late = Utc.with_ymd_and_hms(2025, 1, 31, 23, 59, 59).unwrap();
+
+        assert_eq!(
+            rollup_key(late, "gpt-4o-mini", ENDPOINT_CHAT, "web"),
+            doc! { "day": "2025-01-31", "model": "gpt-4o-mini", "endpoint": "chat", "caller": "web" }
+        );
+        assert_eq!(
+            rollup_key(late + chrono::Duration::seconds(1), "gpt-4o-mini", ENDPOINT_CHAT, "web").get_str("day"),
+            Ok("2025-02-01")
+        );
+    }
+
+    #[test]
+    fn report_filter_keeps_only_the_given_conditions() {
+        assert_eq!(report_filter(&UsageQuery::default()), doc! {});
+
+        let query = UsageQuery {
+            from: Some("2025-01-01".to_string()),
+            to: Some("2025-01-31".to_string()),
+            caller: Some("web".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            report_filter(&query),
+            doc! { "day": { "$gte": "2025-01-01", "$lte": "2025-01-31" }, "caller": "web" }
+        );
+        assert_eq!(
+            report_filter(&UsageQuery { endpoint: Some(ENDPOINT_EMBEDDINGS.to_string()), ..Default::default() }),
+            doc! { "endpoint": "embeddings" }
+        );
+    }
+}
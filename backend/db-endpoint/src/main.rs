@@ -3,7 +3,7 @@ use axum::{
     http::{HeaderMap, StatusCode},
     middleware,
     response::Json,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 use serde::{Deserialize, Serialize};
@@ -11,25 +11,185 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
-use mongodb::{bson::doc, Client, Collection};
+use mongodb::{bson::doc, Client, Collection, Database};
+use openai::chat::ChatOpenAI;
 use openai::embed::EmbedOpenAI;
+use openai::usage::{PriceTable, TokenUsage};
 use env_logger::Env;
-use futures::TryStreamExt;
+
+mod chunks;
+use chunks::{chunk_index, ChunkWriter, CHUNK_COLLECTION, CHUNK_INDEX};
 
 mod document;
-use document::{ShortTermRental, ResponseSearch};
+use document::ResponseSearch;
+
+mod embeddings;
+use embeddings::{EmbeddingConfig, EmbeddingStatus, ListingEmbedder, TextPreview};
+
+mod indexes;
+use indexes::{IndexError, IndexReport, IndexState, SearchIndexes};
+
+mod ledger;
+use ledger::{UsageLedger, UsageQuery, UsageReport, ENDPOINT_EMBEDDINGS};
+
+mod reembed;
+use reembed::{ReembedOptions, ReembedWorker};
+
+mod rerank;
+use rerank::{Relevance, RerankOptions, RerankOutcome, Reranker, DEFAULT_RERANK_MODEL};
+
+mod search;
+use search::{
+    Aggregation, AtlasSearch, ExplainContext, Explanation, FacetQuery, Facets, GeoQuery, HybridQuery, PassageHit,
+    PassageQuery, PassageSearch, SearchBackend, SearchBackendKind, SearchError, SearchFilter, VectorQuery,
+};
+
+mod session;
+use session::{
+    AppendMessages, ListSessionsParams, SessionStore, SessionSummary, SessionView,
+    DEFAULT_SESSION_TTL_HOURS,
+};
+
+mod templates;
 
 // OpenAI
 pub mod openai;
-pub const DEBUG_PRE: bool = false;
-pub const DEBUG_POST: bool = false;
+
+/// Model behind the legacy `text_embeddings` field, which searches use
+/// unless `SEARCH_VECTOR_FIELD` names another
+pub const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+pub const EMBEDDING_DIMENSIONS: u32 = 1536;
 
 #[derive(Debug, Clone)]
 struct AppState {
     http_client: reqwest::Client,
     services: HashMap<String, ServiceConfig>,
     collection: Collection<ResponseSearch>,
+    usage: UsageLedger,
+    sessions: SessionStore,
+    /// Callers allowed to use sessions, by API key
+    caller_keys: HashMap<String, String>,
+    admin_token: Option<String>,
+    /// Embeds search queries with the model of the searched vector field
+    embedder: EmbedOpenAI,
+    embeddings: ListingEmbedder,
+    /// Embeds listing passages with the model of the searched vector field
+    chunks: ChunkWriter,
+    search: Arc<dyn SearchBackend>,
+    passages: PassageSearch,
+    /// Reorders vector search hits when a request asks for it
+    reranker: Reranker,
+    indexes: SearchIndexes,
+    /// Health checks require the declared search indexes, set when `search` runs on Atlas
+    verify_indexes: bool,
+}
+
+impl AppState {
+    /// Builds the state on top of `database`, which holds the `airbnb`
+    /// listings, the usage ledger and the sessions
+    ///
+    /// # Arguments
+    /// * `services` - Backends reachable through the proxy routes
+    /// * `embedder` - Client search queries are embedded with, cloned per request
+    /// * `admin_token` - Token admin endpoints require, `None` disables them
+    fn new(
+        database: &Database,
+        services: HashMap<String, ServiceConfig>,
+        embedder: EmbedOpenAI,
+        admin_token: Option<String>,
+    ) -> Self {
+        let session_ttl_hours = std::env::var("SESSION_TTL_HOURS")
+            .ok()
+            .and_then(|hours| hours.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_SESSION_TTL_HOURS);
+
+        let collection: Collection<ResponseSearch> = database.collection("airbnb");
+        let usage = UsageLedger::new(database.collection("usage_daily"), PriceTable::from_env());
+        let config = EmbeddingConfig::default();
+        let search = Arc::new(AtlasSearch::new(collection.clone_with_type(), config.search_field().clone()));
+        let indexes = SearchIndexes::new(&collection, indexes::expected_indexes(&config.fields));
+        let chunk_collection = database.collection(CHUNK_COLLECTION);
+        let chunks = ChunkWriter::new(
+            &collection,
+            chunk_collection.clone(),
+            config.search_field().clone(),
+            embedder.clone(),
+            usage.clone(),
+        );
+        let passages = PassageSearch::new(chunk_collection, &collection);
+        let embeddings = ListingEmbedder::new(&collection, config, embedder.clone(), usage.clone());
+        let reranker = Reranker::new(ChatOpenAI::new(DEFAULT_RERANK_MODEL), RerankOptions::default(), usage.clone());
+
+        Self {
+            http_client: reqwest::Client::new(),
+            services,
+            collection,
+            usage,
+            sessions: SessionStore::new(database.collection("sessions"), session_ttl_hours),
+            caller_keys: HashMap::new(),
+            admin_token,
+            embedder,
+            embeddings,
+            chunks,
+            search,
+            passages,
+            reranker,
+            indexes,
+            verify_indexes: false,
+        }
+    }
+
+    /// Uses the vector fields of `config`: queries are embedded with the
+    /// search field's model and searched on Atlas over its index, and every
+    /// field gets a declared vector index
+    fn with_embedding_config(mut self, config: EmbeddingConfig) -> Self {
+        let field = config.search_field().clone();
+        self.embedder = self.embedder.with_model(&field.model).with_dimensions(field.dimensions);
+        self.search = Arc::new(AtlasSearch::new(self.collection.clone_with_type(), field.clone()));
+        self.indexes = SearchIndexes::new(&self.collection, indexes::expected_indexes(&config.fields));
+        self.chunks = ChunkWriter::new(
+            &self.collection,
+            self.chunks.collection().clone(),
+            field.clone(),
+            self.embedder.clone(),
+            self.usage.clone(),
+        );
+        self.embeddings = ListingEmbedder::new(&self.collection, config, self.embedder.clone(), self.usage.clone());
+        self
+    }
+
+    /// Lets the callers of `keys`, mapping API keys to caller names, use
+    /// sessions
+    fn with_caller_keys(mut self, keys: HashMap<String, String>) -> Self {
+        self.caller_keys = keys;
+        self
+    }
+
+    /// Answers listing queries with `search` instead of Atlas
+    fn with_search_backend(mut self, search: Arc<dyn SearchBackend>) -> Self {
+        self.search = search;
+        self
+    }
+
+    /// Judges rerank candidates with `reranker` instead of the default model
+    fn with_reranker(mut self, reranker: Reranker) -> Self {
+        self.reranker = reranker;
+        self
+    }
+
+    /// Ranks passages in process, for deployments without Atlas Search
+    fn with_exact_passage_search(mut self) -> Self {
+        self.passages = self.passages.exact();
+        self
+    }
+
+    /// Fails health checks while the search indexes differ from their declaration
+    fn with_index_verification(mut self) -> Self {
+        self.verify_indexes = true;
+        self
+    }
 }
+
 #[derive(Debug, Clone)]
 struct ServiceConfig {
     base_url: String,
@@ -41,6 +201,27 @@ struct QueryParams {
     service: Option<String>,
 }
 
+/// Per-request rerank toggle of the search routes
+///
+/// # Fields
+/// * `rerank` - Reorders the vector search candidates with the chat model
+/// * `rerank_budget_ms` - Optional - Time to wait for the model, defaults to
+///   `RERANK_BUDGET_MS` and is capped at `MAX_RERANK_BUDGET`
+#[derive(Debug, Deserialize, Default)]
+struct RerankParams {
+    #[serde(default)]
+    rerank: bool,
+    rerank_budget_ms: Option<u64>,
+}
+
+/// How the returned listing was reranked, attached to it as `rerank`
+#[derive(Debug, Serialize)]
+struct RerankSummary {
+    outcome: RerankOutcome,
+    #[serde(flatten)]
+    relevance: Option<Relevance>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct ApiResponse<T> {
     success: bool,
@@ -58,7 +239,7 @@ struct HealthCheck {
 }
 
 async fn get_data(
-    Query(params): Query<HashMap<String, String>>,
+    Query(_params): Query<HashMap<String, String>>,
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<ApiResponse<ResponseSearch>>, StatusCode> {
     
@@ -69,7 +250,10 @@ async fn get_data(
     let results = state.collection
         .find_one(doc! { "name": "Private Room in Bushwick" })
         .await
-        .expect("Failed to query database");
+        .map_err(|e| {
+            tracing::error!("Failed to query database: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
     Ok(Json(ApiResponse {
         success: true,
@@ -113,14 +297,20 @@ async fn get_data(
 // }
 
 async fn mock_get_data(
-    Query(params): Query<HashMap<String, String>>,
+    Query(_params): Query<HashMap<String, String>>,
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<ApiResponse<ResponseSearch>>, StatusCode> {
 
-    let mock_result = state.collection
-        .find_one(doc! { "_id": 10084023 })
+    let mock_result = state.search
+        .get_by_id(10084023)
         .await
-        .expect("Failed to query database");
+        .map_err(|e| search_error_status("listing lookup", e))?
+        .map(mongodb::bson::from_document::<ResponseSearch>)
+        .transpose()
+        .map_err(|e| {
+            tracing::error!("Failed to read listing: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
     // let mock_data = ShortTermRental {
     //     id: 123,
@@ -138,17 +328,15 @@ async fn mock_get_data(
     }))
 }
 
+/// Embeds the body and returns the closest listing; with `rerank=true` the
+/// top candidates are reordered by the chat model first
 async fn post_embed(
-    Path(path): Path<String>,
-    Query(params): Query<QueryParams>,
+    Path(_path): Path<String>,
+    Query(params): Query<RerankParams>,
     headers: HeaderMap,
     State(state): State<Arc<AppState>>,
     body: String,
 ) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
-    let service_name = params.service.unwrap_or_else(|| "default".to_string());
-
-    let llm = EmbedOpenAI::new("text-embedding-3-small");   
-
     let input_str = body.trim().to_string();
 
     if input_str.is_empty() {
@@ -157,54 +345,47 @@ async fn post_embed(
     }
     tracing::info!("Embedding: {}", input_str);
 
-    let response = llm
-        .with_dimensions(1536)
-        .embed_content(&input_str)
-        .await
-        .expect("Failed to get embedding");
+    let vector = embed_query(&state, &input_str, &caller_id(&headers)).await?;
 
-    let embeddings: Vec<f32> = response.data[0].embedding.clone();
-        
-    let pipeline = vec! [
-        doc! {
-            "$vectorSearch": doc! {
-            "queryVector": embeddings,
-            "path": "text_embeddings",
-            "numCandidates": 120,
-            "index": "vector_index",
-            "limit": 1
-        }
+    let query = VectorQuery {
+        vector,
+        filter: SearchFilter::default(),
+        projection: doc! {
+            "_id": 0,
+            "name": 1,
+            "summary": 1,
+            "description": 1,
+            "beds": 1,
+            "bathrooms": 1,
+            "bedrooms": 1,
+            "amenities": 1,
+            "price": 1,
         },
-        doc! {
-            "$project": doc! {
-                "_id": 0,
-                "name": 1,
-                "summary": 1,
-                "description": 1,
-                "beds": 1,
-                "bathrooms": 1,
-                "bedrooms": 1,
-                "amenities": 1,
-                "price": 1,
-            }
-        }
-    ]; 
+        limit: if params.rerank { state.reranker.options().candidates } else { 1 },
+        num_candidates: 120,
+    };
 
-    let mut results = state.collection
-        .aggregate(pipeline)
-        .await
-        .expect("Failed to execute aggregation");
-    
-    let first_result = results
-        .try_next()
+    let hits = state.search
+        .vector_search(&query)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+        .map_err(|e| search_error_status("vector search", e))?;
+
+    let (first_result, rerank) = if params.rerank {
+        let budget = state.reranker.options().budget_for(params.rerank_budget_ms);
+        let reranked = state.reranker.rerank(&input_str, hits, budget, &caller_id(&headers)).await;
+        let first = reranked.hits.into_iter().next().ok_or(StatusCode::NOT_FOUND)?;
+        (first.hit.listing, Some(RerankSummary { outcome: reranked.outcome, relevance: first.relevance }))
+    } else {
+        (hits.into_iter().next().ok_or(StatusCode::NOT_FOUND)?.listing, None)
+    };
 
     // Convert Document to serde_json::Value
     let bson_value = mongodb::bson::to_bson(&first_result)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let json_value = serde_json::Value::from(bson_value);
+    let mut json_value = serde_json::Value::from(bson_value);
+    if let (Some(rerank), Some(listing)) = (rerank, json_value.as_object_mut()) {
+        listing.insert("rerank".to_string(), serde_json::json!(rerank));
+    }
 
     Ok(Json(ApiResponse {
         success: true,
@@ -215,20 +396,30 @@ async fn post_embed(
     }))
 }
 
-async fn health_check() -> Json<ApiResponse<HealthCheck>> {
+async fn health_check(
+    State(state): State<Arc<AppState>>,
+) -> (StatusCode, Json<ApiResponse<HealthCheck>>) {
+    let problem = if state.verify_indexes {
+        let report = state.indexes.health().await;
+        (!report.healthy).then(|| report.summary())
+    } else {
+        None
+    };
+
     let health = HealthCheck {
-        status: "healthy".to_string(),
+        status: if problem.is_none() { "healthy" } else { "unhealthy" }.to_string(),
         timestamp: chrono::Utc::now().to_rfc3339(),
         version: env!("CARGO_PKG_VERSION").to_string(),
     };
+    let status = if problem.is_none() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
 
-    Json(ApiResponse {
-        success: true,
+    (status, Json(ApiResponse {
+        success: problem.is_none(),
         data: Some(health),
         embed: None,
-        error: None,
+        error: problem,
         request_id: uuid::Uuid::new_v4().to_string(),
-    })
+    }))
 }
 
 async fn proxy_get_request(
@@ -305,97 +496,1724 @@ async fn proxy_post_request(
     Ok(Json(json_response))
 }
 
-fn should_forward_header(header_name: &str) -> bool {
-    match header_name.to_lowercase().as_str() {
-        "authorization" | "content-type" | "accept" | "user-agent" => true,
-        name if name.starts_with("x-") => true,
-        _ => false,
+async fn get_usage(
+    Query(params): Query<UsageQuery>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<UsageReport>>, StatusCode> {
+    require_admin(&state, &headers)?;
+
+    let report = state.usage
+        .report(&params)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load usage report: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(report),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+async fn get_indexes(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<IndexReport>>, StatusCode> {
+    require_admin(&state, &headers)?;
+
+    let report = state.indexes.verify().await;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(report),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+/// Coverage of each vector field, to tell when a migration target can
+/// become the search field
+async fn get_embeddings(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<EmbeddingStatus>>, StatusCode> {
+    require_admin(&state, &headers)?;
+
+    let status = state.embeddings
+        .status()
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to count embeddings: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(status),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+/// A listing search
+///
+/// # Fields
+/// * `query` - What the user typed; embedded, and matched as keywords too
+///   when `keywords` is set
+/// * `keywords` - Fuses vector and keyword rankings instead of ranking by
+///   vector alone
+/// * `filter` - Conditions listings must meet
+/// * `near` - Optional - Point explanations measure distances from
+/// * `limit` - Optional - Listings to return, 10 by default and 50 at most
+/// * `explain` - Adds an `explain` block to every result
+/// * `justify` - Also asks the chat model for a one-sentence justification
+///   per result; implies `explain`
+#[derive(Debug, Deserialize)]
+struct SearchRequest {
+    query: String,
+    #[serde(default)]
+    keywords: bool,
+    #[serde(default)]
+    filter: SearchFilter,
+    near: Option<NearPoint>,
+    limit: Option<usize>,
+    #[serde(default)]
+    explain: bool,
+    #[serde(default)]
+    justify: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+struct NearPoint {
+    longitude: f64,
+    latitude: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    score: f64,
+    listing: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explain: Option<Explanation>,
+}
+
+/// Listings closest to the query, optionally with why each one matched
+async fn search_listings(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SearchRequest>,
+) -> Result<Json<ApiResponse<Vec<SearchResult>>>, StatusCode> {
+    let text = request.query.trim();
+    if text.is_empty() {
+        tracing::error!("Search query is empty");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if let Some(near) = request.near {
+        if !(-180.0..=180.0).contains(&near.longitude) || !(-90.0..=90.0).contains(&near.latitude) {
+            tracing::error!("Reference point ({}, {}) is out of range", near.longitude, near.latitude);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    let caller = caller_id(&headers);
+    let vector = embed_query(&state, text, &caller).await?;
+
+    let projection = doc! {
+        "_id": 1,
+        "name": 1,
+        "summary": 1,
+        "property_type": 1,
+        "bedrooms": 1,
+        "price": 1,
+        "address.market": 1,
+    };
+    let explain = request.explain || request.justify;
+    let context = ExplainContext {
+        text,
+        vector: &vector,
+        vector_path: &state.embeddings.config().search_field().name,
+        filter: &request.filter,
+        near: request.near.map(|near| (near.longitude, near.latitude)),
+    };
+    let fetch = if explain { context.projection(&projection) } else { projection.clone() };
+    let limit = request.limit.unwrap_or(10).clamp(1, 50);
+
+    let hits = if request.keywords {
+        let query = HybridQuery {
+            vector: vector.clone(),
+            text: text.to_string(),
+            filter: request.filter.clone(),
+            projection: fetch,
+            limit,
+            num_candidates: 120,
+        };
+        state.search.hybrid_search(&query).await
+    } else {
+        let query = VectorQuery {
+            vector: vector.clone(),
+            filter: request.filter.clone(),
+            projection: fetch,
+            limit,
+            num_candidates: 120,
+        };
+        state.search.vector_search(&query).await
+    }
+    .map_err(|e| search_error_status("listing search", e))?;
+
+    let (hits, mut explanations): (Vec<_>, Vec<_>) = hits
+        .into_iter()
+        .map(|hit| {
+            if explain {
+                let (hit, explanation) = context.explain(hit, &projection);
+                (hit, Some(explanation))
+            } else {
+                (hit, None)
+            }
+        })
+        .unzip();
+
+    if request.justify {
+        let listings: Vec<&mongodb::bson::Document> = hits.iter().map(|hit| &hit.listing).collect();
+        let sentences = state.reranker
+            .justify(text, &listings, state.reranker.options().budget, &caller)
+            .await;
+        for (explanation, sentence) in explanations.iter_mut().zip(sentences) {
+            if let Some(explanation) = explanation {
+                explanation.justification = sentence;
+            }
+        }
     }
+
+    let results = hits
+        .into_iter()
+        .zip(explanations)
+        .map(|(hit, explain)| SearchResult {
+            score: hit.score,
+            listing: mongodb::bson::Bson::Document(hit.listing).into_relaxed_extjson(),
+            explain,
+        })
+        .collect();
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(results),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
 }
 
-async fn request_logging_middleware(
-    req: axum::http::Request<axum::body::Body>,
-    next: axum::middleware::Next,
-) -> axum::response::Response {
-    let method = req.method().clone();
-    let uri = req.uri().clone();
-    let start = std::time::Instant::now();
-    
-    let response = next.run(req).await;
-    
-    let elapsed = start.elapsed();
-    tracing::info!(
-        method = %method,
-        uri = %uri,
-        status = response.status().as_u16(),
-        duration_ms = elapsed.as_millis(),
-        "Request processed"
-    );
-    
-    response
+/// Listings around a point
+///
+/// # Fields
+/// * `longitude`, `latitude` - Centre of the search, in degrees
+/// * `max_distance_m` - Optional - Radius in meters, 2000 by default
+/// * `filter` - Conditions listings must meet
+/// * `limit` - Optional - Listings to return, 10 by default and 50 at most
+#[derive(Debug, Deserialize)]
+struct NearbyRequest {
+    longitude: f64,
+    latitude: f64,
+    max_distance_m: Option<f64>,
+    #[serde(default)]
+    filter: SearchFilter,
+    limit: Option<usize>,
 }
 
-#[tokio::main]
-async fn main() {
-    // Initialize tracing
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+/// Listings within a radius of a point, nearest first; each result's
+/// `score` is its distance in meters
+async fn search_nearby(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<NearbyRequest>,
+) -> Result<Json<ApiResponse<Vec<SearchResult>>>, StatusCode> {
+    let query = GeoQuery {
+        longitude: request.longitude,
+        latitude: request.latitude,
+        max_distance_m: request.max_distance_m.unwrap_or(2000.0),
+        filter: request.filter,
+        projection: doc! {
+            "_id": 1,
+            "name": 1,
+            "summary": 1,
+            "property_type": 1,
+            "bedrooms": 1,
+            "price": 1,
+            "address.market": 1,
+        },
+        limit: request.limit.unwrap_or(10).clamp(1, 50),
+    };
 
-    // MongoDB connection
-    let mongodb_uri = std::env::var("MONGODB_URI")
-        .expect("MONGODB_URI environment variable must be set");
-    
-    let client = Client::with_uri_str(&mongodb_uri)
+    let hits = state.search
+        .geo_search(&query)
         .await
-        .expect("Failed to connect to MongoDB");
-        
-    let collection: Collection<ResponseSearch> = client
-        .database("sample_airbnb")
-        .collection("airbnb");
+        .map_err(|e| search_error_status("nearby search", e))?;
 
-    // Configure services
-    let mut services = HashMap::new();
-    services.insert("auth".to_string(), ServiceConfig {
-        base_url: std::env::var("AUTH_SERVICE_URL")
-            .unwrap_or_else(|_| "http://localhost:8001".to_string()),
-        timeout_ms: 5000,
-    });
-    services.insert("users".to_string(), ServiceConfig {
-        base_url: std::env::var("USERS_SERVICE_URL")
-            .unwrap_or_else(|_| "http://localhost:8002".to_string()),
-        timeout_ms: 3000,
-    });
+    let results = hits
+        .into_iter()
+        .map(|hit| SearchResult {
+            score: hit.score,
+            listing: mongodb::bson::Bson::Document(hit.listing).into_relaxed_extjson(),
+            explain: None,
+        })
+        .collect();
 
-    let state = Arc::new(AppState {
-        http_client: reqwest::Client::new(),
-        services,
-        collection,
-    });
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(results),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
 
-    let app = Router::new()
-        .route("/health", get(health_check))
-        // .route("/data", post(store_data))
-        .route("/data", get(get_data))
-        .route("/mock", get(mock_get_data))
-        .route("/embed/{*path}", post(post_embed))
-        .route("/api/{*path}", get(proxy_get_request))
-        .route("/api/{*path}", post(proxy_post_request))
-        .layer(middleware::from_fn(request_logging_middleware))
-        .layer(TraceLayer::new_for_http())
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+/// Refinements available for a query
+///
+/// # Fields
+/// * `query` - What the user typed
+/// * `filter` - Filters already applied
+/// * `candidates` - Optional - Closest listings counted, 200 by default and
+///   1000 at most
+#[derive(Debug, Deserialize)]
+struct FacetsRequest {
+    query: String,
+    #[serde(default)]
+    filter: SearchFilter,
+    candidates: Option<usize>,
+}
 
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse::<u16>()
-        .expect("PORT must be a valid number");
+/// Value counts and price/bedroom histograms over the listings closest to
+/// the query, so the frontend can offer only refinements that match
+async fn search_facets(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<FacetsRequest>,
+) -> Result<Json<ApiResponse<Facets>>, StatusCode> {
+    let text = request.query.trim();
+    if text.is_empty() {
+        tracing::error!("Facet query is empty");
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
+    let vector = embed_query(&state, text, &caller_id(&headers)).await?;
+
+    let query = FacetQuery {
+        vector,
+        filter: request.filter,
+        candidates: request.candidates.unwrap_or(200).clamp(1, 1000),
+    };
+    let facets = state.search
+        .facets(&query)
         .await
-        .expect("Failed to bind to address");
+        .map_err(|e| search_error_status("facet search", e))?;
 
-    tracing::info!("DB-ENDPOINT listening on port {}", port);
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(facets),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
 
-    axum::serve(listener, app)
+#[derive(Debug, Deserialize)]
+struct PassageSearchRequest {
+    query: String,
+    #[serde(default)]
+    aggregation: Aggregation,
+    limit: Option<usize>,
+}
+
+/// Listings whose description, space or neighborhood passages best match
+/// the query, each with the matching passage as highlight
+async fn search_passages(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PassageSearchRequest>,
+) -> Result<Json<ApiResponse<Vec<PassageHit>>>, StatusCode> {
+    let text = request.query.trim();
+    if text.is_empty() {
+        tracing::error!("Passage query is empty");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let vector = embed_query(&state, text, &caller_id(&headers)).await?;
+
+    let query = PassageQuery {
+        vector,
+        aggregation: request.aggregation,
+        projection: doc! {
+            "_id": 1,
+            "name": 1,
+            "summary": 1,
+            "property_type": 1,
+            "bedrooms": 1,
+            "price": 1,
+            "address.market": 1,
+        },
+        limit: request.limit.unwrap_or(5).min(50),
+    };
+    let hits = state.passages
+        .search(&query)
         .await
-        .expect("Failed to start server");
-}
\ No newline at end of file
+        .map_err(|e| search_error_status("passage search", e))?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(hits),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+/// Text each vector field's template renders for a listing, with its token
+/// count and whether the stored vector was computed from it
+async fn preview_embedding_text(
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<Vec<TextPreview>>>, StatusCode> {
+    require_admin(&state, &headers)?;
+
+    let previews = state.embeddings
+        .preview(&mongodb::bson::Bson::Int32(id))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load listing {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(previews),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+/// Creates the named index from its declaration, or updates it to match
+async fn put_index(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<IndexState>>, StatusCode> {
+    require_admin(&state, &headers)?;
+
+    let index = state.indexes
+        .apply(&name)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to apply search index {}: {}", name, e);
+            match e {
+                IndexError::Undeclared(_) => StatusCode::NOT_FOUND,
+                IndexError::Database(_) => StatusCode::BAD_GATEWAY,
+            }
+        })?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(index),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+async fn delete_index(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, StatusCode> {
+    require_admin(&state, &headers)?;
+
+    let dropped = state.indexes
+        .drop(&name)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to drop search index {}: {}", name, e);
+            match e {
+                IndexError::Undeclared(_) => StatusCode::NOT_FOUND,
+                IndexError::Database(_) => StatusCode::BAD_GATEWAY,
+            }
+        })?;
+
+    if dropped {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn create_session(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<SessionView>>, StatusCode> {
+    let caller = authenticated_caller(&state, &headers)?;
+    let session = state.sessions
+        .create(&caller)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create session: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(session.into()),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+async fn list_sessions(
+    Query(params): Query<ListSessionsParams>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<Vec<SessionSummary>>>, StatusCode> {
+    let caller = authenticated_caller(&state, &headers)?;
+    let limit = params.limit.unwrap_or(20).clamp(1, 100);
+
+    let sessions = state.sessions
+        .list(&caller, limit)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to list sessions: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(sessions),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+/// One of the caller's sessions; another caller's session is not found
+async fn get_session(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse<SessionView>>, StatusCode> {
+    let caller = authenticated_caller(&state, &headers)?;
+    let session = state.sessions
+        .get(&id, &caller)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load session {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(session.into()),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+async fn append_session_messages(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<AppendMessages>,
+) -> Result<Json<ApiResponse<SessionView>>, StatusCode> {
+    let caller = authenticated_caller(&state, &headers)?;
+    if payload.messages.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let session = state.sessions
+        .append(&id, &caller, payload.messages)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to append to session {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(session.into()),
+        embed: None,
+        error: None,
+        request_id: uuid::Uuid::new_v4().to_string(),
+    }))
+}
+
+async fn delete_session(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, StatusCode> {
+    let caller = authenticated_caller(&state, &headers)?;
+    let deleted = state.sessions
+        .delete(&id, &caller)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to delete session {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// Embeds a search query with the search field's model, recording the
+/// usage against `caller`
+///
+/// # Errors
+/// * `StatusCode::BAD_GATEWAY` - The provider failed or returned no vector
+async fn embed_query(state: &AppState, text: &str, caller: &str) -> Result<Vec<f32>, StatusCode> {
+    let response = state.embedder
+        .clone()
+        .embed_content(text)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get embedding: {}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+    state.usage.record_detached(
+        &response.model,
+        ENDPOINT_EMBEDDINGS,
+        caller,
+        TokenUsage::from(&response.usage),
+    );
+
+    match response.data.into_iter().next() {
+        Some(data) if !data.embedding.is_empty() => Ok(data.embedding),
+        _ => {
+            tracing::error!("Embedding response for {} has no vector", response.model);
+            Err(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// Logs a failed listing query and maps it to a status code
+fn search_error_status(operation: &str, error: SearchError) -> StatusCode {
+    tracing::error!("Failed to execute {}: {}", operation, error);
+    match error {
+        SearchError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+        SearchError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Rejects requests without a matching `x-admin-token` header; admin
+/// endpoints stay hidden behind 404 when `ADMIN_TOKEN` is not set
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(admin_token) = &state.admin_token else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let provided = headers
+        .get("x-admin-token")
+        .and_then(|value| value.to_str().ok());
+    if provided != Some(admin_token.as_str()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+/// Caller owning the API key in the `x-api-key` header; sessions stay
+/// hidden behind 404 when `CALLER_KEYS` is not set
+fn authenticated_caller(state: &AppState, headers: &HeaderMap) -> Result<String, StatusCode> {
+    if state.caller_keys.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|key| state.caller_keys.get(key))
+        .cloned()
+        .ok_or(StatusCode::UNAUTHORIZED)
+}
+
+/// API keys by caller name from `CALLER_KEYS`, as comma-separated
+/// `caller=key` pairs; pairs without a caller or a key are skipped
+fn parse_caller_keys(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let (caller, key) = pair.split_once('=')?;
+            let (caller, key) = (caller.trim(), key.trim());
+            (!caller.is_empty() && !key.is_empty()).then(|| (key.to_string(), caller.to_string()))
+        })
+        .collect()
+}
+
+/// Identifies the API caller for usage accounting from the `x-caller-id` header
+fn caller_id(headers: &HeaderMap) -> String {
+    headers
+        .get("x-caller-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .unwrap_or("anonymous")
+        .to_string()
+}
+
+fn should_forward_header(header_name: &str) -> bool {
+    match header_name.to_lowercase().as_str() {
+        "authorization" | "content-type" | "accept" | "user-agent" => true,
+        name if name.starts_with("x-") => true,
+        _ => false,
+    }
+}
+
+async fn request_logging_middleware(
+    req: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let start = std::time::Instant::now();
+    
+    let response = next.run(req).await;
+    
+    let elapsed = start.elapsed();
+    tracing::info!(
+        method = %method,
+        uri = %uri,
+        status = response.status().as_u16(),
+        duration_ms = elapsed.as_millis(),
+        "Request processed"
+    );
+    
+    response
+}
+
+/// Proxied backends, with URLs overridable through the environment
+fn services_from_env() -> HashMap<String, ServiceConfig> {
+    let mut services = HashMap::new();
+    services.insert("auth".to_string(), ServiceConfig {
+        base_url: std::env::var("AUTH_SERVICE_URL")
+            .unwrap_or_else(|_| "http://localhost:8001".to_string()),
+        timeout_ms: 5000,
+    });
+    services.insert("users".to_string(), ServiceConfig {
+        base_url: std::env::var("USERS_SERVICE_URL")
+            .unwrap_or_else(|_| "http://localhost:8002".to_string()),
+        timeout_ms: 3000,
+    });
+    services
+}
+
+/// Routes and middleware of the gateway, kept out of `main` so tests can
+/// drive the same `Router` in-process
+fn build_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/health", get(health_check))
+        // .route("/data", post(store_data))
+        .route("/data", get(get_data))
+        .route("/mock", get(mock_get_data))
+        .route("/embed/{*path}", post(post_embed))
+        .route("/search", post(search_listings))
+        .route("/search/nearby", post(search_nearby))
+        .route("/search/passages", post(search_passages))
+        .route("/search/facets", post(search_facets))
+        .route("/admin/usage", get(get_usage))
+        .route("/admin/embeddings", get(get_embeddings))
+        .route("/admin/embeddings/{id}/preview", get(preview_embedding_text))
+        .route("/admin/indexes", get(get_indexes))
+        .route("/admin/indexes/{name}", put(put_index).delete(delete_index))
+        .route("/sessions", get(list_sessions).post(create_session))
+        .route("/sessions/{id}", get(get_session).delete(delete_session))
+        .route("/sessions/{id}/messages", post(append_session_messages))
+        .route("/api/{*path}", get(proxy_get_request))
+        .route("/api/{*path}", post(proxy_post_request))
+        .layer(middleware::from_fn(request_logging_middleware))
+        .layer(TraceLayer::new_for_http())
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() {
+    // Initialize tracing
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    // MongoDB connection
+    let mongodb_uri = std::env::var("MONGODB_URI")
+        .expect("MONGODB_URI environment variable must be set");
+    
+    let client = Client::with_uri_str(&mongodb_uri)
+        .await
+        .expect("Failed to connect to MongoDB");
+        
+    let database = client.database("sample_airbnb");
+    let embedder = EmbedOpenAI::new(EMBEDDING_MODEL).with_dimensions(EMBEDDING_DIMENSIONS);
+    let embedding_config = EmbeddingConfig::from_env().expect("Invalid vector field configuration");
+    let state = AppState::new(
+        &database,
+        services_from_env(),
+        embedder,
+        std::env::var("ADMIN_TOKEN").ok(),
+    )
+    .with_embedding_config(embedding_config)
+    .with_caller_keys(std::env::var("CALLER_KEYS").map(|keys| parse_caller_keys(&keys)).unwrap_or_default());
+    let rerank_model = std::env::var("RERANK_MODEL").unwrap_or_else(|_| DEFAULT_RERANK_MODEL.to_string());
+    let reranker = Reranker::new(ChatOpenAI::new(&rerank_model), RerankOptions::from_env(), state.usage.clone());
+    let state = state.with_reranker(reranker);
+    tracing::info!("Searching vector field {}", state.embeddings.config().search_field().name);
+    match state.embeddings.coverage(state.embeddings.config().search_field()).await {
+        Ok(coverage) if !coverage.complete => tracing::warn!(
+            "{} of {} listings have no vector in {}; searches will not find them until it is backfilled",
+            coverage.missing, coverage.total, coverage.field.name
+        ),
+        Ok(_) => {}
+        Err(e) => tracing::error!("Failed to check coverage of the search field: {}", e),
+    }
+    let search_kind = SearchBackendKind::from_env();
+    let search = search_kind
+        .connect(&state.collection, state.embeddings.config().search_field())
+        .await
+        .expect("Failed to build search backend");
+    tracing::info!("Search backend: {}", search.name());
+    let mut state = state.with_search_backend(search);
+    if search_kind == SearchBackendKind::Atlas {
+        let report = state.indexes.verify().await;
+        if report.healthy {
+            tracing::info!("Search indexes match their declarations");
+        }
+        state = state.with_index_verification();
+    } else {
+        state = state.with_exact_passage_search();
+    }
+    let index_passages = std::env::var("INDEX_PASSAGES").is_ok_and(|value| value == "true");
+    if index_passages && search_kind == SearchBackendKind::Atlas {
+        let chunk_indexes = SearchIndexes::new(state.chunks.collection(), vec![chunk_index(state.chunks.field())]);
+        if let Err(e) = chunk_indexes.apply(CHUNK_INDEX).await {
+            tracing::error!("Failed to create {}: {}", CHUNK_INDEX, e);
+        }
+    }
+    let state = Arc::new(state);
+    if let Err(e) = state.sessions.ensure_indexes().await {
+        tracing::error!("Failed to create session indexes: {}", e);
+    }
+    if state.embeddings.config().migration_target().is_some() {
+        let embeddings = state.embeddings.clone();
+        tokio::spawn(async move {
+            if let Err(e) = embeddings.migrate().await {
+                tracing::error!("Embedding migration stopped: {}", e);
+            }
+        });
+    }
+    if index_passages {
+        let chunks = state.chunks.clone();
+        tokio::spawn(async move {
+            if let Err(e) = chunks.backfill().await {
+                tracing::error!("Passage indexing stopped: {}", e);
+            }
+        });
+    }
+    if std::env::var("REEMBED_ON_CHANGE").is_ok_and(|value| value == "true") {
+        let mut worker = ReembedWorker::new(
+            &state.collection,
+            database.collection("change_stream_tokens"),
+            state.embeddings.clone(),
+            ReembedOptions::from_env(),
+        )
+        .with_dead_letters(database.collection("reembed_dead_letters"));
+        if index_passages {
+            worker = worker.with_chunks(state.chunks.clone());
+        }
+        tokio::spawn(worker.run());
+    }
+
+    let app = build_router(state);
+
+    let port = std::env::var("PORT")
+        .unwrap_or_else(|_| "8080".to_string())
+        .parse::<u16>()
+        .expect("PORT must be a valid number");
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
+        .await
+        .expect("Failed to bind to address");
+
+    tracing::info!("DB-ENDPOINT listening on port {}", port);
+
+    axum::serve(listener, app)
+        .await
+        .expect("Failed to start server");
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::VectorField;
+    use crate::search::HnswSearch;
+    use crate::openai::mock::{MockEndpoint, MockOpenAI, MockReply};
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use mongodb::bson::Document;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    /// Listings seeded into the test database, a few documents in the
+    /// `sample_airbnb.listingsAndReviews` shape
+    const AIRBNB_FIXTURE: &str = include_str!("../tests/fixtures/airbnb.json");
+
+    /// Listing whose stored embedding equals the mock's query embedding
+    const CLOSEST_LISTING: &str = "Ribeira Charming Duplex";
+
+    /// State on `database`, embedding queries through `openai`
+    fn test_state(database: &Database, openai: &MockOpenAI, services: HashMap<String, ServiceConfig>) -> AppState {
+        let embedder = EmbedOpenAI::new(EMBEDDING_MODEL)
+            .with_dimensions(EMBEDDING_DIMENSIONS)
+            .with_api_key("sk-test")
+            .with_base_url(&openai.base_url());
+        let state = AppState::new(database, services, embedder, Some("admin-secret".to_string()))
+            .with_caller_keys(parse_caller_keys("web=web-key,mobile=mobile-key"));
+        let chat = ChatOpenAI::new(DEFAULT_RERANK_MODEL)
+            .with_api_key("sk-test")
+            .with_base_url(&openai.base_url());
+        let reranker = Reranker::new(chat, RerankOptions::default(), state.usage.clone());
+        state.with_reranker(reranker)
+    }
+
+    fn test_app(database: &Database, openai: &MockOpenAI, services: HashMap<String, ServiceConfig>) -> Router {
+        build_router(Arc::new(test_state(database, openai, services)))
+    }
+
+    /// The gateway searching `database` by brute force, for tests on a
+    /// `mongod` without Atlas Search
+    async fn brute_force_app(database: &Database, openai: &MockOpenAI) -> Router {
+        let search = SearchBackendKind::BruteForce.connect(&database.collection::<Document>("airbnb"), &VectorField::legacy())
+            .await
+            .unwrap();
+        build_router(Arc::new(test_state(database, openai, HashMap::new()).with_search_backend(search)))
+    }
+
+    /// A database nobody listens on, for routes that must not touch MongoDB
+    /// or must fail cleanly when it is down
+    async fn offline_database() -> Database {
+        Client::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap()
+            .database("db_endpoint_offline")
+    }
+
+    /// `AIRBNB_FIXTURE` with the stored embeddings `fixture_embedding` gives
+    fn fixture_listings() -> Vec<Document> {
+        let listings: Vec<Value> = serde_json::from_str(AIRBNB_FIXTURE).unwrap();
+        listings
+            .into_iter()
+            .map(|listing| {
+                let mut document = mongodb::bson::to_document(&listing).unwrap();
+                let embedding = fixture_embedding(listing["name"] == CLOSEST_LISTING);
+                document.insert("text_embeddings", embedding);
+                document
+            })
+            .collect()
+    }
+
+    /// A fresh database on the `mongod` at `TEST_MONGODB_URI`, seeded with
+    /// `fixture_listings`; tests using it are ignored unless run with `--ignored`
+    async fn seeded_database() -> Database {
+        let uri = std::env::var("TEST_MONGODB_URI").expect("TEST_MONGODB_URI must point at a mongod");
+
+        let client = Client::with_uri_str(&uri).await.unwrap();
+        let database = client.database(&format!("db_endpoint_test_{}", uuid::Uuid::new_v4().simple()));
+        database.collection::<Document>("airbnb").insert_many(fixture_listings()).await.unwrap();
+        database
+    }
+
+    /// The gateway serving `fixture_listings` from an in-memory index, with
+    /// MongoDB offline, so search and listing routes run without a `mongod`
+    async fn memory_app(openai: &MockOpenAI) -> Router {
+        let search = Arc::new(HnswSearch::from_listings(fixture_listings(), VectorField::legacy()));
+        build_router(Arc::new(test_state(&offline_database().await, openai, HashMap::new()).with_search_backend(search)))
+    }
+
+    /// The mock's query embedding for the closest listing, an orthogonal
+    /// vector for every other one
+    fn fixture_embedding(closest: bool) -> Vec<f64> {
+        (0..EMBEDDING_DIMENSIONS)
+            .map(|i| if closest || i % 2 == 0 { 0.25 } else { -0.25 })
+            .collect()
+    }
+
+    /// Creates the declared `vector_index` and waits until it answers
+    /// queries; needs an Atlas deployment such as `mongodb/mongodb-atlas-local`
+    async fn create_vector_index(database: &Database) {
+        let indexes = SearchIndexes::new(
+            &database.collection::<Document>("airbnb"),
+            indexes::expected_indexes(&[VectorField::legacy()]),
+        );
+        indexes.apply(search::VECTOR_INDEX).await.unwrap();
+
+        for _ in 0..120 {
+            let report = indexes.inspect().await.unwrap();
+            let vector_index = report.indexes.iter().find(|index| index.name == search::VECTOR_INDEX);
+            if vector_index.is_some_and(|index| index.status == indexes::IndexStatus::Ready) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        panic!("vector_index never became queryable");
+    }
+
+    /// A stand-in backend for the proxy routes that echoes what it received
+    async fn start_upstream() -> String {
+        async fn echo(
+            Path(path): Path<String>,
+            headers: HeaderMap,
+            body: String,
+        ) -> Json<Value> {
+            let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+            Json(json!({
+                "path": path,
+                "body": body,
+                "x-caller-id": header("x-caller-id"),
+                "cookie": header("cookie"),
+            }))
+        }
+
+        let app = Router::new()
+            .route("/echo/{*path}", get(echo).post(echo))
+            .route("/text", get(|| async { "not json" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    fn service(base_url: &str) -> HashMap<String, ServiceConfig> {
+        HashMap::from([(
+            "upstream".to_string(),
+            ServiceConfig { base_url: base_url.to_string(), timeout_ms: 2000 },
+        )])
+    }
+
+    async fn send(app: Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    fn get_request(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn post_request(uri: &str, body: &str) -> Request<Body> {
+        Request::post(uri).body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn health_reports_version() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, body) = send(app, get_request("/health")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["success"], true);
+        assert_eq!(body["data"]["status"], "healthy");
+        assert_eq!(body["data"]["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn health_fails_while_search_indexes_are_unavailable() {
+        let openai = MockOpenAI::start().await;
+        let state = test_state(&offline_database().await, &openai, HashMap::new()).with_index_verification();
+        let app = build_router(Arc::new(state));
+
+        let (status, body) = send(app, get_request("/health")).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["success"], false);
+        assert_eq!(body["data"]["status"], "unhealthy");
+        assert!(body["error"].as_str().unwrap().starts_with("search indexes unavailable"));
+    }
+
+    #[tokio::test]
+    async fn index_commands_require_admin_token_and_declared_name() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, _) = send(app.clone(), Request::put("/admin/indexes/vector_index").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = send(app.clone(), Request::delete("/admin/indexes/vector_index").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let request = Request::put("/admin/indexes/typo_index")
+            .header("x-admin-token", "admin-secret")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(app.clone(), request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::delete("/admin/indexes/someone_elses_index")
+            .header("x-admin-token", "admin-secret")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_route_is_not_found() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, _) = send(app, get_request("/nope")).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn proxy_forwards_path_and_caller_headers() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, service(&start_upstream().await));
+        let request = Request::get("/api/echo/users/42?service=upstream")
+            .header("x-caller-id", "web")
+            .header("cookie", "session=secret")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["path"], "users/42");
+        assert_eq!(body["x-caller-id"], "web");
+        assert_eq!(body["cookie"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn proxy_forwards_post_body() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, service(&start_upstream().await));
+
+        let (status, body) = send(app, post_request("/api/echo/login?service=upstream", "{\"user\":\"a\"}")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["path"], "login");
+        assert_eq!(body["body"], "{\"user\":\"a\"}");
+    }
+
+    #[tokio::test]
+    async fn proxy_rejects_unknown_service() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, service(&start_upstream().await));
+
+        let (status, _) = send(app, get_request("/api/echo/users?service=billing")).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn proxy_reports_unreachable_service() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, service(&closed));
+
+        let (status, _) = send(app, get_request("/api/users?service=upstream")).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn proxy_rejects_non_json_upstream() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, service(&start_upstream().await));
+
+        let (status, _) = send(app, get_request("/api/text?service=upstream")).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn embed_rejects_empty_query() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, _) = send(app, post_request("/embed/search", "   ")).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(openai.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn embed_reports_provider_failure() {
+        let openai = MockOpenAI::start().await;
+        openai.enqueue(
+            MockEndpoint::Embeddings,
+            MockReply::error(400, "invalid_request_error", "bad input"),
+        );
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, _) = send(app, post_request("/embed/search", "quiet flat")).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn listing_reports_database_failure() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, _) = send(app, get_request("/data")).await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn usage_requires_admin_token() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, _) = send(app, get_request("/admin/usage")).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn sessions_are_only_visible_to_their_caller() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&database, &openai, HashMap::new());
+        let as_caller = |key: &str, request: axum::http::request::Builder, body: Body| {
+            request.header("x-api-key", key).header("content-type", "application/json").body(body).unwrap()
+        };
+        let (status, body) = send(app.clone(), as_caller("web-key", Request::post("/sessions"), Body::empty())).await;
+        assert_eq!(status, StatusCode::OK);
+        let uri = format!("/sessions/{}", body["data"]["id"].as_str().unwrap());
+        let turn = json!({ "messages": [{ "role": "user", "content": [{ "type": "text", "text": "Lofts in Porto?" }] }] });
+
+        let (read, _) = send(app.clone(), as_caller("mobile-key", Request::get(&uri), Body::empty())).await;
+        let (append, _) = send(
+            app.clone(),
+            as_caller("mobile-key", Request::post(format!("{}/messages", uri)), Body::from(turn.to_string())),
+        ).await;
+        let (delete, _) = send(app.clone(), as_caller("mobile-key", Request::delete(&uri), Body::empty())).await;
+        assert_eq!((read, append, delete), (StatusCode::NOT_FOUND, StatusCode::NOT_FOUND, StatusCode::NOT_FOUND));
+
+        let (status, body) = send(app, as_caller("web-key", Request::get(&uri), Body::empty())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["messages"], json!([]));
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sessions_require_a_known_api_key() {
+        let openai = MockOpenAI::start().await;
+        let state = test_state(&offline_database().await, &openai, HashMap::new());
+        let app = build_router(Arc::new(state));
+
+        for request in [
+            Request::post("/sessions"),
+            Request::get("/sessions"),
+            Request::get("/sessions/3f2a"),
+            Request::delete("/sessions/3f2a"),
+        ] {
+            let (status, _) = send(app.clone(), request.header("x-caller-id", "web").body(Body::empty()).unwrap()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let request = Request::get("/sessions").header("x-api-key", "stolen").body(Body::empty()).unwrap();
+        let (status, _) = send(app, request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_hidden_without_caller_keys() {
+        let openai = MockOpenAI::start().await;
+        let state = test_state(&offline_database().await, &openai, HashMap::new()).with_caller_keys(HashMap::new());
+        let app = build_router(Arc::new(state));
+
+        let request = Request::get("/sessions").header("x-api-key", "web-key").body(Body::empty()).unwrap();
+        let (status, _) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn caller_keys_map_keys_to_callers() {
+        let keys = parse_caller_keys(" web = web-key ,mobile=mobile-key,=orphan,nokey=,garbage");
+
+        assert_eq!(
+            keys,
+            HashMap::from([
+                ("web-key".to_string(), "web".to_string()),
+                ("mobile-key".to_string(), "mobile".to_string()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_are_hidden_without_a_configured_token() {
+        let openai = MockOpenAI::start().await;
+        let state = AppState { admin_token: None, ..test_state(&offline_database().await, &openai, HashMap::new()) };
+        let app = build_router(Arc::new(state));
+
+        for request in [
+            Request::get("/admin/usage"),
+            Request::put("/admin/indexes/vector_index"),
+            Request::delete("/admin/indexes/vector_index"),
+        ] {
+            let (status, _) = send(app.clone(), request.header("x-admin-token", "").body(Body::empty()).unwrap()).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn listing_detail_reads_seeded_listings() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&database, &openai, HashMap::new());
+
+        let (status, by_name) = send(app.clone(), get_request("/data")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(by_name["data"]["_id"], 10059244);
+
+        let (status, by_id) = send(app, get_request("/mock")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(by_id["data"]["name"], "City center private room with bed");
+        assert_eq!(by_id["data"]["address"]["country_code"], "HK");
+
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn listing_detail_reads_the_search_backend() {
+        let openai = MockOpenAI::start().await;
+        let app = memory_app(&openai).await;
+
+        let (status, by_id) = send(app, get_request("/mock")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(by_id["data"]["name"], "City center private room with bed");
+        assert_eq!(by_id["data"]["address"]["country_code"], "HK");
+        assert!(openai.received().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI on Atlas"]
+    async fn search_returns_closest_listing() {
+        let database = seeded_database().await;
+        create_vector_index(&database).await;
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&database, &openai, HashMap::new());
+
+        assert_search_finds_closest_listing(app, &openai).await;
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn brute_force_search_returns_closest_listing() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let app = brute_force_app(&database, &openai).await;
+
+        assert_search_finds_closest_listing(app, &openai).await;
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reranked_search_returns_the_listing_the_model_prefers() {
+        let openai = MockOpenAI::start().await;
+        let judgements = json!({ "judgements": [
+            { "candidate": 1, "relevance": 2, "reason": "No mention of a garden" },
+            { "candidate": 2, "relevance": 9, "reason": "Private room with a garden" },
+            { "candidate": 3, "relevance": 4, "reason": "Central but no garden" },
+        ] });
+        openai.enqueue(MockEndpoint::Chat, MockReply::chat_text(&judgements.to_string()));
+        let app = memory_app(&openai).await;
+
+        let (status, body) = send(app, post_request("/embed/search?rerank=true", "room with a garden")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(body["data"]["name"], CLOSEST_LISTING);
+        assert_eq!(body["data"]["rerank"], json!({ "outcome": "reranked", "relevance": 9, "reason": "Private room with a garden" }));
+        let received = openai.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].endpoint, MockEndpoint::Chat);
+        assert_eq!(received[1].body["model"], DEFAULT_RERANK_MODEL);
+    }
+
+    #[tokio::test]
+    async fn in_memory_search_returns_closest_listing() {
+        let openai = MockOpenAI::start().await;
+        let app = memory_app(&openai).await;
+
+        assert_search_finds_closest_listing(app, &openai).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn hnsw_search_loads_the_collection() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let search = SearchBackendKind::Hnsw.connect(&database.collection::<Document>("airbnb"), &VectorField::legacy())
+            .await.unwrap();
+        let state = test_state(&database, &openai, HashMap::new()).with_search_backend(search);
+        let app = build_router(Arc::new(state));
+
+        let (status, by_id) = send(app.clone(), get_request("/mock")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(by_id["data"]["name"], "City center private room with bed");
+
+        assert_search_finds_closest_listing(app, &openai).await;
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn search_backends_benchmark() {
+        let uri = std::env::var("TEST_MONGODB_URI").expect("TEST_MONGODB_URI must point at a mongod");
+        let database = Client::with_uri_str(&uri)
+            .await
+            .unwrap()
+            .database(&format!("db_endpoint_bench_{}", uuid::Uuid::new_v4().simple()));
+        let collection = database.collection::<Document>("airbnb");
+        let field = VectorField::new("bench_embedding", "bench-model", 64);
+        collection.insert_many(search::bench::synthetic_listings(5000, &field)).await.unwrap();
+        let queries = search::bench::synthetic_queries(100, &field, 10);
+
+        let brute_force = SearchBackendKind::BruteForce.connect(&collection, &field).await.unwrap();
+        let hnsw = SearchBackendKind::Hnsw.connect(&collection, &field).await.unwrap();
+        let exact = search::bench::BenchRun::run(brute_force.as_ref(), &queries).await.unwrap();
+        let approximate = search::bench::BenchRun::run(hnsw.as_ref(), &queries).await.unwrap();
+
+        let recall = approximate.recall_against(&exact);
+        println!("{}\n{}  recall@10 {:.3}", exact, approximate, recall);
+        database.drop().await.unwrap();
+        assert!(recall >= 0.9, "HNSW recall@10 is {:.3}", recall);
+    }
+
+    #[tokio::test]
+    async fn embeddings_status_requires_admin_token() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+
+        let (status, _) = send(app, get_request("/admin/embeddings")).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn migration_fills_target_field_until_coverage_is_complete() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let config = EmbeddingConfig::parse(
+            Some("text_embeddings_v2=text-embedding-3-large:8"),
+            None,
+            Some("text_embeddings_v2"),
+        )
+        .unwrap();
+        let state = test_state(&database, &openai, HashMap::new()).with_embedding_config(config);
+
+        let report = state.embeddings.migrate().await.unwrap();
+        assert_eq!((report.embedded, report.skipped, report.failed), (3, 0, 0));
+        assert_eq!(state.embeddings.migrate().await.unwrap().embedded, 0);
+        assert!(openai.received().iter().all(|request| request.body["model"] == "text-embedding-3-large"));
+
+        let app = build_router(Arc::new(state));
+        let request = Request::get("/admin/embeddings")
+            .header("x-admin-token", "admin-secret")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["search_field"], "text_embeddings");
+        let fields = body["data"]["fields"].as_array().unwrap();
+        assert_eq!(fields[0]["legacy"], 3);
+        assert_eq!(fields[0]["complete"], true);
+        assert_eq!(fields[1]["field"]["name"], "text_embeddings_v2");
+        assert_eq!(fields[1]["current"], 3);
+        assert_eq!(fields[1]["complete"], true);
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI on a replica set"]
+    async fn edited_listing_is_reembedded_from_change_stream() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let state = test_state(&database, &openai, HashMap::new());
+        let listings = database.collection::<Document>("airbnb");
+        let options = ReembedOptions { batch_window: Duration::from_millis(50), ..Default::default() };
+        let tokens = database.collection::<Document>("change_stream_tokens");
+        let worker = ReembedWorker::new(&listings, tokens.clone(), state.embeddings.clone(), options);
+        let task = tokio::spawn(worker.run());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        listings
+            .update_one(doc! { "_id": 10006546 }, doc! { "$set": { "summary": "Renovated in 2025." } })
+            .await
+            .unwrap();
+
+        let mut meta = None;
+        for _ in 0..40 {
+            let listing = listings.find_one(doc! { "_id": 10006546 }).await.unwrap().unwrap();
+            meta = embeddings::EmbeddingMeta::of(&listing, &VectorField::legacy());
+            if meta.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        task.abort();
+
+        let meta = meta.expect("listing was not re-embedded");
+        let listing = listings.find_one(doc! { "_id": 10006546 }).await.unwrap().unwrap();
+        let text = templates::LISTING_V1.text(&listing);
+        assert!(text.contains("Renovated in 2025."));
+        assert_eq!(meta.text_hash, embeddings::text_hash(&text));
+        assert_eq!(openai.received().len(), 1);
+        let token = tokens.find_one(doc! { "_id": "airbnb" }).await.unwrap();
+        assert!(token.is_some_and(|token| token.contains_key("token")));
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn preview_renders_each_field_template() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let config = EmbeddingConfig::parse(Some("text_embeddings_v2=text-embedding-3-large:8@listing_v2"), None, None).unwrap();
+        let app = build_router(Arc::new(test_state(&database, &openai, HashMap::new()).with_embedding_config(config)));
+        let admin_get = |uri: &str| {
+            Request::get(uri).header("x-admin-token", "admin-secret").body(Body::empty()).unwrap()
+        };
+
+        let (status, body) = send(app.clone(), admin_get("/admin/embeddings/10006546/preview")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let previews = body["data"].as_array().unwrap();
+        assert_eq!(previews[0]["template"], "listing_v1");
+        assert!(previews[0]["text"].as_str().unwrap().starts_with(CLOSEST_LISTING));
+        assert_eq!(previews[0]["current"], false);
+        assert_eq!(previews[1]["template"], "listing_v2");
+        assert!(previews[1]["text"].as_str().unwrap().contains("Market: Porto"));
+        assert_eq!(previews[1]["truncated"], false);
+        assert!(openai.received().is_empty());
+
+        let (status, _) = send(app, admin_get("/admin/embeddings/1/preview")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn search_rejects_empty_query_and_out_of_range_point() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+        let search = |body: Value| {
+            Request::post("/search")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let (empty, _) = send(app.clone(), search(json!({ "query": "" }))).await;
+        let (far, _) = send(app, search(json!({ "query": "flat", "near": { "longitude": 200.0, "latitude": 0.0 } }))).await;
+
+        assert_eq!((empty, far), (StatusCode::BAD_REQUEST, StatusCode::BAD_REQUEST));
+        assert!(openai.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_embedding_responses_are_upstream_errors() {
+        let openai = MockOpenAI::start().await;
+        let empty = || MockReply::json(json!({
+            "object": "list",
+            "model": EMBEDDING_MODEL,
+            "data": [],
+            "usage": { "prompt_tokens": 2, "total_tokens": 2 },
+        }));
+        openai.enqueue(MockEndpoint::Embeddings, empty());
+        openai.enqueue(MockEndpoint::Embeddings, empty());
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+        let search = Request::post("/search")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "query": "quiet flat" }).to_string()))
+            .unwrap();
+
+        let (embed, _) = send(app.clone(), post_request("/embed/search", "quiet flat")).await;
+        let (search, _) = send(app, search).await;
+
+        assert_eq!((embed, search), (StatusCode::BAD_GATEWAY, StatusCode::BAD_GATEWAY));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn search_explains_why_listings_matched() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let justifications = json!({ "justifications": [{ "listing": 1, "sentence": "A duplex in the historic area of Porto." }] });
+        openai.enqueue(MockEndpoint::Chat, MockReply::chat_text(&justifications.to_string()));
+        let app = brute_force_app(&database, &openai).await;
+        let request = Request::post("/search")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({
+                "query": "historic duplex",
+                "keywords": true,
+                "filter": { "max_price": 100.0 },
+                "near": { "longitude": -8.61308, "latitude": 41.1413 },
+                "limit": 1,
+                "justify": true,
+            }).to_string()))
+            .unwrap();
+
+        let (status, body) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let result = &body["data"][0];
+        assert_eq!(result["listing"]["name"], CLOSEST_LISTING);
+        assert_eq!(result["listing"].get("text_embeddings"), None);
+        let explain = &result["explain"];
+        assert!((explain["vector_score"].as_f64().unwrap() - 1.0).abs() < 1e-6);
+        assert_eq!(explain["filters"], json!([{ "field": "price", "condition": "<= 100", "value": 80.0, "matched": true }]));
+        assert_eq!(explain["distance_m"], 0.0);
+        assert_eq!(explain["highlights"][0]["path"], "name");
+        assert_eq!(explain["justification"], "A duplex in the historic area of Porto.");
+        database.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn nearby_search_returns_listings_within_the_radius() {
+        let openai = MockOpenAI::start().await;
+        let app = memory_app(&openai).await;
+        let request = |body: Value| {
+            Request::post("/search/nearby")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let (status, body) = send(app.clone(), request(json!({ "longitude": -8.61308, "latitude": 41.1413, "max_distance_m": 5000.0 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let names: Vec<&str> = body["data"].as_array().unwrap().iter().map(|hit| hit["listing"]["name"].as_str().unwrap()).collect();
+        assert_eq!(names, [CLOSEST_LISTING]);
+        assert!(body["data"][0]["score"].as_f64().unwrap() < 5000.0);
+
+        let (status, _) = send(app, request(json!({ "longitude": -8.6, "latitude": 91.0 }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(openai.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn facets_reject_empty_query() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+        let request = Request::post("/search/facets")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "query": " " }).to_string()))
+            .unwrap();
+
+        let (status, _) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(openai.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn facets_count_the_candidates_of_a_query() {
+        let openai = MockOpenAI::start().await;
+        let app = memory_app(&openai).await;
+        let request = Request::post("/search/facets")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "query": "flat", "filter": { "max_price": 100.0 } }).to_string()))
+            .unwrap();
+
+        let (status, body) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let facets = &body["data"];
+        assert_eq!(facets["candidates"], 2);
+        assert_eq!(facets["address.market"].as_array().unwrap().len(), 2);
+        assert!(facets["amenities"].as_array().unwrap().iter().any(|value| value["value"] == "Wifi" && value["count"] == 2));
+        assert_eq!(facets["price"], json!([
+            { "min": 0.0, "max": 50.0, "count": 1 },
+            { "min": 50.0, "max": 100.0, "count": 1 },
+        ]));
+    }
+
+    #[tokio::test]
+    async fn passage_search_rejects_empty_query() {
+        let openai = MockOpenAI::start().await;
+        let app = test_app(&offline_database().await, &openai, HashMap::new());
+        let request = Request::post("/search/passages")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "query": "  " }).to_string()))
+            .unwrap();
+
+        let (status, _) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(openai.received().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGODB_URI"]
+    async fn passage_search_returns_listings_with_highlights() {
+        let database = seeded_database().await;
+        let openai = MockOpenAI::start().await;
+        let state = test_state(&database, &openai, HashMap::new()).with_exact_passage_search();
+        let report = state.chunks.backfill().await.unwrap();
+        assert_eq!((report.listings, report.failed), (3, 0));
+        assert!(report.passages >= 3);
+        assert_eq!(state.chunks.backfill().await.unwrap().passages, 0);
+
+        let app = build_router(Arc::new(state));
+        let request = Request::post("/search/passages")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "query": "quiet flat by the river", "aggregation": "sum", "limit": 2 }).to_string()))
+            .unwrap();
+        let (status, body) = send(app, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let hits = body["data"].as_array().unwrap();
+        assert_eq!(hits.len(), 2);
+        for hit in hits {
+            let highlight = &hit["highlight"];
+            assert!(chunks::CHUNK_FIELDS.contains(&highlight["field"].as_str().unwrap()));
+            assert!(!highlight["text"].as_str().unwrap().is_empty());
+            assert!(hit["listing"]["name"].is_string());
+            assert_eq!(hit["listing"].get("text_embeddings"), None);
+        }
+        database.drop().await.unwrap();
+    }
+
+    async fn assert_search_finds_closest_listing(app: Router, openai: &MockOpenAI) {
+        let (status, body) = send(app, post_request("/embed/search", "historic flat in Porto")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["name"], CLOSEST_LISTING);
+        assert_eq!(body["data"].get("_id"), None);
+        assert_eq!(body["data"].get("text_embeddings"), None);
+        assert_eq!(body["data"].get("address"), None);
+
+        let received = openai.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].body["model"], EMBEDDING_MODEL);
+        assert_eq!(received[0].body["dimensions"], EMBEDDING_DIMENSIONS);
+        assert_eq!(received[0].body["input"], "historic flat in Porto");
+    }
+}
@@ -2,15 +2,26 @@ use futures::pin_mut;
 use futures::StreamExt;
 use async_stream::stream;
 use crate::openai::requests::{request_chat, strem_chat};
-use crate::openai::utils::GetApiKey;
+use crate::openai::utils::{strict_json_schema, GetApiKey};
 use crate::openai::libs::{
     MainRequest, ChatRequest, InputContent, ResponseFormat,
-    Message, Role, ChatResponse, ImageUrl,
+    Message, Role, ChatResponse, ErrorDetails, StreamOptions,
 };
-use crate::openai::OPENAI_BASE_URL;
+use crate::openai::compaction::HistoryCompaction;
+use crate::openai::transport::{default_transport, Transport};
+use crate::openai::tokens::{count_messages, trim_history, TokenBudget};
+use crate::openai::usage::{TokenUsage, UsageSink, ENDPOINT_CHAT};
+use crate::openai::models::instruction_role;
+use crate::openai::shaping::shape_chat_request;
+use crate::openai::validation::{validate_chat_request, validate_images};
+use crate::openai::vision::{chat_content, ImageInput};
+use crate::openai::{api_base, endpoint_url};
 use crate::openai::error::OpenAIError;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
 use std::time::Duration;
-use log::error;
+use log::{error, warn};
 
 
 #[allow(dead_code)]
@@ -20,6 +31,12 @@ pub struct ChatOpenAI {
     pub request: ChatRequest,
     pub timeout: Duration,
     pub max_retries: u32,
+    pub trim_history: bool,
+    pub compaction: Option<HistoryCompaction>,
+    pub images: Vec<ImageInput>,
+    pub transport: Arc<dyn Transport>,
+    pub base_url: String,
+    pub usage_sink: Option<Arc<dyn UsageSink>>,
 }
 
 #[allow(dead_code)]
@@ -42,15 +59,95 @@ impl ChatOpenAI {
             presence_penalty: None,
             top_p: None,
             stream: Some(false),
+            stream_options: None,
             n_completion: Some(1),
             stop: None,
+            reasoning_effort: None,
         };
         
         Self {
-            api_key: api_key,
-            request: request,
+            api_key,
+            request,
             timeout: Duration::from_secs(300), // default: 5 minutes
             max_retries: 3,         // default: 3 times
+            trim_history: false,
+            compaction: None,
+            images: vec![],
+            transport: default_transport(),
+            base_url: api_base(),
+            usage_sink: None,
+        }
+    }
+
+    /// Checks the configured parameters against the API ranges and the model
+    ///
+    /// Called by `invoke` and `stream_response` before anything is sent;
+    /// call it directly to fail at build time.
+    ///
+    /// # Errors
+    /// * `OpenAIError::ValidationError` - Lists every invalid parameter
+    pub fn validate(&self) -> Result<(), OpenAIError> {
+        validate_chat_request(&self.request)?;
+        validate_images(&self.request.model, self.images.len())
+    }
+
+    /// Summarizes older turns once the prompt crosses the compaction threshold,
+    /// keeping the history unchanged if the summary request fails
+    async fn compact_history(&mut self) {
+        let compaction = match &self.compaction {
+            Some(compaction) => compaction.clone(),
+            None => return,
+        };
+        let model = self.request.model.clone();
+        let budget = TokenBudget::for_model(&model, self.request.max_completion_tokens);
+
+        let messages = match self.request.messages.take() {
+            Some(messages) if compaction.should_compact(&model, &messages, &budget) => messages,
+            other => {
+                self.request.messages = other;
+                return;
+            }
+        };
+
+        match compaction.compact(
+            messages.clone(),
+            instruction_role(&model).into(),
+            &self.api_key,
+            self.timeout,
+            self.max_retries,
+            self.transport.as_ref(),
+            &endpoint_url(&self.base_url, "chat/completions"),
+            self.usage_sink.as_deref(),
+        ).await {
+            Ok(compacted) => self.request.messages = Some(compacted),
+            Err(e) => {
+                warn!("Failed to compact chat history: {}", e);
+                self.request.messages = Some(messages);
+            }
+        }
+    }
+
+    /// Checks the messages against the model's context window, dropping the
+    /// oldest turns when history trimming is enabled
+    fn fit_context(&mut self) {
+        let model = self.request.model.clone();
+        let budget = TokenBudget::for_model(&model, self.request.max_completion_tokens);
+
+        if let Some(messages) = self.request.messages.take() {
+            let messages = if self.trim_history {
+                trim_history(&model, messages, &budget)
+            } else {
+                messages
+            };
+
+            let prompt_tokens = count_messages(&model, &messages);
+            if prompt_tokens > budget.prompt_tokens() {
+                warn!(
+                    "Prompt is about {} tokens, {} only has room for {}",
+                    prompt_tokens, model, budget.prompt_tokens()
+                );
+            }
+            self.request.messages = Some(messages);
         }
     }
 
@@ -58,13 +155,8 @@ impl ChatOpenAI {
         mut self,
         prompt: &str,
     ) -> Result<ChatResponse, OpenAIError> {
-        
-        let content = vec![InputContent {
-            content_type: "text".to_string(),
-            text: Some(prompt.to_string()),
-            source: None,
-            image_url: None,
-        }];
+        self.validate()?;
+        let content = chat_content(prompt, &self.images);
 
         let new_message = Message {
             role: Role::User,
@@ -79,14 +171,19 @@ impl ChatOpenAI {
             self.request.messages = Some(vec![new_message]);
         }
 
+        self.compact_history().await;
+        self.fit_context();
+        shape_chat_request(&mut self.request);
+
         let body_request = MainRequest::Chat(self.request.clone());
 
         let response: String = match request_chat(
             &body_request,
-            OPENAI_BASE_URL,
+            &endpoint_url(&self.base_url, "chat/completions"),
             &self.api_key,
             self.timeout,
             self.max_retries,
+            self.transport.as_ref(),
         ).await {
             Ok(response) => response,
             Err(openai_error) => {
@@ -102,6 +199,10 @@ impl ChatOpenAI {
             }
         };
 
+        if let (Some(sink), Some(usage)) = (&self.usage_sink, &chat_response.usage) {
+            sink.record(&self.request.model, ENDPOINT_CHAT, TokenUsage::from(usage));
+        }
+
         let format_response = ChatResponse {
             choices: chat_response.choices,
             created: chat_response.created,
@@ -117,19 +218,52 @@ impl ChatOpenAI {
         Ok(format_response)
     }
 
+    /// Sends the prompt with a strict JSON schema generated from `T` and
+    /// deserializes the model's answer into `T`
+    ///
+    /// # Errors
+    /// * `OpenAIError::RefusalError` - The model refused to answer
+    /// * `OpenAIError::ResponseContentError` - The answer is missing or was cut
+    ///   off by the token limit
+    /// * `OpenAIError::JsonError` - The answer does not match `T`
+    pub async fn invoke_structured<T: JsonSchema + DeserializeOwned>(
+        self,
+        prompt: &str,
+    ) -> Result<T, OpenAIError> {
+        let json_schema = strict_json_schema::<T>()?;
+        let response = self.with_json_schema(json_schema).invoke(prompt).await?;
+
+        let choice = response.choices
+            .and_then(|choices| choices.into_iter().next())
+            .ok_or(OpenAIError::ResponseContentError)?;
+
+        if choice.finish_reason.as_deref() == Some("length") {
+            error!("Structured output was truncated by the token limit. ERROR-req-0025");
+            return Err(OpenAIError::ResponseContentError);
+        }
+
+        let message = choice.message.ok_or(OpenAIError::ResponseContentError)?;
+        if let Some(refusal) = message.refusal {
+            return Err(OpenAIError::RefusalError(refusal));
+        }
+
+        let content = message.content.ok_or(OpenAIError::ResponseContentError)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
     pub fn stream_response(
         mut self,
         prompt: String,  // Don't change type for stream
     ) -> impl futures::Stream<Item = ChatResponse> {
         stream! {     
-            
-            let content = vec![InputContent {
-                content_type: "text".to_string(),
-                text: Some(prompt),
-                source: None,
-                image_url: None,
-            }];       
-            
+            if let Err(e) = self.validate() {
+                error!("Invalid chat request: {}", e);
+                yield error_response(e);
+                return;
+            }
+
+            let content = chat_content(&prompt, &self.images);
+
             let new_message = Message {
                 role: Role::User,
                 content: content.clone(),
@@ -143,34 +277,34 @@ impl ChatOpenAI {
                 self.request.messages = Some(vec![new_message]);
             }
 
+            self.compact_history().await;
+            self.fit_context();
+            shape_chat_request(&mut self.request);
             self.request.stream = Some(true);
-            let endpoint_string = OPENAI_BASE_URL.to_string();
+            self.request.stream_options = Some(StreamOptions { include_usage: true });
+            let endpoint_string = endpoint_url(&self.base_url, "chat/completions");
 
             let stream = strem_chat(
                 endpoint_string.clone(),
                 self.api_key.clone(),
                 self.request.clone(),
+                self.transport.clone(),
             );
 
             pin_mut!(stream);
 
             while let Some(chat_response) = stream.next().await {
+                if let (Some(sink), Some(usage)) = (&self.usage_sink, &chat_response.usage) {
+                    sink.record(&self.request.model, ENDPOINT_CHAT, TokenUsage::from(usage));
+                }
                 yield chat_response;
             }
         }
     }
 
     pub fn with_temperature(mut self, temperature: f32) -> Self {
-        if temperature < 0.0 || temperature > 2.0 {
-            println!(
-                "[ERROR] Temperature must be between 0.0 and 2.0. Actual temperature is {}", 
-                self.request.temperature.unwrap_or(0.0)
-            );
-            self
-        } else {
-            self.request.temperature = Some(temperature);
-            self
-        }
+        self.request.temperature = Some(temperature);
+        self
     }
     
     pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
@@ -184,69 +318,30 @@ impl ChatOpenAI {
     }
 
     pub fn with_frequency_penalty(mut self, frequency_penalty: f32) -> Self {
-        if frequency_penalty < -2.0 || frequency_penalty > 2.0 {
-            println!(
-                "[ERROR] Frequency penalty must be between -2.0 and 2.0. Actual frequency penalty is {}",
-                self.request.frequency_penalty.unwrap_or(0.0)
-            );
-            self
-        } else {
-            self.request.frequency_penalty = Some(frequency_penalty);
-            self
-        }
+        self.request.frequency_penalty = Some(frequency_penalty);
+        self
     }
 
     pub fn with_presence_penalty(mut self, presence_penalty: f32) -> Self {
-        if presence_penalty < -2.0 || presence_penalty > 2.0 {
-            println!(
-                "[ERROR] Presence penalty must be between -2.0 and 2.0. Actual presence penalty is {}",
-                self.request.presence_penalty.unwrap_or(0.0)
-            );
-            self
-        } else {
-            self.request.presence_penalty = Some(presence_penalty);
-            self
-        }
+        self.request.presence_penalty = Some(presence_penalty);
+        self
     }
 
-    pub fn with_image_url(mut self, image_url: &str) -> Self {
-        let url = ImageUrl {
-            url: image_url.to_string(),
-        };
-        
-        let content = vec![InputContent {
-            content_type: "image_url".to_string(),
-            text: None,
-            source: None,
-            image_url: Some(url),
-        }];
-
-        let new_message = Message {
-            role: Role::User,
-            content: content.clone(),
-            recipient: None,
-            end_turn: None,
-        };
+    /// Attaches an image the API downloads itself to the prompt, like `with_images`
+    pub fn with_image_url(self, image_url: &str) -> Self {
+        self.with_images(vec![ImageInput::url(image_url)])
+    }
 
-        if let Some(messages) = &mut self.request.messages {
-            messages.push(new_message);
-        } else {
-            self.request.messages = Some(vec![new_message]);
-        }
+    /// Attaches images to the prompt passed to `invoke` or `stream_response`,
+    /// so text and images are sent in a single user message
+    pub fn with_images(mut self, images: Vec<ImageInput>) -> Self {
+        self.images.extend(images);
         self
     }
 
     pub fn with_top_p(mut self, top_p: f32) -> Self {
-        if top_p < 0.0 || top_p > 1.0 {
-            println!(
-                "[ERROR] Top p must be between 0.0 and 1.0. Actual top p is {}",
-                self.request.top_p.unwrap_or(0.0)
-            );
-            self
-        } else {
-            self.request.top_p = Some(top_p);
-            self
-        }
+        self.request.top_p = Some(top_p);
+        self
     }
 
     pub fn with_n_completion(mut self, n_completion: u32) -> Self {
@@ -268,10 +363,7 @@ impl ChatOpenAI {
         }];
 
         let new_message = Message {
-            role: match self.request.model.as_str() {
-                "o1-mini" => Role::User,
-                _ => Role::Developer,
-            },
+            role: instruction_role(&self.request.model).into(),
             content: content.clone(),
             recipient: None,
             end_turn: None,
@@ -315,6 +407,20 @@ impl ChatOpenAI {
         self
     }
 
+    /// Summarizes older turns with a cheaper model when the history grows
+    /// close to the model's context window
+    pub fn with_history_compaction(mut self, compaction: HistoryCompaction) -> Self {
+        self.compaction = Some(compaction);
+        self
+    }
+
+    /// Drops the oldest turns of the chat history before sending when the
+    /// prompt would not fit in the model's context window
+    pub fn with_history_trimming(mut self, trim_history: bool) -> Self {
+        self.trim_history = trim_history;
+        self
+    }
+
     pub fn with_json_schema(mut self, json_schema: serde_json::Value) -> Self {
         let response_format = ResponseFormat {
             response_type: "json_schema".to_string(),
@@ -335,6 +441,26 @@ impl ChatOpenAI {
         self
     }
 
+    /// Reasoning effort, one of `low`, `medium` or `high`, for models that
+    /// support it
+    pub fn with_reasoning_effort(mut self, effort: &str) -> Self {
+        self.request.reasoning_effort = Some(effort.to_string());
+        self
+    }
+
+    /// Sends requests through `transport`, for example a `ReplayTransport`
+    /// serving recorded fixtures
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// API root requests are sent to, `https://api.openai.com/v1` by default
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
     pub fn with_max_retries(mut self, max_retries: u32) -> Self {
         self.max_retries = max_retries;
         self
@@ -344,6 +470,62 @@ impl ChatOpenAI {
         self.api_key = api_key.to_string();
         self
     }
+
+    /// Reports the usage of every completed `invoke` to `sink`
+    pub fn with_usage_sink(mut self, sink: Arc<dyn UsageSink>) -> Self {
+        self.usage_sink = Some(sink);
+        self
+    }
+}
+
+/// Streamed chunk carrying an error that occurred before the request was sent
+fn error_response(error: OpenAIError) -> ChatResponse {
+    ChatResponse {
+        choices: None,
+        created: None,
+        id: None,
+        model: None,
+        object: None,
+        system_fingerprint: None,
+        usage: None,
+        chat_history: None,
+        service_tier: None,
+        error: Some(ErrorDetails {
+            code: "invalid_request".to_string(),
+            message: error.to_string(),
+            param: None,
+            error_type: Some("invalid_request_error".to_string()),
+        }),
+    }
 }
 
-impl GetApiKey for ChatOpenAI {}
\ No newline at end of file
+impl GetApiKey for ChatOpenAI {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::mock::{MockEndpoint, MockOpenAI, MockReply};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn image_urls_join_the_prompt_in_one_user_message() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::chat_text("A loft"));
+
+        ChatOpenAI::new("gpt-4o-mini")
+            .with_base_url(&server.base_url())
+            .with_system_prompt("Describe listings.")
+            .with_image_url("https://example.com/loft.jpg")
+            .invoke("What is this?")
+            .await
+            .unwrap();
+
+        let messages = &server.received()[0].body["messages"];
+        assert_eq!(messages.as_array().unwrap().len(), 2);
+        assert_eq!(messages[1]["role"], "user");
+        assert_eq!(messages[1]["content"], json!([
+            { "type": "text", "text": "What is this?" },
+            { "type": "image_url", "image_url": { "url": "https://example.com/loft.jpg", "detail": "auto" } },
+        ]));
+    }
+}
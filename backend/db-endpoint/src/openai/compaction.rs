@@ -0,0 +1,424 @@
+use crate::openai::requests::request_chat;
+use crate::openai::libs::{
+    MainRequest, ChatRequest, ChatResponse, InputContent, Message, Role,
+};
+use crate::openai::transport::Transport;
+use crate::openai::tokens::{count_messages, TokenBudget};
+use crate::openai::shaping::shape_chat_request;
+use crate::openai::usage::{TokenUsage, UsageSink, ENDPOINT_CHAT};
+use crate::openai::error::OpenAIError;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use log::{error, info};
+
+const SUMMARY_PROMPT: &str = "You compress conversations between a user and a \
+short-term rental search assistant. Summarize the conversation you are given in \
+a few sentences: what the user is looking for (location, dates, budget, guests, \
+amenities), which listings were discussed and what the user thought of them, \
+and any open questions. Keep every listing id exactly as written. \
+Reply with the summary only.";
+
+/// Strategy for compacting a long chat history into a summary
+///
+/// When the prompt grows past `trigger_ratio` of the model's prompt budget,
+/// every turn except the last `keep_last_turns` is summarized with
+/// `summary_model` into a single developer message. The last user turn is
+/// always kept, so the prompt being answered never ends up in the summary.
+/// Listing ids found in the summarized turns are appended to the summary
+/// verbatim.
+///
+/// # Fields
+/// * `summary_model` - Cheaper model used to write the summary
+/// * `keep_last_turns` - Number of recent user turns kept as they are
+/// * `trigger_ratio` - Fraction of the prompt budget that triggers compaction
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct HistoryCompaction {
+    pub summary_model: String,
+    pub keep_last_turns: usize,
+    pub trigger_ratio: f32,
+}
+
+impl Default for HistoryCompaction {
+    fn default() -> Self {
+        Self {
+            summary_model: "gpt-4o-mini".to_string(),
+            keep_last_turns: 4,
+            trigger_ratio: 0.75,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl HistoryCompaction {
+    pub fn new(summary_model: &str) -> Self {
+        Self {
+            summary_model: summary_model.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_keep_last_turns(mut self, keep_last_turns: usize) -> Self {
+        self.keep_last_turns = keep_last_turns;
+        self
+    }
+
+    pub fn with_trigger_ratio(mut self, trigger_ratio: f32) -> Self {
+        self.trigger_ratio = trigger_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Whether the messages are large enough to be compacted
+    pub fn should_compact(&self, model: &str, messages: &[Message], budget: &TokenBudget) -> bool {
+        let limit = budget.prompt_tokens() as f32 * self.trigger_ratio;
+        count_messages(model, messages) as f32 > limit
+    }
+
+    /// Splits messages into leading instructions, turns to summarize and
+    /// recent turns to keep, returned as index boundaries `(pinned, keep_from)`
+    ///
+    /// At least the last user turn is kept, even with `keep_last_turns` at 0.
+    pub fn split(&self, messages: &[Message]) -> (usize, usize) {
+        let pinned = messages
+            .iter()
+            .take_while(|message| matches!(message.role, Role::Developer | Role::System | Role::Platform))
+            .count();
+
+        let user_turns: Vec<usize> = messages
+            .iter()
+            .enumerate()
+            .skip(pinned)
+            .filter(|(_, message)| matches!(message.role, Role::User))
+            .map(|(index, _)| index)
+            .collect();
+
+        let keep_turns = self.keep_last_turns.max(1);
+        let keep_from = if user_turns.len() > keep_turns {
+            user_turns[user_turns.len() - keep_turns]
+        } else {
+            pinned
+        };
+
+        (pinned, keep_from)
+    }
+
+    /// Replaces the older turns of `messages` with a summary message
+    ///
+    /// Returns the messages unchanged when there is nothing to summarize. The
+    /// usage of the summary request is recorded to `usage_sink` when given.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn compact(
+        &self,
+        messages: Vec<Message>,
+        summary_role: Role,
+        api_key: &str,
+        timeout: Duration,
+        max_retries: u32,
+        transport: &dyn Transport,
+        endpoint: &str,
+        usage_sink: Option<&dyn UsageSink>,
+    ) -> Result<Vec<Message>, OpenAIError> {
+        let (pinned, keep_from) = self.split(&messages);
+        if keep_from <= pinned {
+            return Ok(messages);
+        }
+
+        let transcript = render_transcript(&messages[pinned..keep_from]);
+        let listing_ids = extract_listing_ids(&transcript);
+        let summary = self.summarize(&transcript, api_key, timeout, max_retries, transport, endpoint, usage_sink)
+            .await?;
+
+        let mut text = format!("Summary of the earlier conversation:\n{}", summary.trim());
+        if !listing_ids.is_empty() {
+            let ids: Vec<String> = listing_ids.into_iter().collect();
+            text.push_str(&format!("\n\nListing ids mentioned earlier: {}", ids.join(", ")));
+        }
+
+        info!(
+            "Compacted {} messages of chat history into a summary",
+            keep_from - pinned
+        );
+
+        let mut compacted = Vec::with_capacity(pinned + 1 + messages.len() - keep_from);
+        let mut messages = messages.into_iter();
+        compacted.extend(messages.by_ref().take(pinned));
+        compacted.push(text_message(summary_role, &text));
+        compacted.extend(messages.skip(keep_from - pinned));
+
+        Ok(compacted)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn summarize(
+        &self,
+        transcript: &str,
+        api_key: &str,
+        timeout: Duration,
+        max_retries: u32,
+        transport: &dyn Transport,
+        endpoint: &str,
+        usage_sink: Option<&dyn UsageSink>,
+    ) -> Result<String, OpenAIError> {
+        let mut request = ChatRequest {
+            model: self.summary_model.clone(),
+            messages: Some(vec![
+                text_message(Role::Developer, SUMMARY_PROMPT),
+                text_message(Role::User, transcript),
+            ]),
+            temperature: None,
+            tools: None,
+            tool_choice: None,
+            max_completion_tokens: Some(1024),
+            response_format: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            top_p: None,
+            stream: Some(false),
+            stream_options: None,
+            n_completion: Some(1),
+            stop: None,
+            reasoning_effort: None,
+        };
+        shape_chat_request(&mut request);
+
+        let response = request_chat(
+            &MainRequest::Chat(request),
+            endpoint,
+            api_key,
+            timeout,
+            max_retries,
+            transport,
+        ).await?;
+
+        let chat_response: ChatResponse = match serde_json::from_str(&response) {
+            Ok(response_form) => response_form,
+            Err(e) => {
+                error!("Failed to parse summary response: {}. ERROR-req-0024", e);
+                return Err(OpenAIError::ResponseContentError);
+            }
+        };
+
+        if let (Some(sink), Some(usage)) = (usage_sink, &chat_response.usage) {
+            sink.record(&self.summary_model, ENDPOINT_CHAT, TokenUsage::from(usage));
+        }
+
+        chat_response.choices
+            .and_then(|choices| choices.into_iter().next())
+            .and_then(|choice| choice.message)
+            .and_then(|message| message.content)
+            .ok_or(OpenAIError::ResponseContentError)
+    }
+}
+
+fn text_message(role: Role, text: &str) -> Message {
+    Message {
+        role,
+        content: vec![InputContent {
+            content_type: "text".to_string(),
+            text: Some(text.to_string()),
+            source: None,
+            image_url: None,
+        }],
+        recipient: None,
+        end_turn: None,
+    }
+}
+
+/// Renders messages as a plain `role: text` transcript for the summary model
+pub fn render_transcript(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let role = match message.role {
+                Role::Platform => "platform",
+                Role::System => "system",
+                Role::Developer => "developer",
+                Role::User => "user",
+                Role::Assistant => "assistant",
+                Role::Tool => "tool",
+            };
+            let text: Vec<&str> = message.content
+                .iter()
+                .filter_map(|item| item.text.as_deref())
+                .collect();
+            format!("{}: {}", role, text.join("\n"))
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+/// Words after which a number is taken for a listing id
+const LISTING_ID_CONTEXT: [&str; 7] = ["listing", "listings", "id", "ids", "_id", "listing_id", "listing_ids"];
+
+/// Finds listing ids mentioned in a text
+///
+/// A listing id is a number of at least 5 digits that fits an `i32` and
+/// follows one of `LISTING_ID_CONTEXT`, directly or through a list such as
+/// `listings 10006546, 10084023 and 10059244` or `"listing_ids": [...]`, so
+/// prices, dates and phone numbers are left out.
+pub fn extract_listing_ids(text: &str) -> BTreeSet<String> {
+    let mut ids = BTreeSet::new();
+    let mut in_context = false;
+    for word in text.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).filter(|word| !word.is_empty()) {
+        if word.bytes().all(|byte| byte.is_ascii_digit()) {
+            if in_context && word.len() >= 5 && word.parse::<i32>().is_ok() {
+                ids.insert(word.to_string());
+            } else {
+                in_context = false;
+            }
+            continue;
+        }
+
+        let word = word.to_ascii_lowercase();
+        in_context = LISTING_ID_CONTEXT.contains(&word.as_str()) || (in_context && matches!(word.as_str(), "and" | "or"));
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::mock::{MockEndpoint, MockOpenAI, MockReply};
+    use crate::openai::transport::HttpTransport;
+    use crate::openai::endpoint_url;
+    use std::sync::Mutex;
+
+    /// Keeps what the summary request reports
+    #[derive(Debug, Default)]
+    struct RecordedUsage(Mutex<Vec<(String, String, TokenUsage)>>);
+
+    impl UsageSink for RecordedUsage {
+        fn record(&self, model: &str, endpoint: &str, usage: TokenUsage) {
+            self.0.lock().unwrap().push((model.to_string(), endpoint.to_string(), usage));
+        }
+    }
+
+    async fn compact_with(
+        compaction: HistoryCompaction,
+        server: &MockOpenAI,
+        messages: Vec<Message>,
+        usage_sink: Option<&dyn UsageSink>,
+    ) -> Vec<Message> {
+        compaction
+            .compact(
+                messages,
+                Role::Developer,
+                "sk-test",
+                Duration::from_secs(5),
+                0,
+                &HttpTransport::new(),
+                &endpoint_url(&server.base_url(), "chat/completions"),
+                usage_sink,
+            )
+            .await
+            .unwrap()
+    }
+
+    fn texts(messages: &[Message]) -> Vec<&str> {
+        messages.iter().filter_map(|message| message.content[0].text.as_deref()).collect()
+    }
+
+    #[test]
+    fn listing_ids_need_a_listing_context() {
+        let text = "Listing 10006546 costs 120000 per year. Call +351 912345678 before 20240501. \
+            Results: {\"_id\": 10084023, \"price\": 80}, listing_ids [10059244, 10084023] and listings 1234567 or 7654321.";
+
+        let ids: Vec<String> = extract_listing_ids(text).into_iter().collect();
+
+        assert_eq!(ids, ["10006546", "10059244", "10084023", "1234567", "7654321"]);
+    }
+
+    #[test]
+    fn listing_ids_must_fit_an_i32() {
+        assert!(extract_listing_ids("listing 99999999999 and id 1234").is_empty());
+    }
+
+    #[test]
+    fn split_keeps_instructions_and_the_last_turns() {
+        let messages = vec![
+            text_message(Role::System, "Be brief."),
+            text_message(Role::User, "Lofts in Porto?"),
+            text_message(Role::Assistant, "Listing 10006546 fits."),
+            text_message(Role::User, "Cheaper?"),
+            text_message(Role::Assistant, "Listing 10059244."),
+        ];
+
+        assert_eq!(HistoryCompaction::default().with_keep_last_turns(1).split(&messages), (1, 3));
+        assert_eq!(HistoryCompaction::default().with_keep_last_turns(2).split(&messages), (1, 1));
+    }
+
+    #[test]
+    fn split_always_keeps_the_last_user_turn() {
+        let messages = vec![
+            text_message(Role::System, "Be brief."),
+            text_message(Role::User, "Lofts in Porto?"),
+            text_message(Role::Assistant, "Listing 10006546 fits."),
+            text_message(Role::User, "Cheaper?"),
+        ];
+
+        assert_eq!(HistoryCompaction::default().with_keep_last_turns(0).split(&messages), (1, 3));
+        assert_eq!(HistoryCompaction::default().with_keep_last_turns(0).split(&messages[..2]), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn compact_replaces_older_turns_with_a_summary_keeping_listing_ids() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::chat_text("The user wants a loft in Porto."));
+        let messages = vec![
+            text_message(Role::System, "Be brief."),
+            text_message(Role::User, "Lofts in Porto?"),
+            text_message(Role::Assistant, "Listing 10006546 fits."),
+            text_message(Role::User, "Cheaper?"),
+        ];
+
+        let compacted = compact_with(HistoryCompaction::default().with_keep_last_turns(1), &server, messages, None).await;
+
+        assert_eq!(texts(&compacted), [
+            "Be brief.",
+            "Summary of the earlier conversation:\nThe user wants a loft in Porto.\n\nListing ids mentioned earlier: 10006546",
+            "Cheaper?",
+        ]);
+        assert!(matches!(compacted[1].role, Role::Developer));
+        let transcript = server.received()[0].body["messages"][1]["content"][0]["text"].clone();
+        assert_eq!(transcript, "user: Lofts in Porto?\n\nassistant: Listing 10006546 fits.");
+    }
+
+    #[tokio::test]
+    async fn compact_without_kept_turns_still_keeps_the_prompt() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::chat_text("The user wants a loft in Porto."));
+        let messages = vec![
+            text_message(Role::System, "Be brief."),
+            text_message(Role::User, "Lofts in Porto?"),
+            text_message(Role::Assistant, "Listing 10006546 fits."),
+            text_message(Role::User, "Cheaper?"),
+        ];
+
+        let compacted = compact_with(HistoryCompaction::default().with_keep_last_turns(0), &server, messages, None).await;
+
+        assert_eq!(texts(&compacted).last(), Some(&"Cheaper?"));
+        assert!(matches!(compacted.last().unwrap().role, Role::User));
+        let transcript = server.received()[0].body["messages"][1]["content"][0]["text"].clone();
+        assert_eq!(transcript, "user: Lofts in Porto?\n\nassistant: Listing 10006546 fits.");
+    }
+
+    #[tokio::test]
+    async fn summary_request_is_shaped_and_reports_its_usage() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::chat_text("The user wants a loft in Porto."));
+        let messages = vec![
+            text_message(Role::User, "Lofts in Porto?"),
+            text_message(Role::Assistant, "Listing 10006546 fits."),
+            text_message(Role::User, "Cheaper?"),
+        ];
+        let sink = RecordedUsage::default();
+
+        compact_with(HistoryCompaction::new("o1-mini").with_keep_last_turns(1), &server, messages, Some(&sink)).await;
+
+        assert_eq!(server.received()[0].body["messages"][0]["role"], "user");
+        let recorded = sink.0.lock().unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!((recorded[0].0.as_str(), recorded[0].1.as_str()), ("o1-mini", ENDPOINT_CHAT));
+        assert_eq!(recorded[0].2.total_tokens(), 12);
+    }
+}
@@ -1,7 +1,10 @@
 use crate::openai::requests::request_embed;
 use crate::openai::libs::{EmbedRequest, EmbedResponse};
+use crate::openai::transport::{default_transport, Transport};
 use crate::openai::utils::GetApiKey;
+use crate::openai::{api_base, endpoint_url};
 use crate::openai::error::OpenAIError;
+use std::sync::Arc;
 use std::time::Duration;
 use log::error;
 
@@ -12,6 +15,8 @@ pub struct EmbedOpenAI {
     pub request: EmbedRequest,
     pub timeout: Duration,
     pub api_key: String,
+    pub transport: Arc<dyn Transport>,
+    pub base_url: String,
 }
 
 #[allow(dead_code)]
@@ -30,9 +35,11 @@ impl EmbedOpenAI {
 
         Self {
             model: model.to_string(),
-            request: request,
+            request,
             timeout: Duration::from_secs(300), // default: 5 minutes
-            api_key: api_key,
+            api_key,
+            transport: default_transport(),
+            base_url: api_base(),
         }
     }
 
@@ -41,7 +48,10 @@ impl EmbedOpenAI {
         
         let response: String = match request_embed(
             &self.request,
+            &endpoint_url(&self.base_url, "embeddings"),
             &self.api_key,
+            self.timeout,
+            self.transport.as_ref(),
         ).await {
             Ok(response) => response,
             Err(e) => {
@@ -59,12 +69,18 @@ impl EmbedOpenAI {
         };
         if let Some(error) = embed_response.error {
             error!("Error {}", error.message);
-            return Err(OpenAIError::ResponseContentError);
+            Err(OpenAIError::ResponseContentError)
         } else {
             Ok(embed_response)
         }    
     }
 
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self.request.model = model.to_string();
+        self
+    }
+
     pub fn with_timeout_sec(mut self, timeout: u64) -> Self {
         self.timeout = Duration::from_secs(timeout);
         self
@@ -80,6 +96,19 @@ impl EmbedOpenAI {
         self.api_key = api_key.to_string();
         self
     }
+
+    /// Sends requests through `transport`, for example a `ReplayTransport`
+    /// serving recorded fixtures
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// API root requests are sent to, `https://api.openai.com/v1` by default
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
 }
 
 impl GetApiKey for EmbedOpenAI {}
\ No newline at end of file
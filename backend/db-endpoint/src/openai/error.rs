@@ -15,6 +15,9 @@ pub enum OpenAIError {
     #[error("Bad Request Error: Malformed request or missing parameters. {0}")]
     BadRequestError(String),
 
+    #[error("Validation Error: Invalid request parameters. {0}")]
+    ValidationError(String),
+
     #[error("Conflict Error: Resource was updated by another request. {0}")]
     ConflictError(String),
 
@@ -47,6 +50,21 @@ pub enum OpenAIError {
     
     #[error("Failed to get response content")]
     ResponseContentError,
+
+    #[error("Invalid JSON schema for structured outputs: {0}")]
+    SchemaError(String),
+
+    #[error("Incomplete response: {0}")]
+    IncompleteResponseError(String),
+
+    #[error("The model refused to respond: {0}")]
+    RefusalError(String),
+
+    #[error("Invalid arguments for tool `{name}`: {message}")]
+    ToolArgumentsError {
+        name: String,
+        message: String,
+    },
     
     #[error("{message}")]
     GenericError {
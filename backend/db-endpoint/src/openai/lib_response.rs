@@ -109,11 +109,11 @@ pub struct ResponseRequest {
     /// 
     /// # Strategies
     /// - **auto**: If the context of this response and previous ones exceeds 
-    ///         the model's context window size, the model will truncate 
-    ///         the response to fit the context window by dropping input 
-    ///         items in the middle of the conversation.
+    ///   the model's context window size, the model will truncate 
+    ///   the response to fit the context window by dropping input 
+    ///   items in the middle of the conversation.
     /// - **disabled** (default):  If a model response will exceed the context window size 
-    ///                        for a model, the request will fail with a 400 error.
+    ///   for a model, the request will fail with a 400 error.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub truncation: Option<String>,
 
@@ -305,13 +305,13 @@ pub struct ResponseFormat {
 
 /// An object specifying the format that the model must output.
 /// - Configuring `{ "type": "json_schema" }`` enables Structured Outputs, 
-/// which ensures the model will match your supplied JSON schema.
+///   which ensures the model will match your supplied JSON schema.
 /// - The default format is `{ "type": "text" }`` with no additional options.
 /// 
 /// # Not recommended for gpt-4o and newer models:
 /// - Setting to `{ "type": "json_object" }`` enables the older JSON mode, 
-/// which ensures the message the model generates is valid JSON. 
-/// Using json_schema is preferred for models that support it.
+///   which ensures the message the model generates is valid JSON. 
+///   Using json_schema is preferred for models that support it.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
@@ -363,12 +363,14 @@ pub struct JsonSchema {
     pub type_: String,
 
     /// **Optional.** A description of the response format.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
 
     /// **Optional.** Whether to enable strict schema adherence when generating 
     /// the output. If set to true, the model will always follow the exact schema 
     /// defined in the `schema` field. Only a subset of JSON Schema is supported 
     /// when `strict` is `true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub strict: Option<bool>,
 }
 
@@ -598,6 +600,7 @@ pub struct OutputText {
 /// The annotations of the text output.
 #[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
 pub enum Annotation {
     /// A citation to a file.
     FileCitation(FileCitation),
@@ -667,6 +670,7 @@ pub struct FilePath {
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     Platform,
+    System,
     Developer,
     User,
     Assistant,
@@ -732,11 +736,11 @@ pub struct ResponseObject {
     /// An array of content items generated by the model.
     /// 
     /// * The length and order of items in the output array is dependent 
-    /// on the model's response.
+    ///   on the model's response.
     /// * Rather than accessing the first item in the output array and 
-    /// assuming it's an assistant message with the content generated by 
-    /// the model, you might consider using the output_text 
-    /// property where supported in SDKs.
+    ///   assuming it's an assistant message with the content generated by 
+    ///   the model, you might consider using the output_text 
+    ///   property where supported in SDKs.
     pub output: Vec<OutputItem>,
 
     /// SDK-only convenience property that contains the aggregated text 
@@ -801,7 +805,27 @@ pub struct ResponseObject {
 pub enum OutputItem {
     Reasoning(ReasoningOutput),
     Message(MessageOutput),
+    FunctionCall(FunctionCallOutput),
     WebSearchCall(Value),
+    FileSearchCall(Value),
+    ComputerCall(Value),
+    /// Output types this client does not know yet
+    #[serde(other)]
+    Unknown,
+}
+
+// Specific struct for the "function_call" type output
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Clone)]
+pub struct FunctionCallOutput {
+    pub id: Option<String>,
+    /// ID used to send the function output back with `function_call_output`
+    pub call_id: String,
+    pub name: String,
+    /// JSON encoded arguments generated by the model
+    pub arguments: String,
+    pub status: Option<String>,
+    // The 'type' field is handled by the enum tag
 }
 
 // Specific struct for the "reasoning" type output
@@ -827,8 +851,13 @@ pub struct MessageOutput {
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 pub struct ContentItem {
+    #[serde(default)]
     pub annotations: Vec<Value>,
+    /// Text of an `output_text` item; empty for refusals.
+    #[serde(default)]
     pub text: String,
+    /// Explanation of a `refusal` item.
+    pub refusal: Option<String>,
     #[serde(rename = "type")]
     pub content_type: String,
 }
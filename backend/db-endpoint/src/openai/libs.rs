@@ -3,6 +3,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 #[allow(dead_code)]
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum MainRequest {
@@ -35,10 +36,25 @@ pub struct ChatRequest {
     pub top_p: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
     #[serde(rename = "n")]
     pub n_completion: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>, // low, medium or high; reasoning models only
+}
+
+/// Options of a streamed chat completion
+///
+/// # Fields
+/// * `include_usage` - Sends a last chunk with empty `choices` carrying the
+///   usage of the whole request
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamOptions {
+    pub include_usage: bool,
 }
 
 #[allow(dead_code)]
@@ -82,6 +98,7 @@ pub struct EmbeddingData {
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     Platform,
+    System,
     Developer,
     User,
     Assistant,
@@ -116,6 +133,30 @@ pub struct InputContent {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageUrl {
     pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+/// Resolution a vision model looks at an image with. `low` costs a fixed
+/// small number of tokens, `high` tiles the image for fine detail.
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    Low,
+    High,
+    #[default]
+    Auto,
+}
+
+impl ImageDetail {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageDetail::Low => "low",
+            ImageDetail::High => "high",
+            ImageDetail::Auto => "auto",
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -157,7 +198,7 @@ pub struct Choice {
 pub struct Delta {
     pub content: Option<String>,
     pub role: Option<String>,
-    pub tool_calls: Option<Vec<Value>>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 #[allow(dead_code)]
@@ -166,7 +207,57 @@ pub struct ChatMessage {
     pub content: Option<String>,
     pub refusal: Option<String>,
     pub role: String,
-    pub tool_calls: Option<Vec<Value>>,
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+/// A tool call requested by the model
+///
+/// # Fields
+/// * `id` - Identifier to reference in the tool result message
+/// * `call_type` - Always `function`
+/// * `function` - Name of the function and its JSON-encoded arguments
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub function: FunctionCall,
+}
+
+/// The function the model wants to call
+///
+/// # Fields
+/// * `name` - Name of the function
+/// * `arguments` - Arguments as a JSON string generated by the model, which
+///   may be invalid or not match the function's parameters
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// A fragment of a tool call received while streaming
+///
+/// The first fragment of a call carries its `id`, `type` and function name;
+/// the following ones only append to `function.arguments`. Fragments of
+/// parallel calls are told apart by `index`.
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ToolCallDelta {
+    pub index: u32,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub call_type: Option<String>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -223,7 +314,6 @@ pub struct ErrorResponse {
 /// * `message` - Detailed description of what went wrong
 /// * `param` - Optional - Parameter that caused the error
 /// * `error_type` - Optional - Specific type or category of the error
-
 #[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ErrorDetails {
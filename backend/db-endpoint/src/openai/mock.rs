@@ -0,0 +1,372 @@
+//! In-process stand-in for the OpenAI API, used by tests
+//!
+//! Replies are scripted per endpoint and served in order; once a script runs
+//! out, every endpoint answers with a canned success.
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Endpoints served by the mock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MockEndpoint {
+    Chat,
+    Responses,
+    Embeddings,
+}
+
+/// One scripted reply
+///
+/// # Fields
+/// * `status` - HTTP status to answer with
+/// * `body` - JSON body, or the events of an SSE stream
+/// * `delay` - Time to wait before answering
+#[derive(Debug, Clone)]
+pub struct MockReply {
+    pub status: u16,
+    pub body: MockBody,
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum MockBody {
+    Json(Value),
+    /// `data:` events, followed by `data: [DONE]`
+    Events(Vec<Value>),
+}
+
+impl MockReply {
+    pub fn json(body: Value) -> Self {
+        Self {
+            status: 200,
+            body: MockBody::Json(body),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// An error in the API's `{"error": {...}}` shape
+    pub fn error(status: u16, code: &str, message: &str) -> Self {
+        Self {
+            status,
+            body: MockBody::Json(json!({
+                "error": {
+                    "code": code,
+                    "message": message,
+                    "param": null,
+                    "type": code,
+                }
+            })),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn events(events: Vec<Value>) -> Self {
+        Self {
+            status: 200,
+            body: MockBody::Events(events),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// A chat completion answering with `text`
+    pub fn chat_text(text: &str) -> Self {
+        Self::json(chat_completion(json!({ "role": "assistant", "content": text }), "stop"))
+    }
+
+    /// A chat completion calling one function with JSON `arguments`
+    pub fn chat_tool_call(name: &str, arguments: Value) -> Self {
+        Self::json(chat_completion(
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": name, "arguments": arguments.to_string() }
+                }]
+            }),
+            "tool_calls",
+        ))
+    }
+
+    /// A streamed chat completion sending `pieces` as content deltas
+    pub fn chat_stream(pieces: &[&str]) -> Self {
+        let mut events: Vec<Value> = pieces
+            .iter()
+            .map(|piece| chunk(json!({ "content": piece }), None))
+            .collect();
+        events.push(chunk(json!({}), Some("stop")));
+        Self::events(events)
+    }
+
+    /// A streamed chat completion building one tool call from argument
+    /// fragments
+    pub fn chat_stream_tool_call(name: &str, fragments: &[&str]) -> Self {
+        let mut events = vec![chunk(
+            json!({
+                "tool_calls": [{
+                    "index": 0,
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": name, "arguments": "" }
+                }]
+            }),
+            None,
+        )];
+        events.extend(fragments.iter().map(|fragment| {
+            chunk(
+                json!({
+                    "tool_calls": [{ "index": 0, "function": { "arguments": fragment } }]
+                }),
+                None,
+            )
+        }));
+        events.push(chunk(json!({}), Some("tool_calls")));
+        Self::events(events)
+    }
+
+    /// A completed Responses API answer with `text`
+    pub fn response_text(text: &str) -> Self {
+        Self::json(json!({
+            "id": "resp_mock",
+            "object": "response",
+            "created_at": 1_700_000_000,
+            "status": "completed",
+            "model": "gpt-4o-mini",
+            "output": [{
+                "type": "message",
+                "id": "msg_mock",
+                "role": "assistant",
+                "status": "completed",
+                "content": [{ "type": "output_text", "text": text, "annotations": [] }]
+            }],
+            "usage": {
+                "input_tokens": 10,
+                "output_tokens": 2,
+                "total_tokens": 12,
+                "input_tokens_details": { "cached_tokens": 0 },
+                "output_tokens_details": { "reasoning_tokens": 0 }
+            }
+        }))
+    }
+
+    /// An embedding response with one vector of `dimensions` values
+    pub fn embedding(dimensions: usize) -> Self {
+        Self::json(json!({
+            "object": "list",
+            "model": "text-embedding-3-small",
+            "data": [{
+                "object": "embedding",
+                "index": 0,
+                "embedding": vec![0.25; dimensions],
+            }],
+            "usage": { "prompt_tokens": 4, "total_tokens": 4 }
+        }))
+    }
+}
+
+fn chat_completion(message: Value, finish_reason: &str) -> Value {
+    json!({
+        "id": "chatcmpl-mock",
+        "object": "chat.completion",
+        "created": 1_700_000_000,
+        "model": "gpt-4o-mini",
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12 }
+    })
+}
+
+fn chunk(delta: Value, finish_reason: Option<&str>) -> Value {
+    json!({
+        "id": "chatcmpl-mock",
+        "object": "chat.completion.chunk",
+        "created": 1_700_000_000,
+        "model": "gpt-4o-mini",
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+    })
+}
+
+/// Last chunk of a stream asked to include usage
+fn usage_chunk() -> Value {
+    json!({
+        "id": "chatcmpl-mock",
+        "object": "chat.completion.chunk",
+        "created": 1_700_000_000,
+        "model": "gpt-4o-mini",
+        "choices": [],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12 }
+    })
+}
+
+/// A request received by the mock
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    pub endpoint: MockEndpoint,
+    pub authorization: Option<String>,
+    pub body: Value,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    scripts: Mutex<HashMap<MockEndpoint, VecDeque<MockReply>>>,
+    received: Mutex<Vec<ReceivedRequest>>,
+}
+
+/// A mock OpenAI API listening on a random local port
+///
+/// # Examples
+/// ```
+/// let server = MockOpenAI::start().await;
+/// server.enqueue(MockEndpoint::Chat, MockReply::error(500, "server_error", "boom"));
+/// let chat = ChatOpenAI::new("gpt-4o-mini").with_base_url(&server.base_url());
+/// ```
+pub struct MockOpenAI {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    handle: JoinHandle<()>,
+}
+
+impl MockOpenAI {
+    pub async fn start() -> Self {
+        let state = Arc::new(MockState::default());
+        let app = Router::new()
+            .route("/v1/chat/completions", post(chat))
+            .route("/v1/responses", post(responses))
+            .route("/v1/embeddings", post(embeddings))
+            .with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        Self { addr, state, handle }
+    }
+
+    /// API root to pass to `with_base_url`
+    pub fn base_url(&self) -> String {
+        format!("http://{}/v1", self.addr)
+    }
+
+    /// Queues a reply for the next request to `endpoint`
+    pub fn enqueue(&self, endpoint: MockEndpoint, reply: MockReply) {
+        self.state.scripts
+            .lock()
+            .unwrap()
+            .entry(endpoint)
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Requests received so far, oldest first
+    pub fn received(&self) -> Vec<ReceivedRequest> {
+        self.state.received.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockOpenAI {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn chat(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    let streaming = body["stream"].as_bool().unwrap_or(false);
+    let fallback = if streaming {
+        MockReply::chat_stream(&["ok"])
+    } else {
+        MockReply::chat_text("ok")
+    };
+    reply(&state, MockEndpoint::Chat, headers, body, fallback).await
+}
+
+async fn responses(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    reply(&state, MockEndpoint::Responses, headers, body, MockReply::response_text("ok")).await
+}
+
+async fn embeddings(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    let dimensions = body["dimensions"].as_u64().unwrap_or(8) as usize;
+    reply(&state, MockEndpoint::Embeddings, headers, body, MockReply::embedding(dimensions)).await
+}
+
+async fn reply(
+    state: &MockState,
+    endpoint: MockEndpoint,
+    headers: HeaderMap,
+    body: Value,
+    fallback: MockReply,
+) -> Response {
+    let include_usage = body["stream_options"]["include_usage"].as_bool() == Some(true);
+    state.received.lock().unwrap().push(ReceivedRequest {
+        endpoint,
+        authorization: headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        body,
+    });
+
+    let scripted = state.scripts
+        .lock()
+        .unwrap()
+        .get_mut(&endpoint)
+        .and_then(|script| script.pop_front());
+    let reply = scripted.unwrap_or(fallback);
+
+    if !reply.delay.is_zero() {
+        tokio::time::sleep(reply.delay).await;
+    }
+
+    let status = StatusCode::from_u16(reply.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    match reply.body {
+        MockBody::Json(body) => (status, Json(body)).into_response(),
+        MockBody::Events(events) => {
+            let frames: Vec<Result<String, Infallible>> = events
+                .iter()
+                .chain(include_usage.then(usage_chunk).as_ref())
+                .map(|event| format!("data: {}\n\n", event))
+                .chain(std::iter::once("data: [DONE]\n\n".to_string()))
+                .map(Ok)
+                .collect();
+
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "text/event-stream")
+                .body(Body::from_stream(futures::stream::iter(frames)))
+                .unwrap()
+        }
+    }
+}
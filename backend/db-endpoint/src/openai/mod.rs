@@ -1,16 +1,48 @@
 use std::time::Duration;
 
 pub mod chat;
+pub mod compaction;
 pub mod response;
 pub mod embed;
 pub mod error;
 pub mod libs;
+pub mod models;
+pub mod output;
 pub mod lib_response;
 pub mod utils;
+pub mod validation;
 pub mod requests;
+pub mod shaping;
+pub mod tokens;
+pub mod tools;
+pub mod transport;
+pub mod usage;
+pub mod vision;
 
+#[cfg(test)]
+pub mod mock;
+
+#[cfg(not(test))]
 pub const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
 
+/// Keeps the retry tests against the mock server fast
+#[cfg(test)]
+pub const RETRY_BASE_DELAY: Duration = Duration::from_millis(10);
+
+/// API root used when `OPENAI_API_BASE` is not set
+pub static OPENAI_API_BASE: &str = "https://api.openai.com/v1";
+
 pub static OPENAI_BASE_URL: &str = "https://api.openai.com/v1/chat/completions";
 pub static OPENAI_RESPONSE_URL: &str = "https://api.openai.com/v1/responses";
-pub static OPENAI_EMBED_URL: &str = "https://api.openai.com/v1/embeddings";
\ No newline at end of file
+pub static OPENAI_EMBED_URL: &str = "https://api.openai.com/v1/embeddings";
+
+/// Root of the OpenAI API, overridable with `OPENAI_API_BASE` to point the
+/// clients at a proxy or a local mock server
+pub fn api_base() -> String {
+    std::env::var("OPENAI_API_BASE").unwrap_or_else(|_| OPENAI_API_BASE.to_string())
+}
+
+/// Joins an API root and an endpoint path such as `chat/completions`
+pub fn endpoint_url(base_url: &str, path: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), path)
+}
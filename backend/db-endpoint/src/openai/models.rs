@@ -0,0 +1,170 @@
+/// Role a model expects system-level instructions in
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InstructionRole {
+    /// `system`, for models older than the `developer` role
+    System,
+    /// `developer`, for current models
+    Developer,
+    /// `user`, for early reasoning models that accept no instructions at all
+    User,
+}
+
+/// Static limits and feature support of an OpenAI model
+///
+/// # Fields
+/// * `name` - Model id, dated snapshots like `gpt-4o-2024-08-06` resolve to it by prefix
+/// * `context_window` - Maximum number of input plus output tokens
+/// * `max_output_tokens` - Maximum number of tokens the model can generate
+/// * `instruction_role` - Role system prompts are sent with
+/// * `supports_json_schema` - Whether `response_format` / `text.format` accepts `json_schema`
+/// * `supports_tools` - Whether function tools can be attached
+/// * `supports_vision` - Whether image inputs are accepted
+/// * `supports_reasoning_effort` - Whether `reasoning_effort` / `reasoning.effort` is accepted
+/// * `reasoning` - O-series model, which rejects sampling parameters and
+///   only limits output through `max_completion_tokens`
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelCapabilities {
+    pub name: &'static str,
+    pub context_window: u32,
+    pub max_output_tokens: u32,
+    pub instruction_role: InstructionRole,
+    pub supports_json_schema: bool,
+    pub supports_tools: bool,
+    pub supports_vision: bool,
+    pub supports_reasoning_effort: bool,
+    pub reasoning: bool,
+}
+
+/// Defaults of current chat models; table entries override what differs
+const CHAT: ModelCapabilities = ModelCapabilities {
+    name: "",
+    context_window: 128_000,
+    max_output_tokens: 16_384,
+    instruction_role: InstructionRole::Developer,
+    supports_json_schema: true,
+    supports_tools: true,
+    supports_vision: true,
+    supports_reasoning_effort: false,
+    reasoning: false,
+};
+
+/// Defaults of o-series reasoning models
+const REASONING: ModelCapabilities = ModelCapabilities {
+    context_window: 200_000,
+    max_output_tokens: 100_000,
+    supports_reasoning_effort: true,
+    reasoning: true,
+    ..CHAT
+};
+
+/// Defaults of embedding models, which only have an input limit
+const EMBEDDING: ModelCapabilities = ModelCapabilities {
+    context_window: 8_191,
+    max_output_tokens: 0,
+    instruction_role: InstructionRole::System,
+    supports_json_schema: false,
+    supports_tools: false,
+    supports_vision: false,
+    ..CHAT
+};
+
+pub static MODELS: &[ModelCapabilities] = &[
+    // chat
+    ModelCapabilities { name: "gpt-4o", ..CHAT },
+    ModelCapabilities { name: "gpt-4o-mini", ..CHAT },
+    ModelCapabilities { name: "gpt-4.1", context_window: 1_047_576, max_output_tokens: 32_768, ..CHAT },
+    ModelCapabilities { name: "gpt-4.1-mini", context_window: 1_047_576, max_output_tokens: 32_768, ..CHAT },
+    ModelCapabilities { name: "gpt-4.1-nano", context_window: 1_047_576, max_output_tokens: 32_768, ..CHAT },
+    ModelCapabilities {
+        name: "gpt-4-turbo",
+        max_output_tokens: 4_096,
+        instruction_role: InstructionRole::System,
+        supports_json_schema: false,
+        ..CHAT
+    },
+    ModelCapabilities {
+        name: "gpt-3.5-turbo",
+        context_window: 16_385,
+        max_output_tokens: 4_096,
+        instruction_role: InstructionRole::System,
+        supports_json_schema: false,
+        supports_vision: false,
+        ..CHAT
+    },
+    // reasoning
+    ModelCapabilities { name: "o1", ..REASONING },
+    ModelCapabilities {
+        name: "o1-mini",
+        context_window: 128_000,
+        max_output_tokens: 65_536,
+        instruction_role: InstructionRole::User,
+        supports_json_schema: false,
+        supports_tools: false,
+        supports_vision: false,
+        supports_reasoning_effort: false,
+        ..REASONING
+    },
+    ModelCapabilities {
+        name: "o1-preview",
+        context_window: 128_000,
+        max_output_tokens: 32_768,
+        instruction_role: InstructionRole::User,
+        supports_json_schema: false,
+        supports_tools: false,
+        supports_vision: false,
+        supports_reasoning_effort: false,
+        ..REASONING
+    },
+    ModelCapabilities { name: "o3", ..REASONING },
+    ModelCapabilities { name: "o3-mini", supports_vision: false, ..REASONING },
+    ModelCapabilities { name: "o4-mini", ..REASONING },
+    // embeddings
+    ModelCapabilities { name: "text-embedding-3-small", ..EMBEDDING },
+    ModelCapabilities { name: "text-embedding-3-large", ..EMBEDDING },
+    ModelCapabilities { name: "text-embedding-ada-002", ..EMBEDDING },
+];
+
+/// Context window assumed for models missing from `MODELS`
+pub const DEFAULT_CONTEXT_WINDOW: u32 = 8_192;
+
+/// Looks up a model, matching dated snapshots against the longest known prefix
+///
+/// # Examples
+/// ```
+/// let caps = capabilities("gpt-4o-mini-2024-07-18").unwrap();
+/// assert_eq!(caps.name, "gpt-4o-mini");
+/// ```
+pub fn capabilities(model: &str) -> Option<&'static ModelCapabilities> {
+    if let Some(caps) = MODELS.iter().find(|caps| caps.name == model) {
+        return Some(caps);
+    }
+
+    MODELS
+        .iter()
+        .filter(|caps| model.starts_with(caps.name)
+            && model.as_bytes().get(caps.name.len()) == Some(&b'-'))
+        .max_by_key(|caps| caps.name.len())
+}
+
+/// Returns the context window of a model, or `DEFAULT_CONTEXT_WINDOW` if unknown
+pub fn context_window(model: &str) -> u32 {
+    capabilities(model)
+        .map(|caps| caps.context_window)
+        .unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+/// Whether a model is a reasoning model; unknown models are assumed not to be
+pub fn is_reasoning_model(model: &str) -> bool {
+    capabilities(model)
+        .map(|caps| caps.reasoning)
+        .unwrap_or(false)
+}
+
+/// Role system prompts are sent with; unknown models get `developer`
+pub fn instruction_role(model: &str) -> InstructionRole {
+    capabilities(model)
+        .map(|caps| caps.instruction_role)
+        .unwrap_or(InstructionRole::Developer)
+}
@@ -0,0 +1,221 @@
+use crate::openai::error::OpenAIError;
+use crate::openai::libs::{FunctionCall, ToolCall};
+use crate::openai::lib_response::{
+    Annotation, ContentItem, FunctionCallOutput, OutputItem, ResponseObject,
+};
+use serde_json::Value;
+
+#[allow(dead_code)]
+impl ResponseObject {
+    /// Content items of every assistant message in the output
+    fn content_items(&self) -> impl Iterator<Item = &ContentItem> {
+        self.output
+            .iter()
+            .filter_map(|item| match item {
+                OutputItem::Message(message) => Some(message.content.iter()),
+                _ => None,
+            })
+            .flatten()
+    }
+
+    /// Text of every `output_text` item, concatenated in order
+    ///
+    /// Uses the `output_text` field when the API already filled it.
+    pub fn output_text(&self) -> String {
+        if let Some(text) = &self.output_text {
+            return text.clone();
+        }
+
+        self.content_items()
+            .filter(|content| content.content_type == "output_text")
+            .map(|content| content.text.as_str())
+            .collect()
+    }
+
+    /// Explanations of every `refusal` item
+    pub fn refusals(&self) -> Vec<String> {
+        self.content_items()
+            .filter_map(|content| content.refusal.clone())
+            .collect()
+    }
+
+    /// Function calls requested by the model, as `ToolCall`s whose `id` is
+    /// the `call_id` to answer with a `function_call_output` item
+    pub fn function_calls(&self) -> Vec<ToolCall> {
+        self.output
+            .iter()
+            .filter_map(|item| match item {
+                OutputItem::FunctionCall(call) => Some(ToolCall::from(call)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Text of the reasoning summaries, when a summary was requested
+    pub fn reasoning_summaries(&self) -> Vec<String> {
+        self.output
+            .iter()
+            .filter_map(|item| match item {
+                OutputItem::Reasoning(reasoning) => Some(reasoning.summary.iter()),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|summary| summary["text"].as_str().map(str::to_string))
+            .collect()
+    }
+
+    /// URL citations, file citations and file paths attached to the text
+    ///
+    /// Annotations of unknown types are skipped.
+    pub fn annotations(&self) -> Vec<Annotation> {
+        self.content_items()
+            .flat_map(|content| content.annotations.iter())
+            .filter_map(parse_annotation)
+            .collect()
+    }
+
+    /// Turns a failed or incomplete response into an error
+    ///
+    /// # Errors
+    /// * `OpenAIError::GenericError` - The response has an `error` object or
+    ///   its status is `failed`
+    /// * `OpenAIError::IncompleteResponseError` - The status is `incomplete`;
+    ///   the message is the reason from `incomplete_details`
+    pub fn check_status(&self) -> Result<(), OpenAIError> {
+        if let Some(error) = self.error.as_ref().filter(|error| !error.is_null()) {
+            return Err(OpenAIError::GenericError {
+                code: error["code"].as_str().unwrap_or("response_failed").to_string(),
+                message: error["message"]
+                    .as_str()
+                    .unwrap_or("The response failed")
+                    .to_string(),
+                detail: error.to_string(),
+            });
+        }
+
+        match self.status.as_deref() {
+            Some("failed") => Err(OpenAIError::GenericError {
+                code: "response_failed".to_string(),
+                message: "The response failed".to_string(),
+                detail: self.id.clone().unwrap_or_default(),
+            }),
+            Some("incomplete") => {
+                let reason = self.incomplete_details
+                    .as_ref()
+                    .and_then(|details| details["reason"].as_str())
+                    .unwrap_or("unknown reason");
+                Err(OpenAIError::IncompleteResponseError(reason.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<&FunctionCallOutput> for ToolCall {
+    fn from(call: &FunctionCallOutput) -> Self {
+        Self {
+            id: call.call_id.clone(),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            },
+        }
+    }
+}
+
+/// Reads an annotation by its `type`, since file citations and file paths
+/// have the same fields
+fn parse_annotation(value: &Value) -> Option<Annotation> {
+    let annotation = match value["type"].as_str()? {
+        "url_citation" => Annotation::UrlCitation(serde_json::from_value(value.clone()).ok()?),
+        "file_citation" => Annotation::FileCitation(serde_json::from_value(value.clone()).ok()?),
+        "file_path" => Annotation::FilePath(serde_json::from_value(value.clone()).ok()?),
+        _ => return None,
+    };
+    Some(annotation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIXED_OUTPUT: &str = include_str!("../../tests/fixtures/responses/mixed_output.json");
+    const EMPTY_OUTPUT: &str = include_str!("../../tests/fixtures/responses/empty_output.json");
+    const FAILED: &str = include_str!("../../tests/fixtures/responses/failed.json");
+    const INCOMPLETE: &str = include_str!("../../tests/fixtures/responses/incomplete.json");
+
+    fn response(fixture: &str) -> ResponseObject {
+        serde_json::from_str(fixture).unwrap()
+    }
+
+    #[test]
+    fn output_text_joins_text_items_and_skips_refusals() {
+        assert_eq!(
+            response(MIXED_OUTPUT).output_text(),
+            "Listing 10006546 is a loft near Ribeira. See the attached map."
+        );
+        assert_eq!(response(EMPTY_OUTPUT).output_text(), "");
+    }
+
+    #[test]
+    fn function_calls_keep_their_order_and_call_ids() {
+        let calls: Vec<(String, String, String)> = response(MIXED_OUTPUT)
+            .function_calls()
+            .into_iter()
+            .map(|call| (call.id, call.function.name, call.function.arguments))
+            .collect();
+
+        assert_eq!(calls, [
+            ("call_search".to_string(), "search_listings".to_string(), r#"{"market":"Porto","max_price":90}"#.to_string()),
+            ("call_detail".to_string(), "listing_detail".to_string(), r#"{"id":10006546}"#.to_string()),
+        ]);
+        assert!(response(EMPTY_OUTPUT).function_calls().is_empty());
+    }
+
+    #[test]
+    fn reasoning_summaries_are_read_from_reasoning_items() {
+        assert_eq!(
+            response(MIXED_OUTPUT).reasoning_summaries(),
+            ["The user wants a loft in Porto.", "Two listings fit the budget."]
+        );
+        assert!(response(EMPTY_OUTPUT).reasoning_summaries().is_empty());
+    }
+
+    #[test]
+    fn annotations_are_read_by_type_skipping_unknown_ones() {
+        let annotations = response(MIXED_OUTPUT).annotations();
+
+        assert_eq!(annotations.len(), 3);
+        assert!(matches!(&annotations[0], Annotation::UrlCitation(citation) if citation.url == "https://example.com/rooms/10006546"));
+        assert!(matches!(&annotations[1], Annotation::FileCitation(citation) if citation.file_id == "file_guide"));
+        assert!(matches!(&annotations[2], Annotation::FilePath(path) if path.file_id == "file_map"));
+        assert!(response(EMPTY_OUTPUT).annotations().is_empty());
+    }
+
+    #[test]
+    fn refusals_are_read_from_message_content() {
+        assert_eq!(response(MIXED_OUTPUT).refusals(), ["I can't share the host's phone number."]);
+        assert!(response(EMPTY_OUTPUT).refusals().is_empty());
+    }
+
+    #[test]
+    fn check_status_accepts_completed_responses() {
+        assert!(response(MIXED_OUTPUT).check_status().is_ok());
+        assert!(response(EMPTY_OUTPUT).check_status().is_ok());
+    }
+
+    #[test]
+    fn check_status_reports_failed_and_incomplete_responses() {
+        match response(FAILED).check_status() {
+            Err(OpenAIError::GenericError { code, message, .. }) => {
+                assert_eq!((code.as_str(), message.as_str()), ("server_error", "The model crashed."));
+            }
+            other => panic!("expected a generic error, got {:?}", other),
+        }
+        assert!(matches!(
+            response(INCOMPLETE).check_status(),
+            Err(OpenAIError::IncompleteResponseError(reason)) if reason == "max_output_tokens"
+        ));
+    }
+}
@@ -1,99 +1,86 @@
-use reqwest::{Client, Response};
-use log::{warn, error};
+use log::{debug, warn, error};
 use async_stream::stream;
 use futures::StreamExt;
-use crate::openai::{
-    OPENAI_EMBED_URL, RETRY_BASE_DELAY,
-};
-use crate::{DEBUG_PRE, DEBUG_POST};
+use crate::openai::RETRY_BASE_DELAY;
 use crate::openai::error::OpenAIError;
 use crate::openai::libs::{
     MainRequest, ChatRequest, EmbedRequest, 
     ErrorResponse, ChatResponse,
 };
-use crate::openai::utils::print_pre;
+use crate::openai::transport::{
+    StreamingResponse, Transport, TransportRequest, TransportResponse,
+};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 
+/// Streams are not cut off by a timeout while the model is still writing
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
 pub async fn request_chat(
     request: &MainRequest,
     api_endpoint: &str,
     api_key: &str,
     timeout: Duration,
     max_retries: u32,
+    transport: &dyn Transport,
 ) -> Result<String, OpenAIError> {
-    // Creates an HTTPS-capable client using rustls TLS implementation.
-    let client = Client::builder()
-        .use_rustls_tls()
-        .build()?;
-    
-    print_pre(&request, DEBUG_PRE);
-    
     // Serializes the request struct into a JSON byte vector
-    let request_body = serde_json::to_vec(request)?;
-
-    let mut response: Response = make_request(
-        &client,
-        api_endpoint,
-        api_key, 
-        &request_body, 
+    let transport_request = TransportRequest {
+        url: api_endpoint.to_string(),
+        api_key: api_key.to_string(),
+        body: serde_json::to_vec(request)?,
         timeout,
-    ).await?;
+    };
+    debug!("Request to {}: {}", api_endpoint, String::from_utf8_lossy(&transport_request.body));
+
+    let mut response: TransportResponse = transport.send(&transport_request).await?;
 
     for attempt in 1..=max_retries {
-        if response.status().is_success() || response.status().as_u16() == 401 {
+        if response.is_success() || response.status == 401 {
             // 401 - Invalid Authentication
             break;
         }
 
-        warn!("Server error (attempt {}/{}): {}", attempt, max_retries, response.status());
+        warn!("Server error (attempt {}/{}): {}", attempt, max_retries, response.status);
 
         sleep(RETRY_BASE_DELAY).await;
         
-        response = make_request(
-            &client,
-            api_endpoint,
-            api_key,
-            &request_body,
-            timeout,
-        ).await?;
+        response = transport.send(&transport_request).await?;
     }
 
     // Checks if the response status is not successful (i.e., not in the 200-299 range).
-    if !response.status().is_success() {
-        let openai_error: OpenAIError = manage_error(response).await;
+    if !response.is_success() {
+        let openai_error: OpenAIError = manage_error(response.status, &response.body);
         return Err(openai_error);
     }
 
-    let response_data = response.json::<serde_json::Value>().await?;
-    print_pre(&response_data, DEBUG_POST);
-    
+    let response_data = serde_json::from_slice::<serde_json::Value>(&response.body)?;
+    debug!("Response from {}: {}", api_endpoint, response_data);
+
     let response_string = response_data.to_string();
     Ok(response_string)
 }
 
 pub async fn request_embed(
     request: &EmbedRequest,
+    api_endpoint: &str,
     api_key: &str,
+    timeout: Duration,
+    transport: &dyn Transport,
 ) -> Result<String, OpenAIError> {
-    let client = Client::builder()
-        .use_rustls_tls()
-        .build()?;
-    let response: serde_json::Value;
-    
-    print_pre(&request, DEBUG_PRE);
-
-    response = client
-        .post(OPENAI_EMBED_URL)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .json(request)
-        .send()
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
-
-    print_pre(&response, DEBUG_POST);
+    let transport_request = TransportRequest {
+        url: api_endpoint.to_string(),
+        api_key: api_key.to_string(),
+        body: serde_json::to_vec(request)?,
+        timeout,
+    };
+    debug!("Request to {}: {}", api_endpoint, String::from_utf8_lossy(&transport_request.body));
+
+    // Error bodies are returned as well; `EmbedResponse.error` reports them
+    let response = transport.send(&transport_request).await?;
+    let response: serde_json::Value = serde_json::from_slice(&response.body)?;
+    debug!("Response from {}: {}", api_endpoint, response);
 
     let response_string = response.to_string();
     Ok(response_string)
@@ -103,26 +90,33 @@ pub fn strem_chat(
     api_endpoint: String,
     api_key: String,
     request: ChatRequest,
+    transport: Arc<dyn Transport>,
 ) -> impl futures::Stream<Item = ChatResponse> {
     stream! {
-        let client = Client::new();
-
-        let response: Response = match client
-            .post(&api_endpoint)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
-            .await {
-                Ok(response) => response,
-                Err(e) => {
-                    error!("Error Error sending request: {}", e);
-                    return
-                }
-            };
+        let body = match serde_json::to_vec(&request) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Error Error serializing request: {}", e);
+                return
+            }
+        };
+        let transport_request = TransportRequest {
+            url: api_endpoint,
+            api_key,
+            body,
+            timeout: STREAM_TIMEOUT,
+        };
 
-        if response.status().is_success() {
-            let mut stream = response.bytes_stream();
+        let response: StreamingResponse = match transport.send_streaming(&transport_request).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Error Error sending request: {}", e);
+                return
+            }
+        };
+
+        if (200..300).contains(&response.status) {
+            let mut stream = response.body;
 
             while let Some(chunk) = stream.next().await {
                 match chunk {
@@ -136,7 +130,8 @@ pub fn strem_chat(
 
                             if !part.is_empty() && part.starts_with("data:") {
                                 let json_part = part.trim_start_matches("data:");
-                                if !json_part.contains("delta") {
+                                // The usage chunk has no delta, only empty `choices`
+                                if !json_part.contains("delta") && !json_part.contains("\"usage\"") {
                                     continue;
                                 }
                             
@@ -145,8 +140,7 @@ pub fn strem_chat(
                                         yield stream_response;
                                     },
                                     Err(e) => {
-                                        println!("Error parsing chunk: {:?}", json_part);
-                                        warn!("Error Error parsing chunk: {}", e);
+                                        warn!("Error parsing chunk {:?}: {}", json_part, e);
                                     }
                                 }    
                             }
@@ -158,34 +152,18 @@ pub fn strem_chat(
                 }
             }
         } else {
-            error!("Error Request failed with status code: {}", response.status());
+            error!("Error Request failed with status code: {}", response.status);
         }
     }
 }
 
-pub async fn make_request(
-    client: &Client,
-    url: &str,
-    api_key: &str,
-    request_body: &[u8],
-    timeout: Duration,
-) -> Result<Response, reqwest::Error> {
-    Ok(client
-        .post(url)
-        .timeout(timeout)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .body(request_body.to_vec())
-        .send()
-        .await?)
-}
-
-pub async fn manage_error(
-    response: Response,
+pub fn manage_error(
+    status: u16,
+    body: &[u8],
 ) -> OpenAIError {
-    error!("Response code: {}", response.status());
+    error!("Response code: {}", status);
 
-    match response.json::<ErrorResponse>().await {
+    match serde_json::from_slice::<ErrorResponse>(body) {
         Ok(error_detail) => {
             match error_detail.error.code.as_str() {
                 "invalid_api_key" => OpenAIError::AuthenticationError(
@@ -227,4 +205,286 @@ pub async fn manage_error(
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::chat::ChatOpenAI;
+    use crate::openai::embed::EmbedOpenAI;
+    use crate::openai::endpoint_url;
+    use crate::openai::mock::{MockEndpoint, MockOpenAI, MockReply};
+    use crate::openai::response::ResponseOpenAI;
+    use crate::openai::tools::ToolCallAccumulator;
+    use crate::openai::transport::HttpTransport;
+    use crate::openai::usage::{TokenUsage, UsageSink};
+    use futures::pin_mut;
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps what clients report, in order
+    #[derive(Debug, Default)]
+    struct RecordedUsage(Mutex<Vec<(String, String, TokenUsage)>>);
+
+    impl UsageSink for RecordedUsage {
+        fn record(&self, model: &str, endpoint: &str, usage: TokenUsage) {
+            self.0.lock().unwrap().push((model.to_string(), endpoint.to_string(), usage));
+        }
+    }
+
+    async fn send_chat(server: &MockOpenAI, timeout: Duration, max_retries: u32) -> Result<String, OpenAIError> {
+        let request = MainRequest::Chat(ChatOpenAI::new("gpt-4o-mini").request);
+        request_chat(
+            &request,
+            &endpoint_url(&server.base_url(), "chat/completions"),
+            "sk-test",
+            timeout,
+            max_retries,
+            &HttpTransport::new(),
+        ).await
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_until_success() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::error(500, "server_error", "boom"));
+        server.enqueue(MockEndpoint::Chat, MockReply::error(503, "server_error", "busy"));
+
+        let response = send_chat(&server, Duration::from_secs(5), 3).await.unwrap();
+
+        assert!(response.contains("chatcmpl-mock"));
+        assert_eq!(server.received().len(), 3);
+        assert_eq!(server.received()[0].authorization.as_deref(), Some("Bearer sk-test"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let server = MockOpenAI::start().await;
+        for _ in 0..3 {
+            server.enqueue(MockEndpoint::Chat, MockReply::error(500, "server_error", "boom"));
+        }
+
+        let result = send_chat(&server, Duration::from_secs(5), 2).await;
+
+        assert!(matches!(result, Err(OpenAIError::InternalServerError(message)) if message == "boom"));
+        assert_eq!(server.received().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_authentication_errors() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::error(401, "invalid_api_key", "bad key"));
+
+        let result = send_chat(&server, Duration::from_secs(5), 3).await;
+
+        assert!(matches!(result, Err(OpenAIError::AuthenticationError(_))));
+        assert_eq!(server.received().len(), 1);
+    }
+
+    type ErrorCheck = fn(&OpenAIError) -> bool;
+
+    #[tokio::test]
+    async fn maps_error_codes() {
+        let server = MockOpenAI::start().await;
+        let cases: [(u16, &str, ErrorCheck); 6] = [
+            (400, "invalid_request_error", |e| matches!(e, OpenAIError::BadRequestError(_))),
+            (429, "rate_limit_error", |e| matches!(e, OpenAIError::RateLimitError(_))),
+            (429, "tokens_exceeded_error", |e| matches!(e, OpenAIError::RateLimitError(_))),
+            (403, "permission_error", |e| matches!(e, OpenAIError::PermissionDeniedError(_))),
+            (404, "not_found_error", |e| matches!(e, OpenAIError::NotFoundError(_))),
+            (418, "teapot", |e| matches!(e, OpenAIError::GenericError { code, .. } if code == "teapot")),
+        ];
+
+        for (status, code, expected) in cases {
+            server.enqueue(MockEndpoint::Chat, MockReply::error(status, code, "failed"));
+            let error = send_chat(&server, Duration::from_secs(5), 0).await.unwrap_err();
+            assert!(expected(&error), "{} mapped to {:?}", code, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn unparseable_error_bodies_are_generic_errors() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::json(json!({ "detail": "gateway" })).with_status(502));
+
+        let error = send_chat(&server, Duration::from_secs(5), 0).await.unwrap_err();
+
+        assert!(matches!(error, OpenAIError::GenericError { detail, .. } if detail == "ERROR-req-9823"));
+    }
+
+    #[tokio::test]
+    async fn slow_responses_time_out() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(
+            MockEndpoint::Chat,
+            MockReply::chat_text("late").with_delay(Duration::from_millis(500)),
+        );
+
+        let result = send_chat(&server, Duration::from_millis(50), 0).await;
+
+        assert!(matches!(result, Err(OpenAIError::RequestError(e)) if e.is_timeout()));
+    }
+
+    #[tokio::test]
+    async fn stream_yields_content_deltas() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::chat_stream(&["Two ", "lofts ", "match."]));
+
+        let stream = ChatOpenAI::new("gpt-4o-mini")
+            .with_base_url(&server.base_url())
+            .stream_response("Lofts in Porto?".to_string());
+        pin_mut!(stream);
+
+        let mut text = String::new();
+        while let Some(chunk) = stream.next().await {
+            for choice in chunk.choices.iter().flatten() {
+                if let Some(content) = choice.delta.as_ref().and_then(|delta| delta.content.clone()) {
+                    text.push_str(&content);
+                }
+            }
+        }
+
+        assert_eq!(text, "Two lofts match.");
+        assert_eq!(server.received()[0].body["stream"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn stream_reports_the_usage_of_its_last_chunk() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::chat_stream(&["Two ", "lofts."]));
+        let sink = Arc::new(RecordedUsage::default());
+
+        let stream = ChatOpenAI::new("gpt-4o-mini")
+            .with_base_url(&server.base_url())
+            .with_usage_sink(sink.clone())
+            .stream_response("Lofts in Porto?".to_string());
+        pin_mut!(stream);
+        while stream.next().await.is_some() {}
+
+        assert_eq!(server.received()[0].body["stream_options"], json!({ "include_usage": true }));
+        let recorded = sink.0.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!((recorded[0].0.as_str(), recorded[0].1.as_str()), ("gpt-4o-mini", "chat"));
+        assert_eq!((recorded[0].2.prompt_tokens, recorded[0].2.completion_tokens), (10, 2));
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct SearchArgs {
+        market: String,
+        max_price: f64,
+    }
+
+    #[tokio::test]
+    async fn stream_reassembles_tool_calls() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(
+            MockEndpoint::Chat,
+            MockReply::chat_stream_tool_call(
+                "search_listings",
+                &["{\"market\":", "\"Porto\",", "\"max_price\":120}"],
+            ),
+        );
+
+        let stream = ChatOpenAI::new("gpt-4o-mini")
+            .with_base_url(&server.base_url())
+            .stream_response("Lofts in Porto under 120".to_string());
+        pin_mut!(stream);
+
+        let mut accumulator = ToolCallAccumulator::new();
+        while let Some(chunk) = stream.next().await {
+            accumulator.push_response(&chunk);
+        }
+        let calls = accumulator.finish();
+
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "search_listings");
+        assert_eq!(
+            calls[0].parse_arguments::<SearchArgs>().unwrap(),
+            SearchArgs { market: "Porto".to_string(), max_price: 120.0 }
+        );
+    }
+
+    #[tokio::test]
+    async fn invoke_returns_tool_calls() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(
+            MockEndpoint::Chat,
+            MockReply::chat_tool_call("search_listings", json!({ "market": "Porto", "max_price": 80 })),
+        );
+
+        let response = ChatOpenAI::new("gpt-4o-mini")
+            .with_base_url(&server.base_url())
+            .invoke("Cheap lofts in Porto")
+            .await
+            .unwrap();
+        let calls = response.tool_calls();
+
+        assert_eq!(calls[0].parse_arguments::<SearchArgs>().unwrap().max_price, 80.0);
+    }
+
+    #[tokio::test]
+    async fn chat_and_responses_report_usage_to_their_sink() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Chat, MockReply::chat_text("Porto"));
+        server.enqueue(MockEndpoint::Responses, MockReply::response_text("Porto"));
+        let sink = Arc::new(RecordedUsage::default());
+
+        ChatOpenAI::new("gpt-4o-mini")
+            .with_base_url(&server.base_url())
+            .with_usage_sink(sink.clone())
+            .invoke("Which city?")
+            .await
+            .unwrap();
+        ResponseOpenAI::new("gpt-4.1-mini")
+            .with_base_url(&server.base_url())
+            .with_usage_sink(sink.clone())
+            .with_prompt("Which city?")
+            .invoke()
+            .await
+            .unwrap();
+
+        let usage = TokenUsage { prompt_tokens: 10, completion_tokens: 2, ..TokenUsage::default() };
+        assert_eq!(*sink.0.lock().unwrap(), [
+            ("gpt-4o-mini".to_string(), "chat".to_string(), usage),
+            ("gpt-4.1-mini".to_string(), "responses".to_string(), usage),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn responses_and_embeddings_reach_the_mock() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Responses, MockReply::response_text("Porto"));
+
+        let response = ResponseOpenAI::new("gpt-4o-mini")
+            .with_base_url(&server.base_url())
+            .with_prompt("Which city?")
+            .invoke()
+            .await
+            .unwrap();
+        let embedding = EmbedOpenAI::new("text-embedding-3-small")
+            .with_base_url(&server.base_url())
+            .with_dimensions(4)
+            .embed_content("Porto loft")
+            .await
+            .unwrap();
+
+        assert_eq!(response.output_text(), "Porto");
+        assert_eq!(embedding.data[0].embedding.len(), 4);
+        let endpoints: Vec<MockEndpoint> = server.received().iter().map(|r| r.endpoint).collect();
+        assert_eq!(endpoints, vec![MockEndpoint::Responses, MockEndpoint::Embeddings]);
+    }
+
+    #[tokio::test]
+    async fn embedding_errors_are_reported() {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Embeddings, MockReply::error(400, "invalid_request_error", "too long"));
+
+        let result = EmbedOpenAI::new("text-embedding-3-small")
+            .with_base_url(&server.base_url())
+            .embed_content("Porto loft")
+            .await;
+
+        assert!(matches!(result, Err(OpenAIError::ResponseContentError)));
+    }
+}
@@ -1,16 +1,23 @@
 use crate::openai::requests::request_chat;
-use crate::openai::utils::GetApiKey;
-// use crate::openai::libs::{
-//     ChatRequest, ResponseFormat,
-//     Message, Role, ChatResponse, ImageUrl,
-// };
-
+use crate::openai::utils::{strict_json_schema, GetApiKey};
 use crate::openai::libs::MainRequest;
 use crate::openai::lib_response::{
     ResponseRequest, InputContent, ResponseObject, ToolChoice,
+    ResponseFormat, FormatResponse, JsonSchema,
+    InputItemList, InputMessage, Role, JsonObject, Reasoning,
 };
-use crate::openai::OPENAI_RESPONSE_URL;
+use crate::openai::models::instruction_role;
+use crate::openai::shaping::shape_response_request;
+use crate::openai::validation::{validate_response_request, validate_images};
+use crate::openai::transport::{default_transport, Transport};
+use crate::openai::usage::{TokenUsage, UsageSink, ENDPOINT_RESPONSES};
+use crate::openai::vision::{response_content, ImageInput};
+use crate::openai::{api_base, endpoint_url};
 use crate::openai::error::OpenAIError;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use log::error;
 
@@ -21,6 +28,10 @@ pub struct ResponseOpenAI {
     pub request: ResponseRequest,
     pub timeout: Duration,
     pub max_retries: u32,
+    pub images: Vec<ImageInput>,
+    pub transport: Arc<dyn Transport>,
+    pub base_url: String,
+    pub usage_sink: Option<Arc<dyn UsageSink>>,
 }
 
 #[allow(dead_code)]
@@ -53,24 +64,77 @@ impl ResponseOpenAI {
         };
         
         Self {
-            api_key: api_key,
-            request: request,
+            api_key,
+            request,
             timeout: Duration::from_secs(300), // default: 5 minutes
             max_retries: 3,         // default: 3 times
+            images: vec![],
+            transport: default_transport(),
+            base_url: api_base(),
+            usage_sink: None,
+        }
+    }
+
+    /// Checks the configured parameters against the API ranges and the model
+    ///
+    /// Called by `invoke` before anything is sent; call it directly to fail
+    /// at build time.
+    ///
+    /// # Errors
+    /// * `OpenAIError::ValidationError` - Lists every invalid parameter
+    pub fn validate(&self) -> Result<(), OpenAIError> {
+        validate_response_request(&self.request)?;
+        validate_images(&self.request.model, self.images.len())
+    }
+
+    /// Moves attached images into the input, next to the text of the user
+    /// message that ends it; when the input does not end with a user
+    /// message, the images get a user message of their own
+    fn attach_images(&mut self) {
+        if self.images.is_empty() {
+            return;
+        }
+
+        let images = std::mem::take(&mut self.images);
+        let items = self.input_items();
+        if let Some(InputItemList::InputMessage(message)) = items.last_mut() {
+            if matches!(message.role, Role::User) {
+                let content = std::mem::replace(&mut message.content, InputContent::Null(vec![]));
+                message.content = InputContent::ItemList(match content {
+                    InputContent::String(text) => response_content(&text, &images),
+                    InputContent::ItemList(mut parts) => {
+                        parts.extend(images.iter().map(ImageInput::to_response_input));
+                        parts
+                    }
+                    InputContent::Null(_) => response_content("", &images),
+                });
+                return;
+            }
         }
+
+        items.push(InputItemList::InputMessage(InputMessage {
+            content: InputContent::ItemList(response_content("", &images)),
+            role: Role::User,
+            status: None,
+            type_: Some("message".to_string()),
+        }));
     }
 
     pub async fn invoke(
-        self,
+        mut self,
     ) -> Result<ResponseObject, OpenAIError> {
+        self.validate()?;
+        self.attach_images();
+        shape_response_request(&mut self.request);
         let body_request = MainRequest::Responses(self.request.clone());
 
         let response: String = match request_chat(
             &body_request,
-            OPENAI_RESPONSE_URL,
+            &endpoint_url(&self.base_url, "responses"),
             &self.api_key,
             self.timeout,
             self.max_retries,
+            self.transport.as_ref(),
         ).await {
             Ok(response) => response,
             Err(openai_error) => {
@@ -86,26 +150,73 @@ impl ResponseOpenAI {
             }
         };
 
+        if let (Some(sink), Some(usage)) = (&self.usage_sink, &chat_response.usage) {
+            sink.record(&self.request.model, ENDPOINT_RESPONSES, TokenUsage::from(usage));
+        }
         Ok(chat_response)
     }
 
+    /// Sends the request with a strict JSON schema generated from `T` as the
+    /// text format and deserializes the model's answer into `T`
+    ///
+    /// # Errors
+    /// * `OpenAIError::RefusalError` - The model refused to answer
+    /// * `OpenAIError::IncompleteResponseError` - The output was cut short
+    /// * `OpenAIError::ResponseContentError` - The response has no text output
+    /// * `OpenAIError::JsonError` - The answer does not match `T`
+    pub async fn invoke_structured<T: schemars::JsonSchema + DeserializeOwned>(
+        mut self,
+    ) -> Result<T, OpenAIError> {
+        let json_schema = strict_json_schema::<T>()?;
+        self = self.with_json_schema(json_schema);
+
+        let response = self.invoke().await?;
+        if let Err(e) = response.check_status() {
+            error!("Structured output failed: {}. ERROR-req-0025", e);
+            return Err(e);
+        }
+        if let Some(refusal) = response.refusals().into_iter().next() {
+            return Err(OpenAIError::RefusalError(refusal));
+        }
+
+        let text = response.output_text();
+        if text.is_empty() {
+            return Err(OpenAIError::ResponseContentError);
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Sets the text prompt, or appends it as a user message after the
+    /// items already in the input
     pub fn with_prompt(mut self, prompt: &str) -> Self {
-        self.request.input = InputContent::String(prompt.to_string());
+        match &mut self.request.input {
+            InputContent::ItemList(items) => items.push(text_message(Role::User, prompt)),
+            input => *input = InputContent::String(prompt.to_string()),
+        }
         self
     }
 
-    pub fn with_temperature(mut self, temperature: f32) -> Self {
-        if temperature < 0.0 || temperature > 2.0 {
-            println!(
-                "[ERROR] Temperature must be between 0.0 and 2.0. Actual temperature is {}", 
-                self.request.temperature.unwrap_or(0.0)
-            );
-            self
-        } else {
-            self.request.temperature = Some(temperature);
-            self
+    /// Input items as a list, turning a text prompt into a user message
+    fn input_items(&mut self) -> &mut Vec<InputItemList> {
+        let input = std::mem::replace(&mut self.request.input, InputContent::Null(vec![]));
+        self.request.input = match input {
+            InputContent::String(prompt) => {
+                InputContent::ItemList(vec![text_message(Role::User, &prompt)])
+            }
+            InputContent::ItemList(items) => InputContent::ItemList(items),
+            InputContent::Null(_) => InputContent::ItemList(vec![]),
+        };
+
+        match &mut self.request.input {
+            InputContent::ItemList(items) => items,
+            _ => unreachable!("input was just converted to an item list"),
         }
     }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.request.temperature = Some(temperature);
+        self
+    }
     
     pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
         self.request.max_output_tokens = Some(max_tokens);
@@ -127,147 +238,274 @@ impl ResponseOpenAI {
         self
     }
 
-    // pub fn with_tools(mut self, tools_data: Vec<serde_json::Value>) -> Self {
-    //     self.request.tools = Some(tools_data);
-    //     self
-    // }
-
-    // pub fn with_tool_choice(mut self, tool_choice: serde_json::Value) -> Self {
-    //     self.request.tool_choice = Some(tool_choice);
-    //     self
-    // }
-
-    // pub fn with_image_url(mut self, image_url: &str) -> Self {
-    //     self.request.input = InputContent::ImageUrl(InputItemList {
-    //         image_url: Some(image_url.to_string()),
-    //         text: None,
-    //     });
-
-    //     self
-    // }
-
-    // pub fn with_top_p(mut self, top_p: f32) -> Self {
-    //     if top_p < 0.0 || top_p > 1.0 {
-    //         println!(
-    //             "[ERROR] Top p must be between 0.0 and 1.0. Actual top p is {}",
-    //             self.request.top_p.unwrap_or(0.0)
-    //         );
-    //         self
-    //     } else {
-    //         self.request.top_p = Some(top_p);
-    //         self
-    //     }
-    // }
-
-    // pub fn with_n_completion(mut self, n_completion: u32) -> Self {
-    //     self.request.n_completion = Some(n_completion);
-    //     self
-    // }
-
-    // pub fn with_stop(mut self, stop: Vec<String>) -> Self {
-    //     self.request.stop = Some(stop);
-    //     self
-    // }
-
-    // pub fn with_system_prompt(mut self, system_prompt: &str) -> Self {
-    //     let content = vec![InputContent {
-    //         content_type: "text".to_string(),
-    //         text: Some(system_prompt.to_string()),
-    //         source: None,
-    //         image_url: None,
-    //     }];
-
-    //     let new_message = Message {
-    //         role: match self.request.model.as_str() {
-    //             "o1-mini" => Role::User,
-    //             _ => Role::Developer,
-    //         },
-    //         content: content.clone(),
-    //         recipient: None,
-    //         end_turn: None,
-    //     };
-
-    //     if let Some(messages) = &mut self.request.messages {
-    //         messages.insert(0, new_message);
-    //     } else {
-    //         self.request.messages = Some(vec![new_message]);
-    //     }
-
-    //     self
-    // }
-
-    // pub fn with_assistant_response(mut self,  assistant_response: &str) -> Self {
-    //     let content = vec![InputContent {
-    //         content_type: "text".to_string(),
-    //         text: Some(assistant_response.to_string()),
-    //         source: None,
-    //         image_url: None,
-    //     }];
-
-    //     let new_message = Message {
-    //         role: Role::Assistant,
-    //         content: content.clone(),
-    //         recipient: None,
-    //         end_turn: None,
-    //     };
-
-    //     if let Some(messages) = &mut self.request.messages {
-    //         messages.push(new_message);
-    //     } else {
-    //         self.request.messages = Some(vec![new_message]);
-    //     }
-
-    //     self
-    // }
-
-    // pub fn with_chat_history(mut self, history: Vec<Message>) -> Self {
-    //     self.request.messages = Some(history);
-    //     self
-    // }
-
-    // pub fn with_json_schema(mut self, json_schema: serde_json::Value) -> Self {
-    //     let response_format = ResponseFormat {
-    //         response_type: "json_schema".to_string(),
-    //         json_schema: Some(json_schema),
-    //     };
-
-    //     self.request.response_format = Some(response_format);
-    //     self
-    // }
-
-    // pub fn with_max_retries(mut self, max_retries: u32) -> Self {
-    //     self.max_retries = max_retries;
-    //     self
-    // }
-
-    // pub fn with_api_key(mut self, api_key: &str) -> Self {
-    //     self.api_key = api_key.to_string();
-    //     self
-    // }
-
-    // pub fn with_json_format(mut self) -> Self {
-    //     let text_format = TextFormat {
-    //         format: json!({
-    //             "type": "json_object"
-    //         })
-    //     };
-    //     self.request.text = Some(text_format);
-    //     self
-    // }
-
-    // pub fn with_store(mut self, store: bool) -> Self {
-    //     self.request.store = Some(store);
-    //     self
-    // }
-
-    // pub fn with_reasoning(mut self, effort: &str) -> Self {
-    //     let reasoning = Reasoning {
-    //         effort: effort.to_string(),
-    //     };
-
-    //     self.request.reasoning = Some(reasoning);
-    //     self
-    // }
+    pub fn with_parallel_tool_calls(mut self, parallel_tool_calls: bool) -> Self {
+        self.request.parallel_tool_calls = Some(parallel_tool_calls);
+        self
+    }
+
+    pub fn with_image_url(self, image_url: &str) -> Self {
+        self.with_images(vec![ImageInput::url(image_url)])
+    }
+
+    /// Attaches images to the prompt, so text and images are sent in a
+    /// single user message
+    pub fn with_images(mut self, images: Vec<ImageInput>) -> Self {
+        self.images.extend(images);
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.request.top_p = Some(top_p);
+        self
+    }
+
+    /// Sets the `instructions` field, which replaces the instructions of
+    /// `previous_response_id` instead of being carried over
+    pub fn with_instructions(mut self, instructions: &str) -> Self {
+        self.request.instructions = Some(instructions.to_string());
+        self
+    }
+
+    /// Inserts a developer message before every other input item
+    pub fn with_system_prompt(mut self, system_prompt: &str) -> Self {
+        let role = instruction_role(&self.request.model).into();
+        self.input_items().insert(0, text_message(role, system_prompt));
+        self
+    }
+
+    /// Appends a user message after the current input
+    pub fn with_user_message(mut self, user_message: &str) -> Self {
+        self.input_items().push(text_message(Role::User, user_message));
+        self
+    }
+
+    pub fn with_assistant_response(mut self, assistant_response: &str) -> Self {
+        self.input_items().push(text_message(Role::Assistant, assistant_response));
+        self
+    }
+
+    /// Replaces the input with a multi-turn conversation
+    pub fn with_chat_history(mut self, history: Vec<InputItemList>) -> Self {
+        self.request.input = InputContent::ItemList(history);
+        self
+    }
+
+    /// Appends input items such as messages, tool outputs or item references
+    pub fn with_input_items(mut self, items: Vec<InputItemList>) -> Self {
+        self.input_items().extend(items);
+        self
+    }
+
+    /// Continues the conversation stored with a previous response
+    pub fn with_previous_response_id(mut self, previous_response_id: &str) -> Self {
+        self.request.previous_response_id = Some(previous_response_id.to_string());
+        self
+    }
+
+    /// Sets a `json_schema` text format from the same `{name, schema, strict}`
+    /// object accepted by `ChatOpenAI::with_json_schema`
+    pub fn with_json_schema(mut self, json_schema: serde_json::Value) -> Self {
+        let format = JsonSchema {
+            name: json_schema["name"].as_str().unwrap_or("response").to_string(),
+            schema: json_schema["schema"].clone(),
+            type_: "json_schema".to_string(),
+            description: json_schema["description"].as_str().map(str::to_string),
+            strict: json_schema["strict"].as_bool(),
+        };
+
+        self.request.text = Some(ResponseFormat {
+            format: Some(FormatResponse::JsonSchema(format)),
+        });
+        self
+    }
+
+    /// Enables the older JSON mode; prefer `with_json_schema` when the model
+    /// supports it
+    pub fn with_json_format(mut self) -> Self {
+        self.request.text = Some(ResponseFormat {
+            format: Some(FormatResponse::JsonObject(JsonObject {
+                type_: "json_object".to_string(),
+            })),
+        });
+        self
+    }
+
+    /// Reasoning effort, one of `low`, `medium` or `high`. O-series models only.
+    pub fn with_reasoning(mut self, effort: &str) -> Self {
+        let reasoning = self.request.reasoning.get_or_insert(Reasoning {
+            effort: None,
+            generate_summary: None,
+        });
+        reasoning.effort = Some(effort.to_string());
+        self
+    }
+
+    /// Asks for a summary of the reasoning, one of `concise` or `detailed`
+    pub fn with_reasoning_summary(mut self, summary: &str) -> Self {
+        let reasoning = self.request.reasoning.get_or_insert(Reasoning {
+            effort: None,
+            generate_summary: None,
+        });
+        reasoning.generate_summary = Some(summary.to_string());
+        self
+    }
+
+    /// Additional output data to include, see `ResponseRequest::include`
+    pub fn with_include(mut self, include: Vec<String>) -> Self {
+        self.request.include = Some(include);
+        self
+    }
+
+    /// Attaches up to 16 key-value pairs to the response; keys are limited to
+    /// 64 characters and values to 512
+    pub fn with_metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.request.metadata = Some(json!(metadata));
+        self
+    }
+
+    /// Truncation strategy, `auto` or `disabled`
+    pub fn with_truncation(mut self, truncation: &str) -> Self {
+        self.request.truncation = Some(truncation.to_string());
+        self
+    }
+
+    pub fn with_store(mut self, store: bool) -> Self {
+        self.request.store = Some(store);
+        self
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.request.user = Some(user.to_string());
+        self
+    }
+
+    /// Sends requests through `transport`, for example a `ReplayTransport`
+    /// serving recorded fixtures
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// API root requests are sent to, `https://api.openai.com/v1` by default
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = api_key.to_string();
+        self
+    }
+
+    /// Reports the usage of every completed `invoke` to `sink`
+    pub fn with_usage_sink(mut self, sink: Arc<dyn UsageSink>) -> Self {
+        self.usage_sink = Some(sink);
+        self
+    }
 }
 
-impl GetApiKey for ResponseOpenAI {}
\ No newline at end of file
+/// A `message` input item holding plain text
+pub fn text_message(role: Role, text: &str) -> InputItemList {
+    InputItemList::InputMessage(InputMessage {
+        content: InputContent::String(text.to_string()),
+        role,
+        status: None,
+        type_: Some("message".to_string()),
+    })
+}
+
+impl GetApiKey for ResponseOpenAI {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::mock::{MockEndpoint, MockOpenAI, MockReply};
+
+    async fn sent_input(client: ResponseOpenAI) -> serde_json::Value {
+        let server = MockOpenAI::start().await;
+        server.enqueue(MockEndpoint::Responses, MockReply::response_text("A loft"));
+        client.with_base_url(&server.base_url()).invoke().await.unwrap();
+        server.received()[0].body["input"].clone()
+    }
+
+    fn image(url: &str) -> serde_json::Value {
+        json!({ "type": "input_image", "image_url": url, "detail": "auto" })
+    }
+
+    #[tokio::test]
+    async fn prompt_alone_is_sent_as_text() {
+        let input = sent_input(ResponseOpenAI::new("gpt-4o-mini").with_prompt("Lofts in Porto?")).await;
+
+        assert_eq!(input, json!("Lofts in Porto?"));
+    }
+
+    #[tokio::test]
+    async fn prompt_follows_the_items_added_before_it() {
+        let client = ResponseOpenAI::new("gpt-4o-mini")
+            .with_system_prompt("Be brief.")
+            .with_input_items(vec![
+                text_message(Role::User, "Lofts in Porto?"),
+                text_message(Role::Assistant, "Two lofts match."),
+            ])
+            .with_prompt("Which is cheaper?");
+
+        let input = sent_input(client).await;
+
+        let turns: Vec<(&str, &str)> = input
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| (item["role"].as_str().unwrap(), item["content"].as_str().unwrap()))
+            .collect();
+        assert_eq!(turns, [
+            ("developer", "Be brief."),
+            ("user", "Lofts in Porto?"),
+            ("assistant", "Two lofts match."),
+            ("user", "Which is cheaper?"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn prompt_replaces_an_earlier_text_prompt() {
+        let client = ResponseOpenAI::new("gpt-4o-mini").with_prompt("Lofts?").with_prompt("Lofts in Porto?");
+
+        assert_eq!(sent_input(client).await, json!("Lofts in Porto?"));
+    }
+
+    #[tokio::test]
+    async fn images_join_the_last_user_message_of_an_item_list() {
+        let client = ResponseOpenAI::new("gpt-4o-mini")
+            .with_input_items(vec![text_message(Role::User, "Compare these")])
+            .with_image_url("https://example.com/1.jpg")
+            .with_image_url("https://example.com/2.jpg");
+
+        let input = sent_input(client).await;
+
+        assert_eq!(input.as_array().unwrap().len(), 1);
+        assert_eq!(input[0]["role"], "user");
+        assert_eq!(input[0]["content"], json!([
+            { "type": "input_text", "text": "Compare these" },
+            image("https://example.com/1.jpg"),
+            image("https://example.com/2.jpg"),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn images_after_an_assistant_turn_get_their_own_user_message() {
+        let client = ResponseOpenAI::new("gpt-4o-mini")
+            .with_user_message("Show me a loft")
+            .with_assistant_response("Here is one.")
+            .with_image_url("https://example.com/1.jpg");
+
+        let input = sent_input(client).await;
+
+        assert_eq!(input.as_array().unwrap().len(), 3);
+        assert_eq!(input[2]["role"], "user");
+        assert_eq!(input[2]["content"], json!([image("https://example.com/1.jpg")]));
+    }
+}
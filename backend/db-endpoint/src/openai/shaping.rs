@@ -0,0 +1,168 @@
+use crate::openai::libs::{self, ChatRequest};
+use crate::openai::lib_response::{self, InputContent, InputItemList, ResponseRequest};
+use crate::openai::models::{instruction_role, InstructionRole};
+use crate::openai::response::text_message;
+use log::debug;
+
+impl From<InstructionRole> for libs::Role {
+    fn from(role: InstructionRole) -> Self {
+        match role {
+            InstructionRole::System => Self::System,
+            InstructionRole::Developer => Self::Developer,
+            InstructionRole::User => Self::User,
+        }
+    }
+}
+
+impl From<InstructionRole> for lib_response::Role {
+    fn from(role: InstructionRole) -> Self {
+        match role {
+            InstructionRole::System => Self::System,
+            InstructionRole::Developer => Self::Developer,
+            InstructionRole::User => Self::User,
+        }
+    }
+}
+
+/// Rewrites a Chat Completions request into the shape its model expects
+///
+/// `system` and `developer` messages are sent with the model's instruction
+/// role. Parameters the model cannot honour are left in place for
+/// `validate_chat_request` to reject, since dropping them would silently
+/// change the answer.
+pub fn shape_chat_request(request: &mut ChatRequest) {
+    let role = instruction_role(&request.model);
+
+    for message in request.messages.iter_mut().flatten() {
+        if matches!(message.role, libs::Role::System | libs::Role::Developer) {
+            message.role = role.into();
+        }
+    }
+}
+
+/// Rewrites a Responses API request into the shape its model expects
+///
+/// `system` and `developer` input messages are sent with the model's
+/// instruction role. For models that take instructions as a user message,
+/// `instructions` is moved into a leading user message.
+pub fn shape_response_request(request: &mut ResponseRequest) {
+    let role = instruction_role(&request.model);
+
+    if role == InstructionRole::User {
+        if let Some(instructions) = request.instructions.take() {
+            debug!("Moving instructions into a user message for {}", request.model);
+            let input = std::mem::replace(&mut request.input, InputContent::Null(vec![]));
+            let mut items = vec![text_message(lib_response::Role::User, &instructions)];
+            match input {
+                InputContent::String(prompt) => {
+                    items.push(text_message(lib_response::Role::User, &prompt));
+                }
+                InputContent::ItemList(list) => items.extend(list),
+                InputContent::Null(_) => {}
+            }
+            request.input = InputContent::ItemList(items);
+        }
+    }
+
+    if let InputContent::ItemList(items) = &mut request.input {
+        for item in items.iter_mut() {
+            if let InputItemList::InputMessage(message) = item {
+                if matches!(
+                    message.role,
+                    lib_response::Role::System | lib_response::Role::Developer
+                ) {
+                    message.role = role.into();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::chat::ChatOpenAI;
+    use crate::openai::libs::{InputContent as ChatContent, Message};
+    use crate::openai::response::ResponseOpenAI;
+
+    fn chat_message(role: libs::Role, text: &str) -> Message {
+        Message {
+            role,
+            content: vec![ChatContent {
+                content_type: "text".to_string(),
+                text: Some(text.to_string()),
+                source: None,
+                image_url: None,
+            }],
+            recipient: None,
+            end_turn: None,
+        }
+    }
+
+    fn chat_roles(model: &str) -> Vec<serde_json::Value> {
+        let mut request = ChatOpenAI::new(model)
+            .with_chat_history(vec![
+                chat_message(libs::Role::System, "Be brief."),
+                chat_message(libs::Role::Developer, "Answer in English."),
+                chat_message(libs::Role::User, "Lofts in Porto?"),
+            ])
+            .request;
+        shape_chat_request(&mut request);
+        request.messages.unwrap().iter().map(|message| serde_json::json!(message.role)).collect()
+    }
+
+    #[test]
+    fn chat_instructions_take_the_model_role() {
+        assert_eq!(chat_roles("gpt-4o-mini"), ["developer", "developer", "user"]);
+        assert_eq!(chat_roles("gpt-4-turbo"), ["system", "system", "user"]);
+        assert_eq!(chat_roles("o1-mini"), ["user", "user", "user"]);
+    }
+
+    #[test]
+    fn responses_keep_instructions_for_models_that_accept_them() {
+        let mut request = ResponseOpenAI::new("gpt-4o-mini")
+            .with_instructions("Be brief.")
+            .with_prompt("Lofts in Porto?")
+            .request;
+
+        shape_response_request(&mut request);
+
+        assert_eq!(request.instructions.as_deref(), Some("Be brief."));
+        assert_eq!(serde_json::json!(request.input), "Lofts in Porto?");
+    }
+
+    #[test]
+    fn responses_move_instructions_into_a_leading_user_message() {
+        let mut request = ResponseOpenAI::new("o1-mini")
+            .with_instructions("Be brief.")
+            .with_prompt("Lofts in Porto?")
+            .request;
+
+        shape_response_request(&mut request);
+
+        assert_eq!(request.instructions, None);
+        let input = serde_json::json!(request.input);
+        let turns: Vec<(&str, &str)> = input
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| (item["role"].as_str().unwrap(), item["content"].as_str().unwrap()))
+            .collect();
+        assert_eq!(turns, [("user", "Be brief."), ("user", "Lofts in Porto?")]);
+    }
+
+    #[test]
+    fn responses_instruction_messages_take_the_model_role() {
+        let mut request = ResponseOpenAI::new("gpt-4-turbo")
+            .with_input_items(vec![
+                text_message(lib_response::Role::Developer, "Be brief."),
+                text_message(lib_response::Role::User, "Lofts in Porto?"),
+            ])
+            .request;
+
+        shape_response_request(&mut request);
+
+        let input = serde_json::json!(request.input);
+        assert_eq!((input[0]["role"].as_str(), input[1]["role"].as_str()), (Some("system"), Some("user")));
+    }
+}
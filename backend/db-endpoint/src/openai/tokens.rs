@@ -0,0 +1,306 @@
+use crate::openai::libs::{ImageDetail, Message, Role};
+use crate::openai::models::{capabilities, context_window};
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton, CoreBPE};
+use log::warn;
+
+/// Tokens added by the chat format around every message
+pub const TOKENS_PER_MESSAGE: usize = 3;
+
+/// Tokens the assistant reply is primed with
+pub const TOKENS_PER_REPLY: usize = 3;
+
+/// Rough cost of one image input, used for budgeting only
+pub const TOKENS_PER_IMAGE: usize = 765;
+
+/// Fixed cost of one image sent with `detail: low`
+pub const TOKENS_PER_LOW_DETAIL_IMAGE: usize = 85;
+
+/// Output tokens reserved when the request does not set a limit
+pub const DEFAULT_RESERVED_OUTPUT: u32 = 4_096;
+
+/// Returns the tokenizer for a model, defaulting to `o200k_base` for unknown
+/// models and `cl100k_base` for the embedding models
+fn bpe_for(model: &str) -> &'static CoreBPE {
+    match get_tokenizer(model) {
+        Some(Tokenizer::Cl100kBase) => cl100k_base_singleton(),
+        Some(Tokenizer::O200kBase) => o200k_base_singleton(),
+        _ if model.starts_with("text-embedding") => cl100k_base_singleton(),
+        _ => o200k_base_singleton(),
+    }
+}
+
+/// Counts the tokens of a text with the model's tokenizer
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    bpe_for(model).encode_with_special_tokens(text).len()
+}
+
+/// Estimates the prompt tokens of a single chat message
+pub fn count_message(model: &str, message: &Message) -> usize {
+    let content: usize = message.content
+        .iter()
+        .map(|item| match (&item.text, &item.image_url) {
+            (Some(text), _) => count_tokens(model, text),
+            (None, Some(image)) if image.detail == Some(ImageDetail::Low) => {
+                TOKENS_PER_LOW_DETAIL_IMAGE
+            }
+            (None, Some(_)) => TOKENS_PER_IMAGE,
+            (None, None) => 0,
+        })
+        .sum();
+
+    TOKENS_PER_MESSAGE + content
+}
+
+/// Estimates the prompt tokens of a chat request's messages
+pub fn count_messages(model: &str, messages: &[Message]) -> usize {
+    let total: usize = messages
+        .iter()
+        .map(|message| count_message(model, message))
+        .sum();
+
+    total + TOKENS_PER_REPLY
+}
+
+/// Truncates a text to at most `max_tokens` tokens of the model's tokenizer
+pub fn truncate_to_tokens(model: &str, text: &str, max_tokens: usize) -> String {
+    let bpe = bpe_for(model);
+    let tokens = bpe.encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return text.to_string();
+    }
+
+    match bpe.decode(tokens[..max_tokens].to_vec()) {
+        Ok(truncated) => truncated,
+        Err(_) => {
+            // The cut landed inside a multi-byte character; back off until it decodes
+            (1..max_tokens)
+                .rev()
+                .find_map(|end| bpe.decode(tokens[..end].to_vec()).ok())
+                .unwrap_or_default()
+        }
+    }
+}
+
+/// Prompt token budget of a request against a model's context window
+///
+/// # Fields
+/// * `context_window` - Total tokens the model accepts
+/// * `reserved_output` - Tokens kept free for the completion
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBudget {
+    pub context_window: u32,
+    pub reserved_output: u32,
+}
+
+impl TokenBudget {
+    /// Budget for a model, reserving `max_output` tokens (or the model's
+    /// maximum output, capped at `DEFAULT_RESERVED_OUTPUT`) for the reply
+    pub fn for_model(model: &str, max_output: Option<u32>) -> Self {
+        let reserved_output = max_output.unwrap_or_else(|| {
+            capabilities(model)
+                .map(|caps| caps.max_output_tokens.min(DEFAULT_RESERVED_OUTPUT))
+                .unwrap_or(DEFAULT_RESERVED_OUTPUT)
+        });
+
+        Self {
+            context_window: context_window(model),
+            reserved_output,
+        }
+    }
+
+    /// Tokens available for the prompt
+    pub fn prompt_tokens(&self) -> usize {
+        self.context_window.saturating_sub(self.reserved_output) as usize
+    }
+
+    /// Returns a budget with `tokens` fewer prompt tokens available, e.g. for
+    /// the instructions of a prompt whose remaining room is filled with
+    /// `fit_items`
+    pub fn reserve(mut self, tokens: usize) -> Self {
+        let tokens = u32::try_from(tokens).unwrap_or(u32::MAX);
+        self.reserved_output = self.reserved_output.saturating_add(tokens);
+        self
+    }
+}
+
+/// Drops the oldest conversation turns until the messages fit in `budget`
+///
+/// Leading `developer`/`system`/`platform` messages and the last message are always
+/// kept; everything between them is removed oldest first. A message is dropped
+/// together with the `tool` results following it, since the API rejects tool
+/// results whose call is missing.
+pub fn trim_history(model: &str, messages: Vec<Message>, budget: &TokenBudget) -> Vec<Message> {
+    let limit = budget.prompt_tokens();
+    let mut total = count_messages(model, &messages);
+    if total <= limit {
+        return messages;
+    }
+
+    let pinned = messages
+        .iter()
+        .take_while(|message| matches!(message.role, Role::Developer | Role::System | Role::Platform))
+        .count();
+
+    let mut groups: Vec<Vec<Message>> = Vec::new();
+    for (index, message) in messages.into_iter().enumerate() {
+        match groups.last_mut() {
+            Some(group) if index > pinned && matches!(message.role, Role::Tool) => group.push(message),
+            _ => groups.push(vec![message]),
+        }
+    }
+    let last = groups.len().saturating_sub(1);
+
+    let mut dropped = 0;
+    let mut kept = Vec::with_capacity(groups.len());
+    for (index, group) in groups.into_iter().enumerate() {
+        if total > limit && index >= pinned && index < last {
+            total -= group.iter().map(|message| count_message(model, message)).sum::<usize>();
+            dropped += group.len();
+            continue;
+        }
+        kept.extend(group);
+    }
+
+    if total > limit {
+        warn!("Chat history still needs {} tokens after trimming, limit is {}", total, limit);
+    } else {
+        warn!("Dropped {} messages from chat history to fit {} tokens", dropped, limit);
+    }
+
+    kept
+}
+
+/// Keeps the leading items whose rendered text fits in `max_tokens`
+///
+/// Used to cut retrieved listings down to what fits in a prompt; items are
+/// assumed to be ranked best first.
+pub fn fit_items<T, F>(model: &str, items: Vec<T>, max_tokens: usize, render: F) -> Vec<T>
+where
+    F: Fn(&T) -> String,
+{
+    let mut total = 0;
+    items
+        .into_iter()
+        .take_while(|item| {
+            total += count_tokens(model, &render(item));
+            total <= max_tokens
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::libs::InputContent;
+
+    const MODEL: &str = "gpt-4o-mini";
+
+    fn message(role: Role, text: &str) -> Message {
+        Message {
+            role,
+            content: vec![InputContent {
+                content_type: "text".to_string(),
+                text: Some(text.to_string()),
+                source: None,
+                image_url: None,
+            }],
+            recipient: None,
+            end_turn: None,
+        }
+    }
+
+    fn texts(messages: &[Message]) -> Vec<&str> {
+        messages.iter().filter_map(|message| message.content[0].text.as_deref()).collect()
+    }
+
+    /// A budget with room for exactly `messages`
+    fn budget_for(messages: &[Message]) -> TokenBudget {
+        TokenBudget { context_window: count_messages(MODEL, messages) as u32, reserved_output: 0 }
+    }
+
+    #[test]
+    fn trim_history_keeps_fitting_messages() {
+        let messages = vec![message(Role::System, "Be brief."), message(Role::User, "Lofts in Porto?")];
+
+        let trimmed = trim_history(MODEL, messages.clone(), &budget_for(&messages));
+
+        assert_eq!(texts(&trimmed), texts(&messages));
+    }
+
+    #[test]
+    fn trim_history_drops_oldest_turns_and_keeps_instructions() {
+        let messages = vec![
+            message(Role::System, "Be brief."),
+            message(Role::User, "Lofts in Porto?"),
+            message(Role::Assistant, "Two lofts match."),
+            message(Role::User, "And in Lisbon?"),
+        ];
+        let budget = budget_for(&[messages[0].clone(), messages[2].clone(), messages[3].clone()]);
+
+        let trimmed = trim_history(MODEL, messages, &budget);
+
+        assert_eq!(texts(&trimmed), ["Be brief.", "Two lofts match.", "And in Lisbon?"]);
+    }
+
+    #[test]
+    fn trim_history_drops_tool_results_with_their_call() {
+        let messages = vec![
+            message(Role::System, "Be brief."),
+            message(Role::Assistant, "search_listings(Porto)"),
+            message(Role::Tool, "[1001, 1002]"),
+            message(Role::Tool, "[1003]"),
+            message(Role::User, "Which is cheapest?"),
+        ];
+        // Room for all but the tool call itself: its results must go too
+        let budget = budget_for(&[messages[0].clone(), messages[2].clone(), messages[3].clone(), messages[4].clone()]);
+
+        let trimmed = trim_history(MODEL, messages, &budget);
+
+        assert_eq!(texts(&trimmed), ["Be brief.", "Which is cheapest?"]);
+    }
+
+    #[test]
+    fn trim_history_keeps_the_call_of_trailing_tool_results() {
+        let messages = vec![
+            message(Role::User, "Lofts in Porto?"),
+            message(Role::Assistant, "search_listings(Porto)"),
+            message(Role::Tool, "[1001]"),
+        ];
+        let budget = budget_for(&messages[1..]);
+
+        let trimmed = trim_history(MODEL, messages, &budget);
+
+        assert_eq!(texts(&trimmed), ["search_listings(Porto)", "[1001]"]);
+    }
+
+    #[test]
+    fn truncate_to_tokens_cuts_long_texts_only() {
+        let text = "Bright loft by the river with a view of the bridge";
+
+        assert_eq!(truncate_to_tokens(MODEL, text, 100), text);
+        let truncated = truncate_to_tokens(MODEL, text, 3);
+        assert_eq!(count_tokens(MODEL, &truncated), 3);
+        assert!(text.starts_with(&truncated));
+    }
+
+    #[test]
+    fn reserve_takes_tokens_from_the_prompt() {
+        let budget = TokenBudget { context_window: 1_000, reserved_output: 100 };
+
+        assert_eq!(budget.reserve(250).prompt_tokens(), 650);
+        assert_eq!(budget.reserve(5_000).prompt_tokens(), 0);
+        assert_eq!(budget.reserve(usize::MAX).reserved_output, u32::MAX);
+    }
+
+    #[test]
+    fn fit_items_keeps_the_leading_items_that_fit() {
+        let items = vec!["Loft near the beach", "Quiet studio", "Tiny room"];
+        let first_two = count_tokens(MODEL, items[0]) + count_tokens(MODEL, items[1]);
+
+        assert_eq!(fit_items(MODEL, items.clone(), first_two, |item| item.to_string()), ["Loft near the beach", "Quiet studio"]);
+        assert_eq!(fit_items(MODEL, items.clone(), first_two - 1, |item| item.to_string()), ["Loft near the beach"]);
+        assert!(fit_items(MODEL, items, 0, |item| item.to_string()).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::openai::error::OpenAIError;
+use crate::openai::libs::{
+    ChatResponse, Delta, FunctionCall, ToolCall, ToolCallDelta,
+};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+
+/// Longest argument excerpt quoted in parsing errors
+const ARGUMENTS_EXCERPT_LEN: usize = 200;
+
+#[allow(dead_code)]
+impl ToolCall {
+    /// Deserializes the arguments generated by the model into `T`
+    ///
+    /// # Errors
+    /// * `OpenAIError::ToolArgumentsError` - The arguments are not valid JSON
+    ///   or do not match `T`; the message names the failing field and
+    ///   position and quotes the start of the arguments.
+    ///
+    /// # Examples
+    /// ```
+    /// #[derive(Deserialize)]
+    /// struct SearchArgs { query: String, max_price: Option<f64> }
+    ///
+    /// let args: SearchArgs = tool_call.parse_arguments()?;
+    /// ```
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> Result<T, OpenAIError> {
+        let arguments = if self.function.arguments.trim().is_empty() {
+            "{}"
+        } else {
+            self.function.arguments.as_str()
+        };
+
+        serde_json::from_str(arguments).map_err(|e| {
+            let excerpt: String = arguments.chars().take(ARGUMENTS_EXCERPT_LEN).collect();
+            let ellipsis = if excerpt.len() < arguments.len() { "..." } else { "" };
+
+            OpenAIError::ToolArgumentsError {
+                name: self.function.name.clone(),
+                message: format!(
+                    "{} (line {}, column {}) in arguments `{}{}`",
+                    e, e.line(), e.column(), excerpt, ellipsis
+                ),
+            }
+        })
+    }
+}
+
+#[allow(dead_code)]
+impl ChatResponse {
+    /// Tool calls of the first choice, or an empty list if the model did not
+    /// call any tool
+    pub fn tool_calls(&self) -> Vec<ToolCall> {
+        self.choices
+            .as_ref()
+            .and_then(|choices| choices.first())
+            .and_then(|choice| choice.message.as_ref())
+            .and_then(|message| message.tool_calls.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct PartialToolCall {
+    id: String,
+    call_type: String,
+    name: String,
+    arguments: String,
+}
+
+/// Reassembles tool calls from streamed `Delta` fragments
+///
+/// # Examples
+/// ```
+/// let mut accumulator = ToolCallAccumulator::new();
+/// while let Some(chunk) = stream.next().await {
+///     accumulator.push_response(&chunk);
+/// }
+/// let tool_calls = accumulator.finish();
+/// ```
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone)]
+pub struct ToolCallAccumulator {
+    calls: BTreeMap<u32, PartialToolCall>,
+}
+
+#[allow(dead_code)]
+impl ToolCallAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merges the tool call fragments of every choice in a streamed chunk
+    pub fn push_response(&mut self, response: &ChatResponse) {
+        let deltas = response.choices
+            .iter()
+            .flatten()
+            .filter_map(|choice| choice.delta.as_ref());
+
+        for delta in deltas {
+            self.push_delta(delta);
+        }
+    }
+
+    pub fn push_delta(&mut self, delta: &Delta) {
+        for fragment in delta.tool_calls.iter().flatten() {
+            self.push(fragment);
+        }
+    }
+
+    pub fn push(&mut self, fragment: &ToolCallDelta) {
+        let call = self.calls.entry(fragment.index).or_default();
+
+        if let Some(id) = &fragment.id {
+            call.id = id.clone();
+        }
+        if let Some(call_type) = &fragment.call_type {
+            call.call_type = call_type.clone();
+        }
+        if let Some(function) = &fragment.function {
+            if let Some(name) = &function.name {
+                call.name.push_str(name);
+            }
+            if let Some(arguments) = &function.arguments {
+                call.arguments.push_str(arguments);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Returns the complete tool calls ordered by index
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.calls
+            .into_values()
+            .map(|call| ToolCall {
+                id: call.id,
+                call_type: if call.call_type.is_empty() {
+                    "function".to_string()
+                } else {
+                    call.call_type
+                },
+                function: FunctionCall {
+                    name: call.name,
+                    arguments: call.arguments,
+                },
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    fn fragment(value: serde_json::Value) -> ToolCallDelta {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall { name: name.to_string(), arguments: arguments.to_string() },
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct SearchArgs {
+        market: String,
+        max_price: Option<f64>,
+    }
+
+    #[test]
+    fn accumulator_reassembles_interleaved_parallel_calls() {
+        let mut accumulator = ToolCallAccumulator::new();
+        for value in [
+            json!({ "index": 1, "id": "call_b", "type": "function", "function": { "name": "get_listing", "arguments": "" } }),
+            json!({ "index": 0, "id": "call_a", "function": { "name": "search_listings", "arguments": "{\"market\":" } }),
+            json!({ "index": 1, "function": { "arguments": "{\"id\":10006546}" } }),
+            json!({ "index": 0, "function": { "arguments": "\"Porto\"}" } }),
+        ] {
+            accumulator.push(&fragment(value));
+        }
+
+        let calls = accumulator.finish();
+
+        assert_eq!(calls.len(), 2);
+        assert_eq!((calls[0].id.as_str(), calls[0].call_type.as_str()), ("call_a", "function"));
+        assert_eq!(calls[0].function.name, "search_listings");
+        assert_eq!(calls[0].function.arguments, "{\"market\":\"Porto\"}");
+        assert_eq!((calls[1].id.as_str(), calls[1].function.name.as_str()), ("call_b", "get_listing"));
+        assert_eq!(calls[1].function.arguments, "{\"id\":10006546}");
+    }
+
+    #[test]
+    fn accumulator_starts_empty() {
+        let accumulator = ToolCallAccumulator::new();
+
+        assert!(accumulator.is_empty());
+        assert!(accumulator.finish().is_empty());
+    }
+
+    #[test]
+    fn parse_arguments_reads_empty_arguments_as_an_empty_object() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct NoArgs {}
+
+        assert_eq!(call("list_markets", " ").parse_arguments::<NoArgs>().unwrap(), NoArgs {});
+    }
+
+    #[test]
+    fn parse_arguments_names_the_call_and_quotes_the_arguments() {
+        let args = call("search_listings", "{\"market\": 42}").parse_arguments::<SearchArgs>();
+
+        let Err(OpenAIError::ToolArgumentsError { name, message }) = args else {
+            panic!("expected a tool arguments error, got {:?}", args);
+        };
+        assert_eq!(name, "search_listings");
+        assert!(message.contains("line 1, column"), "{}", message);
+        assert!(message.ends_with("in arguments `{\"market\": 42}`"), "{}", message);
+    }
+
+    #[test]
+    fn parse_arguments_cuts_long_arguments_in_errors() {
+        let arguments = format!("{{\"market\": \"{}", "a".repeat(300));
+
+        let Err(OpenAIError::ToolArgumentsError { message, .. }) = call("search_listings", &arguments).parse_arguments::<SearchArgs>() else {
+            panic!("expected a tool arguments error");
+        };
+        assert!(message.ends_with("...`"), "{}", message);
+    }
+}
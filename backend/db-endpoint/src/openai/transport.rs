@@ -0,0 +1,550 @@
+use crate::openai::error::OpenAIError;
+use crate::openai::utils::fnv1a;
+use async_stream::stream;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use log::{info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Placeholder written to fixtures instead of the API key
+pub const REDACTED: &str = "Bearer [REDACTED]";
+
+/// Directory fixtures are read from and written to when `OPENAI_FIXTURES_DIR`
+/// is not set
+pub const DEFAULT_FIXTURES_DIR: &str = "tests/fixtures/openai";
+
+/// A POST request to the OpenAI API
+///
+/// # Fields
+/// * `url` - Endpoint URL
+/// * `api_key` - Bearer token, never written to fixtures
+/// * `body` - Serialized JSON request body
+/// * `timeout` - Time allowed for the whole exchange
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    pub url: String,
+    pub api_key: String,
+    pub body: Vec<u8>,
+    pub timeout: Duration,
+}
+
+/// A complete HTTP response
+#[derive(Debug, Clone)]
+pub struct TransportResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl TransportResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// An HTTP response whose body is read chunk by chunk, used for SSE streams
+pub struct StreamingResponse {
+    pub status: u16,
+    pub body: BoxStream<'static, Result<Vec<u8>, OpenAIError>>,
+}
+
+/// How the OpenAI clients reach the API
+///
+/// `HttpTransport` talks to the network; `RecordingTransport` and
+/// `ReplayTransport` save and serve fixture files so tests run offline.
+pub trait Transport: Debug + Send + Sync {
+    /// Sends a request and reads the whole response body
+    fn send<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, OpenAIError>>;
+
+    /// Sends a request and returns the body as a stream of chunks
+    ///
+    /// Defaults to reading the whole body with `send` and yielding it as a
+    /// single chunk.
+    fn send_streaming<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, Result<StreamingResponse, OpenAIError>> {
+        Box::pin(async move {
+            let response = self.send(request).await?;
+            Ok(StreamingResponse {
+                status: response.status,
+                body: futures::stream::once(async move { Ok(response.body) }).boxed(),
+            })
+        })
+    }
+}
+
+/// Transport selected by the environment
+///
+/// `OPENAI_TRANSPORT=record` records every exchange into
+/// `OPENAI_FIXTURES_DIR`, `OPENAI_TRANSPORT=replay` serves them back without
+/// network access; anything else uses the network.
+pub fn default_transport() -> Arc<dyn Transport> {
+    let dir = std::env::var("OPENAI_FIXTURES_DIR")
+        .unwrap_or_else(|_| DEFAULT_FIXTURES_DIR.to_string());
+
+    match std::env::var("OPENAI_TRANSPORT").as_deref() {
+        Ok("record") => {
+            info!("Recording OpenAI traffic into {}", dir);
+            Arc::new(RecordingTransport::new(Arc::new(HttpTransport::new()), &dir))
+        }
+        Ok("replay") => {
+            info!("Replaying OpenAI traffic from {}", dir);
+            Arc::new(ReplayTransport::new(&dir))
+        }
+        _ => Arc::new(HttpTransport::new()),
+    }
+}
+
+/// Sends requests over HTTPS with reqwest
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    client: Client,
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpTransport {
+    pub fn new() -> Self {
+        // Creates an HTTPS-capable client using rustls TLS implementation.
+        let client = Client::builder()
+            .use_rustls_tls()
+            .build()
+            .unwrap_or_else(|e| {
+                warn!("Unable to build the rustls client, using defaults: {}", e);
+                Client::new()
+            });
+
+        Self { client }
+    }
+
+    async fn post(&self, request: &TransportRequest) -> Result<reqwest::Response, OpenAIError> {
+        Ok(self.client
+            .post(&request.url)
+            .timeout(request.timeout)
+            .header("Authorization", format!("Bearer {}", request.api_key))
+            .header("Content-Type", "application/json")
+            .body(request.body.clone())
+            .send()
+            .await?)
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, OpenAIError>> {
+        Box::pin(async move {
+            let response = self.post(request).await?;
+            let status = response.status().as_u16();
+            let body = response.bytes().await?.to_vec();
+
+            Ok(TransportResponse { status, body })
+        })
+    }
+
+    fn send_streaming<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, Result<StreamingResponse, OpenAIError>> {
+        Box::pin(async move {
+            let response = self.post(request).await?;
+            let status = response.status().as_u16();
+            let body = response
+                .bytes_stream()
+                .map(|chunk| chunk.map(|bytes| bytes.to_vec()).map_err(OpenAIError::from))
+                .boxed();
+
+            Ok(StreamingResponse { status, body })
+        })
+    }
+}
+
+/// A recorded request/response pair, stored as one JSON file
+///
+/// # Fields
+/// * `url` - Endpoint URL
+/// * `authorization` - Always `REDACTED`
+/// * `request` - Request body
+/// * `status` - HTTP status of the response
+/// * `response` - Response body; SSE streams are kept as raw text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    pub url: String,
+    pub authorization: String,
+    pub request: Value,
+    pub status: u16,
+    pub response: Value,
+}
+
+impl Fixture {
+    fn new(request: &TransportRequest, status: u16, body: &[u8]) -> Self {
+        let response = serde_json::from_slice(body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()));
+
+        Self {
+            url: request.url.clone(),
+            authorization: REDACTED.to_string(),
+            request: request_json(request),
+            status,
+            response: redact(response, &request.api_key),
+        }
+    }
+
+    fn body(&self) -> Vec<u8> {
+        match &self.response {
+            Value::String(text) => text.as_bytes().to_vec(),
+            other => other.to_string().into_bytes(),
+        }
+    }
+}
+
+/// Request body as JSON, with the API key removed wherever it appears
+fn request_json(request: &TransportRequest) -> Value {
+    let body = serde_json::from_slice(&request.body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&request.body).into_owned()));
+    redact(body, &request.api_key)
+}
+
+fn redact(value: Value, api_key: &str) -> Value {
+    if api_key.is_empty() {
+        return value;
+    }
+
+    match value {
+        Value::String(text) if text.contains(api_key) => {
+            Value::String(text.replace(api_key, "[REDACTED]"))
+        }
+        Value::Array(items) => {
+            Value::Array(items.into_iter().map(|item| redact(item, api_key)).collect())
+        }
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, item)| (key, redact(item, api_key)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// File a request is recorded under: the endpoint path and a hash of the
+/// URL and the canonical JSON body, so identical requests share a fixture
+pub fn fixture_path(dir: &Path, request: &TransportRequest) -> PathBuf {
+    let endpoint = request.url
+        .split("/v1/")
+        .nth(1)
+        .unwrap_or(&request.url)
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "-");
+
+    let canonical = json!({ "url": request.url, "body": request_json(request) }).to_string();
+    dir.join(format!("{}-{:016x}.json", endpoint, fnv1a(canonical.as_bytes())))
+}
+
+fn write_fixture(path: &Path, fixture: &Fixture) -> Result<(), OpenAIError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            OpenAIError::BadRequestError(format!("Unable to create {}: {}", parent.display(), e))
+        })?;
+    }
+
+    let content = serde_json::to_string_pretty(fixture)?;
+    std::fs::write(path, content).map_err(|e| {
+        OpenAIError::BadRequestError(format!("Unable to write {}: {}", path.display(), e))
+    })
+}
+
+/// Forwards requests to another transport and saves every exchange as a
+/// fixture file, with the API key redacted
+#[derive(Debug, Clone)]
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    dir: PathBuf,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Arc<dyn Transport>, dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner,
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, OpenAIError>> {
+        Box::pin(async move {
+            let response = self.inner.send(request).await?;
+            let path = fixture_path(&self.dir, request);
+            write_fixture(&path, &Fixture::new(request, response.status, &response.body))?;
+            info!("Recorded {} into {}", request.url, path.display());
+
+            Ok(response)
+        })
+    }
+
+    fn send_streaming<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, Result<StreamingResponse, OpenAIError>> {
+        Box::pin(async move {
+            let response = self.inner.send_streaming(request).await?;
+            let status = response.status;
+            let path = fixture_path(&self.dir, request);
+            let request = request.clone();
+            let mut chunks = response.body;
+
+            // Chunks are passed through as they arrive and written out once
+            // the stream ends
+            let body = stream! {
+                let mut recorded = Vec::new();
+                while let Some(chunk) = chunks.next().await {
+                    if let Ok(bytes) = &chunk {
+                        recorded.extend_from_slice(bytes);
+                    }
+                    yield chunk;
+                }
+
+                match write_fixture(&path, &Fixture::new(&request, status, &recorded)) {
+                    Ok(()) => info!("Recorded {} into {}", request.url, path.display()),
+                    Err(e) => warn!("Failed to record {}: {}", request.url, e),
+                }
+            };
+
+            Ok(StreamingResponse { status, body: body.boxed() })
+        })
+    }
+}
+
+/// Serves recorded fixtures instead of calling the API
+///
+/// A request without a fixture fails with `OpenAIError::NotFoundError`
+/// naming the file it looked for.
+#[derive(Debug, Clone)]
+pub struct ReplayTransport {
+    dir: PathBuf,
+}
+
+impl ReplayTransport {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send<'a>(
+        &'a self,
+        request: &'a TransportRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, OpenAIError>> {
+        Box::pin(async move {
+            let path = fixture_path(&self.dir, request);
+            let content = std::fs::read_to_string(&path).map_err(|_| {
+                OpenAIError::NotFoundError(format!(
+                    "fixture {} for {}", path.display(), request.url
+                ))
+            })?;
+            let fixture: Fixture = serde_json::from_str(&content)?;
+
+            Ok(TransportResponse {
+                status: fixture.status,
+                body: fixture.body(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::chat::ChatOpenAI;
+    use crate::openai::embed::EmbedOpenAI;
+    use crate::openai::response::ResponseOpenAI;
+    use std::sync::Mutex;
+
+    /// Answers every request with the same response and remembers the keys
+    /// it was called with
+    #[derive(Debug)]
+    struct StaticTransport {
+        status: u16,
+        body: Value,
+        api_keys: Mutex<Vec<String>>,
+    }
+
+    impl StaticTransport {
+        fn new(status: u16, body: Value) -> Arc<Self> {
+            Arc::new(Self {
+                status,
+                body,
+                api_keys: Mutex::new(vec![]),
+            })
+        }
+    }
+
+    impl Transport for StaticTransport {
+        fn send<'a>(
+            &'a self,
+            request: &'a TransportRequest,
+        ) -> BoxFuture<'a, Result<TransportResponse, OpenAIError>> {
+            self.api_keys.lock().unwrap().push(request.api_key.clone());
+            let response = TransportResponse {
+                status: self.status,
+                body: self.body.to_string().into_bytes(),
+            };
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    fn fixtures_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "openai-fixtures-{}-{}", name, std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn chat_completion(text: &str) -> Value {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1_700_000_000,
+            "model": "gpt-4o-mini",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 }
+        })
+    }
+
+    #[tokio::test]
+    async fn chat_replays_recorded_exchange() {
+        let dir = fixtures_dir("chat");
+        let upstream = StaticTransport::new(200, chat_completion("Two listings match."));
+
+        let recorded = ChatOpenAI::new("gpt-4o-mini")
+            .with_api_key("sk-secret")
+            .with_transport(Arc::new(RecordingTransport::new(upstream.clone(), &dir)))
+            .invoke("Lofts in Porto?")
+            .await
+            .unwrap();
+
+        let replayed = ChatOpenAI::new("gpt-4o-mini")
+            .with_api_key("sk-other")
+            .with_transport(Arc::new(ReplayTransport::new(&dir)))
+            .invoke("Lofts in Porto?")
+            .await
+            .unwrap();
+
+        let text = |response: &crate::openai::libs::ChatResponse| {
+            response.choices.as_ref().unwrap()[0].message.as_ref().unwrap().content.clone()
+        };
+        assert_eq!(text(&recorded), Some("Two listings match.".to_string()));
+        assert_eq!(text(&replayed), text(&recorded));
+        assert_eq!(upstream.api_keys.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fixtures_redact_the_api_key() {
+        let dir = fixtures_dir("redact");
+        let upstream = StaticTransport::new(200, chat_completion("ok"));
+
+        ChatOpenAI::new("gpt-4o-mini")
+            .with_api_key("sk-secret")
+            .with_transport(Arc::new(RecordingTransport::new(upstream, &dir)))
+            .invoke("Echo sk-secret")
+            .await
+            .unwrap();
+
+        let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let content = std::fs::read_to_string(file).unwrap();
+        assert!(!content.contains("sk-secret"));
+        assert!(content.contains(REDACTED));
+    }
+
+    #[tokio::test]
+    async fn responses_and_embeddings_replay() {
+        let dir = fixtures_dir("responses");
+        let response_body = json!({
+            "id": "resp_1",
+            "object": "response",
+            "created_at": 1_700_000_000,
+            "status": "completed",
+            "model": "gpt-4o-mini",
+            "output": [{
+                "type": "message",
+                "id": "msg_1",
+                "role": "assistant",
+                "status": "completed",
+                "content": [{ "type": "output_text", "text": "Porto", "annotations": [] }]
+            }]
+        });
+        let embed_body = json!({
+            "object": "list",
+            "model": "text-embedding-3-small",
+            "data": [{ "object": "embedding", "index": 0, "embedding": [0.1, 0.2] }],
+            "usage": { "prompt_tokens": 2, "total_tokens": 2 }
+        });
+
+        ResponseOpenAI::new("gpt-4o-mini")
+            .with_prompt("Which city?")
+            .with_transport(Arc::new(RecordingTransport::new(
+                StaticTransport::new(200, response_body), &dir,
+            )))
+            .invoke()
+            .await
+            .unwrap();
+        EmbedOpenAI::new("text-embedding-3-small")
+            .with_transport(Arc::new(RecordingTransport::new(
+                StaticTransport::new(200, embed_body), &dir,
+            )))
+            .embed_content("Porto loft")
+            .await
+            .unwrap();
+
+        let replay: Arc<dyn Transport> = Arc::new(ReplayTransport::new(&dir));
+        let response = ResponseOpenAI::new("gpt-4o-mini")
+            .with_prompt("Which city?")
+            .with_transport(replay.clone())
+            .invoke()
+            .await
+            .unwrap();
+        let embedding = EmbedOpenAI::new("text-embedding-3-small")
+            .with_transport(replay)
+            .embed_content("Porto loft")
+            .await
+            .unwrap();
+
+        assert_eq!(response.output_text(), "Porto");
+        assert_eq!(embedding.data[0].embedding, vec![0.1, 0.2]);
+    }
+
+    #[tokio::test]
+    async fn replay_without_fixture_is_not_found() {
+        let dir = fixtures_dir("missing");
+        let result = ChatOpenAI::new("gpt-4o-mini")
+            .with_transport(Arc::new(ReplayTransport::new(&dir)))
+            .invoke("Never recorded")
+            .await;
+
+        assert!(matches!(result, Err(OpenAIError::NotFoundError(_))));
+    }
+}
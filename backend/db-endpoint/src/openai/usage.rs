@@ -0,0 +1,267 @@
+use crate::openai::error::OpenAIError;
+use crate::openai::libs;
+use crate::openai::lib_response;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Debug;
+use log::{info, error};
+
+pub const ENDPOINT_CHAT: &str = "chat";
+pub const ENDPOINT_RESPONSES: &str = "responses";
+pub const ENDPOINT_EMBEDDINGS: &str = "embeddings";
+
+/// Receives the token usage of every request a client completes
+///
+/// The chat and responses clients report to their sink after each `invoke`,
+/// so usage is accounted for without every call site recording it.
+pub trait UsageSink: Debug + Send + Sync {
+    /// # Arguments
+    /// * `model` - Model the request was sent to
+    /// * `endpoint` - One of `ENDPOINT_CHAT`, `ENDPOINT_RESPONSES` or `ENDPOINT_EMBEDDINGS`
+    fn record(&self, model: &str, endpoint: &str, usage: TokenUsage);
+}
+
+/// Token counts normalized across the chat, responses and embeddings endpoints
+///
+/// `libs::Usage` and `lib_response::Usage` report the same quantities under
+/// different names; this struct is the common shape the usage ledger works with.
+///
+/// # Fields
+/// * `prompt_tokens` - Input tokens, including cached ones
+/// * `completion_tokens` - Output tokens, including reasoning ones
+/// * `reasoning_tokens` - Output tokens spent on reasoning
+/// * `cached_tokens` - Input tokens served from the prompt cache
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub reasoning_tokens: u64,
+    pub cached_tokens: u64,
+}
+
+#[allow(dead_code)]
+impl TokenUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+        self.cached_tokens += other.cached_tokens;
+    }
+}
+
+impl From<&libs::Usage> for TokenUsage {
+    fn from(usage: &libs::Usage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens.unwrap_or(0) as u64,
+            completion_tokens: usage.completion_tokens.unwrap_or(0) as u64,
+            reasoning_tokens: usage.completion_tokens_details
+                .as_ref()
+                .map(|details| details.reasoning_tokens as u64)
+                .unwrap_or(0),
+            cached_tokens: usage.prompt_tokens_details
+                .as_ref()
+                .map(|details| details.cached_tokens as u64)
+                .unwrap_or(0),
+        }
+    }
+}
+
+impl From<&lib_response::Usage> for TokenUsage {
+    fn from(usage: &lib_response::Usage) -> Self {
+        Self {
+            prompt_tokens: usage.input_tokens.unwrap_or(0),
+            completion_tokens: usage.output_tokens.unwrap_or(0),
+            reasoning_tokens: usage.output_tokens_details
+                .as_ref()
+                .map(|details| details.reasoning_tokens)
+                .unwrap_or(0),
+            cached_tokens: usage.input_tokens_details
+                .as_ref()
+                .map(|details| details.cached_tokens)
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Price of a model in USD per one million tokens
+///
+/// # Fields
+/// * `input` - Price of uncached input tokens
+/// * `cached_input` - Optional - Price of cached input tokens, defaults to `input`
+/// * `output` - Price of output tokens (reasoning tokens are billed as output)
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached_input: Option<f64>,
+    pub output: f64,
+}
+
+/// Per-model price table used to estimate the cost of a request
+///
+/// The table can be overridden with a JSON file whose path is given in the
+/// `USAGE_PRICE_TABLE` environment variable, mapping model ids to `ModelPrice`:
+///
+/// ```json
+/// { "gpt-4o-mini": { "input": 0.15, "cached_input": 0.075, "output": 0.6 } }
+/// ```
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceTable {
+    pub prices: HashMap<String, ModelPrice>,
+}
+
+const DEFAULT_PRICES: &[(&str, f64, Option<f64>, f64)] = &[
+    ("gpt-4o", 2.50, Some(1.25), 10.00),
+    ("gpt-4o-mini", 0.15, Some(0.075), 0.60),
+    ("gpt-4.1", 2.00, Some(0.50), 8.00),
+    ("gpt-4.1-mini", 0.40, Some(0.10), 1.60),
+    ("gpt-4.1-nano", 0.10, Some(0.025), 0.40),
+    ("o1", 15.00, Some(7.50), 60.00),
+    ("o1-mini", 1.10, Some(0.55), 4.40),
+    ("o3-mini", 1.10, Some(0.55), 4.40),
+    ("o4-mini", 1.10, Some(0.275), 4.40),
+    ("text-embedding-3-small", 0.02, None, 0.0),
+    ("text-embedding-3-large", 0.13, None, 0.0),
+];
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        let prices = DEFAULT_PRICES
+            .iter()
+            .map(|(model, input, cached_input, output)| {
+                (model.to_string(), ModelPrice {
+                    input: *input,
+                    cached_input: *cached_input,
+                    output: *output,
+                })
+            })
+            .collect();
+
+        Self { prices }
+    }
+}
+
+#[allow(dead_code)]
+impl PriceTable {
+    /// Loads the price table from the file in `USAGE_PRICE_TABLE`, falling back
+    /// to the built-in prices when the variable is unset or the file is invalid
+    pub fn from_env() -> Self {
+        match env::var("USAGE_PRICE_TABLE") {
+            Ok(path) => match Self::from_json_file(&path) {
+                Ok(table) => table,
+                Err(e) => {
+                    error!("Unable to load price table from {}: {}", path, e);
+                    Self::default()
+                }
+            },
+            Err(_) => {
+                info!("USAGE_PRICE_TABLE not set, using built-in prices");
+                Self::default()
+            }
+        }
+    }
+
+    /// Reads a JSON map of model id to `ModelPrice`
+    pub fn from_json_file(path: &str) -> Result<Self, OpenAIError> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            OpenAIError::GenericError {
+                code: "price_table".to_string(),
+                message: format!("Unable to read {}: {}", path, e),
+                detail: "ERROR-usage-0001".to_string(),
+            }
+        })?;
+        let prices: HashMap<String, ModelPrice> = serde_json::from_str(&content)?;
+        Ok(Self { prices })
+    }
+
+    /// Returns the price of a model, matching dated snapshots such as
+    /// `gpt-4o-2024-08-06` against the longest known model id prefix
+    pub fn price_for(&self, model: &str) -> Option<&ModelPrice> {
+        if let Some(price) = self.prices.get(model) {
+            return Some(price);
+        }
+
+        self.prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(&format!("{}-", name)))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| price)
+    }
+
+    /// Estimates the cost in USD of the given usage, or `None` for unknown models
+    pub fn estimate_cost(&self, model: &str, usage: &TokenUsage) -> Option<f64> {
+        let price = self.price_for(model)?;
+        let cached = usage.cached_tokens.min(usage.prompt_tokens) as f64;
+        let uncached = usage.prompt_tokens as f64 - cached;
+        let cached_price = price.cached_input.unwrap_or(price.input);
+
+        let cost = uncached * price.input
+            + cached * cached_price
+            + usage.completion_tokens as f64 * price.output;
+
+        Some(cost / 1_000_000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u64, cached_tokens: u64, completion_tokens: u64) -> TokenUsage {
+        TokenUsage { prompt_tokens, completion_tokens, reasoning_tokens: 0, cached_tokens }
+    }
+
+    #[test]
+    fn price_for_matches_dated_snapshots_by_their_longest_prefix() {
+        let table = PriceTable::default();
+
+        assert_eq!(table.price_for("gpt-4o-mini-2024-07-18").map(|price| price.input), Some(0.15));
+        assert_eq!(table.price_for("gpt-4o-2024-08-06").map(|price| price.input), Some(2.50));
+        assert_eq!(table.price_for("gpt-4o").map(|price| price.input), Some(2.50));
+    }
+
+    #[test]
+    fn price_for_rejects_unknown_models_and_bare_prefixes() {
+        let table = PriceTable::default();
+
+        assert!(table.price_for("claude-3").is_none());
+        assert!(table.price_for("gpt-4omni").is_none());
+        assert_eq!(table.estimate_cost("claude-3", &usage(1_000, 0, 1_000)), None);
+    }
+
+    #[test]
+    fn estimate_cost_bills_cached_input_at_its_own_price() {
+        let table = PriceTable::default();
+
+        // gpt-4o-mini: 600k uncached at 0.15, 400k cached at 0.075, 1M output at 0.60
+        let cost = table.estimate_cost("gpt-4o-mini", &usage(1_000_000, 400_000, 1_000_000)).unwrap();
+
+        assert!((cost - (0.09 + 0.03 + 0.60)).abs() < 1e-9, "{}", cost);
+    }
+
+    #[test]
+    fn estimate_cost_bills_cached_input_as_input_without_a_cached_price() {
+        let table = PriceTable::default();
+
+        let cost = table.estimate_cost("text-embedding-3-small", &usage(1_000_000, 500_000, 0)).unwrap();
+
+        assert!((cost - 0.02).abs() < 1e-9, "{}", cost);
+    }
+
+    #[test]
+    fn estimate_cost_never_counts_more_cached_than_prompt_tokens() {
+        let table = PriceTable::default();
+
+        let cost = table.estimate_cost("gpt-4o", &usage(1_000_000, 2_000_000, 0)).unwrap();
+
+        assert!((cost - 1.25).abs() < 1e-9, "{}", cost);
+    }
+}
@@ -1,9 +1,18 @@
 use std::env;
 use crate::openai::error::OpenAIError;
 use serde_json::{json, Value};
+use schemars::gen::SchemaSettings;
 use schemars::schema::RootSchema;
+use schemars::JsonSchema;
 use log::{info, error};
 
+/// 64-bit FNV-1a, stable across Rust releases unlike `DefaultHasher`
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
 /// Gets the API key from the environment variables
 ///
 /// # Returns
@@ -33,27 +42,20 @@ pub trait GetApiKey {
     }
 }
 
-/// Prints the given request as a pretty-printed JSON string
+/// Transforms a JSON schema into the representation expected by OpenAI
 ///
-/// # Arguments
-/// * `request` - The request to be printed
-///
-pub fn print_pre(request: &impl serde::Serialize, active: bool) {
-    if !active {
-        println!();
-    } else {
-        match serde_json::to_string_pretty(request) {
-            Ok(json) => println!("Pretty-printed JSON:\n{}", json),
-            Err(e) => error!("Error {:?}", e)
-        }
-    }
-}
-
-/// Transforms a JSON schema into a simplified representation
+/// Definitions are re-homed under `$defs` (or inlined when `sub_struct` is set,
+/// since the array wrapper is embedded in another schema), `Option` fields are
+/// turned into nullable unions and, when `strict` is set, every property is
+/// marked as required and the result is validated against the JSON Schema
+/// subset supported by structured outputs.
 ///
 /// # Arguments
 ///
 /// * `schema` - A RootSchema instance containing the JSON schema to transform
+/// * `name` - The name of the response format
+/// * `strict` - Whether to apply and validate the strict-mode rules
+/// * `additional_properties` - Value of `additionalProperties` on the top-level object
 /// * `sub_struct` - A boolean flag indicating whether to wrap the schema in an array structure
 ///
 /// # Returns
@@ -63,6 +65,7 @@ pub fn print_pre(request: &impl serde::Serialize, active: bool) {
 /// * Err(Box<dyn Error>) - An error if:
 ///   - The schema cannot be serialized to JSON
 ///   - The serialized JSON is not an object
+///   - The schema uses features strict mode does not support
 ///
 pub fn generate_schema(
     schema: RootSchema,
@@ -71,37 +74,625 @@ pub fn generate_schema(
     additional_properties: bool,
     sub_struct: bool
 ) -> Result<Value, Box<dyn std::error::Error>> {
-    let response_json = match serde_json::to_value(schema) {
-        Ok(value) => value,
-        Err(e) => return Err(Box::new(e)),
-    };
-
-    let mut response = match response_json.as_object() {
-        Some(obj) => obj.clone(),
-        None => return Err("Serialized JSON is not an object".into()),
-    };
+    let response_json = serde_json::to_value(schema)?;
+    if !response_json.is_object() {
+        return Err("Serialized JSON is not an object".into());
+    }
 
-    response.remove("$schema");
-    response.remove("title");
-    response.remove("definitions");
+    let mut response = normalize_schema(response_json, strict);
 
     if sub_struct {
-        let mut format_response = json!({
+        inline_definitions(&mut response)?;
+        response["additionalProperties"] = Value::Bool(additional_properties);
+        if strict {
+            validate_strict_schema(&response)?;
+        }
+
+        Ok(json!({
             "type": "array",
             "items": response
-        });
-        let a_resp = Value::Bool(additional_properties);
-        format_response["items"]["additionalProperties"] = a_resp;
-        
-        return Ok(format_response);
+        }))
     } else {
-        let mut format_response = json!({
+        response["additionalProperties"] = Value::Bool(additional_properties);
+        if strict {
+            validate_strict_schema(&response)?;
+        }
+
+        Ok(json!({
             "name": name,
             "schema": response,
             "strict": strict
-        });
-        let a_resp = Value::Bool(additional_properties);
-        format_response["schema"]["additionalProperties"] = a_resp;
-        return Ok(format_response);
+        }))
+    }
+}
+
+/// Generates a strict-mode `json_schema` response format for a Rust type
+///
+/// Nested structs and enums are kept as `$defs` referenced with `$ref`,
+/// `Option` fields stay required but also accept `null`, and the schema is
+/// validated before it is sent.
+///
+/// # Returns
+///
+/// A `serde_json::Value` with `name`, `schema` and `strict: true`, ready for
+/// `ChatOpenAI::with_json_schema`.
+///
+pub fn strict_json_schema<T: JsonSchema>() -> Result<Value, OpenAIError> {
+    let settings = SchemaSettings::draft07().with(|settings| {
+        settings.option_nullable = false;
+        settings.option_add_null_type = true;
+    });
+    let root = settings.into_generator().into_root_schema_for::<T>();
+
+    let schema = normalize_schema(serde_json::to_value(root)?, true);
+    validate_strict_schema(&schema)?;
+
+    Ok(json!({
+        "name": schema_name(&T::schema_name()),
+        "schema": schema,
+        "strict": true
+    }))
+}
+
+/// String formats accepted by OpenAI structured outputs in strict mode
+const STRICT_FORMATS: &[&str] = &[
+    "date-time", "time", "date", "duration", "email",
+    "hostname", "ipv4", "ipv6", "uuid",
+];
+
+/// Keywords of the JSON Schema subset supported by structured outputs
+const STRICT_KEYWORDS: &[&str] = &[
+    "type", "properties", "required", "additionalProperties", "items",
+    "enum", "const", "anyOf", "$ref", "$defs", "description", "pattern",
+    "format", "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum",
+    "multipleOf", "minItems", "maxItems",
+];
+
+const STRICT_MAX_DEPTH: usize = 10;
+const STRICT_MAX_PROPERTIES: usize = 5000;
+const STRICT_MAX_ENUM_VALUES: usize = 1000;
+
+/// Reference prefixes schemars uses for definitions (draft-07 and OpenAPI 3)
+const DEFINITIONS_PREFIXES: &[&str] = &["#/definitions/", "#/components/schemas/"];
+const DEFS_PREFIX: &str = "#/$defs/";
+
+/// Rewrites a schemars root schema into OpenAI's dialect
+///
+/// Moves `definitions` to `$defs`, rewrites references, collapses single
+/// `allOf` wrappers, turns `oneOf` into `anyOf` and `nullable` into unions
+/// with `null`, and drops keywords strict mode rejects. With `strict`, every
+/// object requires all of its properties and disallows additional ones.
+fn normalize_schema(schema: Value, strict: bool) -> Value {
+    let mut root = match schema {
+        Value::Object(object) => object,
+        other => return other,
+    };
+
+    root.remove("$schema");
+    let mut definitions = match root.remove("definitions") {
+        Some(Value::Object(definitions)) => definitions,
+        _ => serde_json::Map::new(),
+    };
+    if let Some(Value::Object(defs)) = root.remove("$defs") {
+        definitions.extend(defs);
+    }
+
+    let mut root = Value::Object(root);
+    normalize_node(&mut root, strict);
+    for definition in definitions.values_mut() {
+        normalize_node(definition, strict);
+    }
+
+    if !definitions.is_empty() {
+        root["$defs"] = Value::Object(definitions);
+    }
+    root
+}
+
+fn normalize_node(node: &mut Value, strict: bool) {
+    let object = match node.as_object_mut() {
+        Some(object) => object,
+        None => return,
+    };
+
+    // `{ "allOf": [schema] }` is how schemars attaches a description to a $ref
+    let single_all_of = matches!(object.get("allOf"), Some(Value::Array(items)) if items.len() == 1);
+    if single_all_of {
+        if let Some(Value::Array(mut items)) = object.remove("allOf") {
+            if let Some(Value::Object(inner)) = items.pop() {
+                for (key, value) in inner {
+                    object.entry(key).or_insert(value);
+                }
+            }
+        }
+    }
+
+    if let Some(one_of) = object.remove("oneOf") {
+        object.insert("anyOf".to_string(), one_of);
+    }
+
+    if let Some(Value::String(reference)) = object.get_mut("$ref") {
+        let name = DEFINITIONS_PREFIXES
+            .iter()
+            .find_map(|prefix| reference.strip_prefix(prefix));
+        if let Some(name) = name {
+            *reference = format!("{}{}", DEFS_PREFIX, name);
+        }
+    }
+
+    for keyword in ["$schema", "title", "default", "examples", "readOnly", "writeOnly"] {
+        object.remove(keyword);
+    }
+
+    let unsupported_format = object
+        .get("format")
+        .and_then(Value::as_str)
+        .map(|format| !STRICT_FORMATS.contains(&format))
+        .unwrap_or(false);
+    if unsupported_format {
+        object.remove("format");
+    }
+
+    if strict {
+        if let Some(Value::Object(properties)) = object.get("properties") {
+            let required: Vec<Value> = properties
+                .keys()
+                .map(|key| Value::String(key.clone()))
+                .collect();
+            object.insert("required".to_string(), Value::Array(required));
+            object.insert("additionalProperties".to_string(), Value::Bool(false));
+        }
+    }
+
+    if let Some(Value::Object(properties)) = object.get_mut("properties") {
+        for property in properties.values_mut() {
+            normalize_node(property, strict);
+        }
+    }
+    match object.get_mut("items") {
+        Some(Value::Array(items)) => items.iter_mut().for_each(|item| normalize_node(item, strict)),
+        Some(item) => normalize_node(item, strict),
+        None => {}
+    }
+    if let Some(Value::Array(variants)) = object.get_mut("anyOf") {
+        variants.iter_mut().for_each(|variant| normalize_node(variant, strict));
+    }
+    if let Some(additional @ Value::Object(_)) = object.get_mut("additionalProperties") {
+        normalize_node(additional, strict);
+    }
+
+    if object.remove("nullable") == Some(Value::Bool(true)) {
+        make_nullable(node);
+    }
+}
+
+/// Makes a schema also accept `null`
+fn make_nullable(node: &mut Value) {
+    let object = match node.as_object_mut() {
+        Some(object) => object,
+        None => return,
+    };
+
+    match object.get("type").cloned() {
+        Some(Value::String(type_)) => {
+            object.insert("type".to_string(), json!([type_, "null"]));
+        }
+        Some(Value::Array(mut types)) => {
+            if !types.contains(&json!("null")) {
+                types.push(json!("null"));
+            }
+            object.insert("type".to_string(), Value::Array(types));
+        }
+        _ => {
+            let inner = std::mem::take(object);
+            *node = json!({ "anyOf": [inner, { "type": "null" }] });
+            return;
+        }
+    }
+
+    if let Some(Value::Array(values)) = object.get_mut("enum") {
+        if !values.contains(&Value::Null) {
+            values.push(Value::Null);
+        }
+    }
+}
+
+/// Replaces every `$ref` with a copy of its definition and removes `$defs`
+///
+/// # Errors
+/// Fails on recursive types, which can only be expressed with references.
+fn inline_definitions(schema: &mut Value) -> Result<(), OpenAIError> {
+    let definitions = match schema.as_object_mut().and_then(|object| object.remove("$defs")) {
+        Some(Value::Object(definitions)) => definitions,
+        _ => return Ok(()),
+    };
+
+    fn inline(
+        node: &mut Value,
+        definitions: &serde_json::Map<String, Value>,
+        stack: &mut Vec<String>,
+    ) -> Result<(), OpenAIError> {
+        match node {
+            Value::Object(object) => {
+                if let Some(Value::String(reference)) = object.get("$ref") {
+                    let name = reference.trim_start_matches(DEFS_PREFIX).to_string();
+                    if stack.contains(&name) {
+                        return Err(OpenAIError::SchemaError(format!(
+                            "recursive type `{}` cannot be inlined", name
+                        )));
+                    }
+                    let mut definition = definitions
+                        .get(&name)
+                        .cloned()
+                        .ok_or_else(|| OpenAIError::SchemaError(format!(
+                            "missing definition for `{}`", reference
+                        )))?;
+
+                    stack.push(name);
+                    inline(&mut definition, definitions, stack)?;
+                    stack.pop();
+
+                    object.remove("$ref");
+                    if let Value::Object(definition) = definition {
+                        for (key, value) in definition {
+                            object.entry(key).or_insert(value);
+                        }
+                    }
+                    return Ok(());
+                }
+
+                for (key, value) in object.iter_mut() {
+                    if key != "enum" && key != "const" {
+                        inline(value, definitions, stack)?;
+                    }
+                }
+                Ok(())
+            }
+            Value::Array(items) => {
+                for item in items.iter_mut() {
+                    inline(item, definitions, stack)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    inline(schema, &definitions, &mut vec![])
+}
+
+/// Checks a schema against the JSON Schema subset supported by OpenAI
+/// structured outputs in strict mode
+///
+/// # Errors
+/// `OpenAIError::SchemaError` listing every problem found, each prefixed
+/// with the path of the offending subschema.
+pub fn validate_strict_schema(schema: &Value) -> Result<(), OpenAIError> {
+    let mut issues: Vec<String> = vec![];
+
+    let root = match schema.as_object() {
+        Some(root) => root,
+        None => return Err(OpenAIError::SchemaError("schema is not an object".to_string())),
+    };
+    if root.get("type") != Some(&json!("object")) {
+        issues.push("#: the root schema must be of type `object`".to_string());
+    }
+    if root.contains_key("anyOf") {
+        issues.push("#: the root schema must not be `anyOf`".to_string());
+    }
+
+    let definitions = root.get("$defs").and_then(Value::as_object);
+    let mut total_properties = 0;
+
+    validate_node(schema, "#", 0, definitions, &mut total_properties, &mut issues);
+    if let Some(definitions) = definitions {
+        for (name, definition) in definitions {
+            let path = format!("#/$defs/{}", name);
+            validate_node(definition, &path, 0, Some(definitions), &mut total_properties, &mut issues);
+        }
+    }
+
+    if total_properties > STRICT_MAX_PROPERTIES {
+        issues.push(format!(
+            "#: {} properties exceed the limit of {}", total_properties, STRICT_MAX_PROPERTIES
+        ));
+    }
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(OpenAIError::SchemaError(issues.join("; ")))
+    }
+}
+
+fn validate_node(
+    node: &Value,
+    path: &str,
+    depth: usize,
+    definitions: Option<&serde_json::Map<String, Value>>,
+    total_properties: &mut usize,
+    issues: &mut Vec<String>,
+) {
+    let object = match node {
+        Value::Object(object) => object,
+        Value::Bool(true) => {
+            issues.push(format!("{}: `true` schemas accept anything and are not supported", path));
+            return;
+        }
+        _ => return,
+    };
+
+    if depth > STRICT_MAX_DEPTH {
+        issues.push(format!("{}: nesting exceeds {} levels", path, STRICT_MAX_DEPTH));
+        return;
+    }
+
+    for key in object.keys() {
+        if !STRICT_KEYWORDS.contains(&key.as_str()) {
+            issues.push(format!("{}: keyword `{}` is not supported", path, key));
+        }
+    }
+    if path != "#" && object.contains_key("$defs") {
+        issues.push(format!("{}: `$defs` is only allowed on the root schema", path));
+    }
+
+    if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+        let target = reference.strip_prefix(DEFS_PREFIX);
+        let resolved = match (target, definitions) {
+            (Some(name), Some(definitions)) => definitions.contains_key(name),
+            _ => reference == "#",
+        };
+        if !resolved {
+            issues.push(format!("{}: reference `{}` does not resolve", path, reference));
+        }
+    }
+
+    let is_object = match object.get("type") {
+        Some(Value::String(type_)) => type_ == "object",
+        Some(Value::Array(types)) => types.contains(&json!("object")),
+        _ => false,
+    };
+
+    match object.get("properties").and_then(Value::as_object) {
+        Some(properties) => {
+            *total_properties += properties.len();
+
+            if object.get("additionalProperties") != Some(&Value::Bool(false)) {
+                issues.push(format!("{}: `additionalProperties` must be false", path));
+            }
+            let required: Vec<&str> = object
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|required| required.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            for name in properties.keys() {
+                if !required.contains(&name.as_str()) {
+                    issues.push(format!("{}: property `{}` must be required", path, name));
+                }
+            }
+            for (name, property) in properties {
+                let property_path = format!("{}/properties/{}", path, name);
+                validate_node(property, &property_path, depth + 1, definitions, total_properties, issues);
+            }
+        }
+        None if is_object => {
+            issues.push(format!(
+                "{}: objects must declare `properties`; maps with arbitrary keys are not supported",
+                path
+            ));
+        }
+        None => {}
+    }
+
+    if let Some(values) = object.get("enum").and_then(Value::as_array) {
+        if values.len() > STRICT_MAX_ENUM_VALUES {
+            issues.push(format!(
+                "{}: {} enum values exceed the limit of {}",
+                path, values.len(), STRICT_MAX_ENUM_VALUES
+            ));
+        }
+    }
+
+    match object.get("items") {
+        Some(Value::Array(_)) => {
+            issues.push(format!("{}: tuple `items` are not supported", path));
+        }
+        Some(items) => {
+            let items_path = format!("{}/items", path);
+            validate_node(items, &items_path, depth + 1, definitions, total_properties, issues);
+        }
+        None => {}
+    }
+
+    if let Some(variants) = object.get("anyOf").and_then(Value::as_array) {
+        for (index, variant) in variants.iter().enumerate() {
+            let variant_path = format!("{}/anyOf/{}", path, index);
+            validate_node(variant, &variant_path, depth, definitions, total_properties, issues);
+        }
+    }
+}
+
+/// Converts a type name into a valid response format name: a-z, A-Z, 0-9,
+/// underscores and dashes, at most 64 characters
+pub fn schema_name(type_name: &str) -> String {
+    let name: String = type_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .take(64)
+        .collect();
+
+    if name.is_empty() {
+        "response".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{QueryIntent, ShortTermRental};
+    use schemars::schema_for;
+    use std::collections::HashMap;
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Neighborhood {
+        name: String,
+        center: Point,
+        /// Closest point of interest
+        landmark: Option<Point>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Point {
+        lat: f64,
+        lng: f64,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    #[serde(tag = "kind", rename_all = "snake_case")]
+    enum Filter {
+        Price { max: f64 },
+        Amenity { name: String },
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct WithFilters {
+        filters: Vec<Filter>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct WithMap {
+        counts: HashMap<String, u32>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Tree {
+        label: String,
+        children: Vec<Tree>,
+    }
+
+    fn schema_of<T: JsonSchema>() -> Value {
+        strict_json_schema::<T>().expect("schema should be valid")["schema"].clone()
+    }
+
+    #[test]
+    fn nested_structs_are_rehomed_under_defs() {
+        let schema = schema_of::<Neighborhood>();
+
+        assert!(schema.get("definitions").is_none());
+        assert!(schema["$defs"]["Point"].is_object());
+        assert_eq!(schema["properties"]["center"]["$ref"], "#/$defs/Point");
+        assert_eq!(schema["$defs"]["Point"]["additionalProperties"], false);
+        assert_eq!(schema["$defs"]["Point"]["required"], json!(["lat", "lng"]));
+    }
+
+    #[test]
+    fn description_on_ref_collapses_all_of() {
+        let schema = schema_of::<Neighborhood>();
+        let landmark = &schema["properties"]["landmark"];
+
+        assert!(landmark.get("allOf").is_none());
+        assert_eq!(landmark["description"], "Closest point of interest");
+    }
+
+    #[test]
+    fn options_are_required_and_nullable() {
+        let schema = schema_of::<Neighborhood>();
+
+        assert_eq!(schema["required"], json!(["center", "landmark", "name"]));
+        let variants = schema["properties"]["landmark"]["anyOf"].as_array().unwrap();
+        assert!(variants.contains(&json!({ "$ref": "#/$defs/Point" })));
+        assert!(variants.contains(&json!({ "type": "null" })));
+    }
+
+    #[test]
+    fn tagged_enums_become_any_of() {
+        let schema = schema_of::<WithFilters>();
+        let filter = &schema["$defs"]["Filter"];
+
+        assert!(filter.get("oneOf").is_none());
+        let variants = filter["anyOf"].as_array().unwrap();
+        assert_eq!(variants.len(), 2);
+        for variant in variants {
+            assert_eq!(variant["additionalProperties"], false);
+            assert!(variant["required"].as_array().unwrap().contains(&json!("kind")));
+        }
+    }
+
+    #[test]
+    fn maps_are_rejected() {
+        let error = strict_json_schema::<WithMap>().unwrap_err();
+
+        match error {
+            OpenAIError::SchemaError(message) => {
+                assert!(message.contains("#/properties/counts"), "{}", message);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn recursive_types_keep_references() {
+        let schema = schema_of::<Tree>();
+
+        assert_eq!(schema["properties"]["children"]["items"]["$ref"], "#/$defs/Tree");
+        assert_eq!(schema["$defs"]["Tree"]["additionalProperties"], false);
+    }
+
+    #[test]
+    fn unsupported_formats_are_removed() {
+        let schema = schema_of::<ShortTermRental>();
+
+        assert!(schema["properties"]["accommodates"].get("format").is_none());
+        assert_eq!(schema["properties"]["last_scraped"]["format"], "date-time");
+    }
+
+    #[test]
+    fn listing_schema_is_valid() {
+        let schema = schema_of::<ShortTermRental>();
+
+        assert_eq!(schema["$defs"]["Host"]["additionalProperties"], false);
+        assert!(schema["properties"]["address"]["anyOf"].is_array());
+        assert!(validate_strict_schema(&schema).is_ok());
+    }
+
+    #[test]
+    fn query_intent_schema_is_valid() {
+        let format = strict_json_schema::<QueryIntent>().unwrap();
+        let schema = &format["schema"];
+
+        assert_eq!(format["name"], "QueryIntent");
+        assert_eq!(format["strict"], true);
+        assert_eq!(
+            schema["$defs"]["RoomType"]["enum"],
+            json!(["Entire home/apt", "Private room", "Shared room"])
+        );
+        assert_eq!(schema["properties"]["max_price"]["type"], json!(["number", "null"]));
+    }
+
+    #[test]
+    fn generate_schema_wraps_and_inlines_sub_structs() {
+        let schema = generate_schema(schema_for!(Neighborhood), "hood", true, false, true).unwrap();
+
+        assert_eq!(schema["type"], "array");
+        assert!(schema["items"].get("$defs").is_none());
+        assert_eq!(schema["items"]["properties"]["center"]["properties"]["lat"]["type"], "number");
+    }
+
+    #[test]
+    fn generate_schema_converts_nullable() {
+        let settings = SchemaSettings::openapi3();
+        let root = settings.into_generator().into_root_schema_for::<QueryIntent>();
+        let format = generate_schema(root, "intent", true, false, false).unwrap();
+
+        assert_eq!(format["schema"]["properties"]["market"]["type"], json!(["string", "null"]));
+        assert!(format["schema"]["properties"]["market"].get("nullable").is_none());
     }
 }
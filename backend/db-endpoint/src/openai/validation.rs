@@ -0,0 +1,370 @@
+use crate::openai::error::OpenAIError;
+use crate::openai::libs::ChatRequest;
+use crate::openai::lib_response::{FormatResponse, InputContent, InputItemList, ResponseRequest};
+use crate::openai::models::{capabilities, is_reasoning_model, ModelCapabilities};
+use std::ops::RangeInclusive;
+
+/// Accepted values of `reasoning.effort`
+pub const REASONING_EFFORTS: [&str; 3] = ["low", "medium", "high"];
+
+/// Accepted values of `reasoning.generate_summary`
+pub const REASONING_SUMMARIES: [&str; 2] = ["concise", "detailed"];
+
+/// Accepted values of `include`
+pub const INCLUDE_VALUES: [&str; 3] = [
+    "file_search_call.results",
+    "message.input_image.image_url",
+    "computer_call_output.output.image_url",
+];
+
+/// Accepted values of `truncation`
+pub const TRUNCATION_STRATEGIES: [&str; 2] = ["auto", "disabled"];
+
+/// Most stop sequences a chat request accepts
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+pub const MAX_METADATA_PAIRS: usize = 16;
+pub const MAX_METADATA_KEY_LEN: usize = 64;
+pub const MAX_METADATA_VALUE_LEN: usize = 512;
+
+/// Collects every invalid parameter of a request, so a single error lists
+/// all of them
+#[derive(Debug, Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn push(&mut self, problem: String) {
+        self.0.push(problem);
+    }
+
+    fn range(&mut self, name: &str, value: Option<f32>, range: RangeInclusive<f32>) {
+        if let Some(value) = value {
+            if !range.contains(&value) {
+                self.push(format!(
+                    "{} must be between {} and {}, got {}",
+                    name, range.start(), range.end(), value
+                ));
+            }
+        }
+    }
+
+    fn one_of(&mut self, name: &str, value: Option<&str>, allowed: &[&str]) {
+        if let Some(value) = value {
+            if !allowed.contains(&value) {
+                self.push(format!("{} must be one of {:?}, got `{}`", name, allowed, value));
+            }
+        }
+    }
+
+    /// Rejects sampling parameters that reasoning models do not accept
+    fn unsupported(&mut self, model: &str, name: &str, is_set: bool) {
+        if is_set {
+            self.push(format!("{} is not supported by reasoning model {}", name, model));
+        }
+    }
+
+    fn max_output(&mut self, model: &str, name: &str, value: Option<u32>) {
+        let limit = capabilities(model)
+            .map(|caps| caps.max_output_tokens)
+            .filter(|limit| *limit > 0);
+
+        if let (Some(value), Some(limit)) = (value, limit) {
+            if value > limit {
+                self.push(format!("{} is {}, {} generates at most {}", name, value, model, limit));
+            }
+        }
+    }
+
+    /// Rejects a feature the model is known not to support; unknown models
+    /// are let through
+    fn feature(&mut self, model: &str, name: &str, is_used: bool, supported: fn(&ModelCapabilities) -> bool) {
+        if let Some(caps) = capabilities(model) {
+            if is_used && !supported(caps) {
+                self.push(format!("{} is not supported by {}", name, model));
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), OpenAIError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(OpenAIError::ValidationError(self.0.join("; ")))
+        }
+    }
+}
+
+/// Checks the parameters of a Chat Completions request against the API
+/// ranges and the model
+///
+/// # Errors
+/// * `OpenAIError::ValidationError` - Lists every invalid parameter
+pub fn validate_chat_request(request: &ChatRequest) -> Result<(), OpenAIError> {
+    let model = request.model.as_str();
+    let mut problems = Problems::default();
+
+    problems.range("temperature", request.temperature, 0.0..=2.0);
+    problems.range("top_p", request.top_p, 0.0..=1.0);
+    problems.range("frequency_penalty", request.frequency_penalty, -2.0..=2.0);
+    problems.range("presence_penalty", request.presence_penalty, -2.0..=2.0);
+    problems.max_output(model, "max_completion_tokens", request.max_completion_tokens);
+
+    if request.n_completion == Some(0) {
+        problems.push("n must be at least 1".to_string());
+    }
+    if let Some(stop) = &request.stop {
+        if stop.len() > MAX_STOP_SEQUENCES {
+            problems.push(format!(
+                "stop accepts at most {} sequences, got {}",
+                MAX_STOP_SEQUENCES, stop.len()
+            ));
+        }
+    }
+
+    problems.one_of("reasoning_effort", request.reasoning_effort.as_deref(), &REASONING_EFFORTS);
+    problems.feature(model, "reasoning_effort", request.reasoning_effort.is_some(), |caps| {
+        caps.supports_reasoning_effort
+    });
+    problems.feature(model, "tools", request.tools.is_some(), |caps| caps.supports_tools);
+    problems.feature(
+        model,
+        "json_schema response_format",
+        request.response_format
+            .as_ref()
+            .is_some_and(|format| format.response_type == "json_schema"),
+        |caps| caps.supports_json_schema,
+    );
+    problems.feature(
+        model,
+        "image input",
+        request.messages
+            .iter()
+            .flatten()
+            .flat_map(|message| message.content.iter())
+            .any(|content| content.image_url.is_some()),
+        |caps| caps.supports_vision,
+    );
+
+    if is_reasoning_model(model) {
+        problems.unsupported(model, "temperature", request.temperature.is_some());
+        problems.unsupported(model, "top_p", request.top_p.is_some());
+        problems.unsupported(model, "frequency_penalty", request.frequency_penalty.is_some());
+        problems.unsupported(model, "presence_penalty", request.presence_penalty.is_some());
+    }
+
+    problems.finish()
+}
+
+/// Checks the parameters of a Responses API request against the API ranges
+/// and the model
+///
+/// # Errors
+/// * `OpenAIError::ValidationError` - Lists every invalid parameter
+pub fn validate_response_request(request: &ResponseRequest) -> Result<(), OpenAIError> {
+    let model = request.model.as_str();
+    let mut problems = Problems::default();
+
+    problems.range("temperature", request.temperature, 0.0..=2.0);
+    problems.range("top_p", request.top_p, 0.0..=1.0);
+    problems.max_output(model, "max_output_tokens", request.max_output_tokens);
+    problems.one_of("truncation", request.truncation.as_deref(), &TRUNCATION_STRATEGIES);
+
+    if let Some(reasoning) = &request.reasoning {
+        problems.one_of("reasoning.effort", reasoning.effort.as_deref(), &REASONING_EFFORTS);
+        problems.one_of(
+            "reasoning.generate_summary",
+            reasoning.generate_summary.as_deref(),
+            &REASONING_SUMMARIES,
+        );
+        problems.feature(model, "reasoning.effort", reasoning.effort.is_some(), |caps| {
+            caps.supports_reasoning_effort
+        });
+    }
+
+    problems.feature(model, "tools", request.tools.is_some(), |caps| caps.supports_tools);
+    problems.feature(
+        model,
+        "json_schema text.format",
+        matches!(
+            request.text.as_ref().and_then(|text| text.format.as_ref()),
+            Some(FormatResponse::JsonSchema(_))
+        ),
+        |caps| caps.supports_json_schema,
+    );
+    problems.feature(model, "image input", has_input_image(&request.input), |caps| {
+        caps.supports_vision
+    });
+
+    for value in request.include.iter().flatten() {
+        problems.one_of("include", Some(value), &INCLUDE_VALUES);
+    }
+
+    if let Some(metadata) = &request.metadata {
+        match metadata.as_object() {
+            Some(pairs) => {
+                if pairs.len() > MAX_METADATA_PAIRS {
+                    problems.push(format!(
+                        "metadata accepts at most {} pairs, got {}",
+                        MAX_METADATA_PAIRS, pairs.len()
+                    ));
+                }
+                for (key, value) in pairs {
+                    let value_len = value.as_str().map(|value| value.chars().count()).unwrap_or(0);
+                    if key.chars().count() > MAX_METADATA_KEY_LEN || value_len > MAX_METADATA_VALUE_LEN {
+                        problems.push(format!(
+                            "metadata `{}` exceeds {} characters for keys or {} for values",
+                            key, MAX_METADATA_KEY_LEN, MAX_METADATA_VALUE_LEN
+                        ));
+                    }
+                }
+            }
+            None => problems.push("metadata must be an object".to_string()),
+        }
+    }
+
+    if let Some(FormatResponse::JsonSchema(json_schema)) = request.text
+        .as_ref()
+        .and_then(|text| text.format.as_ref())
+    {
+        if !json_schema.schema.is_object() {
+            problems.push(format!("text.format `{}` has no schema object", json_schema.name));
+        }
+    }
+
+    if is_reasoning_model(model) {
+        problems.unsupported(model, "temperature", request.temperature.is_some());
+        problems.unsupported(model, "top_p", request.top_p.is_some());
+    }
+
+    problems.finish()
+}
+
+/// Rejects images attached to a request for a model without vision support
+///
+/// # Errors
+/// * `OpenAIError::ValidationError` - The model is known not to accept images
+pub fn validate_images(model: &str, count: usize) -> Result<(), OpenAIError> {
+    let mut problems = Problems::default();
+    problems.feature(model, "image input", count > 0, |caps| caps.supports_vision);
+    problems.finish()
+}
+
+/// Whether an input holds an `input_image`, at the top level or in a message
+fn has_input_image(input: &InputContent) -> bool {
+    match input {
+        InputContent::ItemList(items) => items.iter().any(|item| match item {
+            InputItemList::InputImage(_) => true,
+            InputItemList::InputMessage(message) => has_input_image(&message.content),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::chat::ChatOpenAI;
+    use crate::openai::response::ResponseOpenAI;
+    use crate::openai::lib_response::{InputMessage, Role};
+    use crate::openai::vision::{response_content, ImageInput};
+    use std::collections::HashMap;
+
+    /// The problems a validation reported, or an empty string if it passed
+    fn problems(result: Result<(), OpenAIError>) -> String {
+        match result {
+            Ok(()) => String::new(),
+            Err(OpenAIError::ValidationError(problems)) => problems,
+            Err(e) => panic!("expected a validation error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn chat_defaults_are_valid() {
+        assert_eq!(problems(validate_chat_request(&ChatOpenAI::new("gpt-4o-mini").request)), "");
+    }
+
+    #[test]
+    fn chat_lists_every_invalid_parameter() {
+        let request = ChatOpenAI::new("gpt-4o-mini")
+            .with_temperature(2.5)
+            .with_top_p(1.5)
+            .with_n_completion(0)
+            .with_stop(vec!["a", "b", "c", "d", "e"].into_iter().map(String::from).collect())
+            .with_max_tokens(1_000_000)
+            .request;
+
+        let problems = problems(validate_chat_request(&request));
+
+        assert_eq!(problems.split("; ").count(), 5, "{}", problems);
+        assert!(problems.contains("temperature must be between 0 and 2, got 2.5"), "{}", problems);
+        assert!(problems.contains("top_p must be between 0 and 1, got 1.5"), "{}", problems);
+        assert!(problems.contains("n must be at least 1"), "{}", problems);
+        assert!(problems.contains("stop accepts at most 4 sequences, got 5"), "{}", problems);
+        assert!(problems.contains("max_completion_tokens is 1000000"), "{}", problems);
+    }
+
+    #[test]
+    fn reasoning_models_reject_sampling_parameters() {
+        let request = ChatOpenAI::new("o3-mini").with_temperature(0.2).with_top_p(0.9).request;
+
+        let problems = problems(validate_chat_request(&request));
+
+        assert!(problems.contains("temperature is not supported by reasoning model o3-mini"), "{}", problems);
+        assert!(problems.contains("top_p is not supported by reasoning model o3-mini"), "{}", problems);
+    }
+
+    #[test]
+    fn features_are_checked_against_known_models_only() {
+        let tools = vec![serde_json::json!({ "type": "function", "function": { "name": "search_listings" } })];
+        let known = ChatOpenAI::new("o1-mini").with_tools(tools.clone()).with_reasoning_effort("low").request;
+        let unknown = ChatOpenAI::new("acme-chat").with_tools(tools).with_reasoning_effort("low").request;
+
+        let known = problems(validate_chat_request(&known));
+
+        assert!(known.contains("tools is not supported by o1-mini"), "{}", known);
+        assert!(known.contains("reasoning_effort is not supported by o1-mini"), "{}", known);
+        assert_eq!(problems(validate_chat_request(&unknown)), "");
+    }
+
+    #[test]
+    fn images_need_a_vision_model() {
+        assert_eq!(problems(validate_images("gpt-4o-mini", 2)), "");
+        assert_eq!(problems(validate_images("gpt-3.5-turbo", 0)), "");
+        assert_eq!(problems(validate_images("gpt-3.5-turbo", 1)), "image input is not supported by gpt-3.5-turbo");
+    }
+
+    #[test]
+    fn responses_check_enumerations_and_metadata() {
+        let metadata: HashMap<String, String> = (0..17).map(|index| (format!("key{}", index), "x".repeat(600))).collect();
+        let request = ResponseOpenAI::new("gpt-4o-mini")
+            .with_truncation("sometimes")
+            .with_include(vec!["message.output_text".to_string()])
+            .with_reasoning("extreme")
+            .with_metadata(metadata)
+            .request;
+
+        let problems = problems(validate_response_request(&request));
+
+        assert!(problems.contains("truncation must be one of [\"auto\", \"disabled\"], got `sometimes`"), "{}", problems);
+        assert!(problems.contains("include must be one of"), "{}", problems);
+        assert!(problems.contains("reasoning.effort must be one of"), "{}", problems);
+        assert!(problems.contains("metadata accepts at most 16 pairs, got 17"), "{}", problems);
+        assert!(problems.contains("exceeds 64 characters for keys or 512 for values"), "{}", problems);
+    }
+
+    #[test]
+    fn responses_find_images_inside_messages() {
+        let request = ResponseOpenAI::new("gpt-3.5-turbo").with_user_message("What is this?").request;
+        let mut with_image = request.clone();
+        with_image.input = InputContent::ItemList(vec![InputItemList::InputMessage(InputMessage {
+            content: InputContent::ItemList(response_content("What is this?", &[ImageInput::url("https://example.com/loft.jpg")])),
+            role: Role::User,
+            status: None,
+            type_: Some("message".to_string()),
+        })]);
+
+        assert_eq!(problems(validate_response_request(&request)), "");
+        assert_eq!(problems(validate_response_request(&with_image)), "image input is not supported by gpt-3.5-turbo");
+    }
+}
@@ -0,0 +1,124 @@
+use crate::openai::error::OpenAIError;
+use crate::openai::libs::{ImageDetail, ImageUrl, InputContent};
+use crate::openai::lib_response::{InputImage, InputItemList, InputText};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::path::Path;
+
+/// An image to send to a vision-capable model
+///
+/// # Fields
+/// * `url` - An `https://` URL or a base64 `data:` URI
+/// * `detail` - Resolution the model looks at the image with
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageInput {
+    pub url: String,
+    pub detail: ImageDetail,
+}
+
+#[allow(dead_code)]
+impl ImageInput {
+    /// An image the API downloads itself, such as `Host.host_picture_url`
+    pub fn url(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            detail: ImageDetail::Auto,
+        }
+    }
+
+    /// An image embedded in the request as a base64 data URI
+    ///
+    /// # Arguments
+    /// * `bytes` - Encoded image content
+    /// * `media_type` - MIME type such as `image/jpeg` or `image/png`
+    pub fn from_bytes(bytes: &[u8], media_type: &str) -> Self {
+        Self {
+            url: format!("data:{};base64,{}", media_type, STANDARD.encode(bytes)),
+            detail: ImageDetail::Auto,
+        }
+    }
+
+    /// Reads a local JPEG, PNG, GIF or WebP file into a data URI
+    pub fn from_file(path: &Path) -> Result<Self, OpenAIError> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase())
+            .unwrap_or_default();
+
+        let media_type = match extension.as_str() {
+            "jpg" | "jpeg" => "image/jpeg",
+            "png" => "image/png",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            _ => {
+                return Err(OpenAIError::BadRequestError(format!(
+                    "Unsupported image type for {}", path.display()
+                )));
+            }
+        };
+
+        let bytes = std::fs::read(path).map_err(|e| {
+            OpenAIError::BadRequestError(format!("Unable to read {}: {}", path.display(), e))
+        })?;
+
+        Ok(Self::from_bytes(&bytes, media_type))
+    }
+
+    pub fn with_detail(mut self, detail: ImageDetail) -> Self {
+        self.detail = detail;
+        self
+    }
+
+    /// Chat Completions content part
+    pub fn to_chat_content(&self) -> InputContent {
+        InputContent {
+            content_type: "image_url".to_string(),
+            text: None,
+            source: None,
+            image_url: Some(ImageUrl {
+                url: self.url.clone(),
+                detail: Some(self.detail),
+            }),
+        }
+    }
+
+    /// Responses API input item
+    pub fn to_response_input(&self) -> InputItemList {
+        InputItemList::InputImage(InputImage {
+            detail: self.detail.as_str().to_string(),
+            type_: "input_image".to_string(),
+            file_id: None,
+            image_url: Some(self.url.clone()),
+        })
+    }
+}
+
+/// Chat Completions content mixing a text part and image parts
+pub fn chat_content(text: &str, images: &[ImageInput]) -> Vec<InputContent> {
+    let mut content = Vec::with_capacity(images.len() + 1);
+    if !text.is_empty() {
+        content.push(InputContent {
+            content_type: "text".to_string(),
+            text: Some(text.to_string()),
+            source: None,
+            image_url: None,
+        });
+    }
+    content.extend(images.iter().map(ImageInput::to_chat_content));
+    content
+}
+
+/// Responses API content mixing an `input_text` part and `input_image` parts
+pub fn response_content(text: &str, images: &[ImageInput]) -> Vec<InputItemList> {
+    let mut content = Vec::with_capacity(images.len() + 1);
+    if !text.is_empty() {
+        content.push(InputItemList::InputText(InputText {
+            text: text.to_string(),
+            type_: "input_text".to_string(),
+        }));
+    }
+    content.extend(images.iter().map(ImageInput::to_response_input));
+    content
+}
@@ -0,0 +1,545 @@
+use crate::chunks::{ChunkWriter, CHUNK_FIELDS};
+use crate::embeddings::{EmbeddingError, ListingEmbedder};
+use futures::{StreamExt, TryStreamExt};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::change_stream::event::{ChangeStreamEvent, ResumeToken};
+use mongodb::error::ErrorKind;
+use mongodb::Collection;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Caller re-embedding usage is recorded under
+const REEMBED_CALLER: &str = "change_stream";
+
+/// Server error when a stored resume token has left the oplog
+const CHANGE_STREAM_HISTORY_LOST: i32 = 286;
+
+/// Longest wait before the change stream is reopened after an error
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// How the re-embedding worker paces itself
+///
+/// # Fields
+/// * `batch_size` - Events handled before the resume token is saved; the
+///   stream is not read further until a batch is done, so a slow embedding
+///   API holds the backlog in the oplog rather than in memory
+/// * `batch_window` - How long a batch waits for more events after its first
+/// * `concurrency` - Embedding requests kept in flight
+/// * `max_per_minute` - Listings refreshed per minute at most
+/// * `max_attempts` - Times a listing is tried before it is dead-lettered
+///   and the stream moves past it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReembedOptions {
+    pub batch_size: usize,
+    pub batch_window: Duration,
+    pub concurrency: usize,
+    pub max_per_minute: u32,
+    pub max_attempts: u32,
+}
+
+impl Default for ReembedOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 32,
+            batch_window: Duration::from_secs(2),
+            concurrency: 4,
+            max_per_minute: 120,
+            max_attempts: 5,
+        }
+    }
+}
+
+impl ReembedOptions {
+    /// Reads `REEMBED_BATCH_SIZE`, `REEMBED_CONCURRENCY`,
+    /// `REEMBED_MAX_PER_MINUTE` and `REEMBED_MAX_ATTEMPTS`, keeping the
+    /// default of any unset or invalid one
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u32>().ok())
+                .filter(|value| *value > 0)
+        };
+        let defaults = Self::default();
+
+        Self {
+            batch_size: var("REEMBED_BATCH_SIZE").map_or(defaults.batch_size, |value| value as usize),
+            batch_window: defaults.batch_window,
+            concurrency: var("REEMBED_CONCURRENCY").map_or(defaults.concurrency, |value| value as usize),
+            max_per_minute: var("REEMBED_MAX_PER_MINUTE").unwrap_or(defaults.max_per_minute),
+            max_attempts: var("REEMBED_MAX_ATTEMPTS").unwrap_or(defaults.max_attempts),
+        }
+    }
+}
+
+/// A listing whose refresh failed, with the error
+type Failure = (Bson, EmbeddingError);
+
+/// Failed refreshes per listing, kept across stream restarts so a listing
+/// that keeps failing is given up instead of holding the resume token
+#[derive(Debug)]
+struct Attempts {
+    max_attempts: u32,
+    failed: HashMap<String, u32>,
+}
+
+impl Attempts {
+    fn new(max_attempts: u32) -> Self {
+        Self { max_attempts: max_attempts.max(1), failed: HashMap::new() }
+    }
+
+    /// Counts the failures of a batch of `ids` and forgets the listings that
+    /// went through; returns the failures to retry, and those out of
+    /// attempts with the number of attempts made
+    fn settle(
+        &mut self,
+        ids: &[Bson],
+        failures: Vec<Failure>,
+    ) -> (Vec<Failure>, Vec<(Failure, u32)>) {
+        for id in ids {
+            if failures.iter().all(|(failed, _)| failed != id) {
+                self.failed.remove(&id.to_string());
+            }
+        }
+
+        let mut retry = Vec::new();
+        let mut exhausted = Vec::new();
+        for (id, error) in failures {
+            let key = id.to_string();
+            let attempts = self.failed.entry(key.clone()).or_default();
+            *attempts += 1;
+            if *attempts >= self.max_attempts {
+                let attempts = *attempts;
+                self.failed.remove(&key);
+                exhausted.push(((id, error), attempts));
+            } else {
+                retry.push((id, error));
+            }
+        }
+        (retry, exhausted)
+    }
+}
+
+/// Spaces acquisitions evenly so no more than a fixed number pass per minute
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn per_minute(max: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / max.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the next free slot
+    async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Change stream stage keeping inserts, replacements and deletes, and
+/// updates that set or remove one of the top-level `fields` or anything
+/// below them
+///
+/// Updated paths arrive as keys such as `address.market`, so the stage
+/// compares their first segment. The worker's own writes only touch the
+/// vector fields and their metadata, so they never match and cannot feed
+/// back into the stream.
+pub fn change_filter(fields: &[&str]) -> Document {
+    let touches = |paths: Document| doc! {
+        "$anyElementTrue": [{
+            "$map": {
+                "input": paths,
+                "in": { "$in": [{ "$arrayElemAt": [{ "$split": ["$$this", "."] }, 0] }, fields.to_vec()] },
+            }
+        }]
+    };
+    let updated = doc! {
+        "$map": {
+            "input": { "$objectToArray": { "$ifNull": ["$updateDescription.updatedFields", {}] } },
+            "in": "$$this.k",
+        }
+    };
+    let removed = doc! { "$ifNull": ["$updateDescription.removedFields", []] };
+
+    doc! {
+        "$match": {
+            "$or": [
+                { "operationType": { "$in": ["insert", "replace", "delete"] } },
+                {
+                    "operationType": "update",
+                    "$expr": { "$or": [touches(updated), touches(removed)] },
+                },
+            ]
+        }
+    }
+}
+
+/// Keeps the vectors of edited listings current by following the
+/// collection's change stream
+///
+/// Events are handled in batches: the listings of a batch are refreshed
+/// through `ListingEmbedder::refresh`, which only calls the embedding API
+/// when the embedded text changed, and deleted listings lose their passages.
+/// The resume token is then saved after the last event preceding the first
+/// listing that still failed after the request layer's retries; when one
+/// failed, the stream is reopened from there after a delay, so the listing
+/// is retried and the refreshes that already went through are no-ops. A
+/// listing that failed `max_attempts` times is dead-lettered instead, and
+/// the token moves past it.
+#[derive(Debug, Clone)]
+pub struct ReembedWorker {
+    collection: Collection<Document>,
+    tokens: Collection<Document>,
+    dead_letters: Option<Collection<Document>>,
+    embedder: ListingEmbedder,
+    chunks: Option<ChunkWriter>,
+    options: ReembedOptions,
+    limiter: Arc<RateLimiter>,
+    attempts: Arc<Mutex<Attempts>>,
+}
+
+impl ReembedWorker {
+    /// # Arguments
+    /// * `collection` - Listings to watch
+    /// * `tokens` - Collection the resume token is saved in, keyed by the
+    ///   watched collection's name
+    pub fn new<T: Send + Sync>(
+        collection: &Collection<T>,
+        tokens: Collection<Document>,
+        embedder: ListingEmbedder,
+        options: ReembedOptions,
+    ) -> Self {
+        Self {
+            collection: collection.clone_with_type(),
+            tokens,
+            dead_letters: None,
+            embedder,
+            chunks: None,
+            limiter: Arc::new(RateLimiter::per_minute(options.max_per_minute)),
+            attempts: Arc::new(Mutex::new(Attempts::new(options.max_attempts))),
+            options,
+        }
+    }
+
+    /// Records the listings given up on in `dead_letters` rather than only
+    /// logging them
+    pub fn with_dead_letters(mut self, dead_letters: Collection<Document>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Also re-indexes the passages of edited listings
+    pub fn with_chunks(mut self, chunks: ChunkWriter) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Top-level fields whose edits trigger a refresh
+    fn watched_fields(&self) -> Vec<&'static str> {
+        let mut fields = self.embedder.watched_fields();
+        if self.chunks.is_some() {
+            fields.extend(CHUNK_FIELDS.iter().filter(|field| !fields.contains(field)).collect::<Vec<_>>());
+        }
+        fields
+    }
+
+    /// Follows the change stream until the task is dropped, reopening it
+    /// with a growing delay after errors
+    pub async fn run(self) {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match self.follow().await {
+                Ok(()) => {
+                    tracing::warn!("Change stream on {} ended, reopening", self.collection.name());
+                    delay = Duration::from_secs(1);
+                }
+                Err(EmbeddingError::Database(e)) if history_lost(&e) => {
+                    tracing::warn!(
+                        "Resume token for {} is no longer in the oplog, edits made meanwhile are not re-embedded",
+                        self.collection.name()
+                    );
+                    if let Err(e) = self.tokens.delete_one(doc! { "_id": self.collection.name() }).await {
+                        tracing::error!("Failed to clear resume token: {}", e);
+                    }
+                    continue;
+                }
+                Err(e) => tracing::error!("Re-embedding worker failed: {}", e),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+        }
+    }
+
+    /// Opens the change stream after the saved token and handles batches
+    /// until it ends or fails
+    async fn follow(&self) -> Result<(), EmbeddingError> {
+        let token = self.load_token().await?;
+        let resuming = token.is_some();
+        let mut stream = self.collection
+            .watch()
+            .pipeline([change_filter(&self.watched_fields())])
+            .resume_after(token)
+            .batch_size(self.options.batch_size as u32)
+            .await?;
+        tracing::info!(
+            "Watching {} for edits to re-embed{}",
+            self.collection.name(),
+            if resuming { ", resuming after the saved token" } else { "" }
+        );
+
+        loop {
+            let Some(first) = stream.try_next().await? else {
+                return Ok(());
+            };
+
+            let mut events = vec![first];
+            let deadline = Instant::now() + self.options.batch_window;
+            while events.len() < self.options.batch_size {
+                match tokio::time::timeout_at(deadline, stream.try_next()).await {
+                    Ok(event) => match event? {
+                        Some(event) => events.push(event),
+                        None => break,
+                    },
+                    Err(_) => break,
+                }
+            }
+
+            let failures = self.handle(&events).await;
+            let ids: Vec<Bson> = events.iter().filter_map(listing_id).cloned().collect();
+            let (mut failures, exhausted) = self.attempts.lock().await.settle(&ids, failures);
+            for ((id, error), attempts) in exhausted {
+                self.dead_letter(&id, &error, attempts).await?;
+            }
+
+            let keys: Vec<Option<&Bson>> = events.iter().map(listing_id).collect();
+            let failed: Vec<&Bson> = failures.iter().map(|(id, _)| id).collect();
+            let handled = handled_prefix(&keys, &failed);
+            if let Some(last) = handled.checked_sub(1).map(|index| &events[index]) {
+                self.save_token(&last.id).await?;
+            }
+            if let Some((_, error)) = failures.pop() {
+                return Err(error);
+            }
+        }
+    }
+
+    /// Refreshes the distinct listings of a batch; the listings that failed
+    async fn handle(&self, events: &[ChangeStreamEvent<Document>]) -> Vec<Failure> {
+        let mut ids: Vec<Bson> = Vec::new();
+        for id in events.iter().filter_map(listing_id) {
+            if !ids.contains(id) {
+                ids.push(id.clone());
+            }
+        }
+
+        let results: Vec<(Bson, Result<bool, EmbeddingError>)> = futures::stream::iter(ids)
+            .map(|id| async move {
+                self.limiter.acquire().await;
+                let mut result = self.embedder.refresh(&id, REEMBED_CALLER).await;
+                if let (Ok(_), Some(chunks)) = (&result, &self.chunks) {
+                    if let Err(e) = chunks.refresh(&id).await {
+                        result = Err(e);
+                    }
+                }
+                (id, result)
+            })
+            .buffer_unordered(self.options.concurrency)
+            .collect()
+            .await;
+
+        let mut refreshed = 0;
+        let mut failures = Vec::new();
+        for (id, result) in results {
+            match result {
+                Ok(true) => refreshed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to re-embed listing {}: {}", id, e);
+                    failures.push((id, e));
+                }
+            }
+        }
+        tracing::info!("Re-embedded {} of {} edited listings", refreshed, events.len());
+        failures
+    }
+
+    /// Gives up on a listing: logs it and, when configured, records it in
+    /// the dead-letter collection for a later backfill
+    async fn dead_letter(&self, id: &Bson, error: &EmbeddingError, attempts: u32) -> Result<(), EmbeddingError> {
+        tracing::error!(
+            "Giving up re-embedding listing {} after {} attempts: {}",
+            id, attempts, error
+        );
+        if let Some(dead_letters) = &self.dead_letters {
+            dead_letters
+                .insert_one(doc! {
+                    "collection": self.collection.name(),
+                    "listing_id": id.clone(),
+                    "attempts": attempts as i64,
+                    "error": error.to_string(),
+                    "failed_at": chrono::Utc::now().to_rfc3339(),
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn load_token(&self) -> Result<Option<ResumeToken>, EmbeddingError> {
+        let saved = self.tokens.find_one(doc! { "_id": self.collection.name() }).await?;
+        Ok(saved
+            .and_then(|saved| saved.get("token").cloned())
+            .and_then(|token| mongodb::bson::from_bson(token).ok()))
+    }
+
+    async fn save_token(&self, token: &ResumeToken) -> Result<(), EmbeddingError> {
+        let token = mongodb::bson::to_bson(token)?;
+        self.tokens
+            .update_one(
+                doc! { "_id": self.collection.name() },
+                doc! { "$set": { "token": token, "updated_at": chrono::Utc::now().to_rfc3339() } },
+            )
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+}
+
+fn listing_id(event: &ChangeStreamEvent<Document>) -> Option<&Bson> {
+    event.document_key.as_ref()?.get("_id")
+}
+
+/// Number of leading events whose listing did not fail, so the resume token
+/// of the last of them can be saved without skipping a failed listing
+fn handled_prefix(keys: &[Option<&Bson>], failed: &[&Bson]) -> usize {
+    keys.iter()
+        .position(|key| key.is_some_and(|key| failed.contains(&key)))
+        .unwrap_or(keys.len())
+}
+
+fn history_lost(error: &mongodb::error::Error) -> bool {
+    matches!(error.kind.as_ref(), ErrorKind::Command(command) if command.code == CHANGE_STREAM_HISTORY_LOST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_filter_compares_the_root_of_updated_paths() {
+        let stage = change_filter(&["name", "address"]);
+        let update = stage.get_document("$match").unwrap().get_array("$or").unwrap()[1].as_document().unwrap().clone();
+
+        assert_eq!(update.get_str("operationType"), Ok("update"));
+        let touched = update.get_document("$expr").unwrap().get_array("$or").unwrap()[0].as_document().unwrap().clone();
+        assert_eq!(
+            touched,
+            doc! {
+                "$anyElementTrue": [{
+                    "$map": {
+                        "input": {
+                            "$map": {
+                                "input": { "$objectToArray": { "$ifNull": ["$updateDescription.updatedFields", {}] } },
+                                "in": "$$this.k",
+                            }
+                        },
+                        "in": { "$in": [{ "$arrayElemAt": [{ "$split": ["$$this", "."] }, 0] }, ["name", "address"]] },
+                    }
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn change_filter_keeps_deletes() {
+        let stage = change_filter(&["name"]);
+        let kept = stage.get_document("$match").unwrap().get_array("$or").unwrap()[0].as_document().unwrap().clone();
+
+        assert_eq!(kept, doc! { "operationType": { "$in": ["insert", "replace", "delete"] } });
+    }
+
+    #[test]
+    fn handled_prefix_stops_before_the_first_failed_listing() {
+        let (a, b, c) = (Bson::Int32(1), Bson::Int32(2), Bson::Int32(3));
+        let keys = [Some(&a), None, Some(&b), Some(&c), Some(&a)];
+
+        assert_eq!(handled_prefix(&keys, &[]), 5);
+        assert_eq!(handled_prefix(&keys, &[&c]), 3);
+        assert_eq!(handled_prefix(&keys, &[&c, &b]), 2);
+        assert_eq!(handled_prefix(&keys, &[&a]), 0);
+    }
+
+    #[test]
+    fn a_listing_that_always_fails_is_given_up_and_later_changes_go_through() {
+        let (a, b, c) = (Bson::Int32(1), Bson::Int32(2), Bson::Int32(3));
+        let mut attempts = Attempts::new(3);
+        let mut handled = Vec::new();
+        let mut dead = Vec::new();
+
+        // The stream replays from the saved token until `b` is given up
+        let mut pending = vec![a.clone(), b.clone(), c.clone()];
+        for _ in 0..5 {
+            if pending.is_empty() {
+                break;
+            }
+            let failures = pending
+                .iter()
+                .filter(|id| **id == b)
+                .map(|id| (id.clone(), EmbeddingError::Config("boom".to_string())))
+                .collect();
+            let (retry, exhausted) = attempts.settle(&pending, failures);
+            dead.extend(exhausted.into_iter().map(|((id, _), attempts)| (id, attempts)));
+
+            let keys: Vec<Option<&Bson>> = pending.iter().map(Some).collect();
+            let failed: Vec<&Bson> = retry.iter().map(|(id, _)| id).collect();
+            let prefix = handled_prefix(&keys, &failed);
+            handled.extend(pending.drain(..prefix));
+        }
+
+        assert_eq!(dead, [(b.clone(), 3)]);
+        assert_eq!(handled, [a, b, c]);
+        assert!(attempts.failed.is_empty());
+    }
+
+    #[test]
+    fn attempts_are_forgotten_once_a_listing_goes_through() {
+        let a = Bson::Int32(1);
+        let mut attempts = Attempts::new(2);
+
+        let ids = std::slice::from_ref(&a);
+        let failed = || vec![(a.clone(), EmbeddingError::Config("boom".to_string()))];
+
+        let (retry, _) = attempts.settle(ids, failed());
+        assert_eq!(retry.len(), 1);
+        attempts.settle(ids, Vec::new());
+        let (retry, exhausted) = attempts.settle(ids, failed());
+
+        assert_eq!((retry.len(), exhausted.len()), (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_spaces_acquisitions() {
+        let limiter = RateLimiter::per_minute(3000);
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            limiter.acquire().await;
+        }
+
+        // 20ms apart, the first slot is immediate
+        assert!(start.elapsed() >= Duration::from_millis(60), "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,603 @@
+use crate::ledger::UsageLedger;
+use crate::openai::chat::ChatOpenAI;
+use crate::openai::error::OpenAIError;
+use crate::openai::tokens::{count_tokens, fit_items, truncate_to_tokens, TokenBudget};
+use crate::openai::utils::fnv1a;
+use crate::search::SearchHit;
+use crate::templates::strings_at;
+use mongodb::bson::Document;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Chat model judging candidates unless `RERANK_MODEL` names another
+pub const DEFAULT_RERANK_MODEL: &str = "gpt-4o-mini";
+
+/// Highest relevance the model can give
+pub const MAX_RELEVANCE: u32 = 10;
+
+/// Tokens of a candidate's summary sent to the model
+const SUMMARY_TOKENS: usize = 150;
+
+/// Judgements kept in the cache
+const CACHE_CAPACITY: usize = 10_000;
+
+/// How long a judgement is reused
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+const RERANK_PROMPT: &str = "You rank holiday rental listings for a search query. \
+For every numbered candidate, rate from 0 to 10 how well it matches what the query asks for: \
+10 is exactly what was asked, 0 is unrelated. Judge the stated requirements (location, size, \
+amenities, type of place) over general closeness of topic. Give one short reason per candidate \
+and judge every candidate, in any order.";
+
+const JUSTIFY_PROMPT: &str = "You explain holiday rental search results. For every numbered listing, \
+write one sentence telling the user why it fits their query, citing only facts stated in the listing. \
+Do not praise or speculate, and write about every listing, in any order.";
+
+/// Longest time a request may ask to wait for the model
+pub const MAX_RERANK_BUDGET: Duration = Duration::from_secs(10);
+
+/// What the model reads of a candidate, as labelled lines of the values at
+/// these paths: short facts first, so the summary limit cuts into the free
+/// text
+const CANDIDATE_FIELDS: [(&str, &[&str]); 6] = [
+    ("Name", &["name"]),
+    ("Type", &["property_type", "room_type"]),
+    ("Market", &["address.market"]),
+    ("Amenities", &["amenities"]),
+    ("Summary", &["summary"]),
+    ("Description", &["description"]),
+];
+
+#[allow(dead_code)]
+#[derive(Debug, thiserror::Error)]
+pub enum RerankError {
+    #[error("Rerank Error: The model call failed. {0}")]
+    Model(#[from] OpenAIError),
+
+    #[error("Rerank Error: The model judged {judged} of {expected} candidates")]
+    Incomplete { judged: usize, expected: usize },
+}
+
+/// How reranking is bounded
+///
+/// # Fields
+/// * `candidates` - Top hits sent to the model; the rest keep their order
+///   after them
+/// * `budget` - Time a request waits for the model before keeping the
+///   vector order
+#[derive(Debug, Clone, PartialEq)]
+pub struct RerankOptions {
+    pub candidates: usize,
+    pub budget: Duration,
+}
+
+impl Default for RerankOptions {
+    fn default() -> Self {
+        Self {
+            candidates: 20,
+            budget: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RerankOptions {
+    /// Reads `RERANK_CANDIDATES` and `RERANK_BUDGET_MS`, keeping the default
+    /// of any unset or invalid one
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|value| *value > 0)
+        };
+        let defaults = Self::default();
+
+        Self {
+            candidates: var("RERANK_CANDIDATES").map_or(defaults.candidates, |value| value as usize),
+            budget: var("RERANK_BUDGET_MS").map_or(defaults.budget, Duration::from_millis),
+        }
+    }
+
+    /// Budget of a request asking for `requested_ms`, at most
+    /// `MAX_RERANK_BUDGET`; `budget` when it asks for none
+    pub fn budget_for(&self, requested_ms: Option<u64>) -> Duration {
+        requested_ms.map_or(self.budget, |requested| Duration::from_millis(requested).min(MAX_RERANK_BUDGET))
+    }
+}
+
+/// The model's judgement of one candidate
+///
+/// # Fields
+/// * `relevance` - 0 (unrelated) to `MAX_RELEVANCE` (exactly what was asked)
+/// * `reason` - Short explanation from the model
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Relevance {
+    pub relevance: u32,
+    pub reason: String,
+}
+
+/// How a result list was reranked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RerankOutcome {
+    /// Ordered by the model's judgements, some of them new
+    Reranked,
+    /// Ordered by judgements that were all cached
+    Cached,
+    /// The model did not answer within the budget; vector order kept
+    OverBudget,
+    /// The model call failed; vector order kept
+    Failed,
+}
+
+/// A hit with the model's judgement, when there is one
+#[derive(Debug, Clone, PartialEq)]
+pub struct RerankedHit {
+    pub hit: SearchHit,
+    pub relevance: Option<Relevance>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reranked {
+    pub hits: Vec<RerankedHit>,
+    pub outcome: RerankOutcome,
+}
+
+/// Answer schema sent as the structured output format
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RerankAnswer {
+    judgements: Vec<Judgement>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct Judgement {
+    /// Number the candidate was listed under
+    candidate: u32,
+    /// 0 when unrelated to the query, 10 when exactly what was asked for
+    relevance: u32,
+    /// One short sentence on why
+    reason: String,
+}
+
+/// Answer schema of justification requests
+#[derive(Debug, Deserialize, JsonSchema)]
+struct JustifyAnswer {
+    justifications: Vec<Justification>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct Justification {
+    /// Number the listing was listed under
+    listing: u32,
+    /// One sentence on why the listing fits the query
+    sentence: String,
+}
+
+/// Judgements by query and candidate, shared by every clone of a `Reranker`
+#[derive(Debug, Clone, Default)]
+struct RerankCache {
+    entries: Arc<Mutex<HashMap<u64, (Instant, Relevance)>>>,
+}
+
+impl RerankCache {
+    fn get(&self, key: u64) -> Option<Relevance> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key)
+            .filter(|(stored, _)| stored.elapsed() < CACHE_TTL)
+            .map(|(_, relevance)| relevance.clone())
+    }
+
+    /// Stores a judgement, dropping expired ones and then the oldest when full
+    fn insert(&self, key: u64, relevance: Relevance) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CACHE_CAPACITY && !entries.contains_key(&key) {
+            entries.retain(|_, (stored, _)| stored.elapsed() < CACHE_TTL);
+            if entries.len() >= CACHE_CAPACITY {
+                let oldest = entries.iter().min_by_key(|(_, (stored, _))| *stored).map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, (Instant::now(), relevance));
+    }
+}
+
+/// Reorders vector search hits by how well a chat model judges them to
+/// answer the query
+///
+/// The top `RerankOptions::candidates` hits are summarized and sent with the
+/// query in one structured output request. Judgements are cached per query
+/// and candidate summary, so repeated queries only send what changed. When
+/// the model does not answer within the budget the vector order is kept,
+/// and the request carries on in the background to fill the cache for the
+/// next search.
+///
+/// The same client writes the one-sentence justifications of search
+/// explanations, which are not cached.
+#[derive(Debug, Clone)]
+pub struct Reranker {
+    chat: ChatOpenAI,
+    options: RerankOptions,
+    usage: UsageLedger,
+    cache: RerankCache,
+}
+
+impl Reranker {
+    /// # Arguments
+    /// * `chat` - Client judging candidates, cloned per request
+    /// * `usage` - Ledger the chat usage is recorded in
+    pub fn new(chat: ChatOpenAI, options: RerankOptions, usage: UsageLedger) -> Self {
+        Self {
+            chat,
+            options,
+            usage,
+            cache: RerankCache::default(),
+        }
+    }
+
+    pub fn options(&self) -> &RerankOptions {
+        &self.options
+    }
+
+    /// Reranks `hits` for `query`, waiting at most `budget` for the model
+    ///
+    /// Hits are only reordered when every candidate has a judgement, ties
+    /// keeping their vector order; otherwise they come back in vector order
+    /// with whatever judgements were cached.
+    pub async fn rerank(&self, query: &str, mut hits: Vec<SearchHit>, budget: Duration, caller: &str) -> Reranked {
+        let rest = hits.split_off(hits.len().min(self.options.candidates));
+        let model = self.chat.request.model.clone();
+        let summaries: Vec<String> = hits.iter().map(|hit| self.summary(&hit.listing)).collect();
+        let keys: Vec<u64> = summaries.iter().map(|summary| cache_key(&model, query, summary)).collect();
+
+        let mut judged: Vec<Option<Relevance>> = keys.iter().map(|key| self.cache.get(*key)).collect();
+        let missing: Vec<usize> = (0..judged.len()).filter(|index| judged[*index].is_none()).collect();
+
+        let outcome = if missing.is_empty() {
+            RerankOutcome::Cached
+        } else {
+            let reranker = self.clone();
+            let query = query.to_string();
+            let caller = caller.to_string();
+            let pending: Vec<(u64, String)> = missing.iter().map(|index| (keys[*index], summaries[*index].clone())).collect();
+            // Spawned so a request over budget still finishes and caches its answer
+            let judging = tokio::spawn(async move { reranker.judge(&query, &pending, &caller).await });
+
+            match tokio::time::timeout(budget, judging).await {
+                Ok(Ok(Ok(relevances))) => {
+                    for (index, relevance) in missing.into_iter().zip(relevances) {
+                        judged[index] = Some(relevance);
+                    }
+                    RerankOutcome::Reranked
+                }
+                Ok(Ok(Err(e))) => {
+                    tracing::warn!("Failed to rerank {} candidates: {}", hits.len(), e);
+                    RerankOutcome::Failed
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("Rerank task failed: {}", e);
+                    RerankOutcome::Failed
+                }
+                Err(_) => {
+                    tracing::info!("Rerank took longer than {:?}, keeping the vector order", budget);
+                    RerankOutcome::OverBudget
+                }
+            }
+        };
+
+        let mut reranked: Vec<RerankedHit> = hits
+            .into_iter()
+            .zip(judged)
+            .map(|(hit, relevance)| RerankedHit { hit, relevance })
+            .collect();
+        if matches!(outcome, RerankOutcome::Reranked | RerankOutcome::Cached) {
+            reranked.sort_by_key(|hit| std::cmp::Reverse(hit.relevance.as_ref().map_or(0, |relevance| relevance.relevance)));
+        }
+        reranked.extend(rest.into_iter().map(|hit| RerankedHit { hit, relevance: None }));
+
+        Reranked { hits: reranked, outcome }
+    }
+
+    /// One sentence per listing on why it fits `query`, written by the model
+    /// from the listing's fields
+    ///
+    /// Listings the model skipped get `None`, and so do the last ones when
+    /// they do not fit in the model's context window, and all of them when
+    /// the request fails or takes longer than `budget`.
+    pub async fn justify(&self, query: &str, listings: &[&Document], budget: Duration, caller: &str) -> Vec<Option<String>> {
+        let mut sentences: Vec<Option<String>> = vec![None; listings.len()];
+        if listings.is_empty() {
+            return sentences;
+        }
+
+        let model = &self.chat.request.model;
+        let tokens = TokenBudget::for_model(model, self.chat.request.max_completion_tokens)
+            .reserve(count_tokens(model, JUSTIFY_PROMPT) + count_tokens(model, query));
+        let listed = numbered_within(model, listings.iter().map(|listing| self.summary(listing)).collect(), &tokens);
+        if listed.len() < listings.len() {
+            tracing::warn!("Only {} of {} listings fit in the justification prompt", listed.len(), listings.len());
+        }
+        let prompt = format!("Query: {}\n\nListings:\n\n{}", query.trim(), listed.join("\n\n"));
+        let request = self.chat
+            .clone()
+            .with_system_prompt(JUSTIFY_PROMPT)
+            .with_temperature(0.0)
+            .with_usage_sink(self.usage.for_caller(caller))
+            .invoke_structured::<JustifyAnswer>(&prompt);
+
+        let answer = match tokio::time::timeout(budget, request).await {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(e)) => {
+                tracing::warn!("Failed to justify {} listings: {}", listings.len(), e);
+                return sentences;
+            }
+            Err(_) => {
+                tracing::info!("Justifications took longer than {:?}, leaving them out", budget);
+                return sentences;
+            }
+        };
+
+        for justification in answer.justifications {
+            if let Some(slot) = (justification.listing as usize).checked_sub(1).and_then(|index| sentences.get_mut(index)) {
+                *slot = Some(justification.sentence.trim().to_string());
+            }
+        }
+        sentences
+    }
+
+    /// What the model reads of a listing, cut to `SUMMARY_TOKENS`
+    fn summary(&self, listing: &Document) -> String {
+        truncate_to_tokens(&self.chat.request.model, &candidate_text(listing), SUMMARY_TOKENS)
+    }
+
+    /// Asks the model to judge `candidates`, given as cache keys and
+    /// summaries, and caches every judgement it returns
+    ///
+    /// # Errors
+    /// * `RerankError::Model` - The request failed or the answer does not
+    ///   match the schema
+    /// * `RerankError::Incomplete` - Some candidates were left unjudged
+    async fn judge(&self, query: &str, candidates: &[(u64, String)], caller: &str) -> Result<Vec<Relevance>, RerankError> {
+        let listed: Vec<String> = candidates
+            .iter()
+            .enumerate()
+            .map(|(index, (_, summary))| format!("[{}]\n{}", index + 1, summary))
+            .collect();
+        let prompt = format!("Query: {}\n\nCandidates:\n\n{}", query.trim(), listed.join("\n\n"));
+
+        let answer = self.chat
+            .clone()
+            .with_system_prompt(RERANK_PROMPT)
+            .with_temperature(0.0)
+            .with_usage_sink(self.usage.for_caller(caller))
+            .invoke_structured::<RerankAnswer>(&prompt)
+            .await?;
+
+        let mut relevances: Vec<Option<Relevance>> = vec![None; candidates.len()];
+        for judgement in answer.judgements {
+            let Some(slot) = (judgement.candidate as usize).checked_sub(1).and_then(|index| relevances.get_mut(index)) else {
+                continue;
+            };
+            let relevance = Relevance {
+                relevance: judgement.relevance.min(MAX_RELEVANCE),
+                reason: judgement.reason.trim().to_string(),
+            };
+            self.cache.insert(candidates[judgement.candidate as usize - 1].0, relevance.clone());
+            *slot = Some(relevance);
+        }
+
+        let judged = relevances.iter().flatten().count();
+        if judged < candidates.len() {
+            return Err(RerankError::Incomplete { judged, expected: candidates.len() });
+        }
+        Ok(relevances.into_iter().flatten().collect())
+    }
+}
+
+/// Cache key of a judgement; queries differing only in case or spacing share it
+fn cache_key(model: &str, query: &str, summary: &str) -> u64 {
+    let query = query.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    fnv1a(format!("{}\0{}\0{}", model, query, summary).as_bytes())
+}
+
+/// `summaries` numbered from 1 for a prompt, as many as fit in the prompt
+/// tokens of `budget`, best first
+fn numbered_within(model: &str, summaries: Vec<String>, budget: &TokenBudget) -> Vec<String> {
+    let numbered: Vec<String> = summaries
+        .into_iter()
+        .enumerate()
+        .map(|(index, summary)| format!("[{}]\n{}", index + 1, summary))
+        .collect();
+    fit_items(model, numbered, budget.prompt_tokens(), |entry| format!("{}\n\n", entry))
+}
+
+/// Lines of `CANDIDATE_FIELDS` that `listing` has values for
+fn candidate_text(listing: &Document) -> String {
+    CANDIDATE_FIELDS
+        .iter()
+        .filter_map(|(label, paths)| {
+            let values: Vec<&str> = paths.iter().flat_map(|path| strings_at(listing, path)).collect();
+            (!values.is_empty()).then(|| format!("{}: {}", label, values.join(", ")))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::mock::{MockEndpoint, MockOpenAI, MockReply};
+    use crate::openai::usage::PriceTable;
+    use mongodb::bson::doc;
+    use serde_json::json;
+
+    async fn reranker(openai: &MockOpenAI) -> Reranker {
+        let client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap();
+        let usage = UsageLedger::new(client.database("db_endpoint_offline").collection("usage_daily"), PriceTable::default());
+        let chat = ChatOpenAI::new(DEFAULT_RERANK_MODEL)
+            .with_api_key("sk-test")
+            .with_base_url(&openai.base_url())
+            .with_max_retries(0);
+        Reranker::new(chat, RerankOptions { candidates: 3, budget: Duration::from_secs(2) }, usage)
+    }
+
+    fn hits() -> Vec<SearchHit> {
+        ["Loft near the beach", "Quiet studio", "Family house with pool", "Tiny room"]
+            .iter()
+            .enumerate()
+            .map(|(index, name)| SearchHit { score: 0.9 - index as f64 * 0.1, listing: doc! { "_id": index as i32, "name": *name } })
+            .collect()
+    }
+
+    fn answer(relevances: &[u32]) -> MockReply {
+        let judgements: Vec<_> = relevances
+            .iter()
+            .enumerate()
+            .map(|(index, relevance)| json!({ "candidate": index + 1, "relevance": relevance, "reason": format!("reason {}", index + 1) }))
+            .collect();
+        MockReply::chat_text(&json!({ "judgements": judgements }).to_string())
+    }
+
+    fn names(reranked: &Reranked) -> Vec<&str> {
+        reranked.hits.iter().map(|hit| hit.hit.listing.get_str("name").unwrap()).collect()
+    }
+
+    #[test]
+    fn candidate_text_labels_the_facts_before_the_free_text() {
+        let listing = doc! {
+            "name": "Ribeira Charming Duplex",
+            "description": "Fantastic duplex apartment.",
+            "property_type": "House",
+            "room_type": "Entire home/apt",
+            "amenities": ["TV", "Wifi"],
+            "address": { "market": "Porto" },
+        };
+
+        assert_eq!(
+            candidate_text(&listing),
+            "Name: Ribeira Charming Duplex\n\
+             Type: House, Entire home/apt\n\
+             Market: Porto\n\
+             Amenities: TV, Wifi\n\
+             Description: Fantastic duplex apartment."
+        );
+    }
+
+    #[test]
+    fn numbered_listings_stop_at_the_prompt_budget() {
+        let model = DEFAULT_RERANK_MODEL;
+        let summaries: Vec<String> = ["Loft near the beach", "Quiet studio", "Family house with pool"]
+            .iter()
+            .map(|summary| summary.to_string())
+            .collect();
+        let entry = |index: usize| count_tokens(model, &format!("[{}]\n{}\n\n", index + 1, summaries[index]));
+        let budget = TokenBudget { context_window: 1_000, reserved_output: 0 }.reserve(1_000 - entry(0) - entry(1));
+
+        assert_eq!(
+            numbered_within(model, summaries.clone(), &budget),
+            ["[1]\nLoft near the beach", "[2]\nQuiet studio"]
+        );
+        assert_eq!(numbered_within(model, summaries, &TokenBudget { context_window: 1_000, reserved_output: 0 }).len(), 3);
+    }
+
+    #[test]
+    fn requested_budgets_are_capped() {
+        let options = RerankOptions::default();
+
+        assert_eq!(options.budget_for(None), options.budget);
+        assert_eq!(options.budget_for(Some(500)), Duration::from_millis(500));
+        assert_eq!(options.budget_for(Some(u64::MAX)), MAX_RERANK_BUDGET);
+    }
+
+    #[tokio::test]
+    async fn reorders_the_top_candidates_and_caches_their_judgements() {
+        let openai = MockOpenAI::start().await;
+        openai.enqueue(MockEndpoint::Chat, answer(&[2, 4, 9]));
+        let reranker = reranker(&openai).await;
+
+        let reranked = reranker.rerank("house with a pool", hits(), Duration::from_secs(2), "test").await;
+
+        assert_eq!(reranked.outcome, RerankOutcome::Reranked);
+        assert_eq!(names(&reranked), ["Family house with pool", "Quiet studio", "Loft near the beach", "Tiny room"]);
+        assert_eq!(reranked.hits[0].relevance, Some(Relevance { relevance: 9, reason: "reason 3".to_string() }));
+        assert_eq!(reranked.hits[3].relevance, None);
+        let request = &openai.received()[0].body;
+        assert_eq!(request["response_format"]["type"], "json_schema");
+        let prompt = request["messages"].as_array().unwrap().last().unwrap()["content"].to_string();
+        assert!(prompt.contains("[3]\\nName: Family house with pool"), "{}", prompt);
+        assert!(!prompt.contains("Tiny room"));
+
+        let cached = reranker.rerank("  House with a POOL ", hits(), Duration::from_secs(2), "test").await;
+
+        assert_eq!(cached.outcome, RerankOutcome::Cached);
+        assert_eq!(names(&cached), names(&reranked));
+        assert_eq!(openai.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_vector_order_over_budget_and_caches_the_late_answer() {
+        let openai = MockOpenAI::start().await;
+        openai.enqueue(MockEndpoint::Chat, answer(&[1, 2, 3]).with_delay(Duration::from_millis(300)));
+        let reranker = reranker(&openai).await;
+
+        let reranked = reranker.rerank("pool", hits(), Duration::from_millis(20), "test").await;
+
+        assert_eq!(reranked.outcome, RerankOutcome::OverBudget);
+        assert_eq!(names(&reranked), ["Loft near the beach", "Quiet studio", "Family house with pool", "Tiny room"]);
+        assert!(reranked.hits.iter().all(|hit| hit.relevance.is_none()));
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let cached = reranker.rerank("pool", hits(), Duration::from_millis(20), "test").await;
+        assert_eq!(cached.outcome, RerankOutcome::Cached);
+        assert_eq!(cached.hits[0].hit.listing.get_str("name"), Ok("Family house with pool"));
+    }
+
+    #[tokio::test]
+    async fn justify_maps_sentences_back_to_listings() {
+        let openai = MockOpenAI::start().await;
+        let answer = json!({ "justifications": [{ "listing": 2, "sentence": " Has a pool. " }, { "listing": 9, "sentence": "Unknown" }] });
+        openai.enqueue(MockEndpoint::Chat, MockReply::chat_text(&answer.to_string()));
+        openai.enqueue(MockEndpoint::Chat, MockReply::chat_text("{}").with_delay(Duration::from_millis(300)));
+        let reranker = reranker(&openai).await;
+        let listings: Vec<Document> = hits().into_iter().map(|hit| hit.listing).collect();
+        let listings: Vec<&Document> = listings.iter().take(2).collect();
+
+        let sentences = reranker.justify("pool", &listings, Duration::from_secs(2), "test").await;
+
+        assert_eq!(sentences, [None, Some("Has a pool.".to_string())]);
+        let prompt = openai.received()[0].body["messages"].to_string();
+        assert!(prompt.contains("[2]\\nName: Quiet studio"), "{}", prompt);
+
+        let late = reranker.justify("pool", &listings, Duration::from_millis(20), "test").await;
+        assert_eq!(late, [None, None]);
+    }
+
+    #[tokio::test]
+    async fn incomplete_answers_keep_vector_order_and_only_missing_candidates_are_resent() {
+        let openai = MockOpenAI::start().await;
+        openai.enqueue(MockEndpoint::Chat, answer(&[1, 8]));
+        openai.enqueue(MockEndpoint::Chat, answer(&[5]));
+        let reranker = reranker(&openai).await;
+
+        let failed = reranker.rerank("pool", hits(), Duration::from_secs(2), "test").await;
+
+        assert_eq!(failed.outcome, RerankOutcome::Failed);
+        assert_eq!(names(&failed)[0], "Loft near the beach");
+
+        let reranked = reranker.rerank("pool", hits(), Duration::from_secs(2), "test").await;
+
+        assert_eq!(reranked.outcome, RerankOutcome::Reranked);
+        assert_eq!(names(&reranked)[..3], ["Quiet studio", "Family house with pool", "Loft near the beach"]);
+        let prompt = openai.received()[1].body["messages"].to_string();
+        assert!(prompt.contains("[1]\\nName: Family house with pool"), "{}", prompt);
+        assert!(!prompt.contains("Quiet studio"));
+    }
+}
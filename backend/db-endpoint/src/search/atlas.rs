@@ -0,0 +1,203 @@
+use super::facets::facet_stage;
+use super::{
+    check_limit, is_inclusion, rank, take_score, FacetQuery, Facets, GeoQuery, HybridQuery, SearchBackend,
+    SearchError, SearchHit, VectorQuery, HIGHLIGHTS_FIELD, LOCATION_PATH, SCORE_FIELD, TEXT_FIELDS,
+    TEXT_INDEX,
+};
+use crate::embeddings::VectorField;
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::Collection;
+
+/// Search on MongoDB Atlas
+///
+/// Vector queries use `$vectorSearch` over the index of the searched vector
+/// field, hybrid queries add `$search` over `TEXT_INDEX`, and geo queries use
+/// `$geoNear`, which needs a `2dsphere` index on `LOCATION_PATH`. Facets are
+/// counted by a `$facet` stage after `$vectorSearch`, so candidates never
+/// leave the server.
+#[derive(Debug, Clone)]
+pub struct AtlasSearch {
+    collection: Collection<Document>,
+    field: VectorField,
+}
+
+impl AtlasSearch {
+    /// # Arguments
+    /// * `field` - Vector field queried through its vector index
+    pub fn new(collection: Collection<Document>, field: VectorField) -> Self {
+        Self { collection, field }
+    }
+
+    /// Runs `stages`, moves the score into each hit and applies `projection`
+    async fn run(
+        &self,
+        mut stages: Vec<Document>,
+        projection: &Document,
+    ) -> Result<Vec<SearchHit>, SearchError> {
+        if !projection.is_empty() {
+            let mut projection = projection.clone();
+            if is_inclusion(&projection) {
+                projection.insert(SCORE_FIELD, 1);
+            }
+            stages.push(doc! { "$project": projection });
+        }
+
+        let listings: Vec<Document> = self.collection.aggregate(stages).await?.try_collect().await?;
+        Ok(listings
+            .into_iter()
+            .map(|mut listing| SearchHit { score: take_score(&mut listing), listing })
+            .collect())
+    }
+
+    fn vector_stages(
+        &self,
+        query_vector: &[f32],
+        filter: &Document,
+        limit: usize,
+        num_candidates: u32,
+    ) -> Vec<Document> {
+        let mut vector_search = doc! {
+            "queryVector": query_vector.to_vec(),
+            "path": &self.field.name,
+            "numCandidates": num_candidates.max(limit as u32),
+            "index": self.field.index_name(),
+            "limit": limit as i64,
+        };
+        if !filter.is_empty() {
+            vector_search.insert("filter", filter.clone());
+        }
+
+        vec![
+            doc! { "$vectorSearch": vector_search },
+            doc! { "$set": { SCORE_FIELD: { "$meta": "vectorSearchScore" } } },
+        ]
+    }
+
+    fn text_stages(text: &str, filter: &Document, limit: usize) -> Vec<Document> {
+        let mut stages = vec![doc! {
+            "$search": {
+                "index": TEXT_INDEX,
+                "text": { "query": text, "path": TEXT_FIELDS.to_vec() },
+                "highlight": { "path": TEXT_FIELDS.to_vec() },
+            }
+        }];
+        if !filter.is_empty() {
+            stages.push(doc! { "$match": filter.clone() });
+        }
+        stages.push(doc! { "$limit": limit as i64 });
+        stages.push(doc! {
+            "$set": {
+                SCORE_FIELD: { "$meta": "searchScore" },
+                HIGHLIGHTS_FIELD: { "$meta": "searchHighlights" },
+            }
+        });
+        stages
+    }
+}
+
+impl SearchBackend for AtlasSearch {
+    fn name(&self) -> &'static str {
+        "atlas"
+    }
+
+    fn vector_search<'a>(
+        &'a self,
+        query: &'a VectorQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            check_limit(query.limit, &query.vector)?;
+            let stages = self.vector_stages(
+                &query.vector,
+                &query.filter.to_match(),
+                query.limit,
+                query.num_candidates,
+            );
+            self.run(stages, &query.projection).await
+        })
+    }
+
+    fn geo_search<'a>(
+        &'a self,
+        query: &'a GeoQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            query.validate()?;
+            let stages = vec![
+                doc! {
+                    "$geoNear": {
+                        "near": { "type": "Point", "coordinates": [query.longitude, query.latitude] },
+                        "distanceField": SCORE_FIELD,
+                        "maxDistance": query.max_distance_m,
+                        "query": query.filter.to_match(),
+                        "key": LOCATION_PATH,
+                        "spherical": true,
+                    }
+                },
+                doc! { "$limit": query.limit as i64 },
+            ];
+            self.run(stages, &query.projection).await
+        })
+    }
+
+    fn hybrid_search<'a>(
+        &'a self,
+        query: &'a HybridQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            check_limit(query.limit, &query.vector)?;
+            let filter = query.filter.to_match();
+            let depth = (query.limit * 4).max(20);
+
+            // Rankings keep `_id` so they can be fused; the caller's
+            // projection is applied afterwards
+            let ranking_projection = if query.projection
+                .get(&self.field.name)
+                .is_some_and(rank::is_truthy)
+            {
+                Document::new()
+            } else {
+                doc! { &self.field.name: 0 }
+            };
+            let vector = self.run(
+                self.vector_stages(&query.vector, &filter, depth, query.num_candidates),
+                &ranking_projection,
+            );
+            let text = self.run(Self::text_stages(&query.text, &filter, depth), &ranking_projection);
+            let (vector, text) = futures::try_join!(vector, text)?;
+
+            let keep_highlights = query.projection.get(HIGHLIGHTS_FIELD).is_some_and(rank::is_truthy);
+            Ok(rank::fuse(vec![vector, text], query.limit)
+                .into_iter()
+                .map(|mut hit| {
+                    if !keep_highlights {
+                        hit.listing.remove(HIGHLIGHTS_FIELD);
+                    }
+                    SearchHit { score: hit.score, listing: rank::project(hit.listing, &query.projection) }
+                })
+                .collect())
+        })
+    }
+
+    fn get_by_id(&self, id: i32) -> BoxFuture<'_, Result<Option<Document>, SearchError>> {
+        Box::pin(async move { Ok(self.collection.find_one(doc! { "_id": id }).await?) })
+    }
+
+    fn facets<'a>(&'a self, query: &'a FacetQuery) -> BoxFuture<'a, Result<Facets, SearchError>> {
+        Box::pin(async move {
+            let candidates = query.candidate_query();
+            check_limit(candidates.limit, &candidates.vector)?;
+            let mut stages = self.vector_stages(
+                &candidates.vector,
+                &candidates.filter.to_match(),
+                candidates.limit,
+                candidates.num_candidates,
+            );
+            stages.push(facet_stage());
+
+            let facets: Option<Document> = self.collection.aggregate(stages).await?.try_next().await?;
+            Ok(facets.map(|facets| Facets::from_facet_document(&facets)).unwrap_or_default())
+        })
+    }
+}
@@ -0,0 +1,149 @@
+//! Runs the same vector queries on several backends and compares latency
+//! and recall, e.g. `BruteForceSearch` against `HnswSearch`
+//!
+//! The benchmark itself needs a `mongod`; run it with
+//! `cargo test --release search_backends_benchmark -- --ignored --nocapture`.
+
+use super::{SearchBackend, SearchError, SearchFilter, VectorQuery};
+use crate::embeddings::VectorField;
+use mongodb::bson::{doc, Bson, Document};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Hits one backend returned for every query, and how long it took
+///
+/// # Fields
+/// * `backend` - `SearchBackend::name` of the backend
+/// * `mean_latency` - Mean time per query
+/// * `ids` - `_id` of the hits of each query, best first
+#[derive(Debug, Clone)]
+pub struct BenchRun {
+    pub backend: &'static str,
+    pub mean_latency: Duration,
+    pub ids: Vec<Vec<Bson>>,
+}
+
+impl BenchRun {
+    /// Runs `queries` one after the other on `backend`
+    ///
+    /// # Errors
+    /// * `SearchError` - A query failed
+    pub async fn run(backend: &dyn SearchBackend, queries: &[VectorQuery]) -> Result<Self, SearchError> {
+        let mut ids = Vec::with_capacity(queries.len());
+        let started = Instant::now();
+        for query in queries {
+            let hits = backend.vector_search(query).await?;
+            ids.push(hits.into_iter().filter_map(|hit| hit.listing.get("_id").cloned()).collect());
+        }
+
+        Ok(Self {
+            backend: backend.name(),
+            mean_latency: started.elapsed() / queries.len().max(1) as u32,
+            ids,
+        })
+    }
+
+    /// Share of the `reference` hits this run also returned, over all queries
+    pub fn recall_against(&self, reference: &BenchRun) -> f64 {
+        let mut expected = 0;
+        let mut found = 0;
+        for (ids, reference_ids) in self.ids.iter().zip(&reference.ids) {
+            let ids: HashSet<String> = ids.iter().map(Bson::to_string).collect();
+            expected += reference_ids.len();
+            found += reference_ids.iter().filter(|id| ids.contains(&id.to_string())).count();
+        }
+
+        if expected == 0 {
+            1.0
+        } else {
+            found as f64 / expected as f64
+        }
+    }
+}
+
+impl fmt::Display for BenchRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<12} {:>10.3} ms/query", self.backend, self.mean_latency.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Deterministic pseudo-random values in `[-1, 1)`
+struct Noise(u64);
+
+impl Noise {
+    fn next(&mut self) -> f64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+
+    fn vector(&mut self, dimensions: u32) -> Vec<f64> {
+        (0..dimensions).map(|_| self.next()).collect()
+    }
+}
+
+/// `count` listings with ids from 1 and a random vector in `field`
+pub fn synthetic_listings(count: usize, field: &VectorField) -> Vec<Document> {
+    let mut noise = Noise(0x9e37_79b9_7f4a_7c15);
+    (1..=count as i32)
+        .map(|id| {
+            doc! {
+                "_id": id,
+                "name": format!("Listing {}", id),
+                "price": 40.0 + (id % 200) as f64,
+                field.name.clone(): noise.vector(field.dimensions),
+            }
+        })
+        .collect()
+}
+
+/// `count` unfiltered queries for the `limit` listings closest to a random
+/// vector of `field`'s dimensions
+pub fn synthetic_queries(count: usize, field: &VectorField, limit: usize) -> Vec<VectorQuery> {
+    let mut noise = Noise(0xd1b5_4a32_d192_ed03);
+    (0..count)
+        .map(|_| VectorQuery {
+            vector: noise.vector(field.dimensions).into_iter().map(|value| value as f32).collect(),
+            filter: SearchFilter::default(),
+            projection: doc! { "_id": 1 },
+            limit,
+            num_candidates: (limit * 10) as u32,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(ids: Vec<Vec<i32>>) -> BenchRun {
+        BenchRun {
+            backend: "test",
+            mean_latency: Duration::ZERO,
+            ids: ids.into_iter().map(|ids| ids.into_iter().map(Bson::Int32).collect()).collect(),
+        }
+    }
+
+    #[test]
+    fn recall_counts_reference_hits_found_over_all_queries() {
+        let reference = run(vec![vec![1, 2], vec![3, 4]]);
+
+        assert_eq!(run(vec![vec![2, 1], vec![3, 5]]).recall_against(&reference), 0.75);
+        assert_eq!(reference.recall_against(&reference), 1.0);
+        assert_eq!(run(vec![]).recall_against(&run(vec![])), 1.0);
+    }
+
+    #[test]
+    fn synthetic_data_is_deterministic() {
+        let field = VectorField::new("bench_embedding", "bench-model", 8);
+
+        let listings = synthetic_listings(3, &field);
+        assert_eq!(listings, synthetic_listings(3, &field));
+        assert_eq!(listings[2].get_i32("_id"), Ok(3));
+        assert_eq!(listings[0].get_array("bench_embedding").unwrap().len(), 8);
+        assert_eq!(synthetic_queries(2, &field, 5)[1].vector.len(), 8);
+    }
+}
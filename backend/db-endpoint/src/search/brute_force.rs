@@ -0,0 +1,156 @@
+use super::rank::{self, TopK};
+use super::{
+    check_limit, fetch_projection, GeoQuery, HybridQuery, SearchBackend, SearchError, SearchHit,
+    VectorQuery, TEXT_FIELDS,
+};
+use crate::embeddings::VectorField;
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::Collection;
+
+/// Exact search computed by the gateway over the listings `find` returns
+///
+/// Needs no search index, so it runs against any `mongod`; every query
+/// streams the matching listings once, which suits development and CI data
+/// sets rather than production.
+#[derive(Debug, Clone)]
+pub struct BruteForceSearch {
+    collection: Collection<Document>,
+    field: VectorField,
+}
+
+impl BruteForceSearch {
+    /// # Arguments
+    /// * `field` - Vector field compared with query vectors
+    pub fn new(collection: Collection<Document>, field: VectorField) -> Self {
+        Self { collection, field }
+    }
+
+    /// Streams the listings matching `filter` with `extra` fields loaded, and
+    /// keeps the `limit` with the highest `score`
+    async fn scan(
+        &self,
+        filter: Document,
+        projection: &Document,
+        extra: &[&str],
+        limit: usize,
+        score: impl Fn(&Document) -> Option<f64>,
+    ) -> Result<Vec<SearchHit>, SearchError> {
+        let mut cursor = self.collection
+            .find(filter)
+            .projection(fetch_projection(projection, extra))
+            .await?;
+
+        let mut top = TopK::new(limit);
+        while let Some(listing) = cursor.try_next().await? {
+            if let Some(score) = score(&listing) {
+                top.push(SearchHit { score, listing });
+            }
+        }
+
+        Ok(top
+            .into_sorted()
+            .into_iter()
+            .map(|hit| SearchHit { score: hit.score, listing: rank::project(hit.listing, projection) })
+            .collect())
+    }
+}
+
+impl SearchBackend for BruteForceSearch {
+    fn name(&self) -> &'static str {
+        "brute_force"
+    }
+
+    fn vector_search<'a>(
+        &'a self,
+        query: &'a VectorQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            check_limit(query.limit, &query.vector)?;
+            self.scan(
+                query.filter.to_match(),
+                &query.projection,
+                &[&self.field.name],
+                query.limit,
+                |listing| {
+                    rank::cosine_similarity(&query.vector, &rank::embedding(listing, &self.field.name)?)
+                },
+            )
+            .await
+        })
+    }
+
+    fn geo_search<'a>(
+        &'a self,
+        query: &'a GeoQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            query.validate()?;
+            let centre = (query.longitude, query.latitude);
+
+            // Ranked by negated distance so the nearest listing scores highest
+            let hits = self.scan(
+                query.filter.to_match(),
+                &query.projection,
+                &["address"],
+                query.limit,
+                |listing| {
+                    let distance = rank::haversine_m(centre, rank::location(listing)?);
+                    (distance <= query.max_distance_m).then_some(-distance)
+                },
+            )
+            .await?;
+
+            Ok(hits
+                .into_iter()
+                .map(|hit| SearchHit { score: -hit.score, listing: hit.listing })
+                .collect())
+        })
+    }
+
+    fn hybrid_search<'a>(
+        &'a self,
+        query: &'a HybridQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            check_limit(query.limit, &query.vector)?;
+            let mut extra = vec![self.field.name.as_str(), "_id"];
+            extra.extend(TEXT_FIELDS);
+            let depth = (query.limit * 4).max(20);
+
+            // One pass scores both rankings; the caller's projection is
+            // applied after fusion
+            let mut cursor = self.collection
+                .find(query.filter.to_match())
+                .projection(fetch_projection(&query.projection, &extra))
+                .await?;
+
+            let mut vector = TopK::new(depth);
+            let mut text = TopK::new(depth);
+            while let Some(listing) = cursor.try_next().await? {
+                let keyword = rank::keyword_score(&query.text, &listing);
+                if keyword > 0.0 {
+                    text.push(SearchHit { score: keyword, listing: listing.clone() });
+                }
+                if let Some(similarity) = rank::embedding(&listing, &self.field.name)
+                    .and_then(|embedding| rank::cosine_similarity(&query.vector, &embedding))
+                {
+                    vector.push(SearchHit { score: similarity, listing });
+                }
+            }
+
+            Ok(rank::fuse(vec![vector.into_sorted(), text.into_sorted()], query.limit)
+                .into_iter()
+                .map(|hit| SearchHit {
+                    score: hit.score,
+                    listing: rank::project(hit.listing, &query.projection),
+                })
+                .collect())
+        })
+    }
+
+    fn get_by_id(&self, id: i32) -> BoxFuture<'_, Result<Option<Document>, SearchError>> {
+        Box::pin(async move { Ok(self.collection.find_one(doc! { "_id": id }).await?) })
+    }
+}
@@ -0,0 +1,360 @@
+use super::rank;
+use crate::chunks::sentences_of;
+use super::{fetch_projection, SearchFilter, SearchHit, TEXT_FIELDS};
+use mongodb::bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+/// Field Atlas `$search` highlights travel in from the text ranking to the
+/// explanation; only kept on hits whose projection includes it
+pub const HIGHLIGHTS_FIELD: &str = "search_highlights";
+
+/// Passages highlighted per text field when highlighting in process
+const HIGHLIGHTS_PER_FIELD: usize = 2;
+
+/// Why a listing matched a query
+///
+/// # Fields
+/// * `score` - Score the backend ranked the hit by: similarity for vector
+///   queries, fused rank score for hybrid ones
+/// * `vector_score` - Cosine similarity of the query and the stored vector,
+///   `None` when the backend does not load vectors
+/// * `filters` - Every condition of the query's filter, with the listing's value
+/// * `distance_m` - Distance from the query's reference point, when it has one
+/// * `highlights` - Query terms found in the text fields, from Atlas `$search`
+///   highlighting when the hit came through it
+/// * `justification` - One sentence from the chat model, when requested
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Explanation {
+    pub score: f64,
+    pub vector_score: Option<f64>,
+    pub filters: Vec<FilterCheck>,
+    pub distance_m: Option<f64>,
+    pub highlights: Vec<Highlight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub justification: Option<String>,
+}
+
+/// One condition of a filter against a listing
+///
+/// # Fields
+/// * `field` - Filtered field, e.g. `address.market`
+/// * `condition` - The condition as written, e.g. `<= 100`
+/// * `value` - What the listing holds; for amenities, the required ones it has
+/// * `matched` - The listing meets the condition
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FilterCheck {
+    pub field: &'static str,
+    pub condition: String,
+    pub value: Value,
+    pub matched: bool,
+}
+
+/// Matches in one text field, in the shape of Atlas `searchHighlights`
+///
+/// # Fields
+/// * `path` - Highlighted field
+/// * `score` - Atlas' relevance of the passage, `None` in process
+/// * `texts` - The passage, cut into matched (`hit`) and surrounding (`text`)
+///   pieces
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Highlight {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    pub texts: Vec<HighlightText>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HighlightText {
+    pub value: String,
+    #[serde(rename = "type")]
+    pub kind: HighlightKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HighlightKind {
+    Hit,
+    Text,
+}
+
+/// What a query asked for, to explain its hits with
+///
+/// # Fields
+/// * `text` - The query as typed
+/// * `vector` - Its embedding
+/// * `vector_path` - Vector field the query searched
+/// * `filter` - Conditions hits had to meet
+/// * `near` - Optional - `(longitude, latitude)` distances are measured from
+#[derive(Debug, Clone, Copy)]
+pub struct ExplainContext<'a> {
+    pub text: &'a str,
+    pub vector: &'a [f32],
+    pub vector_path: &'a str,
+    pub filter: &'a SearchFilter,
+    pub near: Option<(f64, f64)>,
+}
+
+impl ExplainContext<'_> {
+    /// Projection to search with so hits carry what their explanation reads,
+    /// whatever `projection` the caller asked for
+    pub fn projection(&self, projection: &Document) -> Document {
+        let mut extra = vec![self.vector_path, "price", "bedrooms", "address", "property_type", "amenities", HIGHLIGHTS_FIELD];
+        extra.extend(TEXT_FIELDS);
+        fetch_projection(projection, &extra)
+    }
+
+    /// Explains a hit found with `self.projection(projection)` and shapes
+    /// it back to `projection`
+    pub fn explain(&self, hit: SearchHit, projection: &Document) -> (SearchHit, Explanation) {
+        let mut listing = hit.listing;
+        let atlas_highlights = listing
+            .remove(HIGHLIGHTS_FIELD)
+            .and_then(|highlights| mongodb::bson::from_bson::<Vec<Highlight>>(highlights).ok())
+            .filter(|highlights| !highlights.is_empty());
+
+        let explanation = Explanation {
+            score: hit.score,
+            vector_score: rank::embedding(&listing, self.vector_path)
+                .and_then(|embedding| rank::cosine_similarity(self.vector, &embedding)),
+            filters: filter_checks(self.filter, &listing),
+            distance_m: self.near.zip(rank::location(&listing)).map(|(near, location)| rank::haversine_m(near, location)),
+            highlights: atlas_highlights.unwrap_or_else(|| highlight(self.text, &listing)),
+            justification: None,
+        };
+
+        let hit = SearchHit { score: hit.score, listing: rank::project(listing, projection) };
+        (hit, explanation)
+    }
+}
+
+/// Each condition of `filter` checked against `listing`
+pub fn filter_checks(filter: &SearchFilter, listing: &Document) -> Vec<FilterCheck> {
+    let number = |field: &str| listing.get(field).and_then(rank::number);
+    let market = listing
+        .get_document("address")
+        .and_then(|address| address.get_str("market"))
+        .ok();
+    let property_type = listing.get_str("property_type").ok();
+    let amenities: Vec<&str> = listing
+        .get_array("amenities")
+        .map(|amenities| amenities.iter().filter_map(Bson::as_str).collect())
+        .unwrap_or_default();
+
+    let mut checks = Vec::new();
+    if let Some(max_price) = filter.max_price {
+        let price = number("price");
+        checks.push(FilterCheck {
+            field: "price",
+            condition: format!("<= {}", max_price),
+            value: json!(price),
+            matched: price.is_some_and(|price| price <= max_price),
+        });
+    }
+    if let Some(min_bedrooms) = filter.min_bedrooms {
+        let bedrooms = number("bedrooms");
+        checks.push(FilterCheck {
+            field: "bedrooms",
+            condition: format!(">= {}", min_bedrooms),
+            value: json!(bedrooms),
+            matched: bedrooms.is_some_and(|bedrooms| bedrooms >= min_bedrooms as f64),
+        });
+    }
+    if let Some(wanted) = &filter.market {
+        checks.push(FilterCheck {
+            field: "address.market",
+            condition: format!("= {}", wanted),
+            value: json!(market),
+            matched: market == Some(wanted.as_str()),
+        });
+    }
+    if let Some(wanted) = &filter.property_type {
+        checks.push(FilterCheck {
+            field: "property_type",
+            condition: format!("= {}", wanted),
+            value: json!(property_type),
+            matched: property_type == Some(wanted.as_str()),
+        });
+    }
+    if !filter.amenities.is_empty() {
+        let found: Vec<&str> = filter.amenities
+            .iter()
+            .map(String::as_str)
+            .filter(|amenity| amenities.contains(amenity))
+            .collect();
+        checks.push(FilterCheck {
+            field: "amenities",
+            condition: format!("has {}", filter.amenities.join(", ")),
+            matched: found.len() == filter.amenities.len(),
+            value: json!(found),
+        });
+    }
+    checks
+}
+
+/// Sentences of the `TEXT_FIELDS` holding a term of `text`, cut into hit
+/// and text pieces like Atlas highlighting; stop words are never hits
+pub fn highlight(text: &str, listing: &Document) -> Vec<Highlight> {
+    let terms = rank::terms(text);
+    if terms.is_empty() {
+        return Vec::new();
+    }
+
+    TEXT_FIELDS
+        .iter()
+        .filter_map(|field| Some((*field, listing.get_str(field).ok()?)))
+        .flat_map(|(field, value)| {
+            sentences_of(value)
+                .into_iter()
+                .filter_map(|sentence| {
+                    let texts = pieces(sentence, &terms);
+                    texts
+                        .iter()
+                        .any(|piece| piece.kind == HighlightKind::Hit)
+                        .then(|| Highlight { path: field.to_string(), score: None, texts })
+                })
+                .take(HIGHLIGHTS_PER_FIELD)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// `text` as alternating runs of word and non-word characters, words being
+/// the terms `rank::terms` finds
+fn runs(text: &str) -> Vec<(&str, bool)> {
+    let mut runs: Vec<(&str, bool)> = Vec::new();
+    let mut start = 0;
+    let mut current: Option<bool> = None;
+    for (index, c) in text.char_indices() {
+        let is_word = c.is_alphanumeric();
+        if current.is_some_and(|current| current != is_word) {
+            runs.push((&text[start..index], !is_word));
+            start = index;
+        }
+        current = Some(is_word);
+    }
+    if let Some(is_word) = current {
+        runs.push((&text[start..], is_word));
+    }
+    runs
+}
+
+/// `sentence` cut into the words matching `terms` and the text between them
+fn pieces(sentence: &str, terms: &HashSet<String>) -> Vec<HighlightText> {
+    let mut pieces: Vec<HighlightText> = Vec::new();
+    for (run, is_word) in runs(sentence) {
+        let kind = if is_word && terms.contains(&run.to_lowercase()) { HighlightKind::Hit } else { HighlightKind::Text };
+        match pieces.last_mut() {
+            Some(last) if last.kind == HighlightKind::Text && kind == HighlightKind::Text => last.value.push_str(run),
+            _ => pieces.push(HighlightText { value: run.to_string(), kind }),
+        }
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn listing() -> Document {
+        doc! {
+            "_id": 1,
+            "name": "Ribeira Charming Duplex",
+            "summary": "Fantastic duplex apartment. Close to the river! Quiet street.",
+            "price": 80.0,
+            "bedrooms": 3,
+            "property_type": "House",
+            "amenities": ["Wifi", "Kitchen"],
+            "address": { "market": "Porto", "location": { "type": "Point", "coordinates": [-8.61, 41.14] } },
+            "text_embeddings": [1.0, 0.0],
+        }
+    }
+
+    fn text(value: &str) -> HighlightText {
+        HighlightText { value: value.to_string(), kind: HighlightKind::Text }
+    }
+
+    fn hit(value: &str) -> HighlightText {
+        HighlightText { value: value.to_string(), kind: HighlightKind::Hit }
+    }
+
+    #[test]
+    fn highlights_cut_matching_sentences_into_hits() {
+        let highlights = highlight("duplex by the River", &listing());
+
+        assert_eq!(
+            highlights,
+            [
+                Highlight { path: "name".to_string(), score: None, texts: vec![text("Ribeira Charming "), hit("Duplex")] },
+                Highlight { path: "summary".to_string(), score: None, texts: vec![text("Fantastic "), hit("duplex"), text(" apartment.")] },
+                Highlight {
+                    path: "summary".to_string(),
+                    score: None,
+                    texts: vec![text("Close to the "), hit("river"), text("!")],
+                },
+            ]
+        );
+        assert!(highlight(" ,", &listing()).is_empty());
+        assert!(highlight("to the", &listing()).is_empty());
+    }
+
+    #[test]
+    fn highlights_keep_decimals_inside_their_sentence() {
+        let listing = doc! { "summary": "Costs 3.5 EUR per night. Quiet street." };
+
+        let highlights = highlight("night", &listing);
+
+        assert_eq!(highlights.len(), 1);
+        assert_eq!(highlights[0].texts, vec![text("Costs 3.5 EUR per "), hit("night"), text(".")]);
+    }
+
+    #[test]
+    fn filter_checks_report_each_condition() {
+        let filter = SearchFilter {
+            max_price: Some(100.0),
+            market: Some("Lisbon".to_string()),
+            amenities: vec!["Wifi".to_string(), "Pool".to_string()],
+            ..Default::default()
+        };
+
+        let checks = filter_checks(&filter, &listing());
+
+        assert_eq!(checks.len(), 3);
+        assert_eq!(checks[0], FilterCheck { field: "price", condition: "<= 100".to_string(), value: json!(80.0), matched: true });
+        assert_eq!((checks[1].value.clone(), checks[1].matched), (json!("Porto"), false));
+        assert_eq!((checks[2].value.clone(), checks[2].matched), (json!(["Wifi"]), false));
+        assert!(filter_checks(&SearchFilter::default(), &listing()).is_empty());
+    }
+
+    #[test]
+    fn explain_prefers_atlas_highlights_and_restores_the_projection() {
+        let filter = SearchFilter::default();
+        let context = ExplainContext {
+            text: "quiet duplex",
+            vector: &[1.0, 0.0],
+            vector_path: "text_embeddings",
+            filter: &filter,
+            near: Some((-8.61, 41.14)),
+        };
+        let projection = doc! { "_id": 0, "name": 1 };
+        assert_eq!(context.projection(&projection).get("text_embeddings"), Some(&Bson::Int32(1)));
+
+        let mut listing = listing();
+        listing.insert(HIGHLIGHTS_FIELD, vec![doc! {
+            "path": "summary",
+            "score": 1.5,
+            "texts": [{ "value": "Quiet", "type": "hit" }, { "value": " street.", "type": "text" }],
+        }]);
+        let (explained, explanation) = context.explain(SearchHit { score: 0.5, listing }, &projection);
+
+        assert_eq!(explained.listing, doc! { "name": "Ribeira Charming Duplex" });
+        assert_eq!(explanation.vector_score, Some(1.0));
+        assert_eq!(explanation.distance_m, Some(0.0));
+        assert_eq!(explanation.highlights.len(), 1);
+        assert_eq!(explanation.highlights[0].score, Some(1.5));
+        assert_eq!(explanation.highlights[0].texts[0], hit("Quiet"));
+    }
+}
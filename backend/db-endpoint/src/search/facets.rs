@@ -0,0 +1,303 @@
+use super::rank;
+use super::{SearchFilter, VectorQuery};
+use crate::templates::strings_at;
+use mongodb::bson::{doc, Bson, Document};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Fields counted by value; arrays such as `amenities` count each element
+pub const FACET_FIELDS: [&str; 7] = [
+    "property_type",
+    "room_type",
+    "bed_type",
+    "cancellation_policy",
+    "address.market",
+    "address.country",
+    "amenities",
+];
+
+/// Values returned per field, most frequent first
+pub const FACET_VALUES: usize = 20;
+
+/// Lower bounds of the nightly price buckets; the last bucket is open-ended
+pub const PRICE_BOUNDARIES: [f64; 8] = [0.0, 50.0, 100.0, 150.0, 200.0, 300.0, 500.0, 1000.0];
+
+/// Lower bounds of the bedroom buckets; the last bucket is open-ended
+pub const BEDROOM_BOUNDARIES: [f64; 6] = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+
+/// `$bucket` id of values past the last boundary
+const OVERFLOW_BUCKET: &str = "more";
+
+/// Refinements available for a query
+///
+/// # Fields
+/// * `vector` - Query embedding
+/// * `filter` - Filters already applied
+/// * `candidates` - Closest listings counted
+#[derive(Debug, Clone)]
+pub struct FacetQuery {
+    pub vector: Vec<f32>,
+    pub filter: SearchFilter,
+    pub candidates: usize,
+}
+
+impl FacetQuery {
+    /// Vector query loading the candidate set with only the counted fields
+    pub fn candidate_query(&self) -> VectorQuery {
+        let mut projection: Document = FACET_FIELDS
+            .iter()
+            .map(|field| (field.to_string(), Bson::Int32(1)))
+            .collect();
+        projection.insert("price", 1);
+        projection.insert("bedrooms", 1);
+
+        VectorQuery {
+            vector: self.vector.clone(),
+            filter: self.filter.clone(),
+            projection,
+            limit: self.candidates,
+            num_candidates: (self.candidates * 2).min(10_000) as u32,
+        }
+    }
+}
+
+/// Listings sharing a value
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: u64,
+}
+
+/// Listings whose value is in `[min, max)`, `max` being `None` for the
+/// open-ended last bucket
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    pub min: f64,
+    pub max: Option<f64>,
+    pub count: u64,
+}
+
+/// Counts over the candidate set of a query
+///
+/// # Fields
+/// * `candidates` - Listings counted
+/// * `fields` - Per `FACET_FIELDS` path, the `FACET_VALUES` most frequent
+///   values; ties are ordered by value
+/// * `price`, `bedrooms` - Histograms over `PRICE_BOUNDARIES` and
+///   `BEDROOM_BOUNDARIES`, without empty buckets
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Facets {
+    pub candidates: u64,
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, Vec<FacetCount>>,
+    pub price: Vec<Bucket>,
+    pub bedrooms: Vec<Bucket>,
+}
+
+impl Facets {
+    /// Counts `listings` in process, as `facet_stage` does on the server
+    pub fn count<'a>(listings: impl IntoIterator<Item = &'a Document>) -> Self {
+        let mut candidates = 0;
+        let mut values: HashMap<&str, HashMap<String, u64>> = HashMap::new();
+        let mut prices = Vec::new();
+        let mut bedrooms = Vec::new();
+
+        for listing in listings {
+            candidates += 1;
+            for field in FACET_FIELDS {
+                let counts = values.entry(field).or_default();
+                for value in strings_at(listing, field) {
+                    *counts.entry(value.to_string()).or_default() += 1;
+                }
+            }
+            prices.extend(listing.get("price").and_then(rank::number));
+            bedrooms.extend(listing.get("bedrooms").and_then(rank::number));
+        }
+
+        let fields = FACET_FIELDS
+            .iter()
+            .map(|field| {
+                let mut counts: Vec<FacetCount> = values
+                    .remove(field)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(value, count)| FacetCount { value, count })
+                    .collect();
+                counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+                counts.truncate(FACET_VALUES);
+                (field.to_string(), counts)
+            })
+            .collect();
+
+        Self {
+            candidates,
+            fields,
+            price: histogram(&prices, &PRICE_BOUNDARIES),
+            bedrooms: histogram(&bedrooms, &BEDROOM_BOUNDARIES),
+        }
+    }
+
+    /// Reads the single document `facet_stage` outputs
+    pub fn from_facet_document(document: &Document) -> Self {
+        let entries = |name: &str| -> Vec<Document> {
+            document
+                .get_array(name)
+                .map(|entries| entries.iter().filter_map(Bson::as_document).cloned().collect())
+                .unwrap_or_default()
+        };
+        let count = |entry: &Document| entry.get("count").and_then(rank::number).unwrap_or_default() as u64;
+
+        let fields = FACET_FIELDS
+            .iter()
+            .map(|field| {
+                let counts = entries(&facet_name(field))
+                    .iter()
+                    .filter_map(|entry| Some(FacetCount { value: entry.get_str("_id").ok()?.to_string(), count: count(entry) }))
+                    .collect();
+                (field.to_string(), counts)
+            })
+            .collect();
+        let buckets = |name: &str, boundaries: &[f64]| -> Vec<Bucket> {
+            entries(name)
+                .iter()
+                .filter_map(|entry| {
+                    let min = match entry.get("_id")? {
+                        Bson::String(id) if id == OVERFLOW_BUCKET => *boundaries.last()?,
+                        id => rank::number(id)?,
+                    };
+                    let max = boundaries.iter().copied().find(|boundary| *boundary > min);
+                    Some(Bucket { min, max, count: count(entry) })
+                })
+                .collect()
+        };
+
+        Self {
+            candidates: entries("candidates").first().map(count).unwrap_or_default(),
+            fields,
+            price: buckets("price", &PRICE_BOUNDARIES),
+            bedrooms: buckets("bedrooms", &BEDROOM_BOUNDARIES),
+        }
+    }
+}
+
+/// `$facet` stage counting the candidate set into one document
+pub fn facet_stage() -> Document {
+    let mut facets = doc! { "candidates": [{ "$count": "count" }] };
+    for field in FACET_FIELDS {
+        let path = format!("${}", field);
+        facets.insert(facet_name(field), vec![
+            doc! { "$unwind": &path },
+            doc! { "$match": { field: { "$type": "string", "$ne": "" } } },
+            doc! { "$group": { "_id": &path, "count": { "$sum": 1 } } },
+            doc! { "$sort": { "count": -1, "_id": 1 } },
+            doc! { "$limit": FACET_VALUES as i64 },
+        ]);
+    }
+    facets.insert("price", bucket_pipeline("price", &PRICE_BOUNDARIES));
+    facets.insert("bedrooms", bucket_pipeline("bedrooms", &BEDROOM_BOUNDARIES));
+    doc! { "$facet": facets }
+}
+
+/// Output field of a facet; `$facet` names cannot contain dots
+fn facet_name(field: &str) -> String {
+    field.replace('.', "_")
+}
+
+/// Histogram of a numeric field; values from the last boundary on go to
+/// the `OVERFLOW_BUCKET`, since `$bucket` boundaries also close the last range
+fn bucket_pipeline(field: &str, boundaries: &[f64]) -> Vec<Document> {
+    vec![
+        doc! { "$match": { field: { "$type": "number" } } },
+        doc! {
+            "$bucket": {
+                "groupBy": format!("${}", field),
+                "boundaries": boundaries.to_vec(),
+                "default": OVERFLOW_BUCKET,
+                "output": { "count": { "$sum": 1 } },
+            }
+        },
+    ]
+}
+
+/// Counts `values` into buckets starting at `boundaries`, the last one
+/// open-ended; values below the first boundary are skipped
+fn histogram(values: &[f64], boundaries: &[f64]) -> Vec<Bucket> {
+    let mut counts = vec![0u64; boundaries.len()];
+    for value in values {
+        if let Some(index) = boundaries.iter().rposition(|boundary| value >= boundary) {
+            counts[index] += 1;
+        }
+    }
+
+    boundaries
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| counts[*index] > 0)
+        .map(|(index, min)| Bucket { min: *min, max: boundaries.get(index + 1).copied(), count: counts[index] })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listings() -> Vec<Document> {
+        vec![
+            doc! { "property_type": "Apartment", "amenities": ["Wifi", "Kitchen"], "address": { "market": "Porto" }, "price": 80.0, "bedrooms": 1 },
+            doc! { "property_type": "House", "amenities": ["Wifi"], "address": { "market": "Porto" }, "price": 1500.0, "bedrooms": 6 },
+            doc! { "property_type": "Apartment", "amenities": ["Wifi", ""], "address": { "market": "Lisbon" }, "price": 95, "bedrooms": 1 },
+            doc! { "room_type": "", "address": {} },
+        ]
+    }
+
+    fn count(value: &str, count: u64) -> FacetCount {
+        FacetCount { value: value.to_string(), count }
+    }
+
+    #[test]
+    fn count_orders_values_and_fills_histograms() {
+        let facets = Facets::count(&listings());
+
+        assert_eq!(facets.candidates, 4);
+        assert_eq!(facets.fields["property_type"], [count("Apartment", 2), count("House", 1)]);
+        assert_eq!(facets.fields["address.market"], [count("Porto", 2), count("Lisbon", 1)]);
+        assert_eq!(facets.fields["amenities"], [count("Wifi", 3), count("Kitchen", 1)]);
+        assert!(facets.fields["room_type"].is_empty());
+        assert_eq!(
+            facets.price,
+            [Bucket { min: 50.0, max: Some(100.0), count: 2 }, Bucket { min: 1000.0, max: None, count: 1 }]
+        );
+        assert_eq!(
+            facets.bedrooms,
+            [Bucket { min: 1.0, max: Some(2.0), count: 2 }, Bucket { min: 5.0, max: None, count: 1 }]
+        );
+    }
+
+    #[test]
+    fn facet_document_reads_like_in_process_counts() {
+        let document = doc! {
+            "candidates": [{ "count": 4 }],
+            "property_type": [{ "_id": "Apartment", "count": 2 }, { "_id": "House", "count": 1 }],
+            "address_market": [{ "_id": "Porto", "count": 2 }, { "_id": "Lisbon", "count": 1 }],
+            "amenities": [{ "_id": "Wifi", "count": 3 }, { "_id": "Kitchen", "count": 1 }],
+            "price": [{ "_id": 50.0, "count": 2 }, { "_id": "more", "count": 1 }],
+            "bedrooms": [{ "_id": 1.0, "count": 2 }, { "_id": "more", "count": 1 }],
+        };
+
+        assert_eq!(Facets::from_facet_document(&document), Facets::count(&listings()));
+    }
+
+    #[test]
+    fn facet_stage_buckets_up_to_the_last_boundary() {
+        let stage = facet_stage();
+        let facets = stage.get_document("$facet").unwrap();
+
+        assert!(facets.contains_key("address_country"));
+        let price = facets.get_array("price").unwrap()[1].as_document().unwrap().get_document("$bucket").unwrap();
+        assert_eq!(
+            price.get_array("boundaries").unwrap().iter().filter_map(Bson::as_f64).collect::<Vec<_>>(),
+            PRICE_BOUNDARIES
+        );
+        assert_eq!(price.get_str("default"), Ok(OVERFLOW_BUCKET));
+    }
+}
@@ -0,0 +1,617 @@
+use super::rank::{self, TopK};
+use super::{
+    check_limit, GeoQuery, HybridQuery, SearchBackend, SearchError, SearchHit, VectorQuery,
+};
+use crate::embeddings::VectorField;
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Collection;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+
+/// Fields of each listing kept in memory besides the searched vector, enough
+/// to filter, rank and show it
+pub const LISTING_FIELDS: [&str; 15] = [
+    "_id",
+    "name",
+    "summary",
+    "description",
+    "notes",
+    "property_type",
+    "room_type",
+    "bed_type",
+    "cancellation_policy",
+    "beds",
+    "bedrooms",
+    "bathrooms",
+    "price",
+    "amenities",
+    "address",
+];
+
+/// Links per node above layer 0; layer 0 keeps twice as many
+const M: usize = 16;
+
+/// Candidates kept while linking a new node
+const EF_CONSTRUCTION: usize = 100;
+
+/// Search served from memory by a Hierarchical Navigable Small World graph
+///
+/// The listings are loaded once by `load`; later writes to the collection
+/// are not seen until the backend is rebuilt. Vector queries walk the graph
+/// and widen the search until enough candidates pass the filter. Geo and
+/// keyword ranking scan the in-memory listings.
+#[derive(Debug)]
+pub struct HnswSearch {
+    graph: Graph,
+    /// Listing of each graph node
+    nodes: Vec<usize>,
+    /// Loaded listings, without their embedding
+    listings: Vec<Document>,
+    by_id: HashMap<i32, usize>,
+    field: VectorField,
+}
+
+impl HnswSearch {
+    /// Loads every listing of `collection` and indexes their vectors in `field`
+    ///
+    /// Listings without a vector, or with one whose length differs from the
+    /// field's dimensions, are kept for geo, keyword and by-id queries only.
+    ///
+    /// # Errors
+    /// * `SearchError::Database` - The listings could not be read
+    pub async fn load(collection: &Collection<Document>, field: VectorField) -> Result<Self, SearchError> {
+        let mut projection: Document = LISTING_FIELDS.iter().map(|field| (field.to_string(), 1.into())).collect();
+        projection.insert(field.name.clone(), 1);
+        let listings: Vec<Document> = collection.find(doc! {}).projection(projection).await?.try_collect().await?;
+
+        let search = Self::from_listings(listings, field);
+        tracing::info!(
+            "Indexed {} of {} listings in memory",
+            search.nodes.len(),
+            search.listings.len()
+        );
+        Ok(search)
+    }
+
+    /// Indexes listings already in memory, such as a fixture or a snapshot,
+    /// the same way `load` indexes the collection
+    pub fn from_listings(listings: impl IntoIterator<Item = Document>, field: VectorField) -> Self {
+        let mut search = Self {
+            graph: Graph::new(M, EF_CONSTRUCTION),
+            nodes: Vec::new(),
+            listings: Vec::new(),
+            by_id: HashMap::new(),
+            field,
+        };
+        for listing in listings {
+            search.insert(listing);
+        }
+        search
+    }
+
+    fn insert(&mut self, mut listing: Document) {
+        let embedding = rank::embedding(&listing, &self.field.name);
+        listing.remove(&self.field.name);
+
+        let index = self.listings.len();
+        // Ids read from JSON are stored as 64-bit integers
+        let id = match listing.get("_id") {
+            Some(Bson::Int32(id)) => Some(*id),
+            Some(Bson::Int64(id)) => i32::try_from(*id).ok(),
+            _ => None,
+        };
+        if let Some(id) = id {
+            self.by_id.insert(id, index);
+        }
+        self.listings.push(listing);
+
+        let Some(embedding) = embedding else {
+            return;
+        };
+        if embedding.len() == self.field.dimensions as usize {
+            let vector: Vec<f32> = embedding.iter().map(|value| *value as f32).collect();
+            if self.graph.insert(&vector).is_some() {
+                self.nodes.push(index);
+            }
+        }
+    }
+
+    /// Listings closest to `vector` that pass `keep`, widening the graph
+    /// search until `limit` are found or every node was considered
+    fn nearest(
+        &self,
+        vector: &[f32],
+        limit: usize,
+        num_candidates: usize,
+        keep: impl Fn(&Document) -> bool,
+    ) -> Result<Vec<(f64, usize)>, SearchError> {
+        if self.nodes.is_empty() {
+            return Ok(Vec::new());
+        }
+        if vector.len() != self.field.dimensions as usize {
+            return Err(SearchError::InvalidQuery(format!(
+                "query vector has {} dimensions, the index has {}",
+                vector.len(),
+                self.field.dimensions
+            )));
+        }
+
+        let mut ef = num_candidates.max(limit);
+        loop {
+            let found: Vec<(f64, usize)> = self.graph
+                .search(vector, ef)
+                .into_iter()
+                .map(|(similarity, node)| (similarity as f64, self.nodes[node]))
+                .filter(|(_, listing)| keep(&self.listings[*listing]))
+                .take(limit)
+                .collect();
+
+            if found.len() >= limit || ef >= self.nodes.len() {
+                return Ok(found);
+            }
+            ef = (ef * 4).min(self.nodes.len());
+        }
+    }
+
+    fn hit(&self, score: f64, listing: usize, projection: &Document) -> SearchHit {
+        SearchHit { score, listing: rank::project(self.listings[listing].clone(), projection) }
+    }
+}
+
+impl SearchBackend for HnswSearch {
+    fn name(&self) -> &'static str {
+        "hnsw"
+    }
+
+    fn vector_search<'a>(
+        &'a self,
+        query: &'a VectorQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            check_limit(query.limit, &query.vector)?;
+            let nearest = self.nearest(
+                &query.vector,
+                query.limit,
+                query.num_candidates as usize,
+                |listing| query.filter.matches(listing),
+            )?;
+
+            Ok(nearest
+                .into_iter()
+                .map(|(score, listing)| self.hit(score, listing, &query.projection))
+                .collect())
+        })
+    }
+
+    fn geo_search<'a>(
+        &'a self,
+        query: &'a GeoQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            query.validate()?;
+            let centre = (query.longitude, query.latitude);
+
+            let mut nearby: Vec<(f64, usize)> = self.listings
+                .iter()
+                .enumerate()
+                .filter_map(|(index, listing)| {
+                    let distance = rank::haversine_m(centre, rank::location(listing)?);
+                    (distance <= query.max_distance_m && query.filter.matches(listing))
+                        .then_some((distance, index))
+                })
+                .collect();
+            nearby.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            Ok(nearby
+                .into_iter()
+                .take(query.limit)
+                .map(|(distance, index)| self.hit(distance, index, &query.projection))
+                .collect())
+        })
+    }
+
+    fn hybrid_search<'a>(
+        &'a self,
+        query: &'a HybridQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>> {
+        Box::pin(async move {
+            check_limit(query.limit, &query.vector)?;
+            let depth = (query.limit * 4).max(20);
+
+            let vector: Vec<SearchHit> = self
+                .nearest(&query.vector, depth, query.num_candidates as usize, |listing| {
+                    query.filter.matches(listing)
+                })?
+                .into_iter()
+                .map(|(score, listing)| self.hit(score, listing, &doc! {}))
+                .collect();
+
+            let mut text = TopK::new(depth);
+            for listing in &self.listings {
+                let score = rank::keyword_score(&query.text, listing);
+                if score > 0.0 && query.filter.matches(listing) {
+                    text.push(SearchHit { score, listing: listing.clone() });
+                }
+            }
+
+            Ok(rank::fuse(vec![vector, text.into_sorted()], query.limit)
+                .into_iter()
+                .map(|hit| SearchHit {
+                    score: hit.score,
+                    listing: rank::project(hit.listing, &query.projection),
+                })
+                .collect())
+        })
+    }
+
+    fn get_by_id(&self, id: i32) -> BoxFuture<'_, Result<Option<Document>, SearchError>> {
+        Box::pin(async move {
+            Ok(self.by_id.get(&id).map(|index| self.listings[*index].clone()))
+        })
+    }
+}
+
+/// A graph node ranked by similarity to the query
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    similarity: f32,
+    node: usize,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then_with(|| other.node.cmp(&self.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// HNSW graph over unit vectors, where cosine similarity is a dot product
+///
+/// Each node lives on layers `0..=level`, with `level` drawn from an
+/// exponential distribution; searches descend greedily from the sparse top
+/// layer and run a best-first search with `ef` candidates on layer 0.
+#[derive(Debug)]
+struct Graph {
+    m: usize,
+    ef_construction: usize,
+    level_factor: f64,
+    vectors: Vec<Vec<f32>>,
+    /// Neighbours of each node, per layer
+    links: Vec<Vec<Vec<usize>>>,
+    entry: Option<usize>,
+    /// xorshift state; levels are deterministic so a rebuild gives the same graph
+    seed: u64,
+}
+
+impl Graph {
+    fn new(m: usize, ef_construction: usize) -> Self {
+        Self {
+            m,
+            ef_construction,
+            level_factor: 1.0 / (m as f64).ln(),
+            vectors: Vec::new(),
+            links: Vec::new(),
+            entry: None,
+            seed: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 { self.m * 2 } else { self.m }
+    }
+
+    fn random_level(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let uniform = ((self.seed >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() * self.level_factor) as usize
+    }
+
+    fn similarity(&self, query: &[f32], node: usize) -> f32 {
+        query.iter().zip(&self.vectors[node]).map(|(a, b)| a * b).sum()
+    }
+
+    fn top_layer(&self, node: usize) -> usize {
+        self.links[node].len() - 1
+    }
+
+    /// Adds a vector and returns its node, `None` for an all-zero vector
+    fn insert(&mut self, vector: &[f32]) -> Option<usize> {
+        let vector = normalize(vector)?;
+        let node = self.vectors.len();
+        let level = self.random_level();
+        self.vectors.push(vector);
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return Some(node);
+        };
+
+        let query = self.vectors[node].clone();
+        let top = self.top_layer(entry);
+        let mut nearest = entry;
+        for layer in (level + 1..=top).rev() {
+            nearest = self.greedy(&query, nearest, layer);
+        }
+
+        let mut entries = vec![nearest];
+        for layer in (0..=level.min(top)).rev() {
+            let candidates = self.search_layer(&query, &entries, self.ef_construction, layer);
+            let neighbours: Vec<usize> = candidates.iter().take(self.m).map(|c| c.node).collect();
+            for &neighbour in &neighbours {
+                self.links[neighbour][layer].push(node);
+                self.prune(neighbour, layer);
+            }
+            self.links[node][layer] = neighbours;
+            entries = candidates.iter().map(|c| c.node).collect();
+        }
+
+        if level > top {
+            self.entry = Some(node);
+        }
+        Some(node)
+    }
+
+    /// Keeps the closest `max_links` neighbours of a node on a layer
+    fn prune(&mut self, node: usize, layer: usize) {
+        let max = self.max_links(layer);
+        if self.links[node][layer].len() <= max {
+            return;
+        }
+
+        let vector = self.vectors[node].clone();
+        let mut ranked: Vec<Candidate> = self.links[node][layer]
+            .iter()
+            .map(|&other| Candidate { similarity: self.similarity(&vector, other), node: other })
+            .collect();
+        ranked.sort_by(|a, b| b.cmp(a));
+        self.links[node][layer] = ranked.into_iter().take(max).map(|c| c.node).collect();
+    }
+
+    /// Follows the most similar neighbour until none improves on the current node
+    fn greedy(&self, query: &[f32], start: usize, layer: usize) -> usize {
+        let mut current = Candidate { similarity: self.similarity(query, start), node: start };
+        loop {
+            let best = self.links[current.node][layer]
+                .iter()
+                .map(|&node| Candidate { similarity: self.similarity(query, node), node })
+                .max();
+            match best {
+                Some(best) if best > current => current = best,
+                _ => return current.node,
+            }
+        }
+    }
+
+    /// Best-first search of one layer, returning up to `ef` nodes, best first
+    fn search_layer(&self, query: &[f32], entries: &[usize], ef: usize, layer: usize) -> Vec<Candidate> {
+        let mut visited = vec![false; self.vectors.len()];
+        let mut candidates = BinaryHeap::new();
+        let mut results = BinaryHeap::new();
+
+        for &node in entries {
+            if !std::mem::replace(&mut visited[node], true) {
+                let candidate = Candidate { similarity: self.similarity(query, node), node };
+                candidates.push(candidate);
+                results.push(Reverse(candidate));
+            }
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(current) = candidates.pop() {
+            let worst = results.peek().map(|Reverse(worst): &Reverse<Candidate>| *worst);
+            if worst.is_some_and(|worst| results.len() >= ef && current < worst) {
+                break;
+            }
+
+            for &node in &self.links[current.node][layer] {
+                if std::mem::replace(&mut visited[node], true) {
+                    continue;
+                }
+                let candidate = Candidate { similarity: self.similarity(query, node), node };
+                let worst = results.peek().map(|Reverse(worst)| *worst);
+                if results.len() < ef || worst.is_some_and(|worst| candidate > worst) {
+                    candidates.push(candidate);
+                    results.push(Reverse(candidate));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec().into_iter().map(|Reverse(candidate)| candidate).collect()
+    }
+
+    /// Up to `ef` nodes most similar to `query`, best first, as
+    /// `(similarity, node)`
+    fn search(&self, query: &[f32], ef: usize) -> Vec<(f32, usize)> {
+        let (Some(entry), Some(query)) = (self.entry, normalize(query)) else {
+            return Vec::new();
+        };
+
+        let mut nearest = entry;
+        for layer in (1..=self.top_layer(entry)).rev() {
+            nearest = self.greedy(&query, nearest, layer);
+        }
+
+        self.search_layer(&query, &[nearest], ef.max(1), 0)
+            .into_iter()
+            .map(|candidate| (candidate.similarity, candidate.node))
+            .collect()
+    }
+}
+
+fn normalize(vector: &[f32]) -> Option<Vec<f32>> {
+    let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+    (norm > 0.0).then(|| vector.iter().map(|value| value / norm).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::EMBEDDING_PATH;
+
+    /// Deterministic vectors for recall checks
+    fn random_vectors(count: usize, dimensions: usize, mut seed: u64) -> Vec<Vec<f32>> {
+        (0..count)
+            .map(|_| {
+                (0..dimensions)
+                    .map(|_| {
+                        seed ^= seed << 13;
+                        seed ^= seed >> 7;
+                        seed ^= seed << 17;
+                        (seed % 2000) as f32 / 1000.0 - 1.0
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn exact_nearest(vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<usize> {
+        let mut ranked: Vec<(f64, usize)> = vectors
+            .iter()
+            .enumerate()
+            .map(|(node, vector)| {
+                let stored: Vec<f64> = vector.iter().map(|value| *value as f64).collect();
+                (rank::cosine_similarity(query, &stored).unwrap(), node)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranked.into_iter().take(k).map(|(_, node)| node).collect()
+    }
+
+    #[test]
+    fn graph_recall_matches_exact_search() {
+        let vectors = random_vectors(1_000, 24, 42);
+        let mut graph = Graph::new(M, EF_CONSTRUCTION);
+        for vector in &vectors {
+            graph.insert(vector);
+        }
+
+        let queries = random_vectors(25, 24, 7);
+        let mut found = 0;
+        for query in &queries {
+            let expected = exact_nearest(&vectors, query, 10);
+            let actual: Vec<usize> = graph.search(query, 64).into_iter().take(10).map(|(_, node)| node).collect();
+            found += expected.iter().filter(|node| actual.contains(node)).count();
+        }
+
+        let recall = found as f64 / (queries.len() * 10) as f64;
+        assert!(recall >= 0.9, "recall@10 is {}", recall);
+    }
+
+    #[test]
+    fn graph_skips_zero_vectors_and_empty_queries() {
+        let mut graph = Graph::new(M, EF_CONSTRUCTION);
+        assert_eq!(graph.insert(&[0.0, 0.0]), None);
+        assert!(graph.search(&[1.0, 0.0], 10).is_empty());
+
+        assert_eq!(graph.insert(&[1.0, 0.0]), Some(0));
+        assert_eq!(graph.search(&[0.0, 0.0], 10), Vec::new());
+        assert_eq!(graph.search(&[2.0, 0.0], 10), vec![(1.0, 0)]);
+    }
+
+    fn listing(id: i32, market: &str, embedding: [f64; 2], location: [f64; 2]) -> Document {
+        doc! {
+            "_id": id,
+            "name": format!("listing {}", id),
+            "address": { "market": market, "location": { "type": "Point", "coordinates": location.to_vec() } },
+            EMBEDDING_PATH: embedding.to_vec(),
+        }
+    }
+
+    fn search() -> HnswSearch {
+        HnswSearch::from_listings(
+            [
+                listing(1, "Porto", [1.0, 0.0], [-8.61, 41.14]),
+                listing(2, "Porto", [0.8, 0.6], [-8.62, 41.15]),
+                listing(3, "Lisbon", [0.9, 0.1], [-9.14, 38.72]),
+                doc! { "_id": 4, "name": "no embedding" },
+            ],
+            VectorField::new(EMBEDDING_PATH, "test-model", 2),
+        )
+    }
+
+    #[tokio::test]
+    async fn vector_search_filters_and_strips_embeddings() {
+        let search = search();
+        let query = VectorQuery {
+            vector: vec![1.0, 0.0],
+            filter: crate::search::SearchFilter { market: Some("Porto".to_string()), ..Default::default() },
+            projection: doc! { "_id": 1, "name": 1 },
+            limit: 5,
+            num_candidates: 1,
+        };
+
+        let hits = search.vector_search(&query).await.unwrap();
+
+        let ids: Vec<i32> = hits.iter().map(|hit| hit.listing.get_i32("_id").unwrap()).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(hits[1].listing, doc! { "_id": 2, "name": "listing 2" });
+        assert!((hits[0].score - 1.0).abs() < 1e-6);
+
+        let wrong = VectorQuery { vector: vec![1.0, 0.0, 0.0], ..query };
+        assert!(matches!(search.vector_search(&wrong).await, Err(SearchError::InvalidQuery(_))));
+    }
+
+    #[tokio::test]
+    async fn geo_search_returns_nearest_within_radius() {
+        let search = search();
+        let query = GeoQuery {
+            longitude: -8.61,
+            latitude: 41.14,
+            max_distance_m: 5_000.0,
+            filter: Default::default(),
+            projection: doc! { "name": 1 },
+            limit: 5,
+        };
+
+        let hits = search.geo_search(&query).await.unwrap();
+
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].score, 0.0);
+        assert!(hits[1].score > 0.0 && hits[1].score < 5_000.0);
+        assert_eq!(hits[1].listing, doc! { "_id": 2, "name": "listing 2" });
+    }
+
+    #[tokio::test]
+    async fn get_by_id_serves_listings_without_embeddings() {
+        let search = search();
+
+        assert_eq!(search.get_by_id(4).await.unwrap(), Some(doc! { "_id": 4, "name": "no embedding" }));
+        assert!(!search.get_by_id(1).await.unwrap().unwrap().contains_key(EMBEDDING_PATH));
+        assert_eq!(search.get_by_id(99).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn get_by_id_finds_listings_with_64_bit_ids() {
+        let search = HnswSearch::from_listings(
+            [doc! { "_id": 10084023_i64, "name": "read from JSON" }],
+            VectorField::new(EMBEDDING_PATH, "test-model", 2),
+        );
+
+        assert_eq!(search.get_by_id(10084023).await.unwrap().unwrap().get_str("name"), Ok("read from JSON"));
+    }
+}
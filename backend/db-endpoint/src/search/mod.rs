@@ -0,0 +1,452 @@
+//! Listing search behind one interface
+//!
+//! `SearchBackend` answers vector, geo, hybrid and by-id queries over the
+//! listings. `AtlasSearch` runs them as MongoDB aggregations,
+//! `BruteForceSearch` scans the collection and ranks in process for a local
+//! `mongod` without Atlas Search, and `HnswSearch` serves them from an
+//! in-memory HNSW index built at startup. Callers only hold an
+//! `Arc<dyn SearchBackend>`, so backends are swapped with `SEARCH_BACKEND`
+//! and can be benchmarked against each other on the same queries with
+//! `bench`.
+//!
+//! `PassageSearch` ranks the embedded passages of long listing texts and
+//! aggregates them back to listings, and `ExplainContext` tells why a hit
+//! matched, whichever backend found it. `Facets` counts the refinements
+//! available among a query's candidates.
+
+use crate::embeddings::VectorField;
+use futures::future::BoxFuture;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Collection;
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+mod atlas;
+#[cfg(test)]
+pub mod bench;
+mod brute_force;
+mod explain;
+mod facets;
+mod hnsw;
+mod passages;
+mod rank;
+
+pub use atlas::AtlasSearch;
+pub use brute_force::BruteForceSearch;
+pub use explain::{ExplainContext, Explanation, HIGHLIGHTS_FIELD};
+pub use facets::{FacetQuery, Facets};
+pub use hnsw::HnswSearch;
+pub use passages::{Aggregation, PassageHit, PassageQuery, PassageSearch};
+
+/// Atlas Vector Search index over `EMBEDDING_PATH`; other vector fields
+/// are indexed by `VectorField::index_name`
+pub const VECTOR_INDEX: &str = "vector_index";
+
+/// Atlas Search index over `TEXT_FIELDS`, used by hybrid queries
+pub const TEXT_INDEX: &str = "default";
+
+/// Field holding the embedding of a listing before vector fields were
+/// versioned, and the default search field
+pub const EMBEDDING_PATH: &str = "text_embeddings";
+
+/// GeoJSON point of a listing
+pub const LOCATION_PATH: &str = "address.location";
+
+/// Fields keyword matching runs over
+pub const TEXT_FIELDS: [&str; 3] = ["name", "summary", "description"];
+
+/// Fields `SearchFilter` conditions on, declared as `filter` fields of
+/// `VECTOR_INDEX`
+pub const FILTER_FIELDS: [&str; 5] = ["price", "bedrooms", "address.market", "property_type", "amenities"];
+
+/// Field backends put a hit's score in while it travels through a pipeline
+const SCORE_FIELD: &str = "search_score";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SearchError {
+    #[error("Database Error: Listing query failed. {0}")]
+    Database(#[from] mongodb::error::Error),
+
+    #[error("Invalid Query: {0}")]
+    InvalidQuery(String),
+}
+
+/// A listing matched by a query
+///
+/// # Fields
+/// * `score` - Similarity for vector and hybrid queries (higher is closer),
+///   distance in meters for geo queries (lower is closer)
+/// * `listing` - The listing, shaped by the query's projection
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub score: f64,
+    pub listing: Document,
+}
+
+/// Conditions a listing must meet, applied the same way by every backend
+///
+/// For `AtlasSearch` vector and hybrid queries the filtered fields, listed in
+/// `FILTER_FIELDS`, must be declared as `filter` fields of `VECTOR_INDEX`.
+///
+/// # Fields
+/// * `max_price` - Highest nightly `price`
+/// * `min_bedrooms` - Fewest `bedrooms`
+/// * `market` - `address.market`, e.g. `Porto`
+/// * `property_type` - e.g. `Apartment`
+/// * `amenities` - Amenities the listing must all have
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SearchFilter {
+    pub max_price: Option<f64>,
+    pub min_bedrooms: Option<i32>,
+    pub market: Option<String>,
+    pub property_type: Option<String>,
+    pub amenities: Vec<String>,
+}
+
+impl SearchFilter {
+    /// The filter as a MongoDB match expression, in the subset of operators
+    /// `$vectorSearch` also accepts
+    pub fn to_match(&self) -> Document {
+        let mut conditions = Vec::new();
+        if let Some(max_price) = self.max_price {
+            conditions.push(doc! { "price": { "$lte": max_price } });
+        }
+        if let Some(min_bedrooms) = self.min_bedrooms {
+            conditions.push(doc! { "bedrooms": { "$gte": min_bedrooms } });
+        }
+        if let Some(market) = &self.market {
+            conditions.push(doc! { "address.market": { "$eq": market } });
+        }
+        if let Some(property_type) = &self.property_type {
+            conditions.push(doc! { "property_type": { "$eq": property_type } });
+        }
+        for amenity in &self.amenities {
+            conditions.push(doc! { "amenities": { "$eq": amenity } });
+        }
+
+        match conditions.len() {
+            0 => Document::new(),
+            1 => conditions.remove(0),
+            _ => doc! { "$and": conditions },
+        }
+    }
+
+    /// Whether an in-memory listing passes, with the semantics of `to_match`
+    pub fn matches(&self, listing: &Document) -> bool {
+        let number = |field: &str| listing.get(field).and_then(rank::number);
+
+        self.max_price.is_none_or(|max| number("price").is_some_and(|price| price <= max))
+            && self.min_bedrooms.is_none_or(|min| {
+                number("bedrooms").is_some_and(|bedrooms| bedrooms >= min as f64)
+            })
+            && self.market.as_deref().is_none_or(|market| {
+                listing
+                    .get_document("address")
+                    .and_then(|address| address.get_str("market"))
+                    .is_ok_and(|value| value == market)
+            })
+            && self.property_type.as_deref().is_none_or(|property_type| {
+                listing.get_str("property_type").is_ok_and(|value| value == property_type)
+            })
+            && self.amenities.iter().all(|amenity| {
+                listing.get_array("amenities").is_ok_and(|amenities| {
+                    amenities.iter().any(|value| value.as_str() == Some(amenity.as_str()))
+                })
+            })
+    }
+}
+
+/// Nearest neighbours of an embedding
+///
+/// # Fields
+/// * `vector` - Query embedding
+/// * `filter` - Conditions candidates must meet
+/// * `projection` - Fields returned for each hit, empty for the whole listing
+/// * `limit` - Number of hits to return
+/// * `num_candidates` - Candidates approximate backends consider, at least `limit`
+#[derive(Debug, Clone)]
+pub struct VectorQuery {
+    pub vector: Vec<f32>,
+    pub filter: SearchFilter,
+    pub projection: Document,
+    pub limit: usize,
+    pub num_candidates: u32,
+}
+
+/// Listings around a point, nearest first
+///
+/// # Fields
+/// * `longitude`, `latitude` - Centre of the search, in degrees
+/// * `max_distance_m` - Radius in meters
+/// * `filter` - Conditions listings must meet
+/// * `projection` - Fields returned for each hit, empty for the whole listing
+/// * `limit` - Number of hits to return
+#[derive(Debug, Clone)]
+pub struct GeoQuery {
+    pub longitude: f64,
+    pub latitude: f64,
+    pub max_distance_m: f64,
+    pub filter: SearchFilter,
+    pub projection: Document,
+    pub limit: usize,
+}
+
+impl GeoQuery {
+    /// # Errors
+    /// * `SearchError::InvalidQuery` - Coordinates, radius or limit out of range
+    pub fn validate(&self) -> Result<(), SearchError> {
+        if !(-180.0..=180.0).contains(&self.longitude) || !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(SearchError::InvalidQuery(format!(
+                "coordinates ({}, {}) are out of range",
+                self.longitude, self.latitude
+            )));
+        }
+        if self.limit == 0 {
+            return Err(SearchError::InvalidQuery("limit must be at least 1".to_string()));
+        }
+        if self.max_distance_m.is_nan() || self.max_distance_m < 0.0 {
+            return Err(SearchError::InvalidQuery(format!(
+                "max_distance_m must not be negative, got {}",
+                self.max_distance_m
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Vector similarity and keyword matching fused with reciprocal rank fusion
+///
+/// # Fields
+/// * `vector` - Query embedding
+/// * `text` - Keywords matched against `TEXT_FIELDS`
+/// * `filter` - Conditions listings must meet
+/// * `projection` - Fields returned for each hit, empty for the whole listing;
+///   on Atlas, including `HIGHLIGHTS_FIELD` keeps the `$search` highlights
+/// * `limit` - Number of hits to return
+/// * `num_candidates` - Candidates approximate backends consider, at least `limit`
+#[derive(Debug, Clone)]
+pub struct HybridQuery {
+    pub vector: Vec<f32>,
+    pub text: String,
+    pub filter: SearchFilter,
+    pub projection: Document,
+    pub limit: usize,
+    pub num_candidates: u32,
+}
+
+/// Answers listing queries
+///
+/// Hits come back best first and shaped by the query's projection, so
+/// backends are interchangeable behind `Arc<dyn SearchBackend>`.
+pub trait SearchBackend: Debug + Send + Sync {
+    /// Short name for logs and benchmarks, e.g. `atlas`
+    fn name(&self) -> &'static str;
+
+    /// Listings closest to `query.vector` by cosine similarity
+    fn vector_search<'a>(
+        &'a self,
+        query: &'a VectorQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>>;
+
+    /// Listings within `query.max_distance_m` of a point, nearest first
+    fn geo_search<'a>(
+        &'a self,
+        query: &'a GeoQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>>;
+
+    /// Listings ranked by both embedding similarity and keyword matches
+    fn hybrid_search<'a>(
+        &'a self,
+        query: &'a HybridQuery,
+    ) -> BoxFuture<'a, Result<Vec<SearchHit>, SearchError>>;
+
+    /// One listing by `_id`, without a caller projection
+    ///
+    /// `AtlasSearch` and `BruteForceSearch` return the stored document;
+    /// `HnswSearch` returns the `LISTING_FIELDS` it keeps in memory.
+    fn get_by_id(&self, id: i32) -> BoxFuture<'_, Result<Option<Document>, SearchError>>;
+
+    /// Value counts and histograms over the `query.candidates` listings
+    /// closest to `query.vector`; counted in process unless the backend
+    /// can aggregate them itself
+    fn facets<'a>(&'a self, query: &'a FacetQuery) -> BoxFuture<'a, Result<Facets, SearchError>> {
+        Box::pin(async move {
+            let hits = self.vector_search(&query.candidate_query()).await?;
+            Ok(Facets::count(hits.iter().map(|hit| &hit.listing)))
+        })
+    }
+}
+
+/// Backend selected by `SEARCH_BACKEND`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchBackendKind {
+    /// `atlas` (default)
+    #[default]
+    Atlas,
+    /// `brute_force`
+    BruteForce,
+    /// `hnsw`
+    Hnsw,
+}
+
+impl SearchBackendKind {
+    /// Reads `SEARCH_BACKEND`, defaulting to Atlas for unset or unknown values
+    pub fn from_env() -> Self {
+        match std::env::var("SEARCH_BACKEND").as_deref() {
+            Ok("brute_force") => Self::BruteForce,
+            Ok("hnsw") => Self::Hnsw,
+            Ok("atlas") | Err(_) => Self::Atlas,
+            Ok(other) => {
+                tracing::warn!("Unknown SEARCH_BACKEND `{}`, using atlas", other);
+                Self::Atlas
+            }
+        }
+    }
+
+    /// Builds the backend searching `field` of `collection`; `Hnsw` loads
+    /// every listing and indexes it before returning
+    ///
+    /// # Errors
+    /// * `SearchError::Database` - The listings could not be loaded
+    pub async fn connect<T: Send + Sync>(
+        self,
+        collection: &Collection<T>,
+        field: &VectorField,
+    ) -> Result<Arc<dyn SearchBackend>, SearchError> {
+        let collection = collection.clone_with_type::<Document>();
+        let field = field.clone();
+        Ok(match self {
+            Self::Atlas => Arc::new(AtlasSearch::new(collection, field)),
+            Self::BruteForce => Arc::new(BruteForceSearch::new(collection, field)),
+            Self::Hnsw => Arc::new(HnswSearch::load(&collection, field).await?),
+        })
+    }
+}
+
+/// Whether a projection lists the fields to keep rather than those to drop
+fn is_inclusion(projection: &Document) -> bool {
+    projection
+        .iter()
+        .any(|(field, value)| field != "_id" && rank::is_truthy(value))
+}
+
+/// Projection to fetch with so the top-level `extra` fields are loaded
+/// whole for ranking even when the caller's projection leaves them out
+fn fetch_projection(projection: &Document, extra: &[&str]) -> Document {
+    let covered = |field: &str| {
+        extra.iter().any(|extra| field == *extra || field.starts_with(&format!("{}.", extra)))
+    };
+
+    let mut fetch: Document = projection
+        .iter()
+        .filter(|(field, _)| !covered(field))
+        .map(|(field, value)| (field.clone(), value.clone()))
+        .collect();
+    if is_inclusion(projection) {
+        for field in extra {
+            fetch.insert(*field, 1);
+        }
+    }
+    fetch
+}
+
+/// Reads a numeric score left in `SCORE_FIELD` by a pipeline
+fn take_score(listing: &mut Document) -> f64 {
+    listing
+        .remove(SCORE_FIELD)
+        .as_ref()
+        .and_then(rank::number)
+        .unwrap_or_default()
+}
+
+/// Rejects queries backends cannot answer meaningfully
+fn check_limit(limit: usize, vector: &[f32]) -> Result<(), SearchError> {
+    if limit == 0 {
+        return Err(SearchError::InvalidQuery("limit must be at least 1".to_string()));
+    }
+    if vector.is_empty() {
+        return Err(SearchError::InvalidQuery("query vector is empty".to_string()));
+    }
+    Ok(())
+}
+
+/// `_id` of a listing as a map key
+fn listing_id(listing: &Document) -> Option<Bson> {
+    listing.get("_id").cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing() -> Document {
+        doc! {
+            "_id": 1,
+            "price": 80.0,
+            "bedrooms": 3,
+            "property_type": "Apartment",
+            "amenities": ["Wifi", "Kitchen"],
+            "address": { "market": "Porto" },
+        }
+    }
+
+    #[test]
+    fn filter_matches_like_its_mongo_expression() {
+        let filter = SearchFilter {
+            max_price: Some(100.0),
+            min_bedrooms: Some(2),
+            market: Some("Porto".to_string()),
+            property_type: Some("Apartment".to_string()),
+            amenities: vec!["Wifi".to_string()],
+        };
+        assert!(filter.matches(&listing()));
+        assert_eq!(filter.to_match().get_array("$and").unwrap().len(), 5);
+
+        let too_cheap = SearchFilter { max_price: Some(50.0), ..Default::default() };
+        assert!(!too_cheap.matches(&listing()));
+        assert_eq!(too_cheap.to_match(), doc! { "price": { "$lte": 50.0 } });
+
+        let missing = SearchFilter { amenities: vec!["Pool".to_string()], ..Default::default() };
+        assert!(!missing.matches(&listing()));
+        assert!(!SearchFilter { min_bedrooms: Some(1), ..Default::default() }.matches(&doc! {}));
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = SearchFilter::default();
+        assert!(filter.matches(&doc! {}));
+        assert_eq!(filter.to_match(), Document::new());
+    }
+
+    #[test]
+    fn fetch_projection_loads_ranking_fields() {
+        let inclusion = doc! { "_id": 0, "name": 1 };
+        assert_eq!(
+            fetch_projection(&inclusion, &[EMBEDDING_PATH]),
+            doc! { "_id": 0, "name": 1, EMBEDDING_PATH: 1 }
+        );
+
+        let exclusion = doc! { EMBEDDING_PATH: 0, "reviews": 0 };
+        assert_eq!(fetch_projection(&exclusion, &[EMBEDDING_PATH]), doc! { "reviews": 0 });
+
+        let nested = doc! { "name": 1, "address.market": 1 };
+        assert_eq!(fetch_projection(&nested, &["address"]), doc! { "name": 1, "address": 1 });
+    }
+
+    #[test]
+    fn geo_query_rejects_out_of_range_coordinates() {
+        let query = GeoQuery {
+            longitude: -8.6,
+            latitude: 41.1,
+            max_distance_m: 1000.0,
+            filter: SearchFilter::default(),
+            projection: Document::new(),
+            limit: 5,
+        };
+        assert!(query.validate().is_ok());
+        assert!(GeoQuery { latitude: 91.0, ..query.clone() }.validate().is_err());
+        assert!(GeoQuery { max_distance_m: -1.0, ..query }.validate().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use super::rank::{self, TopK};
+use super::{check_limit, fetch_projection, take_score, SearchError, SearchHit, SCORE_FIELD};
+use crate::chunks::{CHUNK_EMBEDDING_PATH, CHUNK_INDEX};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Passages fetched per requested listing before aggregation
+pub const PASSAGES_PER_LISTING: usize = 8;
+
+/// Best passages of a listing `Aggregation::Sum` adds up
+pub const SUM_TOP: usize = 3;
+
+/// How the scores of a listing's passages make up its score
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    /// Best passage only
+    #[default]
+    Max,
+    /// Sum of the `SUM_TOP` best passages, favouring listings that match in
+    /// several places
+    Sum,
+}
+
+/// A passage search
+///
+/// # Fields
+/// * `vector` - Query embedding, from the model passages were embedded with
+/// * `aggregation` - How passage scores combine per listing
+/// * `projection` - Projection applied to the returned listings
+/// * `limit` - Listings to return
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassageQuery {
+    pub vector: Vec<f32>,
+    pub aggregation: Aggregation,
+    pub projection: Document,
+    pub limit: usize,
+}
+
+/// The passage a listing matched best
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Passage {
+    pub field: String,
+    pub text: String,
+    pub score: f64,
+}
+
+/// A listing found through its passages, with the best one as highlight
+///
+/// The listing serializes as relaxed extended JSON, like the results of
+/// `/search`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PassageHit {
+    pub score: f64,
+    #[serde(serialize_with = "relaxed_extjson")]
+    pub listing: Document,
+    pub highlight: Passage,
+}
+
+fn relaxed_extjson<S: serde::Serializer>(listing: &Document, serializer: S) -> Result<S::Ok, S::Error> {
+    Bson::Document(listing.clone()).into_relaxed_extjson().serialize(serializer)
+}
+
+/// Search over the passages of `CHUNK_COLLECTION`, aggregated back to
+/// listings
+///
+/// On Atlas passages are ranked by `$vectorSearch` over `CHUNK_INDEX`;
+/// otherwise every passage is scored in process, as `BruteForceSearch`
+/// does for listings.
+#[derive(Debug, Clone)]
+pub struct PassageSearch {
+    chunks: Collection<Document>,
+    listings: Collection<Document>,
+    exact: bool,
+}
+
+impl PassageSearch {
+    pub fn new<T: Send + Sync>(chunks: Collection<Document>, listings: &Collection<T>) -> Self {
+        Self { chunks, listings: listings.clone_with_type(), exact: false }
+    }
+
+    /// Scores passages in process instead of with `$vectorSearch`
+    pub fn exact(mut self) -> Self {
+        self.exact = true;
+        self
+    }
+
+    /// Listings whose passages are closest to the query
+    ///
+    /// # Errors
+    /// * `SearchError::InvalidQuery` - The limit is 0 or the vector is empty
+    /// * `SearchError::Database` - A query failed
+    pub async fn search(&self, query: &PassageQuery) -> Result<Vec<PassageHit>, SearchError> {
+        check_limit(query.limit, &query.vector)?;
+        let depth = query.limit * PASSAGES_PER_LISTING;
+        let passages = if self.exact {
+            self.scan(&query.vector, depth).await?
+        } else {
+            self.vector_search(&query.vector, depth).await?
+        };
+
+        let ranked = aggregate(passages, query.aggregation, query.limit);
+        let ids: Vec<Bson> = ranked.iter().map(|(id, _, _)| id.clone()).collect();
+        let mut listings: HashMap<String, Document> = self.listings
+            .find(doc! { "_id": { "$in": ids } })
+            .projection(fetch_projection(&query.projection, &["_id"]))
+            .await?
+            .try_collect::<Vec<Document>>()
+            .await?
+            .into_iter()
+            .filter_map(|listing| Some((listing.get("_id")?.to_string(), listing)))
+            .collect();
+
+        Ok(ranked
+            .into_iter()
+            .filter_map(|(id, score, highlight)| {
+                let listing = listings.remove(&id.to_string())?;
+                Some(PassageHit { score, listing: rank::project(listing, &query.projection), highlight })
+            })
+            .collect())
+    }
+
+    async fn vector_search(&self, vector: &[f32], depth: usize) -> Result<Vec<SearchHit>, SearchError> {
+        let stages = vec![
+            doc! {
+                "$vectorSearch": {
+                    "index": CHUNK_INDEX,
+                    "path": CHUNK_EMBEDDING_PATH,
+                    "queryVector": vector.to_vec(),
+                    "numCandidates": (depth * 10) as i64,
+                    "limit": depth as i64,
+                }
+            },
+            doc! {
+                "$project": {
+                    "listing_id": 1,
+                    "field": 1,
+                    "text": 1,
+                    SCORE_FIELD: { "$meta": "vectorSearchScore" },
+                }
+            },
+        ];
+
+        let passages: Vec<Document> = self.chunks.aggregate(stages).await?.try_collect().await?;
+        Ok(passages
+            .into_iter()
+            .map(|mut passage| SearchHit { score: take_score(&mut passage), listing: passage })
+            .collect())
+    }
+
+    async fn scan(&self, vector: &[f32], depth: usize) -> Result<Vec<SearchHit>, SearchError> {
+        let mut cursor = self.chunks
+            .find(doc! {})
+            .projection(doc! { "listing_id": 1, "field": 1, "text": 1, CHUNK_EMBEDDING_PATH: 1 })
+            .await?;
+
+        let mut top = TopK::new(depth);
+        while let Some(mut passage) = cursor.try_next().await? {
+            let Some(score) = rank::embedding(&passage, CHUNK_EMBEDDING_PATH)
+                .and_then(|embedding| rank::cosine_similarity(vector, &embedding))
+            else {
+                continue;
+            };
+            passage.remove(CHUNK_EMBEDDING_PATH);
+            top.push(SearchHit { score, listing: passage });
+        }
+        Ok(top.into_sorted())
+    }
+}
+
+/// Groups ranked passages by listing and keeps the `limit` best listings,
+/// each with its score and best passage
+pub fn aggregate(passages: Vec<SearchHit>, aggregation: Aggregation, limit: usize) -> Vec<(Bson, f64, Passage)> {
+    let mut order: Vec<String> = Vec::new();
+    let mut grouped: HashMap<String, (Bson, Vec<f64>, Passage)> = HashMap::new();
+    for SearchHit { score, listing: passage } in passages {
+        let Some(id) = passage.get("listing_id").cloned() else {
+            continue;
+        };
+        let key = id.to_string();
+        let candidate = Passage {
+            field: passage.get_str("field").unwrap_or_default().to_string(),
+            text: passage.get_str("text").unwrap_or_default().to_string(),
+            score,
+        };
+
+        match grouped.get_mut(&key) {
+            Some((_, scores, best)) => {
+                scores.push(score);
+                if score > best.score {
+                    *best = candidate;
+                }
+            }
+            None => {
+                order.push(key.clone());
+                grouped.insert(key, (id, vec![score], candidate));
+            }
+        }
+    }
+
+    let mut ranked: Vec<(Bson, f64, Passage)> = order
+        .into_iter()
+        .filter_map(|key| grouped.remove(&key))
+        .map(|(id, mut scores, best)| {
+            scores.sort_by(|a, b| b.total_cmp(a));
+            let score = match aggregation {
+                Aggregation::Max => scores[0],
+                Aggregation::Sum => scores.iter().take(SUM_TOP).sum(),
+            };
+            (id, score, best)
+        })
+        .collect();
+
+    // Stable, so ties keep the order of their best passage
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked.truncate(limit);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passage(listing_id: i32, text: &str, score: f64) -> SearchHit {
+        SearchHit { score, listing: doc! { "listing_id": listing_id, "field": "description", "text": text } }
+    }
+
+    #[test]
+    fn hits_serialize_listings_as_relaxed_extjson() {
+        let hit = PassageHit {
+            score: 0.5,
+            listing: doc! { "_id": "10006546", "last_scraped": mongodb::bson::DateTime::from_millis(0) },
+            highlight: Passage { field: "space".to_string(), text: "rooftop terrace".to_string(), score: 0.5 },
+        };
+
+        let json = serde_json::to_value(&hit).unwrap();
+
+        assert_eq!(json["listing"], serde_json::json!({ "_id": "10006546", "last_scraped": { "$date": "1970-01-01T00:00:00Z" } }));
+    }
+
+    fn passages() -> Vec<SearchHit> {
+        vec![
+            passage(1, "rooftop terrace", 0.9),
+            passage(2, "quiet street", 0.8),
+            passage(2, "near the river", 0.7),
+            passage(2, "rooftop bar nearby", 0.6),
+            passage(1, "small kitchen", 0.1),
+        ]
+    }
+
+    #[test]
+    fn max_ranks_listings_by_their_best_passage() {
+        let ranked = aggregate(passages(), Aggregation::Max, 5);
+
+        let ids: Vec<Bson> = ranked.iter().map(|(id, _, _)| id.clone()).collect();
+        assert_eq!(ids, [Bson::Int32(1), Bson::Int32(2)]);
+        assert_eq!(ranked[0].2.text, "rooftop terrace");
+        assert_eq!(ranked[1].2, Passage { field: "description".to_string(), text: "quiet street".to_string(), score: 0.8 });
+    }
+
+    #[test]
+    fn sum_favours_listings_matching_in_several_passages() {
+        let ranked = aggregate(passages(), Aggregation::Sum, 1);
+
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, Bson::Int32(2));
+        assert!((ranked[0].1 - 2.1).abs() < 1e-9);
+        assert_eq!(ranked[0].2.text, "quiet street");
+    }
+}
@@ -0,0 +1,376 @@
+//! Scoring shared by the in-process backends
+
+use super::{listing_id, SearchHit, TEXT_FIELDS};
+use mongodb::bson::{Bson, Document};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Constant of reciprocal rank fusion; damps the weight of the top ranks
+pub const RRF_K: f64 = 60.0;
+
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// English words too common to say anything about a listing, Lucene's
+/// classic list; they neither score nor get highlighted
+const STOP_WORDS: [&str; 33] = [
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it", "no", "not",
+    "of", "on", "or", "such", "that", "the", "their", "then", "there", "these", "they", "this", "to", "was",
+    "will", "with",
+];
+
+/// A BSON number as `f64`, including the `Decimal128` prices of the sample data
+pub fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Double(value) => Some(*value),
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        Bson::Decimal128(value) => value.to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Whether a projection value includes its field
+pub fn is_truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(value) => *value,
+        Bson::Null => false,
+        value => number(value).is_none_or(|value| value != 0.0),
+    }
+}
+
+/// Reads the vector a listing stores at `path`, whatever numeric type it
+/// was saved with
+pub fn embedding(listing: &Document, path: &str) -> Option<Vec<f64>> {
+    listing
+        .get_array(path)
+        .ok()?
+        .iter()
+        .map(|value| match value {
+            Bson::Double(value) => Some(*value),
+            Bson::Int32(value) => Some(*value as f64),
+            Bson::Int64(value) => Some(*value as f64),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Cosine similarity of two vectors, `None` when their lengths differ or
+/// either is all zeros
+pub fn cosine_similarity(query: &[f32], stored: &[f64]) -> Option<f64> {
+    if query.len() != stored.len() || query.is_empty() {
+        return None;
+    }
+
+    let (mut dot, mut query_norm, mut stored_norm) = (0.0, 0.0, 0.0);
+    for (a, b) in query.iter().map(|a| *a as f64).zip(stored.iter().copied()) {
+        dot += a * b;
+        query_norm += a * a;
+        stored_norm += b * b;
+    }
+
+    if query_norm == 0.0 || stored_norm == 0.0 {
+        return None;
+    }
+    Some(dot / (query_norm.sqrt() * stored_norm.sqrt()))
+}
+
+/// `[longitude, latitude]` of a listing's `address.location`
+pub fn location(listing: &Document) -> Option<(f64, f64)> {
+    let coordinates = listing
+        .get_document("address")
+        .and_then(|address| address.get_document("location"))
+        .and_then(|location| location.get_array("coordinates"))
+        .ok()?;
+
+    match coordinates.as_slice() {
+        [longitude, latitude] => Some((number(longitude)?, number(latitude)?)),
+        _ => None,
+    }
+}
+
+/// Great-circle distance in meters between two `(longitude, latitude)` points
+pub fn haversine_m(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lon1, lat1) = (from.0.to_radians(), from.1.to_radians());
+    let (lon2, lat2) = (to.0.to_radians(), to.1.to_radians());
+
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// Lowercased words of `text`, without `STOP_WORDS`
+pub(crate) fn terms(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .filter(|term| !STOP_WORDS.contains(&term.as_str()))
+        .collect()
+}
+
+/// Share of the query's terms found in the listing's `TEXT_FIELDS`, 0 when
+/// none match
+pub fn keyword_score(text: &str, listing: &Document) -> f64 {
+    let wanted = terms(text);
+    if wanted.is_empty() {
+        return 0.0;
+    }
+
+    let found: HashSet<String> = TEXT_FIELDS
+        .iter()
+        .filter_map(|field| listing.get_str(field).ok())
+        .flat_map(terms)
+        .collect();
+    wanted.intersection(&found).count() as f64 / wanted.len() as f64
+}
+
+/// Applies a MongoDB projection in process
+///
+/// Supports inclusion and exclusion of top-level fields; a dotted path is
+/// applied to its top-level field.
+pub fn project(listing: Document, projection: &Document) -> Document {
+    if projection.is_empty() {
+        return listing;
+    }
+
+    let top_level = |field: &str| field.split('.').next().unwrap_or(field).to_string();
+    let keep_id = projection.get("_id").is_none_or(is_truthy);
+
+    if super::is_inclusion(projection) {
+        let included: HashSet<String> = projection
+            .iter()
+            .filter(|(field, value)| *field != "_id" && is_truthy(value))
+            .map(|(field, _)| top_level(field))
+            .collect();
+        listing
+            .into_iter()
+            .filter(|(field, _)| included.contains(field) || (field == "_id" && keep_id))
+            .collect()
+    } else {
+        let excluded: HashSet<String> = projection
+            .iter()
+            .filter(|(field, value)| *field != "_id" && !is_truthy(value))
+            .map(|(field, _)| top_level(field))
+            .collect();
+        listing
+            .into_iter()
+            .filter(|(field, _)| !excluded.contains(field) && (field != "_id" || keep_id))
+            .collect()
+    }
+}
+
+/// Merges rankings with reciprocal rank fusion, keyed by `_id`; listings
+/// without one are dropped
+///
+/// A listing scores `sum(1 / (RRF_K + rank))` over the rankings it appears
+/// in, ranks starting at 1. Fields a later ranking loaded that an earlier
+/// one did not, such as text highlights, are added to the fused listing.
+pub fn fuse(rankings: Vec<Vec<SearchHit>>, limit: usize) -> Vec<SearchHit> {
+    let mut fused: HashMap<String, SearchHit> = HashMap::new();
+    let mut order = Vec::new();
+
+    for ranking in rankings {
+        for (rank, hit) in ranking.into_iter().enumerate() {
+            let Some(id) = listing_id(&hit.listing) else {
+                continue;
+            };
+            let score = 1.0 / (RRF_K + rank as f64 + 1.0);
+            let key = id.to_string();
+            fused
+                .entry(key.clone())
+                .and_modify(|existing| {
+                    existing.score += score;
+                    for (field, value) in &hit.listing {
+                        if !existing.listing.contains_key(field) {
+                            existing.listing.insert(field.clone(), value.clone());
+                        }
+                    }
+                })
+                .or_insert_with(|| {
+                    order.push(key);
+                    SearchHit { score, listing: hit.listing }
+                });
+        }
+    }
+
+    let mut top = TopK::new(limit);
+    for key in order {
+        if let Some(hit) = fused.remove(&key) {
+            top.push(hit);
+        }
+    }
+    top.into_sorted()
+}
+
+/// A hit ranked by score; ties keep the order they were seen in
+struct Ranked {
+    seen: usize,
+    hit: SearchHit,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.hit.score
+            .total_cmp(&other.hit.score)
+            .then_with(|| other.seen.cmp(&self.seen))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+/// Keeps the `limit` highest-scoring hits of a stream in a min-heap
+pub struct TopK {
+    limit: usize,
+    seen: usize,
+    heap: BinaryHeap<Reverse<Ranked>>,
+}
+
+impl TopK {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, seen: 0, heap: BinaryHeap::with_capacity(limit + 1) }
+    }
+
+    pub fn push(&mut self, hit: SearchHit) {
+        self.heap.push(Reverse(Ranked { seen: self.seen, hit }));
+        self.seen += 1;
+        if self.heap.len() > self.limit {
+            self.heap.pop();
+        }
+    }
+
+    /// Hits, best first
+    pub fn into_sorted(self) -> Vec<SearchHit> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ranked)| ranked.hit)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::EMBEDDING_PATH;
+    use mongodb::bson::{doc, Decimal128};
+
+    fn hit(id: i32, score: f64) -> SearchHit {
+        SearchHit { score, listing: doc! { "_id": id } }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<i32> {
+        hits.iter().map(|hit| hit.listing.get_i32("_id").unwrap()).collect()
+    }
+
+    #[test]
+    fn cosine_similarity_ranges_from_opposite_to_identical() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), Some(1.0));
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]), Some(0.0));
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]), Some(-1.0));
+    }
+
+    #[test]
+    fn cosine_similarity_skips_mismatched_and_zero_vectors() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0, 0.0, 0.0]), None);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 0.0]), None);
+        assert_eq!(cosine_similarity(&[], &[]), None);
+    }
+
+    #[test]
+    fn embedding_accepts_any_numeric_type() {
+        let listing = doc! { EMBEDDING_PATH: [0.5, 1_i32, 2_i64] };
+        assert_eq!(embedding(&listing, EMBEDDING_PATH), Some(vec![0.5, 1.0, 2.0]));
+
+        let listing = doc! { EMBEDDING_PATH: [0.5, "x"] };
+        assert_eq!(embedding(&listing, EMBEDDING_PATH), None);
+        assert_eq!(embedding(&doc! {}, EMBEDDING_PATH), None);
+    }
+
+    #[test]
+    fn number_reads_decimal_prices() {
+        let price: Decimal128 = "181.00".parse().unwrap();
+        assert_eq!(number(&Bson::Decimal128(price)), Some(181.0));
+        assert_eq!(number(&Bson::String("181".to_string())), None);
+    }
+
+    #[test]
+    fn haversine_measures_known_distance() {
+        // Porto to Lisbon is about 274 km
+        let distance = haversine_m((-8.6110, 41.1496), (-9.1393, 38.7223));
+        assert!((distance - 274_000.0).abs() < 2_000.0, "{}", distance);
+        assert_eq!(haversine_m((10.0, 10.0), (10.0, 10.0)), 0.0);
+    }
+
+    #[test]
+    fn location_reads_geojson_point() {
+        let listing = doc! {
+            "address": { "location": { "type": "Point", "coordinates": [-8.61, 41.14] } }
+        };
+        assert_eq!(location(&listing), Some((-8.61, 41.14)));
+        assert_eq!(location(&doc! { "address": {} }), None);
+    }
+
+    #[test]
+    fn keyword_score_counts_matched_terms() {
+        let listing = doc! { "name": "Ribeira Charming Duplex", "summary": "Historic area of Porto." };
+        assert_eq!(keyword_score("duplex in Porto", &listing), 1.0);
+        assert_eq!(keyword_score("duplex near Porto", &listing), 2.0 / 3.0);
+        assert_eq!(keyword_score("beach", &listing), 0.0);
+        assert_eq!(keyword_score("the", &listing), 0.0);
+        assert_eq!(keyword_score("  ", &listing), 0.0);
+    }
+
+    #[test]
+    fn project_includes_and_excludes_top_level_fields() {
+        let listing = doc! { "_id": 1, "name": "a", "price": 10, "address": { "market": "Porto" } };
+
+        assert_eq!(
+            project(listing.clone(), &doc! { "_id": 0, "name": 1, "address.market": 1 }),
+            doc! { "name": "a", "address": { "market": "Porto" } }
+        );
+        assert_eq!(project(listing.clone(), &doc! { "price": 0 }), doc! { "_id": 1, "name": "a", "address": { "market": "Porto" } });
+        assert_eq!(project(listing.clone(), &doc! {}), listing);
+    }
+
+    #[test]
+    fn fuse_rewards_listings_ranked_by_both() {
+        let vector = vec![hit(1, 0.9), hit(2, 0.8), hit(3, 0.7)];
+        let text = vec![hit(3, 1.0), hit(4, 0.5)];
+
+        let fused = fuse(vec![vector, text], 3);
+
+        assert_eq!(ids(&fused), [3, 1, 2]);
+        assert_eq!(fused[0].score, 1.0 / 63.0 + 1.0 / 61.0);
+    }
+
+    #[test]
+    fn fuse_keeps_fields_only_a_later_ranking_loaded() {
+        let vector = vec![SearchHit { score: 0.9, listing: doc! { "_id": 1, "name": "a" } }];
+        let text = vec![SearchHit { score: 2.0, listing: doc! { "_id": 1, "name": "b", "search_highlights": [] } }];
+
+        let fused = fuse(vec![vector, text], 1);
+
+        assert_eq!(fused[0].listing, doc! { "_id": 1, "name": "a", "search_highlights": [] });
+    }
+
+    #[test]
+    fn top_k_keeps_best_hits_in_order() {
+        let mut top = TopK::new(2);
+        for (id, score) in [(1, 0.1), (2, 0.9), (3, 0.5), (4, 0.9)] {
+            top.push(hit(id, score));
+        }
+
+        assert_eq!(ids(&top.into_sorted()), [2, 4]);
+    }
+}
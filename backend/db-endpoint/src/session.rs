@@ -0,0 +1,270 @@
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::bson::{self, doc, DateTime};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use crate::openai::libs::{Message, ToolCall};
+use crate::openai::usage::TokenUsage;
+
+/// Session lifetime used when `SESSION_TTL_HOURS` is not set
+pub const DEFAULT_SESSION_TTL_HOURS: i64 = 72;
+
+/// One turn of a stored conversation
+///
+/// # Fields
+/// * `message` - The chat message as sent to or received from the model
+/// * `tool_calls` - Optional - Tool calls requested by the assistant in this turn
+/// * `listing_ids` - Listings referenced by this turn (search results, comparisons)
+/// * `usage` - Optional - Tokens spent producing this turn
+/// * `created_at` - RFC 3339 timestamp, set by the server when appended
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionMessage {
+    #[serde(flatten)]
+    pub message: Message,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+
+    #[serde(default)]
+    pub listing_ids: Vec<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+
+    #[serde(default)]
+    pub created_at: String,
+}
+
+/// A multi-turn conversation stored in MongoDB
+///
+/// Documents expire through a TTL index on `expires_at`, which is pushed
+/// forward every time the session is appended to.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConversationSession {
+    #[serde(rename = "_id")]
+    pub id: String,
+
+    pub caller: String,
+
+    pub messages: Vec<SessionMessage>,
+
+    /// Every listing referenced in the conversation, without duplicates
+    pub listing_ids: Vec<i32>,
+
+    pub usage: TokenUsage,
+
+    pub created_at: String,
+
+    pub updated_at: String,
+
+    pub expires_at: DateTime,
+}
+
+/// Session as returned by the HTTP API, with `expires_at` as RFC 3339
+#[derive(Debug, Serialize, Clone)]
+pub struct SessionView {
+    pub id: String,
+    pub caller: String,
+    pub messages: Vec<SessionMessage>,
+    pub listing_ids: Vec<i32>,
+    pub usage: TokenUsage,
+    pub created_at: String,
+    pub updated_at: String,
+    pub expires_at: String,
+}
+
+impl From<ConversationSession> for SessionView {
+    fn from(session: ConversationSession) -> Self {
+        Self {
+            id: session.id,
+            caller: session.caller,
+            messages: session.messages,
+            listing_ids: session.listing_ids,
+            usage: session.usage,
+            created_at: session.created_at,
+            updated_at: session.updated_at,
+            expires_at: session.expires_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Session without its messages, used by the list endpoint
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionSummary {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub caller: String,
+    pub message_count: i64,
+    pub listing_ids: Vec<i32>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AppendMessages {
+    pub messages: Vec<SessionMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListSessionsParams {
+    pub limit: Option<i64>,
+}
+
+/// CRUD access to the `sessions` collection
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    collection: Collection<ConversationSession>,
+    ttl: Duration,
+}
+
+impl SessionStore {
+    pub fn new(collection: Collection<ConversationSession>, ttl_hours: i64) -> Self {
+        Self {
+            collection,
+            ttl: Duration::hours(ttl_hours),
+        }
+    }
+
+    /// Creates the TTL index that removes sessions once `expires_at` has passed
+    pub async fn ensure_indexes(&self) -> mongodb::error::Result<()> {
+        let ttl_index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder()
+                .expire_after(std::time::Duration::from_secs(0))
+                .build())
+            .build();
+        let caller_index = IndexModel::builder()
+            .keys(doc! { "caller": 1, "updated_at": -1 })
+            .build();
+
+        self.collection.create_indexes([ttl_index, caller_index]).await?;
+        Ok(())
+    }
+
+    fn expires_at(&self) -> DateTime {
+        DateTime::from_millis((Utc::now() + self.ttl).timestamp_millis())
+    }
+
+    pub async fn create(&self, caller: &str) -> mongodb::error::Result<ConversationSession> {
+        let now = Utc::now().to_rfc3339();
+        let session = ConversationSession {
+            id: uuid::Uuid::new_v4().to_string(),
+            caller: caller.to_string(),
+            messages: vec![],
+            listing_ids: vec![],
+            usage: TokenUsage::default(),
+            created_at: now.clone(),
+            updated_at: now,
+            expires_at: self.expires_at(),
+        };
+
+        self.collection.insert_one(&session).await?;
+        Ok(session)
+    }
+
+    /// Loads one of the caller's sessions; other callers' sessions are not found
+    pub async fn get(&self, id: &str, caller: &str) -> mongodb::error::Result<Option<ConversationSession>> {
+        self.collection.find_one(owned_by(id, caller)).await
+    }
+
+    /// Lists the caller's sessions, most recently updated first
+    pub async fn list(
+        &self,
+        caller: &str,
+        limit: i64,
+    ) -> mongodb::error::Result<Vec<SessionSummary>> {
+        let pipeline = vec![
+            doc! { "$match": { "caller": caller } },
+            doc! { "$sort": { "updated_at": -1 } },
+            doc! { "$limit": limit },
+            doc! {
+                "$project": {
+                    "caller": 1,
+                    "message_count": { "$size": "$messages" },
+                    "listing_ids": 1,
+                    "created_at": 1,
+                    "updated_at": 1,
+                }
+            },
+        ];
+
+        let documents: Vec<bson::Document> = self.collection
+            .aggregate(pipeline)
+            .await?
+            .try_collect()
+            .await?;
+
+        documents
+            .into_iter()
+            .map(|document| bson::from_document(document).map_err(Into::into))
+            .collect()
+    }
+
+    /// Appends turns to one of the caller's sessions, merging their listing
+    /// ids and usage into the session totals and extending its expiry
+    pub async fn append(
+        &self,
+        id: &str,
+        caller: &str,
+        mut messages: Vec<SessionMessage>,
+    ) -> mongodb::error::Result<Option<ConversationSession>> {
+        let now = Utc::now().to_rfc3339();
+        let mut usage = TokenUsage::default();
+        let mut listing_ids: Vec<i32> = vec![];
+
+        for turn in messages.iter_mut() {
+            turn.created_at = now.clone();
+            if let Some(turn_usage) = &turn.usage {
+                usage.add(turn_usage);
+            }
+            listing_ids.extend(turn.listing_ids.iter().copied());
+        }
+
+        let update = doc! {
+            "$push": { "messages": { "$each": bson::to_bson(&messages)? } },
+            "$addToSet": { "listing_ids": { "$each": listing_ids } },
+            "$inc": {
+                "usage.prompt_tokens": usage.prompt_tokens as i64,
+                "usage.completion_tokens": usage.completion_tokens as i64,
+                "usage.reasoning_tokens": usage.reasoning_tokens as i64,
+                "usage.cached_tokens": usage.cached_tokens as i64,
+            },
+            "$set": {
+                "updated_at": now,
+                "expires_at": self.expires_at(),
+            },
+        };
+
+        self.collection
+            .find_one_and_update(owned_by(id, caller), update)
+            .return_document(ReturnDocument::After)
+            .await
+    }
+
+    /// Deletes one of the caller's sessions, returning whether it existed
+    pub async fn delete(&self, id: &str, caller: &str) -> mongodb::error::Result<bool> {
+        let result = self.collection.delete_one(owned_by(id, caller)).await?;
+        Ok(result.deleted_count > 0)
+    }
+}
+
+/// Filter matching session `id` only when `caller` owns it
+fn owned_by(id: &str, caller: &str) -> bson::Document {
+    doc! { "_id": id, "caller": caller }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_looked_up_together_with_their_owner() {
+        let filter = owned_by("3f2a", "web");
+
+        assert_eq!(filter, doc! { "_id": "3f2a", "caller": "web" });
+        assert_ne!(filter, owned_by("3f2a", "mobile"));
+    }
+}
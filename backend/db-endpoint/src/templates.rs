@@ -0,0 +1,225 @@
+use crate::openai::models::context_window;
+use crate::openai::tokens::{count_tokens, truncate_to_tokens};
+use mongodb::bson::{Bson, Document};
+use serde::Serialize;
+
+/// Template of the legacy `text_embeddings` field: name, summary and
+/// description, unlabelled
+pub const LISTING_V1: EmbeddingTemplate = EmbeddingTemplate {
+    id: "listing_v1",
+    document_type: "listing",
+    separator: "\n\n",
+    sections: &[
+        Section { label: None, paths: &["name"] },
+        Section { label: None, paths: &["summary"] },
+        Section { label: None, paths: &["description"] },
+    ],
+};
+
+/// Labelled listing text; short facts come first so truncation only ever
+/// cuts into the long free-text sections
+pub const LISTING_V2: EmbeddingTemplate = EmbeddingTemplate {
+    id: "listing_v2",
+    document_type: "listing",
+    separator: "\n",
+    sections: &[
+        Section { label: Some("Name"), paths: &["name"] },
+        Section { label: Some("Type"), paths: &["property_type", "room_type"] },
+        Section { label: Some("Market"), paths: &["address.market"] },
+        Section { label: Some("Amenities"), paths: &["amenities"] },
+        Section { label: Some("Summary"), paths: &["summary"] },
+        Section { label: Some("Space"), paths: &["space"] },
+        Section { label: Some("Neighborhood"), paths: &["neighborhood_overview"] },
+    ],
+};
+
+/// Every template a vector field can name
+pub const TEMPLATES: [EmbeddingTemplate; 2] = [LISTING_V1, LISTING_V2];
+
+/// One part of the rendered text
+///
+/// # Fields
+/// * `label` - Prefix written as `label: value`, `None` writes the value alone
+/// * `paths` - Dotted paths whose values are joined with `, `; arrays of
+///   strings are joined the same way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Section {
+    pub label: Option<&'static str>,
+    pub paths: &'static [&'static str],
+}
+
+/// How a document is rendered into the text its embedding is computed from
+///
+/// The template id is stored with every vector, so changing what a field
+/// embeds means declaring a new template and migrating into a new field
+/// rather than editing one in place.
+///
+/// # Fields
+/// * `id` - Name vector fields refer to, e.g. `listing_v2`
+/// * `document_type` - Kind of document the template reads
+/// * `separator` - Written between sections
+/// * `sections` - Parts of the text, in order; empty ones are skipped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmbeddingTemplate {
+    pub id: &'static str,
+    pub document_type: &'static str,
+    pub separator: &'static str,
+    pub sections: &'static [Section],
+}
+
+/// Text rendered for a document
+///
+/// # Fields
+/// * `text` - What is sent to the embedding model
+/// * `tokens` - Tokens of `text` with the model's tokenizer
+/// * `truncated` - The text was cut to the model's input limit
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RenderedText {
+    pub text: String,
+    pub tokens: usize,
+    pub truncated: bool,
+}
+
+impl EmbeddingTemplate {
+    /// Looks up a template by id
+    ///
+    /// # Examples
+    /// ```ignore
+    /// assert_eq!(EmbeddingTemplate::find("listing_v2"), Some(&LISTING_V2));
+    /// ```
+    pub fn find(id: &str) -> Option<&'static EmbeddingTemplate> {
+        TEMPLATES.iter().find(|template| template.id == id)
+    }
+
+    /// Top-level fields the template reads, to project and to watch
+    pub fn roots(&self) -> Vec<&'static str> {
+        let mut roots: Vec<&'static str> = Vec::new();
+        for path in self.sections.iter().flat_map(|section| section.paths) {
+            let root = path.split('.').next().unwrap_or(path);
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        roots
+    }
+
+    /// Renders `document` without any length limit
+    pub fn text(&self, document: &Document) -> String {
+        self.sections
+            .iter()
+            .filter_map(|section| {
+                let values: Vec<&str> = section.paths
+                    .iter()
+                    .flat_map(|path| strings_at(document, path))
+                    .collect();
+                if values.is_empty() {
+                    return None;
+                }
+
+                let value = values.join(", ");
+                Some(match section.label {
+                    Some(label) => format!("{}: {}", label, value),
+                    None => value,
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(self.separator)
+    }
+
+    /// Renders `document` and cuts the text to the input limit of `model`
+    pub fn render(&self, document: &Document, model: &str) -> RenderedText {
+        let text = self.text(document);
+        let limit = context_window(model) as usize;
+        let tokens = count_tokens(model, &text);
+        if tokens <= limit {
+            return RenderedText { text, tokens, truncated: false };
+        }
+
+        let text = truncate_to_tokens(model, &text, limit);
+        RenderedText { tokens: count_tokens(model, &text), text, truncated: true }
+    }
+}
+
+/// Non-blank strings at a dotted path: the string itself, or the strings of
+/// an array
+pub(crate) fn strings_at<'a>(document: &'a Document, path: &str) -> Vec<&'a str> {
+    let mut segments = path.split('.');
+    let last = segments.next_back().unwrap_or(path);
+    let mut current = document;
+    for segment in segments {
+        match current.get_document(segment) {
+            Ok(inner) => current = inner,
+            Err(_) => return Vec::new(),
+        }
+    }
+
+    let values: Vec<&str> = match current.get(last) {
+        Some(Bson::String(value)) => vec![value.as_str()],
+        Some(Bson::Array(values)) => values.iter().filter_map(Bson::as_str).collect(),
+        _ => Vec::new(),
+    };
+    values.into_iter().map(str::trim).filter(|value| !value.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn listing() -> Document {
+        doc! {
+            "name": "Ribeira Charming Duplex",
+            "summary": "Fantastic duplex apartment.",
+            "description": "Fantastic duplex apartment with three bedrooms.",
+            "space": "  ",
+            "property_type": "House",
+            "room_type": "Entire home/apt",
+            "amenities": ["TV", "Wifi", ""],
+            "address": { "market": "Porto" },
+        }
+    }
+
+    #[test]
+    fn listing_v1_keeps_the_original_text() {
+        assert_eq!(
+            LISTING_V1.text(&listing()),
+            "Ribeira Charming Duplex\n\nFantastic duplex apartment.\n\nFantastic duplex apartment with three bedrooms."
+        );
+        assert_eq!(LISTING_V1.text(&doc! {}), "");
+    }
+
+    #[test]
+    fn listing_v2_labels_sections_and_skips_blank_ones() {
+        assert_eq!(
+            LISTING_V2.text(&listing()),
+            "Name: Ribeira Charming Duplex\n\
+             Type: House, Entire home/apt\n\
+             Market: Porto\n\
+             Amenities: TV, Wifi\n\
+             Summary: Fantastic duplex apartment."
+        );
+    }
+
+    #[test]
+    fn render_truncates_to_the_model_limit() {
+        let long = doc! { "name": "Duplex", "summary": "cosy ".repeat(10_000) };
+
+        let rendered = LISTING_V2.render(&long, "text-embedding-3-small");
+
+        assert!(rendered.truncated);
+        assert!(rendered.tokens <= 8_191, "{}", rendered.tokens);
+        assert!(rendered.text.starts_with("Name: Duplex\nSummary: cosy"));
+        assert!(!LISTING_V2.render(&listing(), "text-embedding-3-small").truncated);
+    }
+
+    #[test]
+    fn roots_are_top_level_and_distinct() {
+        assert_eq!(LISTING_V1.roots(), ["name", "summary", "description"]);
+        assert_eq!(
+            LISTING_V2.roots(),
+            ["name", "property_type", "room_type", "address", "amenities", "summary", "space", "neighborhood_overview"]
+        );
+        assert_eq!(EmbeddingTemplate::find("listing_v2"), Some(&LISTING_V2));
+        assert_eq!(EmbeddingTemplate::find("listing_v9"), None);
+    }
+}